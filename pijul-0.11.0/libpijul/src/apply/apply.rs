@@ -4,7 +4,7 @@ use patch::*;
 use rand;
 use std::collections::HashSet;
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
     /// Applies a patch to a repository. "new_patches" are patches that
     /// just this repository has, and the remote repository doesn't have.
     pub fn apply(
@@ -292,9 +292,8 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
         let mut nodes = nodes.iter();
         if let Some(first_line) = nodes.next() {
             debug!("first_line = {:?}", first_line);
-            let value = self.alloc_value(&first_line)?;
-            debug!("put_contents {:?} {:?}", v, value);
-            self.put_contents(v, value)?;
+            debug!("put_contents {:?} {:?}", v, first_line);
+            self.put_contents(v, &first_line)?;
         }
         for content in nodes {
             e.dest.line = v.line + 1;
@@ -303,9 +302,8 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
             v.line = e.dest.line;
 
             if !content.is_empty() {
-                let value = self.alloc_value(&content)?;
-                debug!("put_contents {:?} {:?}", v, value);
-                self.put_contents(v, value)?;
+                debug!("put_contents {:?} {:?}", v, content);
+                self.put_contents(v, &content)?;
             }
         }
         debug!("newnodes core done");
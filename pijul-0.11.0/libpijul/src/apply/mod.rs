@@ -40,7 +40,7 @@ impl<U: Transaction, R> GenericTxn<U, R> {
     }
 }
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
     /// Assumes all patches have been downloaded. The third argument
     /// `remote_patches` needs to contain at least all the patches we
     /// want to apply, and the fourth one `local_patches` at least all
@@ -50,26 +50,13 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
         branch: &mut Branch,
         r: &Path,
         remote_patches: &[(Hash, Patch)],
-        partial_paths: P,
+        partial_paths: &P,
         mut f: F,
     ) -> Result<()>
     where
         F: FnMut(usize, &Hash),
     {
-        let (pending, local_pending) = {
-            let mut record = RecordState::new();
-            self.record(&mut record, branch, &r, None)?;
-            let (changes, local) = record.finish();
-            let mut p = UnsignedPatch::empty();
-            p.changes = changes
-                .into_iter()
-                .flat_map(|x| x.into_iter())
-                .map(|x| self.globalize_change(x))
-                .collect();
-            p.dependencies = self.dependencies(&branch, p.changes.iter());
-            (p.leave_unsigned(), local)
-        };
-
+        let (pending, local_pending) = self.pending_patch(branch, r)?;
         let mut new_patches_count = 0;
         for &(ref p, ref patch) in remote_patches.iter() {
             debug!("apply_patches: {:?}", p);
@@ -90,6 +77,26 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
         Ok(())
     }
 
+    /// The changes in the working copy `r`, not yet recorded on
+    /// `branch`, that must be kept when outputting the repository.
+    pub fn pending_patch(
+        &mut self,
+        branch: &Branch,
+        r: &Path,
+    ) -> Result<(Patch, HashSet<InodeUpdate>)> {
+        let mut record = RecordState::new();
+        self.record(&mut record, branch, &r, None)?;
+        let (changes, local) = record.finish();
+        let mut p = UnsignedPatch::empty();
+        p.changes = changes
+            .into_iter()
+            .flat_map(|x| x.into_iter())
+            .map(|x| self.globalize_change(x))
+            .collect();
+        p.dependencies = self.dependencies(&branch, p.changes.iter());
+        Ok((p.leave_unsigned(), local))
+    }
+
     /// Lower-level applier. This function only applies patches as
     /// found in `patches_dir`, following dependencies recursively. It
     /// outputs neither the repository nor the "changes file" of the
@@ -204,6 +211,7 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
         for update in inode_updates.iter() {
             self.update_inode(&branch, internal, update)?;
         }
+        self.store_blobs(working_copy, internal, patch)?;
         debug!("committing branch");
         if !is_pending {
             debug!("not pending, adding to changes");
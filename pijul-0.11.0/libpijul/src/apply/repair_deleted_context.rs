@@ -5,7 +5,7 @@ use patch::*;
 use rand;
 use std::collections::HashSet;
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
     #[doc(hidden)]
     /// Deleted contexts are conflicts. Reconnect the graph by
     /// inserting pseudo-edges alongside deleted edges.
@@ -0,0 +1,1062 @@
+//! The Sanakirja storage, used by repositories on disk.
+
+use super::*;
+use rand::Rng;
+use sanakirja;
+use sanakirja::value::{UnsafeValue, Value};
+use sanakirja::Transaction as SanakirjaTransaction;
+use sanakirja::{Commit, Representable};
+use std;
+use std::path::Path;
+use {Error, LacksSpace, Result};
+
+type SanakirjaNodesDb = sanakirja::Db<Key<PatchId>, Edge>;
+type SanakirjaPatchSet = sanakirja::Db<PatchId, ApplyTimestamp>;
+type SanakirjaRevPatchSet = sanakirja::Db<ApplyTimestamp, PatchId>;
+
+// The handles of the tables of a branch are the root pages of the
+// corresponding Sanakirja databases, which change as the tables are
+// updated.
+impl NodesDb {
+    fn db(&self) -> SanakirjaNodesDb {
+        unsafe { std::mem::transmute(self.0) }
+    }
+    fn from_db(db: SanakirjaNodesDb) -> Self {
+        NodesDb(unsafe { std::mem::transmute(db) })
+    }
+}
+
+impl PatchSet {
+    fn db(&self) -> SanakirjaPatchSet {
+        unsafe { std::mem::transmute(self.0) }
+    }
+    fn from_db(db: SanakirjaPatchSet) -> Self {
+        PatchSet(unsafe { std::mem::transmute(db) })
+    }
+}
+
+impl RevPatchSet {
+    fn db(&self) -> SanakirjaRevPatchSet {
+        unsafe { std::mem::transmute(self.0) }
+    }
+    fn from_db(db: SanakirjaRevPatchSet) -> Self {
+        RevPatchSet(unsafe { std::mem::transmute(db) })
+    }
+}
+
+pub struct Dbs {
+    /// A map of the files in the working copy.
+    tree: sanakirja::Db<self::file_id::UnsafeFileId, self::inode::Inode>,
+    /// The reverse of tree.
+    revtree: sanakirja::Db<self::inode::Inode, self::file_id::UnsafeFileId>,
+    /// A map from inodes (in tree) to keys in branches.
+    inodes: sanakirja::Db<self::inode::Inode, self::file_header::FileHeader>,
+    /// The reverse of inodes, minus the header.
+    revinodes: sanakirja::Db<self::key::Key<PatchId>, self::inode::Inode>,
+    /// Text contents of keys.
+    contents: sanakirja::Db<self::key::Key<PatchId>, sanakirja::value::UnsafeValue>,
+    /// A map from external patch hashes to internal ids.
+    internal: sanakirja::Db<self::hash::UnsafeHash, self::patch_id::PatchId>,
+    /// The reverse of internal.
+    external: sanakirja::Db<self::patch_id::PatchId, self::hash::UnsafeHash>,
+    /// A reverse map of patch dependencies, i.e. (k,v) is in this map
+    /// means that v depends on k.
+    revdep: sanakirja::Db<self::patch_id::PatchId, self::patch_id::PatchId>,
+    /// A map from branch names to graphs.
+    branches: sanakirja::Db<
+        self::small_string::UnsafeSmallStr,
+        (
+            SanakirjaNodesDb,
+            SanakirjaPatchSet,
+            SanakirjaRevPatchSet,
+            u64,
+        ),
+    >,
+    /// A map of edges to patches that remove them.
+    cemetery: sanakirja::Db<(self::key::Key<PatchId>, self::edge::Edge), self::patch_id::PatchId>,
+    /// Dependencies
+    dep: sanakirja::Db<self::patch_id::PatchId, self::patch_id::PatchId>,
+    /// Files touched by patches.
+    touched_files: sanakirja::Db<self::key::Key<PatchId>, self::patch_id::PatchId>,
+    /// Partial checkouts: branch -> partial
+    partials: sanakirja::Db<self::small_string::UnsafeSmallStr, self::key::Key<PatchId>>,
+    /// Contents shared between keys. `None` in immutable transactions
+    /// on repositories created before these tables existed.
+    shared: Option<SharedContents>,
+}
+
+/// Large contents, such as the chunks of binary files, are stored
+/// once for all the keys that have them, in the tables below, instead
+/// of the `contents` table.
+struct SharedContents {
+    /// Contents, indexed by their hash.
+    contents: sanakirja::Db<self::hash::UnsafeHash, sanakirja::value::UnsafeValue>,
+    /// The hash of the contents of each key.
+    keys: sanakirja::Db<self::key::Key<PatchId>, self::hash::UnsafeHash>,
+    /// The reverse of keys: the keys that have each contents.
+    refs: sanakirja::Db<self::hash::UnsafeHash, self::key::Key<PatchId>>,
+}
+
+/// Contents at least this long (the size of the smallest chunk of a
+/// binary file) are shared between keys.
+const MIN_SHARED_CONTENTS: usize = 1 << 11;
+
+/// The tables of a repository stored in a Sanakirja database, as
+/// seen by transaction `T`.
+pub struct Pristine<T> {
+    #[doc(hidden)]
+    pub txn: T,
+    dbs: Dbs,
+}
+
+/// A mutable transaction on a repository.
+pub type MutTxn<'env, R> = GenericTxn<Pristine<sanakirja::MutTxn<'env, ()>>, R>;
+/// An immutable transaction on a repository.
+pub type Txn<'env> = GenericTxn<Pristine<sanakirja::Txn<'env>>, ()>;
+
+/// A repository. All operations on repositories must be done via transactions.
+pub struct Repository {
+    env: sanakirja::Env,
+    config: Config,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Root {
+    Tree,
+    RevTree,
+    Inodes,
+    RevInodes,
+    Contents,
+    Internal,
+    External,
+    RevDep,
+    Branches,
+    Cemetery,
+    TouchedFiles,
+    Dep,
+    RevTouchedFiles,
+    Partials,
+    SharedContents,
+    SharedKeys,
+    SharedRefs,
+}
+
+trait OpenDb: SanakirjaTransaction {
+    fn open_db<K: Representable, V: Representable>(
+        &mut self,
+        num: Root,
+    ) -> Result<sanakirja::Db<K, V>> {
+        if let Some(db) = self.root(num as usize) {
+            Ok(db)
+        } else {
+            Err(Error::NoDb(num))
+        }
+    }
+
+    /// Open a table that older repositories might not have.
+    fn open_optional_db<K: Representable, V: Representable>(
+        &mut self,
+        num: Root,
+    ) -> Result<Option<sanakirja::Db<K, V>>> {
+        Ok(self.root(num as usize))
+    }
+}
+
+impl<'a, T> OpenDb for sanakirja::MutTxn<'a, T> {
+    fn open_db<K: Representable, V: Representable>(
+        &mut self,
+        num: Root,
+    ) -> Result<sanakirja::Db<K, V>> {
+        if let Some(db) = self.root(num as usize) {
+            Ok(db)
+        } else {
+            Ok(self.create_db()?)
+        }
+    }
+
+    fn open_optional_db<K: Representable, V: Representable>(
+        &mut self,
+        num: Root,
+    ) -> Result<Option<sanakirja::Db<K, V>>> {
+        Ok(Some(self.open_db(num)?))
+    }
+}
+impl<'a> OpenDb for sanakirja::Txn<'a> {}
+
+// Repositories need at least 2^5 = 32 pages, each of size 2^12.
+const MIN_REPO_SIZE: u64 = 1 << 17;
+
+// Space added to the pristine by each mutable transaction of
+// `Repository::with_mut_txn`, in addition to the caller's estimate.
+const INITIAL_SIZE_INCREASE: u64 = 409600;
+
+impl Repository {
+    #[doc(hidden)]
+    pub fn size(&self) -> u64 {
+        self.env.size()
+    }
+
+    #[doc(hidden)]
+    pub fn repository_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+        let size = sanakirja::Env::file_size(path.as_ref())?;
+        debug!("repository_size = {:?}", size);
+        Ok(size)
+    }
+
+    /// Open a repository, possibly increasing the size of the
+    /// underlying file if `size_increase` is `Some(…)`.
+    pub fn open<P: AsRef<Path>>(path: P, size_increase: Option<u64>) -> Result<Self> {
+        let size = if let Some(size) = size_increase {
+            Repository::repository_size(path.as_ref()).unwrap_or(MIN_REPO_SIZE)
+                + std::cmp::max(size, MIN_REPO_SIZE)
+        } else {
+            if let Ok(len) = Repository::repository_size(path.as_ref()) {
+                std::cmp::max(len, MIN_REPO_SIZE)
+            } else {
+                MIN_REPO_SIZE
+            }
+        };
+        Ok(Repository {
+            env: sanakirja::Env::new(path, size)?,
+            config: Config::default(),
+        })
+    }
+
+    /// Open a repository, possibly increasing the size of the
+    /// underlying file if `size_increase` is `Some(…)`.
+    pub unsafe fn open_nolock<P: AsRef<Path>>(path: P, size_increase: Option<u64>) -> Result<Self> {
+        let size = if let Some(size) = size_increase {
+            Repository::repository_size(path.as_ref()).unwrap_or(MIN_REPO_SIZE)
+                + std::cmp::max(size, MIN_REPO_SIZE)
+        } else {
+            if let Ok(len) = Repository::repository_size(path.as_ref()) {
+                std::cmp::max(len, MIN_REPO_SIZE)
+            } else {
+                MIN_REPO_SIZE
+            }
+        };
+        debug!("sanakirja::Env::new_nolock");
+        Ok(Repository {
+            env: sanakirja::Env::new_nolock(path, size)?,
+            config: Config::default(),
+        })
+    }
+
+    /// Set the configuration of the transactions started from now
+    /// on (the default one is used otherwise).
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config
+    }
+
+    /// Close a repository. It is undefined behaviour to use it afterwards.
+    pub unsafe fn close(&mut self) {
+        self.env.close()
+    }
+
+    /// Start an immutable transaction. Immutable transactions can run
+    /// concurrently.
+    pub fn txn_begin(&self) -> Result<Txn> {
+        let mut txn = self.env.txn_begin()?;
+        let dbs = Dbs::new(&mut txn)?;
+        let repo = GenericTxn {
+            txn: Pristine { txn, dbs },
+            rng: (),
+            config: self.config.clone(),
+            output: StagedOutput::default(),
+        };
+        Ok(repo)
+    }
+
+    /// Start a mutable transaction. Mutable transactions exclude each
+    /// other, but can in principle be run concurrently with immutable
+    /// transactions. In that case, the immutable transaction only
+    /// have access to the state of the repository immediately before
+    /// the mutable transaction started.
+    pub fn mut_txn_begin<R: rand::Rng>(&self, r: R) -> Result<MutTxn<R>> {
+        let mut txn = self.env.mut_txn_begin()?;
+        let dbs = Dbs::new(&mut txn)?;
+        let repo = GenericTxn {
+            txn: Pristine { txn, dbs },
+            rng: r,
+            config: self.config.clone(),
+            output: StagedOutput::default(),
+        };
+        Ok(repo)
+    }
+
+    /// Open the repository at `path`, and call `f` on a new mutable
+    /// transaction with configuration `config`, which `f` is
+    /// responsible for committing.
+    ///
+    /// If the pristine runs out of space, the transaction is dropped,
+    /// leaving the pristine as it was, and `f` is called again on a
+    /// transaction of a larger file, until it succeeds or fails for
+    /// another reason. `size_hint` is an estimate of the space `f`
+    /// needs, for instance the `size_upper_bound` of the patches it
+    /// applies, and avoids most retries.
+    ///
+    /// If `gc::compact` replaced the pristine while this process was
+    /// waiting for the transaction, the new pristine is opened
+    /// instead, since the old one is about to be deleted.
+    ///
+    /// Since `f` may be called several times, its effects outside of
+    /// the transaction must be safe to repeat. Outputting the
+    /// repository only writes the working copy when the transaction
+    /// is committed, so an attempt that runs out of space leaves it
+    /// untouched.
+    pub fn with_mut_txn<P, A, E, F>(
+        path: P,
+        config: &Config,
+        size_hint: u64,
+        mut f: F,
+    ) -> std::result::Result<A, E>
+    where
+        P: AsRef<Path>,
+        E: From<Error> + LacksSpace,
+        F: for<'env> FnMut(MutTxn<'env, rand::ThreadRng>) -> std::result::Result<A, E>,
+    {
+        let mut increase = INITIAL_SIZE_INCREASE + size_hint;
+        loop {
+            let id = pristine_id(path.as_ref());
+            let mut repo = Repository::open(path.as_ref(), Some(increase))?;
+            repo.set_config(config.clone());
+            let txn = repo.mut_txn_begin(rand::thread_rng())?;
+            if pristine_id(path.as_ref()) != id {
+                info!("the pristine was replaced, opening it again");
+                continue;
+            }
+            match f(txn) {
+                Err(ref e) if e.lacks_space() => {
+                    info!("pristine out of space, growing it by {:?}", increase * 2);
+                    increase *= 2
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Identifies the pristine directory at `path`, to detect that it
+/// was replaced by another one.
+#[cfg(unix)]
+fn pristine_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| m.ino())
+}
+
+#[cfg(not(unix))]
+fn pristine_id(_: &Path) -> Option<u64> {
+    None
+}
+
+impl Dbs {
+    fn new<T: OpenDb>(txn: &mut T) -> Result<Self> {
+        let external = txn.open_db(Root::External)?;
+        let branches = txn.open_db(Root::Branches)?;
+        let tree = txn.open_db(Root::Tree)?;
+        let revtree = txn.open_db(Root::RevTree)?;
+        let inodes = txn.open_db(Root::Inodes)?;
+        let revinodes = txn.open_db(Root::RevInodes)?;
+        let internal = txn.open_db(Root::Internal)?;
+        let contents = txn.open_db(Root::Contents)?;
+        let revdep = txn.open_db(Root::RevDep)?;
+        let cemetery = txn.open_db(Root::Cemetery)?;
+        let dep = txn.open_db(Root::Dep)?;
+        let touched_files = txn.open_db(Root::TouchedFiles)?;
+        let partials = txn.open_db(Root::Partials)?;
+        let shared = match (
+            txn.open_optional_db(Root::SharedContents)?,
+            txn.open_optional_db(Root::SharedKeys)?,
+            txn.open_optional_db(Root::SharedRefs)?,
+        ) {
+            (Some(contents), Some(keys), Some(refs)) => Some(SharedContents {
+                contents,
+                keys,
+                refs,
+            }),
+            _ => None,
+        };
+
+        Ok(Dbs {
+            external,
+            branches,
+            inodes,
+            tree,
+            revtree,
+            revinodes,
+            internal,
+            revdep,
+            contents,
+            cemetery,
+            dep,
+            touched_files,
+            partials,
+            shared,
+        })
+    }
+}
+
+impl<T: SanakirjaTransaction> Transaction for Pristine<T> {
+    fn get_branch(&self, name: &str) -> Option<Branch> {
+        let name = SmallString::from_str(name);
+        if let Some((db, patches, revpatches, counter)) =
+            self.txn
+                .get(&self.dbs.branches, name.as_small_str().to_unsafe(), None)
+        {
+            Some(Branch {
+                db: NodesDb::from_db(db),
+                patches: PatchSet::from_db(patches),
+                revpatches: RevPatchSet::from_db(revpatches),
+                apply_counter: counter,
+                name: name,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn iter_branches<'a>(&'a self, name: Option<&SmallStr>) -> Iter<'a, Branch> {
+        Box::new(
+            self.txn
+                .iter(&self.dbs.branches, name.map(|k| (k.to_unsafe(), None)))
+                .map(|(k, v)| unsafe {
+                    Branch {
+                        name: SmallStr::from_unsafe(k).to_owned(),
+                        db: NodesDb::from_db(v.0),
+                        patches: PatchSet::from_db(v.1),
+                        revpatches: RevPatchSet::from_db(v.2),
+                        apply_counter: v.3,
+                    }
+                }),
+        )
+    }
+
+    fn get_nodes(&self, db: &NodesDb, key: Key<PatchId>, edge: Option<Edge>) -> Option<Edge> {
+        self.txn.get(&db.db(), key, edge)
+    }
+
+    fn iter_nodes<'a>(
+        &'a self,
+        db: &NodesDb,
+        key: Option<(Key<PatchId>, Option<Edge>)>,
+    ) -> Iter<'a, (Key<PatchId>, Edge)> {
+        Box::new(self.txn.iter(&db.db(), key))
+    }
+
+    fn get_patch(&self, patches: &PatchSet, patch: PatchId) -> Option<ApplyTimestamp> {
+        self.txn.get(&patches.db(), patch, None)
+    }
+
+    fn iter_patches<'a>(
+        &'a self,
+        patches: &PatchSet,
+        key: Option<PatchId>,
+    ) -> Iter<'a, (PatchId, ApplyTimestamp)> {
+        Box::new(self.txn.iter(&patches.db(), key.map(|k| (k, None))))
+    }
+
+    fn iter_applied<'a>(
+        &'a self,
+        revpatches: &RevPatchSet,
+        key: Option<ApplyTimestamp>,
+    ) -> Iter<'a, (ApplyTimestamp, PatchId)> {
+        Box::new(self.txn.iter(&revpatches.db(), key.map(|k| (k, None))))
+    }
+
+    fn rev_iter_applied<'a>(
+        &'a self,
+        revpatches: &RevPatchSet,
+        key: Option<ApplyTimestamp>,
+    ) -> Iter<'a, (ApplyTimestamp, PatchId)> {
+        Box::new(self.txn.rev_iter(&revpatches.db(), key.map(|k| (k, None))))
+    }
+
+    fn iter_partials<'a>(&'a self, branch: &str) -> Iter<'a, (SmallStr<'a>, Key<PatchId>)> {
+        let key = SmallString::from_str(branch);
+        Box::new(
+            self.txn
+                .iter(
+                    &self.dbs.partials,
+                    Some((key.as_small_str().to_unsafe(), None)),
+                )
+                .map(|(k, v)| unsafe { (SmallStr::from_unsafe(k), v) }),
+        )
+    }
+
+    fn get_tree<'a>(&'a self, key: &FileId) -> Option<Inode> {
+        self.txn.get(&self.dbs.tree, key.to_unsafe(), None)
+    }
+
+    fn iter_tree<'a>(
+        &'a self,
+        key: Option<(&FileId, Option<Inode>)>,
+    ) -> Iter<'a, (FileId<'a>, Inode)> {
+        Box::new(
+            self.txn
+                .iter(&self.dbs.tree, key.map(|(k, v)| (k.to_unsafe(), v)))
+                .map(|(k, v)| unsafe { (FileId::from_unsafe(k), v) }),
+        )
+    }
+
+    fn get_revtree<'a>(&'a self, key: Inode) -> Option<FileId<'a>> {
+        self.txn
+            .get(&self.dbs.revtree, key, None)
+            .map(|e| unsafe { FileId::from_unsafe(e) })
+    }
+
+    fn iter_revtree<'a>(
+        &'a self,
+        key: Option<(Inode, Option<&FileId>)>,
+    ) -> Iter<'a, (Inode, FileId<'a>)> {
+        Box::new(
+            self.txn
+                .iter(
+                    &self.dbs.revtree,
+                    key.map(|(k, v)| (k, v.map(|v| v.to_unsafe()))),
+                )
+                .map(|(k, v)| unsafe { (k, FileId::from_unsafe(v)) }),
+        )
+    }
+
+    fn get_inodes(&self, key: Inode) -> Option<FileHeader> {
+        self.txn.get(&self.dbs.inodes, key, None)
+    }
+
+    fn iter_inodes<'a>(
+        &'a self,
+        key: Option<(Inode, Option<FileHeader>)>,
+    ) -> Iter<'a, (Inode, FileHeader)> {
+        Box::new(self.txn.iter(&self.dbs.inodes, key))
+    }
+
+    fn get_revinodes(&self, key: Key<PatchId>) -> Option<Inode> {
+        self.txn.get(&self.dbs.revinodes, key, None)
+    }
+
+    fn iter_revinodes<'a>(
+        &'a self,
+        key: Option<(Key<PatchId>, Option<Inode>)>,
+    ) -> Iter<'a, (Key<PatchId>, Inode)> {
+        Box::new(self.txn.iter(&self.dbs.revinodes, key))
+    }
+
+    fn get_contents<'a>(&'a self, key: Key<PatchId>) -> Option<Contents<'a>> {
+        if let Some(e) = self.txn.get(&self.dbs.contents, key, None) {
+            return Some(unsafe { Value::from_unsafe(&e, &self.txn).into_cow() });
+        }
+        let shared = self.dbs.shared.as_ref()?;
+        let hash = self.txn.get(&shared.keys, key, None)?;
+        self.txn
+            .get(&shared.contents, hash, None)
+            .map(|e| unsafe { Value::from_unsafe(&e, &self.txn).into_cow() })
+    }
+
+    /// Iterate over the `contents` table, then over the shared
+    /// contents, so the keys are only in order within each part.
+    fn iter_contents<'a>(
+        &'a self,
+        key: Option<Key<PatchId>>,
+    ) -> Iter<'a, (Key<PatchId>, Contents<'a>)> {
+        let txn = &self.txn;
+        let shared = self.dbs.shared.as_ref().into_iter().flat_map(move |shared| {
+            txn.iter(&shared.keys, key.map(|k| (k, None)))
+                .filter_map(move |(k, hash)| {
+                    txn.get(&shared.contents, hash, None)
+                        .map(|v| unsafe { (k, Value::from_unsafe(&v, txn).into_cow()) })
+                })
+        });
+        Box::new(
+            self.txn
+                .iter(&self.dbs.contents, key.map(|k| (k, None)))
+                .map(move |(k, v)| unsafe { (k, Value::from_unsafe(&v, txn).into_cow()) })
+                .chain(shared),
+        )
+    }
+
+    fn get_internal(&self, key: HashRef) -> Option<PatchId> {
+        self.txn.get(&self.dbs.internal, key.to_unsafe(), None)
+    }
+
+    fn iter_internal<'a>(
+        &'a self,
+        key: Option<(HashRef, Option<PatchId>)>,
+    ) -> Iter<'a, (HashRef<'a>, PatchId)> {
+        Box::new(
+            self.txn
+                .iter(&self.dbs.internal, key.map(|(k, v)| (k.to_unsafe(), v)))
+                .map(|(k, v)| unsafe { (HashRef::from_unsafe(k), v) }),
+        )
+    }
+
+    fn get_external<'a>(&'a self, key: PatchId) -> Option<HashRef<'a>> {
+        self.txn
+            .get(&self.dbs.external, key, None)
+            .map(|e| unsafe { HashRef::from_unsafe(e) })
+    }
+
+    fn iter_external<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<HashRef>)>,
+    ) -> Iter<'a, (PatchId, HashRef<'a>)> {
+        Box::new(
+            self.txn
+                .iter(
+                    &self.dbs.external,
+                    key.map(|(k, v)| (k, v.map(|v| v.to_unsafe()))),
+                )
+                .map(|(k, v)| unsafe { (k, HashRef::from_unsafe(v)) }),
+        )
+    }
+
+    fn get_revdep(&self, patch: PatchId, dep: Option<PatchId>) -> Option<PatchId> {
+        self.txn.get(&self.dbs.revdep, patch, dep)
+    }
+
+    fn iter_revdep<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<PatchId>)>,
+    ) -> Iter<'a, (PatchId, PatchId)> {
+        Box::new(self.txn.iter(&self.dbs.revdep, key))
+    }
+
+    fn get_dep(&self, patch: PatchId, dep: Option<PatchId>) -> Option<PatchId> {
+        self.txn.get(&self.dbs.dep, patch, dep)
+    }
+
+    fn iter_dep<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<PatchId>)>,
+    ) -> Iter<'a, (PatchId, PatchId)> {
+        Box::new(self.txn.iter(&self.dbs.dep, key))
+    }
+
+    fn iter_cemetery<'a>(
+        &'a self,
+        key: Key<PatchId>,
+        edge: Edge,
+    ) -> Iter<'a, ((Key<PatchId>, Edge), PatchId)> {
+        Box::new(self.txn.iter(&self.dbs.cemetery, Some(((key, edge), None))))
+    }
+
+    fn get_touched(&self, file: Key<PatchId>, patch: PatchId) -> bool {
+        self.txn
+            .get(&self.dbs.touched_files, file, Some(patch))
+            .is_some()
+    }
+
+    fn iter_touched<'a>(&'a self, file: Key<PatchId>) -> Iter<'a, (Key<PatchId>, PatchId)> {
+        Box::new(self.txn.iter(&self.dbs.touched_files, Some((file, None))))
+    }
+}
+
+impl<'env> Pristine<sanakirja::MutTxn<'env, ()>> {
+    /// Remove `name` from the table of branches, without
+    /// decrementing the reference counts of its tables (which `del`
+    /// would do), hence the transmute.
+    ///
+    /// This would normally be wrong. The only reason it works is
+    /// because we know that dbs_branches has never been forked from
+    /// another database, hence all the reference counts to its
+    /// elements are 1 (and therefore represented as "not referenced"
+    /// in Sanakirja.
+    fn unlink<R: Rng>(&mut self, rng: &mut R, name: &SmallStr) -> Result<bool> {
+        let mut dbs_branches: sanakirja::Db<UnsafeSmallStr, (u64, u64, u64, u64)> =
+            unsafe { std::mem::transmute(self.dbs.branches) };
+        debug!("unlink, dbs_branches = {:?}", dbs_branches);
+        let deleted = self
+            .txn
+            .del(rng, &mut dbs_branches, name.to_unsafe(), None)?;
+        self.dbs.branches = unsafe { std::mem::transmute(dbs_branches) };
+        Ok(deleted)
+    }
+}
+
+impl<'env> MutTransaction for Pristine<sanakirja::MutTxn<'env, ()>> {
+    fn create_branch<R: Rng>(&mut self, _: &mut R, name: &str) -> Result<Branch> {
+        Ok(Branch {
+            db: NodesDb::from_db(self.txn.create_db()?),
+            patches: PatchSet::from_db(self.txn.create_db()?),
+            revpatches: RevPatchSet::from_db(self.txn.create_db()?),
+            name: SmallString::from_str(name),
+            apply_counter: 0,
+        })
+    }
+
+    fn fork_branch<R: Rng>(&mut self, rng: &mut R, branch: &Branch, name: &str) -> Result<Branch> {
+        Ok(Branch {
+            db: NodesDb::from_db(self.txn.fork(rng, &branch.db.db())?),
+            patches: PatchSet::from_db(self.txn.fork(rng, &branch.patches.db())?),
+            revpatches: RevPatchSet::from_db(self.txn.fork(rng, &branch.revpatches.db())?),
+            name: SmallString::from_str(name),
+            apply_counter: branch.apply_counter,
+        })
+    }
+
+    fn put_branch<R: Rng>(&mut self, rng: &mut R, branch: &Branch) -> Result<()> {
+        self.unlink(rng, &branch.name.as_small_str())?;
+        self.txn.put(
+            rng,
+            &mut self.dbs.branches,
+            branch.name.as_small_str().to_unsafe(),
+            (
+                branch.db.db(),
+                branch.patches.db(),
+                branch.revpatches.db(),
+                branch.apply_counter,
+            ),
+        )?;
+        debug!("put_branch, self.dbs.branches = {:?}", self.dbs.branches);
+        Ok(())
+    }
+
+    fn unlink_branch<R: Rng>(&mut self, rng: &mut R, name: &str) -> Result<bool> {
+        let name = SmallString::from_str(name);
+        self.unlink(rng, &name.as_small_str())
+    }
+
+    fn drop_branch<R: Rng>(&mut self, rng: &mut R, name: &str) -> Result<bool> {
+        let name = SmallString::from_str(name);
+        Ok(self.txn.del(
+            rng,
+            &mut self.dbs.branches,
+            name.as_small_str().to_unsafe(),
+            None,
+        )?)
+    }
+
+    fn put_nodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        db: &mut NodesDb,
+        key: Key<PatchId>,
+        edge: Edge,
+    ) -> Result<bool> {
+        let mut d = db.db();
+        let result = self.txn.put(rng, &mut d, key, edge)?;
+        *db = NodesDb::from_db(d);
+        Ok(result)
+    }
+
+    fn del_nodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        db: &mut NodesDb,
+        key: Key<PatchId>,
+        edge: Option<Edge>,
+    ) -> Result<bool> {
+        let mut d = db.db();
+        let result = self.txn.del(rng, &mut d, key, edge)?;
+        *db = NodesDb::from_db(d);
+        Ok(result)
+    }
+
+    fn put_patches<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        patches: &mut PatchSet,
+        patch: PatchId,
+        time: ApplyTimestamp,
+    ) -> Result<bool> {
+        let mut d = patches.db();
+        let result = self.txn.put(rng, &mut d, patch, time)?;
+        *patches = PatchSet::from_db(d);
+        Ok(result)
+    }
+
+    fn del_patches<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        patches: &mut PatchSet,
+        patch: PatchId,
+    ) -> Result<bool> {
+        let mut d = patches.db();
+        let result = self.txn.del(rng, &mut d, patch, None)?;
+        *patches = PatchSet::from_db(d);
+        Ok(result)
+    }
+
+    fn put_revpatches<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        revpatches: &mut RevPatchSet,
+        time: ApplyTimestamp,
+        patch: PatchId,
+    ) -> Result<bool> {
+        let mut d = revpatches.db();
+        let result = self.txn.put(rng, &mut d, time, patch)?;
+        *revpatches = RevPatchSet::from_db(d);
+        Ok(result)
+    }
+
+    fn del_revpatches<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        revpatches: &mut RevPatchSet,
+        time: ApplyTimestamp,
+        patch: PatchId,
+    ) -> Result<bool> {
+        let mut d = revpatches.db();
+        let result = self.txn.del(rng, &mut d, time, Some(patch))?;
+        *revpatches = RevPatchSet::from_db(d);
+        Ok(result)
+    }
+
+    fn put_partials<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        name: &str,
+        path: Key<PatchId>,
+    ) -> Result<bool> {
+        let name = SmallString::from_str(name);
+        Ok(self.txn.put(
+            rng,
+            &mut self.dbs.partials,
+            name.as_small_str().to_unsafe(),
+            path,
+        )?)
+    }
+
+    fn del_partials<R: Rng>(&mut self, rng: &mut R, name: &str) -> Result<bool> {
+        let name = SmallString::from_str(name);
+        Ok(self.txn.del(
+            rng,
+            &mut self.dbs.partials,
+            name.as_small_str().to_unsafe(),
+            None,
+        )?)
+    }
+
+    fn put_tree<R: Rng>(&mut self, rng: &mut R, key: &FileId, inode: Inode) -> Result<bool> {
+        Ok(self
+            .txn
+            .put(rng, &mut self.dbs.tree, key.to_unsafe(), inode)?)
+    }
+
+    fn del_tree<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: &FileId,
+        inode: Option<Inode>,
+    ) -> Result<bool> {
+        Ok(self
+            .txn
+            .del(rng, &mut self.dbs.tree, key.to_unsafe(), inode)?)
+    }
+
+    fn put_revtree<R: Rng>(&mut self, rng: &mut R, key: Inode, value: &FileId) -> Result<bool> {
+        Ok(self
+            .txn
+            .put(rng, &mut self.dbs.revtree, key, value.to_unsafe())?)
+    }
+
+    fn del_revtree<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Inode,
+        value: Option<&FileId>,
+    ) -> Result<bool> {
+        Ok(self.txn.del(
+            rng,
+            &mut self.dbs.revtree,
+            key,
+            value.map(|e| e.to_unsafe()),
+        )?)
+    }
+
+    fn put_inodes<R: Rng>(&mut self, rng: &mut R, key: Inode, value: FileHeader) -> Result<bool> {
+        Ok(self.txn.put(rng, &mut self.dbs.inodes, key, value)?)
+    }
+
+    fn del_inodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Inode,
+        value: Option<FileHeader>,
+    ) -> Result<bool> {
+        Ok(self.txn.del(rng, &mut self.dbs.inodes, key, value)?)
+    }
+
+    fn put_revinodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        value: Inode,
+    ) -> Result<bool> {
+        Ok(self.txn.put(rng, &mut self.dbs.revinodes, key, value)?)
+    }
+
+    fn del_revinodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        value: Option<Inode>,
+    ) -> Result<bool> {
+        Ok(self.txn.del(rng, &mut self.dbs.revinodes, key, value)?)
+    }
+
+    fn put_contents<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        value: &[u8],
+    ) -> Result<bool> {
+        if value.len() >= MIN_SHARED_CONTENTS {
+            if let Some(ref mut shared) = self.dbs.shared {
+                let digest = Hash::of_slice(value)?;
+                let hash = digest.as_ref().to_unsafe();
+                if self.txn.get(&shared.contents, hash, None).is_none() {
+                    let value = UnsafeValue::alloc_if_needed(&mut self.txn, value)?;
+                    self.txn.put(rng, &mut shared.contents, hash, value)?;
+                }
+                self.txn.put(rng, &mut shared.refs, hash, key)?;
+                return Ok(self.txn.put(rng, &mut shared.keys, key, hash)?);
+            }
+        }
+        let value = UnsafeValue::alloc_if_needed(&mut self.txn, value)?;
+        Ok(self.txn.put(rng, &mut self.dbs.contents, key, value)?)
+    }
+
+    /// Delete the contents of `key`. Shared contents are deleted
+    /// with the last key that has them.
+    fn del_contents<R: Rng>(&mut self, rng: &mut R, key: Key<PatchId>) -> Result<bool> {
+        if self.txn.del(rng, &mut self.dbs.contents, key, None)? {
+            return Ok(true);
+        }
+        if let Some(ref mut shared) = self.dbs.shared {
+            if let Some(hash) = self.txn.get(&shared.keys, key, None) {
+                self.txn.del(rng, &mut shared.refs, hash, Some(key))?;
+                if self.txn.get(&shared.refs, hash, None).is_none() {
+                    self.txn.del(rng, &mut shared.contents, hash, None)?;
+                }
+                return Ok(self.txn.del(rng, &mut shared.keys, key, None)?);
+            }
+        }
+        Ok(false)
+    }
+
+    fn put_internal<R: Rng>(&mut self, rng: &mut R, key: HashRef, value: PatchId) -> Result<bool> {
+        Ok(self
+            .txn
+            .put(rng, &mut self.dbs.internal, key.to_unsafe(), value)?)
+    }
+
+    fn del_internal<R: Rng>(&mut self, rng: &mut R, key: HashRef) -> Result<bool> {
+        Ok(self
+            .txn
+            .del(rng, &mut self.dbs.internal, key.to_unsafe(), None)?)
+    }
+
+    fn put_external<R: Rng>(&mut self, rng: &mut R, key: PatchId, value: HashRef) -> Result<bool> {
+        Ok(self
+            .txn
+            .put(rng, &mut self.dbs.external, key, value.to_unsafe())?)
+    }
+
+    fn del_external<R: Rng>(&mut self, rng: &mut R, key: PatchId) -> Result<bool> {
+        Ok(self.txn.del(rng, &mut self.dbs.external, key, None)?)
+    }
+
+    fn put_revdep<R: Rng>(&mut self, rng: &mut R, patch: PatchId, revdep: PatchId) -> Result<bool> {
+        Ok(self.txn.put(rng, &mut self.dbs.revdep, patch, revdep)?)
+    }
+
+    fn del_revdep<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        patch: PatchId,
+        revdep: Option<PatchId>,
+    ) -> Result<bool> {
+        Ok(self.txn.del(rng, &mut self.dbs.revdep, patch, revdep)?)
+    }
+
+    fn put_dep<R: Rng>(&mut self, rng: &mut R, patch: PatchId, dep: PatchId) -> Result<bool> {
+        Ok(self.txn.put(rng, &mut self.dbs.dep, patch, dep)?)
+    }
+
+    fn del_dep<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        patch: PatchId,
+        dep: Option<PatchId>,
+    ) -> Result<bool> {
+        Ok(self.txn.del(rng, &mut self.dbs.dep, patch, dep)?)
+    }
+
+    fn put_cemetery<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        edge: Edge,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self
+            .txn
+            .put(rng, &mut self.dbs.cemetery, (key, edge), patch)?)
+    }
+
+    fn del_cemetery<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        edge: Edge,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self
+            .txn
+            .del(rng, &mut self.dbs.cemetery, (key, edge), Some(patch))?)
+    }
+
+    fn put_touched_file<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        file: Key<PatchId>,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self
+            .txn
+            .put(rng, &mut self.dbs.touched_files, file, patch)?)
+    }
+
+    fn del_touched_file<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        file: Key<PatchId>,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self
+            .txn
+            .del(rng, &mut self.dbs.touched_files, file, Some(patch))?)
+    }
+
+    fn commit(mut self) -> Result<()> {
+        self.txn.set_root(Root::Tree as usize, self.dbs.tree);
+        self.txn.set_root(Root::RevTree as usize, self.dbs.revtree);
+        self.txn.set_root(Root::Inodes as usize, self.dbs.inodes);
+        self.txn
+            .set_root(Root::RevInodes as usize, self.dbs.revinodes);
+        self.txn
+            .set_root(Root::Contents as usize, self.dbs.contents);
+        self.txn
+            .set_root(Root::Internal as usize, self.dbs.internal);
+        self.txn
+            .set_root(Root::External as usize, self.dbs.external);
+        self.txn
+            .set_root(Root::Branches as usize, self.dbs.branches);
+        self.txn.set_root(Root::RevDep as usize, self.dbs.revdep);
+        self.txn
+            .set_root(Root::Cemetery as usize, self.dbs.cemetery);
+        self.txn.set_root(Root::Dep as usize, self.dbs.dep);
+        self.txn
+            .set_root(Root::TouchedFiles as usize, self.dbs.touched_files);
+        self.txn
+            .set_root(Root::Partials as usize, self.dbs.partials);
+        if let Some(ref shared) = self.dbs.shared {
+            self.txn
+                .set_root(Root::SharedContents as usize, shared.contents);
+            self.txn.set_root(Root::SharedKeys as usize, shared.keys);
+            self.txn.set_root(Root::SharedRefs as usize, shared.refs);
+        }
+        self.txn.commit()?;
+        Ok(())
+    }
+}
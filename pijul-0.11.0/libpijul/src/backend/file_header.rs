@@ -3,6 +3,7 @@
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct FileMetadata(u16);
 const DIR_BIT: u16 = 0x200;
+const SYMLINK_BIT: u16 = 0x400;
 use byteorder::ByteOrder;
 impl FileMetadata {
     /// Read the file metadata from the file name encoded in the
@@ -21,6 +22,15 @@ impl FileMetadata {
         m
     }
 
+    /// Create the metadata of a symbolic link. The target of the
+    /// link is stored as the contents of the file.
+    pub fn new_symlink() -> Self {
+        let mut m = FileMetadata(0);
+        m.set_permissions(0o777);
+        m.set_symlink();
+        m
+    }
+
     /// Permissions of this file (as in Unix).
     pub fn permissions(&self) -> u16 {
         u16::from_le(self.0) & 0x1ff
@@ -49,6 +59,17 @@ impl FileMetadata {
         let bits = u16::from_le(self.0);
         self.0 = (bits & !DIR_BIT).to_le()
     }
+
+    /// Tell whether this `FileMetadata` is a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        u16::from_le(self.0) & SYMLINK_BIT != 0
+    }
+
+    /// Set this file metadata to be a symbolic link.
+    pub fn set_symlink(&mut self) {
+        let bits = u16::from_le(self.0);
+        self.0 = (bits | SYMLINK_BIT).to_le()
+    }
 }
 
 use byteorder::{BigEndian, WriteBytesExt};
@@ -19,6 +19,17 @@ impl OwnedFileId {
     }
 }
 
+impl PartialOrd for OwnedFileId {
+    fn partial_cmp(&self, x: &OwnedFileId) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(x))
+    }
+}
+impl Ord for OwnedFileId {
+    fn cmp(&self, x: &OwnedFileId) -> std::cmp::Ordering {
+        self.as_file_id().cmp(&x.as_file_id())
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub struct FileId<'a> {
     pub parent_inode: Inode,
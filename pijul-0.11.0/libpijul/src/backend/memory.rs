@@ -0,0 +1,1017 @@
+//! A storage keeping all the tables in memory, for tests and tools
+//! that don't need to touch the disk.
+//!
+//! Mutable transactions work on a copy of the tables, which replaces
+//! the tables of the repository when the transaction is committed.
+
+use super::*;
+use rand::Rng;
+use std;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound::{Included, Unbounded};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use Result;
+
+/// A map where each key may be bound to several values.
+#[derive(Clone)]
+struct Multimap<K: Ord, V: Ord>(BTreeMap<K, BTreeSet<V>>);
+
+impl<K: Ord, V: Ord> Default for Multimap<K, V> {
+    fn default() -> Self {
+        Multimap(BTreeMap::new())
+    }
+}
+
+impl<K: Ord + Clone, V: Ord + Clone> Multimap<K, V> {
+    /// The smallest value bound to `k`, which is at least `v` if `v`
+    /// is `Some`.
+    fn get(&self, k: &K, v: Option<&V>) -> Option<&V> {
+        self.0.get(k).and_then(|vs| match v {
+            Some(v) => vs.range((Included(v), Unbounded)).next(),
+            None => vs.iter().next(),
+        })
+    }
+
+    /// Iterate over the bindings, starting from the first one at
+    /// least `from`.
+    fn iter<'a>(&'a self, from: Option<(K, Option<V>)>) -> Iter<'a, (&'a K, &'a V)> {
+        let (k, v) = if let Some(from) = from {
+            from
+        } else {
+            return Box::new(
+                self.0
+                    .iter()
+                    .flat_map(|(k, vs)| vs.iter().map(move |v| (k, v))),
+            );
+        };
+        Box::new(
+            self.0
+                .range((Included(&k), Unbounded))
+                .flat_map(move |(k_, vs)| {
+                    let values: Iter<'a, &'a V> = match v {
+                        Some(ref v) if *k_ == k => {
+                            Box::new(vs.range((Included(v.clone()), Unbounded)))
+                        }
+                        _ => Box::new(vs.iter()),
+                    };
+                    values.map(move |v| (k_, v))
+                }),
+        )
+    }
+
+    /// Iterate over the bindings in reverse order, starting from the
+    /// last one at most `from`.
+    fn rev_iter<'a>(&'a self, from: Option<(K, Option<V>)>) -> Iter<'a, (&'a K, &'a V)> {
+        let (k, v) = if let Some(from) = from {
+            from
+        } else {
+            return Box::new(
+                self.0
+                    .iter()
+                    .rev()
+                    .flat_map(|(k, vs)| vs.iter().rev().map(move |v| (k, v))),
+            );
+        };
+        Box::new(
+            self.0
+                .range((Unbounded, Included(&k)))
+                .rev()
+                .flat_map(move |(k_, vs)| {
+                    let values: Iter<'a, &'a V> = match v {
+                        Some(ref v) if *k_ == k => {
+                            Box::new(vs.range((Unbounded, Included(v.clone()))).rev())
+                        }
+                        _ => Box::new(vs.iter().rev()),
+                    };
+                    values.map(move |v| (k_, v))
+                }),
+        )
+    }
+
+    fn put(&mut self, k: K, v: V) -> bool {
+        self.0.entry(k).or_insert_with(BTreeSet::new).insert(v)
+    }
+
+    /// Delete the binding from `k` to `v`, or the smallest value
+    /// bound to `k` if `v` is `None`.
+    fn del(&mut self, k: &K, v: Option<&V>) -> bool {
+        let (deleted, empty) = if let Some(vs) = self.0.get_mut(k) {
+            let deleted = match v {
+                Some(v) => vs.remove(v),
+                None => {
+                    if let Some(v) = vs.iter().next().cloned() {
+                        vs.remove(&v)
+                    } else {
+                        false
+                    }
+                }
+            };
+            (deleted, vs.is_empty())
+        } else {
+            (false, false)
+        };
+        if empty {
+            self.0.remove(k);
+        }
+        deleted
+    }
+}
+
+/// The tables of a `Memory` repository.
+#[derive(Clone, Default)]
+pub struct MemoryTables {
+    /// Branch names, with the handles of their tables, and their
+    /// application counter.
+    branches: BTreeMap<SmallString, (NodesDb, PatchSet, RevPatchSet, u64)>,
+    nodes: HashMap<u64, Multimap<Key<PatchId>, Edge>>,
+    patches: HashMap<u64, Multimap<PatchId, ApplyTimestamp>>,
+    revpatches: HashMap<u64, Multimap<ApplyTimestamp, PatchId>>,
+    /// The next handle to allocate.
+    next_handle: u64,
+    partials: Multimap<SmallString, Key<PatchId>>,
+    tree: Multimap<OwnedFileId, Inode>,
+    revtree: Multimap<Inode, OwnedFileId>,
+    inodes: Multimap<Inode, FileHeader>,
+    revinodes: Multimap<Key<PatchId>, Inode>,
+    /// Identical contents aren't shared between keys.
+    contents: Multimap<Key<PatchId>, Vec<u8>>,
+    internal: Multimap<Hash, PatchId>,
+    external: Multimap<PatchId, Hash>,
+    revdep: Multimap<PatchId, PatchId>,
+    dep: Multimap<PatchId, PatchId>,
+    cemetery: Multimap<(Key<PatchId>, Edge), PatchId>,
+    touched_files: Multimap<Key<PatchId>, PatchId>,
+}
+
+impl MemoryTables {
+    fn handle(&mut self) -> u64 {
+        let h = self.next_handle;
+        self.next_handle += 1;
+        h
+    }
+}
+
+/// A repository stored in memory. Like `Repository`, all operations
+/// must be done via transactions.
+#[derive(Default)]
+pub struct Memory {
+    tables: Mutex<Arc<MemoryTables>>,
+    writer: Mutex<()>,
+}
+
+/// The storage of the transactions on a `Memory` repository.
+pub struct MemoryStorage<T> {
+    tables: T,
+}
+
+/// The tables being modified by a mutable transaction on a `Memory`
+/// repository, which holds the lock on that repository.
+pub struct MemoryWriter<'env> {
+    memory: &'env Memory,
+    _lock: MutexGuard<'env, ()>,
+    tables: MemoryTables,
+}
+
+impl<'env> Deref for MemoryWriter<'env> {
+    type Target = MemoryTables;
+    fn deref(&self) -> &MemoryTables {
+        &self.tables
+    }
+}
+
+impl<'env> DerefMut for MemoryWriter<'env> {
+    fn deref_mut(&mut self) -> &mut MemoryTables {
+        &mut self.tables
+    }
+}
+
+/// An immutable transaction on a `Memory` repository.
+pub type MemoryTxn = GenericTxn<MemoryStorage<Arc<MemoryTables>>, ()>;
+/// A mutable transaction on a `Memory` repository.
+pub type MemoryMutTxn<'env, R> = GenericTxn<MemoryStorage<MemoryWriter<'env>>, R>;
+
+impl Memory {
+    /// Create a new, empty repository.
+    pub fn new() -> Self {
+        Memory::default()
+    }
+
+    /// Start an immutable transaction, which sees the repository as
+    /// it was after the last committed mutable transaction.
+    pub fn txn_begin(&self) -> Result<MemoryTxn> {
+        Ok(GenericTxn {
+            txn: MemoryStorage {
+                tables: self.tables.lock().unwrap().clone(),
+            },
+            rng: (),
+            config: Config::default(),
+            output: StagedOutput::default(),
+        })
+    }
+
+    /// Start a mutable transaction. Mutable transactions exclude each
+    /// other, but can run concurrently with immutable transactions.
+    ///
+    /// The transaction works on a copy of all the tables, so
+    /// starting it takes time and memory proportional to the size of
+    /// the repository.
+    pub fn mut_txn_begin<R: Rng>(&self, r: R) -> Result<MemoryMutTxn<R>> {
+        let lock = self.writer.lock().unwrap();
+        let tables = (**self.tables.lock().unwrap()).clone();
+        Ok(GenericTxn {
+            txn: MemoryStorage {
+                tables: MemoryWriter {
+                    memory: self,
+                    _lock: lock,
+                    tables,
+                },
+            },
+            rng: r,
+            config: Config::default(),
+            output: StagedOutput::default(),
+        })
+    }
+}
+
+fn to_branch(name: &SmallString, handles: &(NodesDb, PatchSet, RevPatchSet, u64)) -> Branch {
+    Branch {
+        db: handles.0,
+        patches: handles.1,
+        revpatches: handles.2,
+        apply_counter: handles.3,
+        name: name.clone(),
+    }
+}
+
+impl<T: Deref<Target = MemoryTables>> Transaction for MemoryStorage<T> {
+    fn get_branch(&self, name: &str) -> Option<Branch> {
+        let name = SmallString::from_str(name);
+        self.tables.branches.get(&name).map(|h| to_branch(&name, h))
+    }
+
+    fn iter_branches<'a>(&'a self, name: Option<&SmallStr>) -> Iter<'a, Branch> {
+        let start = name.map(|name| name.to_owned());
+        let it = if let Some(ref start) = start {
+            self.tables.branches.range((Included(start), Unbounded))
+        } else {
+            self.tables.branches.range::<SmallString, _>(..)
+        };
+        Box::new(it.map(|(name, h)| to_branch(name, h)))
+    }
+
+    fn get_nodes(&self, db: &NodesDb, key: Key<PatchId>, edge: Option<Edge>) -> Option<Edge> {
+        self.tables
+            .nodes
+            .get(&db.0)
+            .and_then(|nodes| nodes.get(&key, edge.as_ref()).cloned())
+    }
+
+    fn iter_nodes<'a>(
+        &'a self,
+        db: &NodesDb,
+        key: Option<(Key<PatchId>, Option<Edge>)>,
+    ) -> Iter<'a, (Key<PatchId>, Edge)> {
+        if let Some(nodes) = self.tables.nodes.get(&db.0) {
+            Box::new(nodes.iter(key).map(|(k, v)| (*k, *v)))
+        } else {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    fn get_patch(&self, patches: &PatchSet, patch: PatchId) -> Option<ApplyTimestamp> {
+        self.tables
+            .patches
+            .get(&patches.0)
+            .and_then(|patches| patches.get(&patch, None).cloned())
+    }
+
+    fn iter_patches<'a>(
+        &'a self,
+        patches: &PatchSet,
+        key: Option<PatchId>,
+    ) -> Iter<'a, (PatchId, ApplyTimestamp)> {
+        if let Some(patches) = self.tables.patches.get(&patches.0) {
+            Box::new(patches.iter(key.map(|k| (k, None))).map(|(k, v)| (*k, *v)))
+        } else {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    fn iter_applied<'a>(
+        &'a self,
+        revpatches: &RevPatchSet,
+        key: Option<ApplyTimestamp>,
+    ) -> Iter<'a, (ApplyTimestamp, PatchId)> {
+        if let Some(revpatches) = self.tables.revpatches.get(&revpatches.0) {
+            Box::new(
+                revpatches
+                    .iter(key.map(|k| (k, None)))
+                    .map(|(k, v)| (*k, *v)),
+            )
+        } else {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    fn rev_iter_applied<'a>(
+        &'a self,
+        revpatches: &RevPatchSet,
+        key: Option<ApplyTimestamp>,
+    ) -> Iter<'a, (ApplyTimestamp, PatchId)> {
+        if let Some(revpatches) = self.tables.revpatches.get(&revpatches.0) {
+            Box::new(
+                revpatches
+                    .rev_iter(key.map(|k| (k, None)))
+                    .map(|(k, v)| (*k, *v)),
+            )
+        } else {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    fn iter_partials<'a>(&'a self, branch: &str) -> Iter<'a, (SmallStr<'a>, Key<PatchId>)> {
+        Box::new(
+            self.tables
+                .partials
+                .iter(Some((SmallString::from_str(branch), None)))
+                .map(|(k, v)| (k.as_small_str(), *v)),
+        )
+    }
+
+    fn get_tree<'a>(&'a self, key: &FileId) -> Option<Inode> {
+        self.tables.tree.get(&key.to_owned(), None).cloned()
+    }
+
+    fn iter_tree<'a>(
+        &'a self,
+        key: Option<(&FileId, Option<Inode>)>,
+    ) -> Iter<'a, (FileId<'a>, Inode)> {
+        Box::new(
+            self.tables
+                .tree
+                .iter(key.map(|(k, v)| (k.to_owned(), v)))
+                .map(|(k, v)| (k.as_file_id(), *v)),
+        )
+    }
+
+    fn get_revtree<'a>(&'a self, key: Inode) -> Option<FileId<'a>> {
+        self.tables.revtree.get(&key, None).map(|v| v.as_file_id())
+    }
+
+    fn iter_revtree<'a>(
+        &'a self,
+        key: Option<(Inode, Option<&FileId>)>,
+    ) -> Iter<'a, (Inode, FileId<'a>)> {
+        Box::new(
+            self.tables
+                .revtree
+                .iter(key.map(|(k, v)| (k, v.map(|v| v.to_owned()))))
+                .map(|(k, v)| (*k, v.as_file_id())),
+        )
+    }
+
+    fn get_inodes(&self, key: Inode) -> Option<FileHeader> {
+        self.tables.inodes.get(&key, None).cloned()
+    }
+
+    fn iter_inodes<'a>(
+        &'a self,
+        key: Option<(Inode, Option<FileHeader>)>,
+    ) -> Iter<'a, (Inode, FileHeader)> {
+        Box::new(self.tables.inodes.iter(key).map(|(k, v)| (*k, *v)))
+    }
+
+    fn get_revinodes(&self, key: Key<PatchId>) -> Option<Inode> {
+        self.tables.revinodes.get(&key, None).cloned()
+    }
+
+    fn iter_revinodes<'a>(
+        &'a self,
+        key: Option<(Key<PatchId>, Option<Inode>)>,
+    ) -> Iter<'a, (Key<PatchId>, Inode)> {
+        Box::new(self.tables.revinodes.iter(key).map(|(k, v)| (*k, *v)))
+    }
+
+    fn get_contents<'a>(&'a self, key: Key<PatchId>) -> Option<Contents<'a>> {
+        self.tables
+            .contents
+            .get(&key, None)
+            .map(|v| Cow::Borrowed(&v[..]))
+    }
+
+    fn iter_contents<'a>(
+        &'a self,
+        key: Option<Key<PatchId>>,
+    ) -> Iter<'a, (Key<PatchId>, Contents<'a>)> {
+        Box::new(
+            self.tables
+                .contents
+                .iter(key.map(|k| (k, None)))
+                .map(|(k, v)| (*k, Cow::Borrowed(&v[..]))),
+        )
+    }
+
+    fn get_internal(&self, key: HashRef) -> Option<PatchId> {
+        self.tables.internal.get(&key.to_owned(), None).cloned()
+    }
+
+    fn iter_internal<'a>(
+        &'a self,
+        key: Option<(HashRef, Option<PatchId>)>,
+    ) -> Iter<'a, (HashRef<'a>, PatchId)> {
+        Box::new(
+            self.tables
+                .internal
+                .iter(key.map(|(k, v)| (k.to_owned(), v)))
+                .map(|(k, v)| (k.as_ref(), *v)),
+        )
+    }
+
+    fn get_external<'a>(&'a self, key: PatchId) -> Option<HashRef<'a>> {
+        self.tables.external.get(&key, None).map(|h| h.as_ref())
+    }
+
+    fn iter_external<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<HashRef>)>,
+    ) -> Iter<'a, (PatchId, HashRef<'a>)> {
+        Box::new(
+            self.tables
+                .external
+                .iter(key.map(|(k, v)| (k, v.map(|v| v.to_owned()))))
+                .map(|(k, v)| (*k, v.as_ref())),
+        )
+    }
+
+    fn get_revdep(&self, patch: PatchId, dep: Option<PatchId>) -> Option<PatchId> {
+        self.tables.revdep.get(&patch, dep.as_ref()).cloned()
+    }
+
+    fn iter_revdep<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<PatchId>)>,
+    ) -> Iter<'a, (PatchId, PatchId)> {
+        Box::new(self.tables.revdep.iter(key).map(|(k, v)| (*k, *v)))
+    }
+
+    fn get_dep(&self, patch: PatchId, dep: Option<PatchId>) -> Option<PatchId> {
+        self.tables.dep.get(&patch, dep.as_ref()).cloned()
+    }
+
+    fn iter_dep<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<PatchId>)>,
+    ) -> Iter<'a, (PatchId, PatchId)> {
+        Box::new(self.tables.dep.iter(key).map(|(k, v)| (*k, *v)))
+    }
+
+    fn iter_cemetery<'a>(
+        &'a self,
+        key: Key<PatchId>,
+        edge: Edge,
+    ) -> Iter<'a, ((Key<PatchId>, Edge), PatchId)> {
+        Box::new(
+            self.tables
+                .cemetery
+                .iter(Some(((key, edge), None)))
+                .map(|(k, v)| (*k, *v)),
+        )
+    }
+
+    fn get_touched(&self, file: Key<PatchId>, patch: PatchId) -> bool {
+        self.tables.touched_files.get(&file, Some(&patch)).is_some()
+    }
+
+    fn iter_touched<'a>(&'a self, file: Key<PatchId>) -> Iter<'a, (Key<PatchId>, PatchId)> {
+        Box::new(
+            self.tables
+                .touched_files
+                .iter(Some((file, None)))
+                .map(|(k, v)| (*k, *v)),
+        )
+    }
+}
+
+impl<'env> MutTransaction for MemoryStorage<MemoryWriter<'env>> {
+    fn create_branch<R: Rng>(&mut self, _: &mut R, name: &str) -> Result<Branch> {
+        let (db, patches, revpatches) = (
+            self.tables.handle(),
+            self.tables.handle(),
+            self.tables.handle(),
+        );
+        self.tables.nodes.insert(db, Multimap::default());
+        self.tables.patches.insert(patches, Multimap::default());
+        self.tables
+            .revpatches
+            .insert(revpatches, Multimap::default());
+        Ok(Branch {
+            db: NodesDb(db),
+            patches: PatchSet(patches),
+            revpatches: RevPatchSet(revpatches),
+            name: SmallString::from_str(name),
+            apply_counter: 0,
+        })
+    }
+
+    fn fork_branch<R: Rng>(&mut self, _: &mut R, branch: &Branch, name: &str) -> Result<Branch> {
+        let (db, patches, revpatches) = (
+            self.tables.handle(),
+            self.tables.handle(),
+            self.tables.handle(),
+        );
+        let nodes = self
+            .tables
+            .nodes
+            .get(&branch.db.0)
+            .cloned()
+            .unwrap_or_default();
+        self.tables.nodes.insert(db, nodes);
+        let p = self
+            .tables
+            .patches
+            .get(&branch.patches.0)
+            .cloned()
+            .unwrap_or_default();
+        self.tables.patches.insert(patches, p);
+        let r = self
+            .tables
+            .revpatches
+            .get(&branch.revpatches.0)
+            .cloned()
+            .unwrap_or_default();
+        self.tables.revpatches.insert(revpatches, r);
+        Ok(Branch {
+            db: NodesDb(db),
+            patches: PatchSet(patches),
+            revpatches: RevPatchSet(revpatches),
+            name: SmallString::from_str(name),
+            apply_counter: branch.apply_counter,
+        })
+    }
+
+    fn put_branch<R: Rng>(&mut self, _: &mut R, branch: &Branch) -> Result<()> {
+        self.tables.branches.insert(
+            branch.name.clone(),
+            (
+                branch.db,
+                branch.patches,
+                branch.revpatches,
+                branch.apply_counter,
+            ),
+        );
+        Ok(())
+    }
+
+    fn unlink_branch<R: Rng>(&mut self, _: &mut R, name: &str) -> Result<bool> {
+        Ok(self
+            .tables
+            .branches
+            .remove(&SmallString::from_str(name))
+            .is_some())
+    }
+
+    fn drop_branch<R: Rng>(&mut self, _: &mut R, name: &str) -> Result<bool> {
+        if let Some((db, patches, revpatches, _)) =
+            self.tables.branches.remove(&SmallString::from_str(name))
+        {
+            self.tables.nodes.remove(&db.0);
+            self.tables.patches.remove(&patches.0);
+            self.tables.revpatches.remove(&revpatches.0);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn put_nodes<R: Rng>(
+        &mut self,
+        _: &mut R,
+        db: &mut NodesDb,
+        key: Key<PatchId>,
+        edge: Edge,
+    ) -> Result<bool> {
+        Ok(self
+            .tables
+            .nodes
+            .entry(db.0)
+            .or_insert_with(Multimap::default)
+            .put(key, edge))
+    }
+
+    fn del_nodes<R: Rng>(
+        &mut self,
+        _: &mut R,
+        db: &mut NodesDb,
+        key: Key<PatchId>,
+        edge: Option<Edge>,
+    ) -> Result<bool> {
+        Ok(self
+            .tables
+            .nodes
+            .get_mut(&db.0)
+            .map(|nodes| nodes.del(&key, edge.as_ref()))
+            .unwrap_or(false))
+    }
+
+    fn put_patches<R: Rng>(
+        &mut self,
+        _: &mut R,
+        patches: &mut PatchSet,
+        patch: PatchId,
+        time: ApplyTimestamp,
+    ) -> Result<bool> {
+        Ok(self
+            .tables
+            .patches
+            .entry(patches.0)
+            .or_insert_with(Multimap::default)
+            .put(patch, time))
+    }
+
+    fn del_patches<R: Rng>(
+        &mut self,
+        _: &mut R,
+        patches: &mut PatchSet,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self
+            .tables
+            .patches
+            .get_mut(&patches.0)
+            .map(|patches| patches.del(&patch, None))
+            .unwrap_or(false))
+    }
+
+    fn put_revpatches<R: Rng>(
+        &mut self,
+        _: &mut R,
+        revpatches: &mut RevPatchSet,
+        time: ApplyTimestamp,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self
+            .tables
+            .revpatches
+            .entry(revpatches.0)
+            .or_insert_with(Multimap::default)
+            .put(time, patch))
+    }
+
+    fn del_revpatches<R: Rng>(
+        &mut self,
+        _: &mut R,
+        revpatches: &mut RevPatchSet,
+        time: ApplyTimestamp,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self
+            .tables
+            .revpatches
+            .get_mut(&revpatches.0)
+            .map(|revpatches| revpatches.del(&time, Some(&patch)))
+            .unwrap_or(false))
+    }
+
+    fn put_partials<R: Rng>(&mut self, _: &mut R, name: &str, path: Key<PatchId>) -> Result<bool> {
+        Ok(self.tables.partials.put(SmallString::from_str(name), path))
+    }
+
+    fn del_partials<R: Rng>(&mut self, _: &mut R, name: &str) -> Result<bool> {
+        Ok(self.tables.partials.del(&SmallString::from_str(name), None))
+    }
+
+    fn put_tree<R: Rng>(&mut self, _: &mut R, key: &FileId, inode: Inode) -> Result<bool> {
+        Ok(self.tables.tree.put(key.to_owned(), inode))
+    }
+
+    fn del_tree<R: Rng>(&mut self, _: &mut R, key: &FileId, inode: Option<Inode>) -> Result<bool> {
+        Ok(self.tables.tree.del(&key.to_owned(), inode.as_ref()))
+    }
+
+    fn put_revtree<R: Rng>(&mut self, _: &mut R, key: Inode, value: &FileId) -> Result<bool> {
+        Ok(self.tables.revtree.put(key, value.to_owned()))
+    }
+
+    fn del_revtree<R: Rng>(
+        &mut self,
+        _: &mut R,
+        key: Inode,
+        value: Option<&FileId>,
+    ) -> Result<bool> {
+        let value = value.map(|v| v.to_owned());
+        Ok(self.tables.revtree.del(&key, value.as_ref()))
+    }
+
+    fn put_inodes<R: Rng>(&mut self, _: &mut R, key: Inode, value: FileHeader) -> Result<bool> {
+        Ok(self.tables.inodes.put(key, value))
+    }
+
+    fn del_inodes<R: Rng>(
+        &mut self,
+        _: &mut R,
+        key: Inode,
+        value: Option<FileHeader>,
+    ) -> Result<bool> {
+        Ok(self.tables.inodes.del(&key, value.as_ref()))
+    }
+
+    fn put_revinodes<R: Rng>(
+        &mut self,
+        _: &mut R,
+        key: Key<PatchId>,
+        value: Inode,
+    ) -> Result<bool> {
+        Ok(self.tables.revinodes.put(key, value))
+    }
+
+    fn del_revinodes<R: Rng>(
+        &mut self,
+        _: &mut R,
+        key: Key<PatchId>,
+        value: Option<Inode>,
+    ) -> Result<bool> {
+        Ok(self.tables.revinodes.del(&key, value.as_ref()))
+    }
+
+    fn put_contents<R: Rng>(&mut self, _: &mut R, key: Key<PatchId>, value: &[u8]) -> Result<bool> {
+        Ok(self.tables.contents.put(key, value.to_vec()))
+    }
+
+    fn del_contents<R: Rng>(&mut self, _: &mut R, key: Key<PatchId>) -> Result<bool> {
+        Ok(self.tables.contents.del(&key, None))
+    }
+
+    fn put_internal<R: Rng>(&mut self, _: &mut R, key: HashRef, value: PatchId) -> Result<bool> {
+        Ok(self.tables.internal.put(key.to_owned(), value))
+    }
+
+    fn del_internal<R: Rng>(&mut self, _: &mut R, key: HashRef) -> Result<bool> {
+        Ok(self.tables.internal.del(&key.to_owned(), None))
+    }
+
+    fn put_external<R: Rng>(&mut self, _: &mut R, key: PatchId, value: HashRef) -> Result<bool> {
+        Ok(self.tables.external.put(key, value.to_owned()))
+    }
+
+    fn del_external<R: Rng>(&mut self, _: &mut R, key: PatchId) -> Result<bool> {
+        Ok(self.tables.external.del(&key, None))
+    }
+
+    fn put_revdep<R: Rng>(&mut self, _: &mut R, patch: PatchId, revdep: PatchId) -> Result<bool> {
+        Ok(self.tables.revdep.put(patch, revdep))
+    }
+
+    fn del_revdep<R: Rng>(
+        &mut self,
+        _: &mut R,
+        patch: PatchId,
+        revdep: Option<PatchId>,
+    ) -> Result<bool> {
+        Ok(self.tables.revdep.del(&patch, revdep.as_ref()))
+    }
+
+    fn put_dep<R: Rng>(&mut self, _: &mut R, patch: PatchId, dep: PatchId) -> Result<bool> {
+        Ok(self.tables.dep.put(patch, dep))
+    }
+
+    fn del_dep<R: Rng>(&mut self, _: &mut R, patch: PatchId, dep: Option<PatchId>) -> Result<bool> {
+        Ok(self.tables.dep.del(&patch, dep.as_ref()))
+    }
+
+    fn put_cemetery<R: Rng>(
+        &mut self,
+        _: &mut R,
+        key: Key<PatchId>,
+        edge: Edge,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self.tables.cemetery.put((key, edge), patch))
+    }
+
+    fn del_cemetery<R: Rng>(
+        &mut self,
+        _: &mut R,
+        key: Key<PatchId>,
+        edge: Edge,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self.tables.cemetery.del(&(key, edge), Some(&patch)))
+    }
+
+    fn put_touched_file<R: Rng>(
+        &mut self,
+        _: &mut R,
+        file: Key<PatchId>,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self.tables.touched_files.put(file, patch))
+    }
+
+    fn del_touched_file<R: Rng>(
+        &mut self,
+        _: &mut R,
+        file: Key<PatchId>,
+        patch: PatchId,
+    ) -> Result<bool> {
+        Ok(self.tables.touched_files.del(&file, Some(&patch)))
+    }
+
+    fn commit(self) -> Result<()> {
+        let MemoryWriter {
+            memory,
+            _lock,
+            tables,
+        } = self.tables;
+        *memory.tables.lock().unwrap() = Arc::new(tables);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use fs_representation::{create, patches_dir, pristine_dir};
+    use patch::{Patch, PatchFlags};
+    use rand;
+    use record::RecordState;
+    use std::fs::{canonicalize, File};
+    use std::io::Write;
+    use std::iter;
+    use std::path::{Path, PathBuf};
+    use tempdir::TempDir;
+
+    /// The patches of a branch, and the edges of its graph, with
+    /// patches identified by their hash, since internal ids are
+    /// random.
+    type BranchState = (
+        BTreeSet<(Hash, ApplyTimestamp)>,
+        BTreeSet<(Key<Hash>, u8, Key<Hash>, Hash)>,
+    );
+
+    fn working_copy(dir: &TempDir) -> PathBuf {
+        let root = canonicalize(dir.path()).unwrap();
+        create(&root, rand::thread_rng()).unwrap();
+        root
+    }
+
+    fn write(root: &Path, file: &str, contents: &str) {
+        File::create(root.join(file))
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap()
+    }
+
+    /// Record the changes of the working copy `root` on `branch_name`,
+    /// and apply the resulting patch.
+    fn record<U: MutTransaction, R: Rng>(
+        txn: &mut GenericTxn<U, R>,
+        root: &Path,
+        branch_name: &str,
+        name: &str,
+    ) -> (Hash, Patch) {
+        let mut branch = txn.open_branch(branch_name).unwrap();
+        let mut record = RecordState::new();
+        txn.record(&mut record, &branch, root, None).unwrap();
+        let (changes, syncs) = record.finish();
+        let changes = changes
+            .into_iter()
+            .flat_map(|x| txn.globalize_record(x).into_iter())
+            .collect();
+        let patch = txn.new_patch(
+            &branch,
+            vec!["me".to_string()],
+            name.to_string(),
+            None,
+            Utc.timestamp(0, 0),
+            changes,
+            iter::empty(),
+            PatchFlags::empty(),
+        );
+        let hash = patch.save(patches_dir(root), None).unwrap();
+        txn.apply_local_patch(&mut branch, root, &hash, &patch, &syncs, false)
+            .unwrap();
+        txn.commit_branch(branch).unwrap();
+        (hash, patch)
+    }
+
+    /// Record two patches on the default branch, forking "other"
+    /// after the first one. Then apply the second one to "other", and
+    /// unrecord it from the default branch. Each step is a separate
+    /// mutable transaction, started by `mut_txn`.
+    fn scenario<U, R, F>(root: &Path, mut mut_txn: F) -> (Hash, Hash)
+    where
+        U: MutTransaction,
+        R: Rng,
+        F: FnMut() -> GenericTxn<U, R>,
+    {
+        write(root, "a", "a\n");
+        let mut txn = mut_txn();
+        txn.add_file("a", false).unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = mut_txn();
+        let (first, _) = record(&mut txn, root, DEFAULT_BRANCH, "a");
+        let branch = txn.open_branch(DEFAULT_BRANCH).unwrap();
+        let other = txn.fork(&branch, "other").unwrap();
+        txn.commit_branch(branch).unwrap();
+        txn.commit_branch(other).unwrap();
+        txn.commit().unwrap();
+
+        write(root, "a", "a\nb\n");
+        let mut txn = mut_txn();
+        let second = vec![record(&mut txn, root, DEFAULT_BRANCH, "b")];
+        txn.commit().unwrap();
+
+        let (ref hash, ref patch) = second[0];
+        let mut txn = mut_txn();
+        let mut other = txn.open_branch("other").unwrap();
+        let mut new_patches_count = 0;
+        txn.apply_patches_rec(&mut other, &second, hash, patch, &mut new_patches_count)
+            .unwrap();
+        assert_eq!(new_patches_count, 1);
+        let mut branch = txn.open_branch(DEFAULT_BRANCH).unwrap();
+        let internal = txn.get_internal(hash.as_ref()).unwrap();
+        assert!(txn.unrecord(&mut branch, internal, patch).unwrap());
+        txn.commit_branch(other).unwrap();
+        txn.commit_branch(branch).unwrap();
+        txn.commit().unwrap();
+        (first, hash.clone())
+    }
+
+    fn state<U: Transaction, R>(txn: &GenericTxn<U, R>) -> BTreeMap<String, BranchState> {
+        let key = |k: Key<PatchId>| Key {
+            patch: txn.external_hash(k.patch).to_owned(),
+            line: k.line,
+        };
+        txn.iter_branches(None)
+            .map(|branch| {
+                let patches = txn.iter_patches(&branch, None)
+                    .map(|(p, t)| (txn.external_hash(p).to_owned(), t))
+                    .collect();
+                let edges = txn.iter_nodes(&branch, None)
+                    .map(|(k, e)| {
+                        (
+                            key(k),
+                            e.flag.bits(),
+                            key(e.dest),
+                            txn.external_hash(e.introduced_by).to_owned(),
+                        )
+                    })
+                    .collect();
+                (branch.name.as_str().to_string(), (patches, edges))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_as_repository() {
+        let dir = TempDir::new("pijul_memory").unwrap();
+        let root = working_copy(&dir);
+        let memory = Memory::new();
+        let (first, second) = scenario(&root, || {
+            memory.mut_txn_begin(rand::thread_rng()).unwrap()
+        });
+
+        let dir = TempDir::new("pijul_disk").unwrap();
+        let root = working_copy(&dir);
+        let repository = Repository::open(pristine_dir(&root), None).unwrap();
+        let hashes = scenario(&root, || {
+            repository.mut_txn_begin(rand::thread_rng()).unwrap()
+        });
+        assert_eq!(hashes, (first.clone(), second.clone()));
+
+        let memory_state = state(&memory.txn_begin().unwrap());
+        assert_eq!(memory_state, state(&repository.txn_begin().unwrap()));
+        let patches = |name: &str| -> Vec<Hash> {
+            memory_state[name].0.iter().map(|&(ref h, _)| h.clone()).collect()
+        };
+        assert_eq!(patches(DEFAULT_BRANCH), vec![first.clone()]);
+        let mut both = vec![first, second];
+        both.sort();
+        assert_eq!(patches("other"), both);
+    }
+
+    #[test]
+    fn uncommitted_transactions() {
+        let memory = Memory::new();
+        let mut txn = memory.mut_txn_begin(rand::thread_rng()).unwrap();
+        let branch = txn.open_branch(DEFAULT_BRANCH).unwrap();
+        txn.commit_branch(branch).unwrap();
+        assert!(txn.has_branch(DEFAULT_BRANCH));
+        // Readers see the repository as it was when they started.
+        let before = memory.txn_begin().unwrap();
+        assert!(!before.has_branch(DEFAULT_BRANCH));
+        txn.commit().unwrap();
+        assert!(!before.has_branch(DEFAULT_BRANCH));
+        assert!(memory.txn_begin().unwrap().has_branch(DEFAULT_BRANCH));
+
+        let mut txn = memory.mut_txn_begin(rand::thread_rng()).unwrap();
+        let branch = txn.open_branch("dropped").unwrap();
+        txn.commit_branch(branch).unwrap();
+        drop(txn);
+        assert!(!memory.txn_begin().unwrap().has_branch("dropped"));
+    }
+}
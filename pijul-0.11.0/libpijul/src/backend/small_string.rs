@@ -63,6 +63,17 @@ impl<'a> Ord for SmallStr<'a> {
     }
 }
 
+impl PartialOrd for SmallString {
+    fn partial_cmp(&self, x: &SmallString) -> Option<std::cmp::Ordering> {
+        self.as_str().partial_cmp(x.as_str())
+    }
+}
+impl Ord for SmallString {
+    fn cmp(&self, x: &SmallString) -> std::cmp::Ordering {
+        self.as_str().cmp(x.as_str())
+    }
+}
+
 impl<'a> std::fmt::Debug for SmallStr<'a> {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         self.as_str().fmt(fmt)
@@ -0,0 +1,299 @@
+//! The interface between `GenericTxn` and the storage of the
+//! tables of a repository.
+//!
+//! All the algorithms of this crate access the tables through the
+//! `Transaction` and `MutTransaction` traits. This crate provides two
+//! implementations: `Pristine`, the Sanakirja database stored in
+//! `.pijul/pristine`, and `Memory`, which keeps all the tables in
+//! memory and never touches the disk.
+//!
+//! The tables are maps where a key may have several values, sorted
+//! by key and then by value. Unless stated otherwise, `get` methods
+//! with a value `v` return the smallest value bound to the key that
+//! is at least `v`, and `iter` methods starting from `(k, v)` start
+//! at the first binding at least `(k, v)`.
+
+use super::*;
+use rand::Rng;
+use std::borrow::Cow;
+use Result;
+
+/// An iterator over the bindings of a table.
+pub type Iter<'a, A> = Box<dyn Iterator<Item = A> + 'a>;
+
+/// The contents of a line. Lines are usually short, in which case
+/// they are borrowed from the storage.
+pub type Contents<'a> = Cow<'a, [u8]>;
+
+/// The graph of a branch, allocated by the storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodesDb(pub(crate) u64);
+
+/// The patches applied to a branch, ordered by patch id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchSet(pub(crate) u64);
+
+/// The patches applied to a branch, ordered by application number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RevPatchSet(pub(crate) u64);
+
+/// Read access to the tables of a repository.
+pub trait Transaction {
+    /// Get the branch with the given name, if it exists.
+    fn get_branch(&self, name: &str) -> Option<Branch>;
+    /// Iterate over the branches, starting from the given name.
+    fn iter_branches<'a>(&'a self, name: Option<&SmallStr>) -> Iter<'a, Branch>;
+
+    fn get_nodes(&self, db: &NodesDb, key: Key<PatchId>, edge: Option<Edge>) -> Option<Edge>;
+    fn iter_nodes<'a>(
+        &'a self,
+        db: &NodesDb,
+        key: Option<(Key<PatchId>, Option<Edge>)>,
+    ) -> Iter<'a, (Key<PatchId>, Edge)>;
+
+    fn get_patch(&self, patches: &PatchSet, patch: PatchId) -> Option<ApplyTimestamp>;
+    fn iter_patches<'a>(
+        &'a self,
+        patches: &PatchSet,
+        key: Option<PatchId>,
+    ) -> Iter<'a, (PatchId, ApplyTimestamp)>;
+    fn iter_applied<'a>(
+        &'a self,
+        revpatches: &RevPatchSet,
+        key: Option<ApplyTimestamp>,
+    ) -> Iter<'a, (ApplyTimestamp, PatchId)>;
+    /// Same as `iter_applied`, in the reverse order.
+    fn rev_iter_applied<'a>(
+        &'a self,
+        revpatches: &RevPatchSet,
+        key: Option<ApplyTimestamp>,
+    ) -> Iter<'a, (ApplyTimestamp, PatchId)>;
+
+    /// Iterate over the partial paths, starting from those of
+    /// `branch`.
+    fn iter_partials<'a>(&'a self, branch: &str) -> Iter<'a, (SmallStr<'a>, Key<PatchId>)>;
+
+    fn get_tree<'a>(&'a self, key: &FileId) -> Option<Inode>;
+    fn iter_tree<'a>(
+        &'a self,
+        key: Option<(&FileId, Option<Inode>)>,
+    ) -> Iter<'a, (FileId<'a>, Inode)>;
+    fn get_revtree<'a>(&'a self, key: Inode) -> Option<FileId<'a>>;
+    fn iter_revtree<'a>(
+        &'a self,
+        key: Option<(Inode, Option<&FileId>)>,
+    ) -> Iter<'a, (Inode, FileId<'a>)>;
+
+    fn get_inodes(&self, key: Inode) -> Option<FileHeader>;
+    fn iter_inodes<'a>(
+        &'a self,
+        key: Option<(Inode, Option<FileHeader>)>,
+    ) -> Iter<'a, (Inode, FileHeader)>;
+    fn get_revinodes(&self, key: Key<PatchId>) -> Option<Inode>;
+    fn iter_revinodes<'a>(
+        &'a self,
+        key: Option<(Key<PatchId>, Option<Inode>)>,
+    ) -> Iter<'a, (Key<PatchId>, Inode)>;
+
+    fn get_contents<'a>(&'a self, key: Key<PatchId>) -> Option<Contents<'a>>;
+    fn iter_contents<'a>(
+        &'a self,
+        key: Option<Key<PatchId>>,
+    ) -> Iter<'a, (Key<PatchId>, Contents<'a>)>;
+
+    fn get_internal(&self, key: HashRef) -> Option<PatchId>;
+    fn iter_internal<'a>(
+        &'a self,
+        key: Option<(HashRef, Option<PatchId>)>,
+    ) -> Iter<'a, (HashRef<'a>, PatchId)>;
+    fn get_external<'a>(&'a self, key: PatchId) -> Option<HashRef<'a>>;
+    fn iter_external<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<HashRef>)>,
+    ) -> Iter<'a, (PatchId, HashRef<'a>)>;
+
+    fn get_revdep(&self, patch: PatchId, dep: Option<PatchId>) -> Option<PatchId>;
+    fn iter_revdep<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<PatchId>)>,
+    ) -> Iter<'a, (PatchId, PatchId)>;
+    fn get_dep(&self, patch: PatchId, dep: Option<PatchId>) -> Option<PatchId>;
+    fn iter_dep<'a>(
+        &'a self,
+        key: Option<(PatchId, Option<PatchId>)>,
+    ) -> Iter<'a, (PatchId, PatchId)>;
+
+    fn iter_cemetery<'a>(
+        &'a self,
+        key: Key<PatchId>,
+        edge: Edge,
+    ) -> Iter<'a, ((Key<PatchId>, Edge), PatchId)>;
+
+    /// Whether `patch` touches `file`.
+    fn get_touched(&self, file: Key<PatchId>, patch: PatchId) -> bool;
+    fn iter_touched<'a>(&'a self, file: Key<PatchId>) -> Iter<'a, (Key<PatchId>, PatchId)>;
+}
+
+/// Write access to the tables of a repository. Nothing is written
+/// until `commit` is called.
+///
+/// The `rng` arguments are the random generator of the `GenericTxn`,
+/// which some storages need to update their tables.
+pub trait MutTransaction: Transaction {
+    /// Allocate the tables of a new, empty branch. The branch is
+    /// only registered by `put_branch`.
+    fn create_branch<R: Rng>(&mut self, rng: &mut R, name: &str) -> Result<Branch>;
+    /// Copy the tables of `branch` into a new branch called `name`,
+    /// which is only registered by `put_branch`.
+    fn fork_branch<R: Rng>(&mut self, rng: &mut R, branch: &Branch, name: &str) -> Result<Branch>;
+    /// Register `branch` under its name, replacing the previous
+    /// version of that branch, if any.
+    fn put_branch<R: Rng>(&mut self, rng: &mut R, branch: &Branch) -> Result<()>;
+    /// Unregister the branch called `name`, without freeing its
+    /// tables. Returns `false` if there was no such branch.
+    fn unlink_branch<R: Rng>(&mut self, rng: &mut R, name: &str) -> Result<bool>;
+    /// Unregister the branch called `name`, and free its tables.
+    fn drop_branch<R: Rng>(&mut self, rng: &mut R, name: &str) -> Result<bool>;
+
+    fn put_nodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        db: &mut NodesDb,
+        key: Key<PatchId>,
+        edge: Edge,
+    ) -> Result<bool>;
+    fn del_nodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        db: &mut NodesDb,
+        key: Key<PatchId>,
+        edge: Option<Edge>,
+    ) -> Result<bool>;
+
+    fn put_patches<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        patches: &mut PatchSet,
+        patch: PatchId,
+        time: ApplyTimestamp,
+    ) -> Result<bool>;
+    fn del_patches<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        patches: &mut PatchSet,
+        patch: PatchId,
+    ) -> Result<bool>;
+    fn put_revpatches<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        revpatches: &mut RevPatchSet,
+        time: ApplyTimestamp,
+        patch: PatchId,
+    ) -> Result<bool>;
+    fn del_revpatches<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        revpatches: &mut RevPatchSet,
+        time: ApplyTimestamp,
+        patch: PatchId,
+    ) -> Result<bool>;
+
+    fn put_partials<R: Rng>(&mut self, rng: &mut R, name: &str, path: Key<PatchId>)
+        -> Result<bool>;
+    /// Delete one of the partial paths of branch `name`.
+    fn del_partials<R: Rng>(&mut self, rng: &mut R, name: &str) -> Result<bool>;
+
+    fn put_tree<R: Rng>(&mut self, rng: &mut R, key: &FileId, inode: Inode) -> Result<bool>;
+    fn del_tree<R: Rng>(&mut self, rng: &mut R, key: &FileId, inode: Option<Inode>)
+        -> Result<bool>;
+    fn put_revtree<R: Rng>(&mut self, rng: &mut R, key: Inode, value: &FileId) -> Result<bool>;
+    fn del_revtree<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Inode,
+        value: Option<&FileId>,
+    ) -> Result<bool>;
+
+    fn put_inodes<R: Rng>(&mut self, rng: &mut R, key: Inode, value: FileHeader) -> Result<bool>;
+    fn del_inodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Inode,
+        value: Option<FileHeader>,
+    ) -> Result<bool>;
+    fn put_revinodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        value: Inode,
+    ) -> Result<bool>;
+    fn del_revinodes<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        value: Option<Inode>,
+    ) -> Result<bool>;
+
+    /// Set the contents of `key`. Storages may store identical
+    /// contents only once, as the Sanakirja storage does for the
+    /// chunks of binary files.
+    fn put_contents<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        value: &[u8],
+    ) -> Result<bool>;
+    fn del_contents<R: Rng>(&mut self, rng: &mut R, key: Key<PatchId>) -> Result<bool>;
+
+    fn put_internal<R: Rng>(&mut self, rng: &mut R, key: HashRef, value: PatchId) -> Result<bool>;
+    fn del_internal<R: Rng>(&mut self, rng: &mut R, key: HashRef) -> Result<bool>;
+    fn put_external<R: Rng>(&mut self, rng: &mut R, key: PatchId, value: HashRef) -> Result<bool>;
+    fn del_external<R: Rng>(&mut self, rng: &mut R, key: PatchId) -> Result<bool>;
+
+    fn put_revdep<R: Rng>(&mut self, rng: &mut R, patch: PatchId, revdep: PatchId) -> Result<bool>;
+    fn del_revdep<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        patch: PatchId,
+        revdep: Option<PatchId>,
+    ) -> Result<bool>;
+    fn put_dep<R: Rng>(&mut self, rng: &mut R, patch: PatchId, dep: PatchId) -> Result<bool>;
+    fn del_dep<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        patch: PatchId,
+        dep: Option<PatchId>,
+    ) -> Result<bool>;
+
+    fn put_cemetery<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        edge: Edge,
+        patch: PatchId,
+    ) -> Result<bool>;
+    fn del_cemetery<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        key: Key<PatchId>,
+        edge: Edge,
+        patch: PatchId,
+    ) -> Result<bool>;
+
+    fn put_touched_file<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        file: Key<PatchId>,
+        patch: PatchId,
+    ) -> Result<bool>;
+    fn del_touched_file<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        file: Key<PatchId>,
+        patch: PatchId,
+    ) -> Result<bool>;
+
+    /// Make all the changes of this transaction visible to the
+    /// transactions started after this call.
+    fn commit(self) -> Result<()>;
+}
@@ -0,0 +1,416 @@
+//! Large files, stored outside of the pristine.
+//!
+//! When a file is "large" (according to the `large_file_threshold`
+//! and `large_file_patterns` fields of the `Config`), record does
+//! not put its contents in the patch. Instead, the file is a single
+//! line, a pointer with the SHA-256 hash and the size of the
+//! contents. The contents are copied to a content-addressed store in
+//! `.pijul/blobs` when the patch is applied, so that computing the
+//! changes (for instance for `status` or `diff`) never writes there.
+//!
+//! When outputting the repository, pointers are replaced by the
+//! contents of their blob. If the blob is not available (for instance
+//! because it hasn't been downloaded yet), the pointer is left in the
+//! working copy, and record considers the file unchanged.
+//!
+//! Whether a file is stored as a blob is decided by the configuration
+//! when the file is added. After that, files whose version in the
+//! pristine is a pointer stay blobs, so that repositories with a
+//! different configuration (for instance fresh clones) record them
+//! the same way.
+
+use backend::{GenericTxn, MutTransaction, PatchId, Transaction, ROOT_INODE};
+use config::Config;
+use fs_representation::blobs_dir;
+use hex;
+use ignore::overrides::{Override, OverrideBuilder};
+use openssl::sha::Sha256;
+use patch::{Change, Patch, Record};
+use std::collections::HashSet;
+use rand;
+use std::fs::{
+    create_dir_all, metadata, remove_file, rename, set_permissions, symlink_metadata, File,
+};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tempdir::TempDir;
+use {Error, Result};
+
+/// Beginning of a pointer. The NUL byte makes pointers binary, so
+/// that they are never split into lines.
+const POINTER_PREFIX: &'static [u8] = b"\0pijul blob sha256:";
+
+/// Pointers are never longer than this.
+const MAX_POINTER_LEN: u64 = 128;
+
+/// A reference to the contents of a large file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BlobPointer {
+    /// Hexadecimal SHA-256 hash of the contents.
+    pub hash: String,
+    pub size: u64,
+}
+
+impl BlobPointer {
+    /// Parse a pointer, as stored in the contents of a file.
+    pub fn parse(contents: &[u8]) -> Option<Self> {
+        if !contents.starts_with(POINTER_PREFIX) {
+            return None;
+        }
+        let s = ::std::str::from_utf8(&contents[POINTER_PREFIX.len()..]).ok()?;
+        let mut words = s.trim_right_matches('\n').split(' ');
+        let hash = words.next()?;
+        let size = words.next()?.parse().ok()?;
+        if !is_blob_hash(hash) || words.next().is_some() {
+            return None;
+        }
+        Some(BlobPointer {
+            hash: hash.to_lowercase(),
+            size,
+        })
+    }
+
+    /// The contents of the pointer.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = POINTER_PREFIX.to_vec();
+        v.extend(format!("{} {}\n", self.hash, self.size).as_bytes());
+        v
+    }
+
+    /// Path of the blob in the repository at `repo_root`.
+    pub fn path(&self, repo_root: &Path) -> PathBuf {
+        blobs_dir(repo_root).join(&self.hash)
+    }
+
+    /// Whether the blob is in the store of `repo_root`.
+    pub fn is_available(&self, repo_root: &Path) -> bool {
+        metadata(self.path(repo_root))
+            .map(|m| m.len() == self.size)
+            .unwrap_or(false)
+    }
+}
+
+/// Whether `hash` is a valid blob name (an hexadecimal SHA-256 hash),
+/// which must be checked before using hashes received from the
+/// network as file names.
+pub fn is_blob_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The SHA-256 hash of `r`, and its size.
+fn hash_reader<R: Read, W: Write>(r: &mut R, mut copy_to: Option<&mut W>) -> Result<BlobPointer> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 1 << 16];
+    let mut size = 0;
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        if let Some(ref mut w) = copy_to {
+            w.write_all(&buf[..n])?
+        }
+        size += n as u64
+    }
+    Ok(BlobPointer {
+        hash: hex::encode(&hasher.finish()),
+        size,
+    })
+}
+
+/// Adds a blob to the store of a repository as its contents arrive
+/// (for instance from the network), without holding them in memory.
+pub struct BlobWriter {
+    repo_root: PathBuf,
+    pointer: BlobPointer,
+    tmp: TempDir,
+    file: File,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl BlobWriter {
+    /// Start writing the blob of `pointer` to the store of
+    /// `repo_root`.
+    pub fn new(repo_root: &Path, pointer: &BlobPointer) -> Result<Self> {
+        let dir = blobs_dir(repo_root);
+        create_dir_all(&dir)?;
+        let tmp = TempDir::new_in(&dir, "pijul_blob")?;
+        let file = File::create(tmp.path().join("blob"))?;
+        Ok(BlobWriter {
+            repo_root: repo_root.to_path_buf(),
+            pointer: pointer.clone(),
+            tmp,
+            file,
+            hasher: Sha256::new(),
+            written: 0,
+        })
+    }
+
+    /// Append `data` to the blob. Returns `false` (and drops `data`)
+    /// if the blob would get larger than the pointer says.
+    pub fn write(&mut self, data: &[u8]) -> Result<bool> {
+        if self.written + data.len() as u64 > self.pointer.size {
+            return Ok(false);
+        }
+        self.hasher.update(data);
+        self.file.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(true)
+    }
+
+    /// Number of bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Whether all the contents have been written.
+    pub fn is_complete(&self) -> bool {
+        self.written == self.pointer.size
+    }
+
+    /// Check the contents against the pointer, and add them to the
+    /// store. Returns `false` (and drops the contents) if they don't
+    /// match.
+    pub fn finish(mut self) -> Result<bool> {
+        self.file.flush()?;
+        if !self.is_complete() || hex::encode(&self.hasher.finish()) != self.pointer.hash {
+            return Ok(false);
+        }
+        if !self.pointer.is_available(&self.repo_root) {
+            rename(
+                self.tmp.path().join("blob"),
+                self.pointer.path(&self.repo_root),
+            )?;
+        }
+        Ok(true)
+    }
+}
+
+/// If `path` contains a pointer, return it.
+pub fn read_pointer(path: &Path) -> Option<BlobPointer> {
+    let meta = metadata(path).ok()?;
+    if !meta.is_file() || meta.len() > MAX_POINTER_LEN {
+        return None;
+    }
+    let mut contents = Vec::new();
+    File::open(path).ok()?.read_to_end(&mut contents).ok()?;
+    BlobPointer::parse(&contents)
+}
+
+/// Add the contents of `path` to the store of `repo_root`, if they
+/// are the blob of `pointer`. Returns `false` if they aren't, for
+/// instance because the file changed since it was recorded.
+pub fn store(repo_root: &Path, path: &Path, pointer: &BlobPointer) -> Result<bool> {
+    if pointer.is_available(repo_root) {
+        return Ok(true);
+    }
+    let mut w = BlobWriter::new(repo_root, pointer)?;
+    let mut f = File::open(path)?;
+    let mut buf = [0; 1 << 16];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if !w.write(&buf[..n])? {
+            return Ok(false);
+        }
+    }
+    w.finish()
+}
+
+/// Add the contents of `file`, recorded as `pointer`, to the store of
+/// `repo_root`, unless `file` is the pointer itself (because its blob
+/// is missing).
+fn store_file(repo_root: &Path, file: &Path, pointer: &BlobPointer) -> Result<()> {
+    if read_pointer(file).as_ref() == Some(pointer) || store(repo_root, file, pointer)? {
+        Ok(())
+    } else {
+        Err(Error::BlobChanged(file.to_path_buf()))
+    }
+}
+
+/// Add the blobs of the large files changed by `records` to the
+/// store of `repo_root`. This is only needed for patches that are
+/// saved without being applied, such as stashes.
+pub fn store_records<C>(repo_root: &Path, records: &[Record<C>]) -> Result<()> {
+    for record in records {
+        let (file, change) = match *record {
+            Record::Change {
+                ref file,
+                ref change,
+                ..
+            } => (file, change),
+            Record::Replace {
+                ref file, ref adds, ..
+            } => (file, adds),
+            _ => continue,
+        };
+        if let Change::NewNodes { ref nodes, .. } = *change {
+            for pointer in nodes.iter().filter_map(|n| BlobPointer::parse(n)) {
+                store_file(repo_root, file, &pointer)?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the pointer in `path` (if any) by the contents of its
+/// blob. Returns the pointer if its blob is missing or corrupted.
+pub fn materialize(repo_root: &Path, path: &Path) -> Result<Option<BlobPointer>> {
+    let pointer = if let Some(pointer) = read_pointer(path) {
+        pointer
+    } else {
+        return Ok(None);
+    };
+    if !pointer.is_available(repo_root) {
+        return Ok(Some(pointer));
+    }
+    debug!("materializing {:?}", path);
+    // Only the contents are copied, checking them on the way: the
+    // file keeps the permissions it was output with.
+    let tmp = TempDir::new_in(blobs_dir(repo_root), "pijul_blob")?;
+    let tmp_file = tmp.path().join("blob");
+    let copied = {
+        let mut w = File::create(&tmp_file)?;
+        hash_reader(&mut File::open(pointer.path(repo_root))?, Some(&mut w))?
+    };
+    if copied != pointer {
+        // Drop the blob, so that it can be downloaded again.
+        error!("blob {} is corrupted", pointer.hash);
+        remove_file(pointer.path(repo_root))?;
+        return Ok(Some(pointer));
+    }
+    set_permissions(&tmp_file, metadata(path)?.permissions())?;
+    rename(&tmp_file, path)?;
+    Ok(None)
+}
+
+/// Materialize all the files of the repository, returning the
+/// pointers whose blobs are missing.
+pub fn materialize_all<A: Transaction, R>(
+    txn: &GenericTxn<A, R>,
+    repo_root: &Path,
+) -> Result<HashSet<BlobPointer>> {
+    let mut missing = HashSet::new();
+    for file in txn.list_files(ROOT_INODE)? {
+        if let Some(pointer) = materialize(repo_root, &repo_root.join(&file))? {
+            missing.insert(pointer);
+        }
+    }
+    Ok(missing)
+}
+
+/// The blobs referenced by the lines added by `patch`.
+pub fn patch_blobs(patch: &Patch) -> HashSet<BlobPointer> {
+    let mut blobs = HashSet::new();
+    for change in patch.changes() {
+        if let Change::NewNodes { ref nodes, .. } = *change {
+            for node in nodes {
+                if let Some(pointer) = BlobPointer::parse(node) {
+                    blobs.insert(pointer);
+                }
+            }
+        }
+    }
+    blobs
+}
+
+/// The configuration of large files in a repository.
+pub struct LargeFiles {
+    threshold: Option<u64>,
+    patterns: Option<Override>,
+}
+
+impl LargeFiles {
+    /// The large files of `repo_root`, according to `config`.
+    pub fn new(repo_root: &Path, config: &Config) -> Self {
+        let patterns = if config.large_file_patterns.is_empty() {
+            None
+        } else {
+            let mut o = OverrideBuilder::new(repo_root);
+            for p in config.large_file_patterns.iter() {
+                if o.add(p).is_err() {
+                    error!("invalid large file pattern: {:?}", p)
+                }
+            }
+            o.build().ok()
+        };
+        LargeFiles {
+            threshold: config.large_file_threshold,
+            patterns,
+        }
+    }
+
+    /// Whether the regular file at `path`, which isn't a blob yet,
+    /// must be stored as a blob.
+    pub fn is_large(&self, path: &Path) -> bool {
+        let size = if let Ok(meta) = symlink_metadata(path) {
+            if !meta.is_file() {
+                return false;
+            }
+            meta.len()
+        } else {
+            return false;
+        };
+        if let Some(threshold) = self.threshold {
+            if size >= threshold {
+                return true;
+            }
+        }
+        if let Some(ref patterns) = self.patterns {
+            if patterns.matched(path, false).is_whitelist() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The pointer to the contents of `path`. If the file is itself a
+    /// pointer (because its blob is missing), that pointer is
+    /// returned. The contents are only added to the store when the
+    /// patch is applied, by `store_blobs`.
+    pub fn record(&self, path: &Path) -> Result<BlobPointer> {
+        if let Some(pointer) = read_pointer(path) {
+            return Ok(pointer);
+        }
+        hash_reader::<_, File>(&mut File::open(path)?, None)
+    }
+}
+
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
+    /// Add the blobs of the large files of `patch`, a patch recorded
+    /// from `working_copy` and just applied as `internal`, to the
+    /// store.
+    pub(crate) fn store_blobs(
+        &self,
+        working_copy: &Path,
+        internal: PatchId,
+        patch: &Patch,
+    ) -> Result<()> {
+        for change in patch.changes() {
+            if let Change::NewNodes {
+                ref nodes,
+                ref inode,
+                ..
+            } = *change
+            {
+                for pointer in nodes.iter().filter_map(|n| BlobPointer::parse(n)) {
+                    if pointer.is_available(working_copy) {
+                        continue;
+                    }
+                    let file = self
+                        .get_revinodes(self.internal_key(inode, internal))
+                        .and_then(|inode| self.filename_of_inode(inode, working_copy));
+                    match file {
+                        Some(file) => store_file(working_copy, &file, &pointer)?,
+                        None => debug!("no file for blob {}", pointer.hash),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,429 @@
+//! Verification of the invariants of a repository, and repair of the
+//! tables of the pristine that can be recomputed from the patches.
+//!
+//! The `dep`, `revdep` and `touched_files` tables are derived from
+//! the patch files: they can be rebuilt as long as these files are
+//! there. Other problems (such as missing reverse edges in a graph)
+//! can only be reported.
+
+use backend::*;
+use fs_representation::{patches_dir, read_patch};
+use patch::{Change, Patch, SignatureFile};
+use rand;
+use serde_json;
+use std;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use {Error, Result};
+
+/// An inconsistency found by `check`.
+#[derive(Debug)]
+pub enum Problem {
+    /// A patch file can't be read, or doesn't hash to its name.
+    CorruptPatch { path: PathBuf },
+    /// A signature file can't be read, or one of its signatures isn't
+    /// a valid signature, by its key, of the patch the file is named
+    /// after.
+    BadSignature { path: PathBuf },
+    /// A patch is applied to a branch, but its file is missing.
+    MissingPatchFile { hash: Hash },
+    /// The `internal` and `external` tables disagree about a patch.
+    PatchIdMismatch { id: PatchId },
+    /// A patch is applied to a branch, but one of its dependencies
+    /// isn't.
+    MissingDependency {
+        branch: String,
+        patch: Hash,
+        dependency: Hash,
+    },
+    /// The `dep`, `revdep` or `touched_files` tables don't match the
+    /// patch file. This can be repaired.
+    DerivedTables { patch: Hash },
+    /// An edge of a graph doesn't have its reverse edge.
+    MissingReverseEdge {
+        branch: String,
+        key: Key<PatchId>,
+        edge: Edge,
+    },
+    /// A key of a graph refers to an unknown patch.
+    DanglingKey { branch: String, key: Key<PatchId> },
+    /// The `inodes` and `revinodes` tables disagree about a file.
+    InodesMismatch { inode: Inode },
+    /// The `tree` and `revtree` tables disagree about a file.
+    TreeMismatch { inode: Inode },
+}
+
+impl Problem {
+    /// Whether `MutTxn::repair` can fix this problem.
+    pub fn is_repairable(&self) -> bool {
+        match *self {
+            Problem::DerivedTables { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Problem::CorruptPatch { ref path } => write!(fmt, "Corrupt patch file {:?}", path),
+            Problem::BadSignature { ref path } => write!(fmt, "Invalid signature file {:?}", path),
+            Problem::MissingPatchFile { ref hash } => {
+                write!(fmt, "Missing file for patch {}", hash.to_base58())
+            }
+            Problem::PatchIdMismatch { ref id } => write!(
+                fmt,
+                "Inconsistent hash for internal patch id {}",
+                id.to_base58()
+            ),
+            Problem::MissingDependency {
+                ref branch,
+                ref patch,
+                ref dependency,
+            } => write!(
+                fmt,
+                "Patch {} is applied to branch {:?}, but its dependency {} isn't",
+                patch.to_base58(),
+                branch,
+                dependency.to_base58()
+            ),
+            Problem::DerivedTables { ref patch } => write!(
+                fmt,
+                "Dependencies or touched files of patch {} are out of date",
+                patch.to_base58()
+            ),
+            Problem::MissingReverseEdge {
+                ref branch,
+                ref key,
+                ref edge,
+            } => write!(
+                fmt,
+                "Edge {:?} -> {:?} ({:?}) has no reverse in branch {:?}",
+                key.to_hex(),
+                edge.dest.to_hex(),
+                edge.flag,
+                branch
+            ),
+            Problem::DanglingKey {
+                ref branch,
+                ref key,
+            } => write!(
+                fmt,
+                "Key {:?} of branch {:?} refers to an unknown patch",
+                key.to_hex(),
+                branch
+            ),
+            Problem::InodesMismatch { ref inode } => write!(
+                fmt,
+                "Tables inodes and revinodes disagree about inode {}",
+                inode.to_hex()
+            ),
+            Problem::TreeMismatch { ref inode } => write!(
+                fmt,
+                "Tables tree and revtree disagree about inode {}",
+                inode.to_hex()
+            ),
+        }
+    }
+}
+
+/// The entries of the derived tables for a patch.
+struct Derived {
+    dependencies: HashSet<PatchId>,
+    touched: HashSet<Key<PatchId>>,
+}
+
+impl<U: Transaction, R> GenericTxn<U, R> {
+    /// Check the patch files of `repo_root` and the invariants of the
+    /// pristine, returning the list of problems found.
+    pub fn check(&self, repo_root: &Path) -> Result<Vec<Problem>> {
+        let mut problems = Vec::new();
+        let branches: Vec<Branch> = self.iter_branches(None).collect();
+        self.check_patch_ids(&mut problems);
+        self.check_patch_files(repo_root, &branches, &mut problems)?;
+        for branch in branches.iter() {
+            self.check_dependencies(branch, &mut problems);
+            self.check_graph(branch, &mut problems);
+        }
+        self.check_inodes(&mut problems);
+        self.check_tree(&mut problems);
+        Ok(problems)
+    }
+
+    fn check_patch_ids(&self, problems: &mut Vec<Problem>) {
+        for (hash, id) in self.iter_internal(None) {
+            if self.get_external(id) != Some(hash) {
+                problems.push(Problem::PatchIdMismatch { id })
+            }
+        }
+        for (id, hash) in self.iter_external(None) {
+            if self.get_internal(hash) != Some(id) {
+                problems.push(Problem::PatchIdMismatch { id })
+            }
+        }
+    }
+
+    /// Check that patch files hash to their names, that signatures
+    /// are valid, that applied patches have a file, and that the
+    /// derived tables match the patches.
+    fn check_patch_files(
+        &self,
+        repo_root: &Path,
+        branches: &[Branch],
+        problems: &mut Vec<Problem>,
+    ) -> Result<()> {
+        let mut present = HashSet::new();
+        for entry in read_dir(patches_dir(repo_root))? {
+            let path = entry?.path();
+            let name = if let Some(name) = path.file_stem().and_then(|x| x.to_str()) {
+                name.to_string()
+            } else {
+                continue;
+            };
+            match path.extension().and_then(|x| x.to_str()) {
+                Some("gz") => {
+                    let hash = Hash::from_base58(&name);
+                    let patch = File::open(&path)
+                        .map_err(Error::from)
+                        .and_then(|f| Patch::from_reader_compressed(&mut BufReader::new(f)));
+                    match (hash, patch) {
+                        (Some(ref hash), Ok((ref actual, _, ref patch))) if actual == hash => {
+                            if let Some(id) = self.get_internal(hash.as_ref()) {
+                                if !self.derived_tables_ok(id, patch, branches) {
+                                    problems.push(Problem::DerivedTables {
+                                        patch: hash.clone(),
+                                    })
+                                }
+                            }
+                            present.insert(hash.clone());
+                        }
+                        _ => problems.push(Problem::CorruptPatch { path }),
+                    }
+                }
+                Some("sig") => {
+                    let signatures: Result<SignatureFile> = File::open(&path)
+                        .map_err(Error::from)
+                        .and_then(|f| Ok(serde_json::from_reader(BufReader::new(f))?));
+                    let valid = match (Hash::from_base58(&name), signatures) {
+                        (Some(ref hash), Ok(ref signatures)) => signatures.verify(hash),
+                        _ => false,
+                    };
+                    if !valid {
+                        problems.push(Problem::BadSignature { path })
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut missing = HashSet::new();
+        for branch in branches {
+            for (id, _) in self.iter_patches(branch, None) {
+                if let Some(hash) = self.get_external(id) {
+                    let hash = hash.to_owned();
+                    if !present.contains(&hash) && missing.insert(hash.clone()) {
+                        problems.push(Problem::MissingPatchFile { hash })
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The entries that the derived tables should have for `patch`,
+    /// whose internal id is `id`.
+    fn derived_tables(&self, id: PatchId, patch: &Patch) -> Derived {
+        let dependencies = patch
+            .dependencies()
+            .iter()
+            .filter_map(|dep| self.get_internal(dep.as_ref()))
+            .collect();
+        let touched = patch
+            .changes()
+            .iter()
+            .filter_map(|change| {
+                let inode = match *change {
+                    Change::NewNodes { ref inode, .. } => inode,
+                    Change::NewEdges { ref inode, .. } => inode,
+                };
+                let patch = match inode.patch {
+                    Some(ref h) => self.get_internal(h.as_ref())?,
+                    None => id,
+                };
+                Some(Key {
+                    patch,
+                    line: inode.line,
+                })
+            })
+            .collect();
+        Derived {
+            dependencies,
+            touched,
+        }
+    }
+
+    /// Whether `(dep, patch)` should be in the `revdep` table, i.e.
+    /// whether some branch has both patches.
+    fn has_revdep(&self, dep: PatchId, patch: PatchId, branches: &[Branch]) -> bool {
+        branches.iter().any(|br| {
+            self.get_patch(&br.patches, patch).is_some() && self.get_patch(&br.patches, dep).is_some()
+        })
+    }
+
+    fn derived_tables_ok(&self, id: PatchId, patch: &Patch, branches: &[Branch]) -> bool {
+        let derived = self.derived_tables(id, patch);
+        let deps: HashSet<PatchId> = self.iter_dep(Some((id, None)))
+            .take_while(|&(p, _)| p == id)
+            .map(|(_, d)| d)
+            .collect();
+        deps == derived.dependencies
+            && derived.dependencies.iter().all(|&dep| {
+                self.get_revdep(dep, Some(id)) == Some(id) || !self.has_revdep(dep, id, branches)
+            })
+            && derived
+                .touched
+                .iter()
+                .all(|&file| self.get_touched(file, id))
+    }
+
+    /// Check that all the dependencies of the patches applied to
+    /// `branch` are applied too.
+    fn check_dependencies(&self, branch: &Branch, problems: &mut Vec<Problem>) {
+        for (id, _) in self.iter_patches(branch, None) {
+            for (_, dep) in self.iter_dep(Some((id, None))).take_while(|&(p, _)| p == id) {
+                if self.get_patch(&branch.patches, dep).is_none() {
+                    if let (Some(patch), Some(dependency)) =
+                        (self.get_external(id), self.get_external(dep))
+                    {
+                        problems.push(Problem::MissingDependency {
+                            branch: branch.name.as_str().to_string(),
+                            patch: patch.to_owned(),
+                            dependency: dependency.to_owned(),
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check that all edges have their reverse, and that all keys
+    /// refer to known patches.
+    fn check_graph(&self, branch: &Branch, problems: &mut Vec<Problem>) {
+        let known = |p: PatchId| p.is_root() || self.get_external(p).is_some();
+        let mut dangling = HashSet::new();
+        for (key, edge) in self.iter_nodes(branch, None) {
+            let mut reverse = edge;
+            reverse.dest = key;
+            reverse.flag.toggle(EdgeFlags::PARENT_EDGE);
+            if self.iter_nodes(branch, Some((edge.dest, Some(reverse)))).next()
+                != Some((edge.dest, reverse))
+            {
+                problems.push(Problem::MissingReverseEdge {
+                    branch: branch.name.as_str().to_string(),
+                    key,
+                    edge,
+                })
+            }
+            for &k in &[key, edge.dest] {
+                if !known(k.patch) && dangling.insert(k) {
+                    problems.push(Problem::DanglingKey {
+                        branch: branch.name.as_str().to_string(),
+                        key: k,
+                    })
+                }
+            }
+        }
+    }
+
+    fn check_inodes(&self, problems: &mut Vec<Problem>) {
+        let mut reported = HashSet::new();
+        for (inode, header) in self.iter_inodes(None) {
+            if self.get_revinodes(header.key) != Some(inode) && reported.insert(inode) {
+                problems.push(Problem::InodesMismatch { inode })
+            }
+        }
+        for (key, inode) in self.iter_revinodes(None) {
+            if self.get_inodes(inode).map(|h| h.key) != Some(key) && reported.insert(inode) {
+                problems.push(Problem::InodesMismatch { inode })
+            }
+        }
+    }
+
+    fn check_tree(&self, problems: &mut Vec<Problem>) {
+        let mut reported = HashSet::new();
+        for (file_id, inode) in self.iter_tree(None) {
+            // Directories have an extra, name-less, binding to
+            // themselves, which is not in revtree.
+            let ok = if file_id.basename.is_empty() {
+                file_id.parent_inode == inode
+            } else {
+                self.get_revtree(inode).map(|f| f.to_owned()) == Some(file_id.to_owned())
+            };
+            if !ok && reported.insert(inode) {
+                problems.push(Problem::TreeMismatch { inode })
+            }
+        }
+        for (inode, file_id) in self.iter_revtree(None) {
+            if self.get_tree(&file_id) != Some(inode) && reported.insert(inode) {
+                problems.push(Problem::TreeMismatch { inode })
+            }
+        }
+    }
+}
+
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
+    /// Rebuild the `dep`, `revdep` and `touched_files` tables from
+    /// the patch files of `repo_root`. The entries of patches whose
+    /// file can't be read are kept. Returns the number of patches
+    /// whose entries were rebuilt.
+    pub fn repair(&mut self, repo_root: &Path) -> Result<usize> {
+        let branches: Vec<Branch> = self.iter_branches(None).collect();
+        let registered: Vec<(PatchId, Hash)> = self.iter_external(None)
+            .map(|(id, hash)| (id, hash.to_owned()))
+            .collect();
+        let mut derived = HashMap::new();
+        for (id, hash) in registered {
+            match read_patch(repo_root, hash.as_ref()) {
+                Ok(patch) => {
+                    derived.insert(id, self.derived_tables(id, &patch));
+                }
+                Err(e) => error!("cannot repair patch {:?}: {}", hash.to_base58(), e),
+            }
+        }
+
+        let old_deps: Vec<_> = self.iter_dep(None)
+            .filter(|&(p, _)| derived.contains_key(&p))
+            .collect();
+        for (p, dep) in old_deps {
+            self.del_dep(p, Some(dep))?;
+        }
+        let old_revdeps: Vec<_> = self.iter_revdep(None)
+            .filter(|&(_, p)| derived.contains_key(&p))
+            .collect();
+        for (dep, p) in old_revdeps {
+            self.del_revdep(dep, Some(p))?;
+        }
+        // `ROOT_KEY` is the smallest key, so this iterates over the
+        // whole table.
+        let old_touched: Vec<_> = self.iter_touched(ROOT_KEY)
+            .filter(|&(_, p)| derived.contains_key(&p))
+            .collect();
+        for (file, p) in old_touched {
+            self.del_touched_file(file, p)?;
+        }
+
+        for (&id, d) in derived.iter() {
+            for &dep in d.dependencies.iter() {
+                self.put_dep(id, dep)?;
+                if self.has_revdep(dep, id, &branches) {
+                    self.put_revdep(dep, id)?;
+                }
+            }
+            for &file in d.touched.iter() {
+                self.put_touched_file(file, id)?;
+            }
+        }
+        Ok(derived.len())
+    }
+}
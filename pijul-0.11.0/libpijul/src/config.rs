@@ -0,0 +1,20 @@
+//! Settings of a repository that change how its working copy is
+//! recorded and output. Libpijul doesn't read them from any file,
+//! the caller passes them to `Repository::with_mut_txn`, or sets the
+//! `config` field of its transactions.
+
+use conflict::MarkerStyle;
+use optimal_diff::DiffAlgorithm;
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Style of the conflict markers output in the working copy.
+    pub conflict_markers: MarkerStyle,
+    /// Diff algorithm used by record.
+    pub diff_algorithm: DiffAlgorithm,
+    /// Files at least this large (in bytes) are recorded as blobs
+    /// (see the `blob` module).
+    pub large_file_threshold: Option<u64>,
+    /// Patterns of files recorded as blobs, regardless of their size.
+    pub large_file_patterns: Vec<String>,
+}
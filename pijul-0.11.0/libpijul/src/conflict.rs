@@ -1,5 +1,306 @@
+//! Conflict markers, and the representation of conflicting files.
+//!
+//! Markers can be written in three styles, chosen by the
+//! `conflict_markers` field of the `Config`:
+//!
+//! - `plain` (the default): Pijul's markers, without labels.
+//!
+//! - `labeled`: Pijul's markers, where the marker starting each side
+//! is followed by the names of the patches that introduced its lines.
+//!
+//! - `git`: the markers used by Git (`<<<<<<<`, `=======` and
+//! `>>>>>>>`), so that editors and merge tools recognise them. The
+//! first and last sides are labeled, as in Git.
+//!
+//! Labels are ignored when recording, so they may be edited freely.
+
 pub const START_MARKER: &'static str = "\n>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>\n";
 
 pub const SEPARATOR: &'static str = "\n================================\n";
 
 pub const END_MARKER: &'static str = "\n<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<\n";
+
+pub const GIT_START_MARKER: &'static str = "\n<<<<<<<\n";
+
+pub const GIT_SEPARATOR: &'static str = "\n=======\n";
+
+pub const GIT_END_MARKER: &'static str = "\n>>>>>>>\n";
+
+use backend::{Contents, Hash, Key, PatchId, Transaction};
+use fs_representation::read_patch_nochanges;
+use graph::LineBuffer;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkerStyle {
+    Plain,
+    Labeled,
+    Git,
+}
+
+impl Default for MarkerStyle {
+    fn default() -> Self {
+        MarkerStyle::Plain
+    }
+}
+
+impl MarkerStyle {
+    /// The start, separator and end markers of this style, without
+    /// labels.
+    pub fn markers(&self) -> (&'static str, &'static str, &'static str) {
+        match *self {
+            MarkerStyle::Plain | MarkerStyle::Labeled => (START_MARKER, SEPARATOR, END_MARKER),
+            MarkerStyle::Git => (GIT_START_MARKER, GIT_SEPARATOR, GIT_END_MARKER),
+        }
+    }
+}
+
+/// Whether `line` is `marker` followed by a label.
+pub fn is_labeled_marker(marker: &[u8], line: &[u8]) -> bool {
+    let marker = if marker.starts_with(b"\n") {
+        &marker[1..]
+    } else {
+        marker
+    };
+    let marker = if marker.ends_with(b"\n") {
+        &marker[..marker.len() - 1]
+    } else {
+        marker
+    };
+    !marker.is_empty() && line.len() > marker.len() && line.starts_with(marker)
+        && line[marker.len()] == b' '
+}
+
+fn with_label(marker: &str, label: &str) -> String {
+    if label.is_empty() {
+        marker.to_string()
+    } else {
+        format!("{} {}\n", marker.trim_right_matches('\n'), label)
+    }
+}
+
+/// Conflict markers in a given style, labeled with the patches that
+/// introduced each side.
+#[derive(Debug)]
+pub struct Markers {
+    pub style: MarkerStyle,
+    /// Repository where the names of patches are read. Patches are
+    /// labeled by their hash if this is `None`.
+    repo_root: Option<PathBuf>,
+    names: HashMap<Hash, String>,
+    /// Label of the current side of each open conflict.
+    sides: Vec<String>,
+}
+
+impl Markers {
+    pub fn new(style: MarkerStyle, repo_root: Option<&Path>) -> Self {
+        Markers {
+            style,
+            repo_root: repo_root.map(|r| r.to_path_buf()),
+            names: HashMap::new(),
+            sides: Vec::new(),
+        }
+    }
+
+    /// The label of a side whose lines were introduced by `patches`:
+    /// their names, or their hashes (shortened) if the names are
+    /// unknown.
+    pub fn label(&mut self, patches: &[Hash]) -> String {
+        let mut label = String::new();
+        for h in patches {
+            if !self.names.contains_key(h) {
+                let name = self
+                    .repo_root
+                    .as_ref()
+                    .and_then(|r| read_patch_nochanges(r, h.as_ref()).ok())
+                    .map(|header| header.name.replace('\n', " "))
+                    .unwrap_or_else(|| h.to_base58().chars().take(10).collect());
+                self.names.insert(h.clone(), name);
+            }
+            if !label.is_empty() {
+                label.push_str(", ")
+            }
+            label.push_str(self.names.get(h).unwrap())
+        }
+        label
+    }
+
+    pub fn begin(&mut self, side: &[Hash]) -> String {
+        let (start, _, _) = self.style.markers();
+        if let MarkerStyle::Plain = self.style {
+            return start.to_string();
+        }
+        let label = self.label(side);
+        let marker = with_label(start, &label);
+        self.sides.push(label);
+        marker
+    }
+
+    pub fn next(&mut self, side: &[Hash]) -> String {
+        let (_, separator, _) = self.style.markers();
+        match self.style {
+            MarkerStyle::Plain => separator.to_string(),
+            MarkerStyle::Labeled => with_label(separator, &self.label(side)),
+            MarkerStyle::Git => {
+                // Git only labels the first and last sides.
+                let label = self.label(side);
+                if let Some(last) = self.sides.last_mut() {
+                    *last = label
+                }
+                separator.to_string()
+            }
+        }
+    }
+
+    pub fn end(&mut self) -> String {
+        let (_, _, end) = self.style.markers();
+        let label = self.sides.pop().unwrap_or(String::new());
+        if let MarkerStyle::Git = self.style {
+            with_label(end, &label)
+        } else {
+            end.to_string()
+        }
+    }
+}
+
+/// A line of a file, along with the vertex it comes from. Markers of
+/// nested conflicts have no vertex.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub key: Option<Key<PatchId>>,
+    pub contents: Vec<u8>,
+}
+
+/// A region of a file, as output by `MutTxn::output_file`.
+#[derive(Debug, Clone)]
+pub enum Region {
+    /// Lines outside of any conflict.
+    Lines(Vec<Line>),
+    /// A conflict, with its sides. Conflicts nested inside a side
+    /// are kept in that side, with their markers.
+    Conflict(Vec<Vec<Line>>),
+}
+
+impl Region {
+    /// Zombie conflicts, i.e. lines that were deleted by a patch
+    /// unaware of their context, only have one side.
+    pub fn is_zombie(&self) -> bool {
+        match *self {
+            Region::Conflict(ref sides) => sides.len() == 1,
+            Region::Lines(_) => false,
+        }
+    }
+}
+
+/// A `LineBuffer` splitting a file into regions, one per top-level
+/// conflict, instead of writing conflict markers.
+#[derive(Debug)]
+pub struct Regions {
+    pub regions: Vec<Region>,
+    depth: usize,
+    new_line: bool,
+    /// Markers of nested conflicts.
+    markers: Markers,
+}
+
+impl Regions {
+    pub fn new() -> Self {
+        Self::with_markers(Markers::new(MarkerStyle::Plain, None))
+    }
+
+    pub fn with_markers(markers: Markers) -> Self {
+        Regions {
+            regions: Vec::new(),
+            depth: 0,
+            new_line: true,
+            markers,
+        }
+    }
+
+    /// Whether this file has at least one conflict (including zombie
+    /// conflicts).
+    pub fn has_conflicts(&self) -> bool {
+        self.regions.iter().any(|r| match *r {
+            Region::Conflict(_) => true,
+            Region::Lines(_) => false,
+        })
+    }
+
+    fn push(&mut self, line: Line) {
+        if self.depth == 0 {
+            if let Some(&mut Region::Lines(ref mut lines)) = self.regions.last_mut() {
+                lines.push(line);
+                return;
+            }
+            self.regions.push(Region::Lines(vec![line]))
+        } else if let Some(&mut Region::Conflict(ref mut sides)) = self.regions.last_mut() {
+            sides.last_mut().unwrap().push(line)
+        }
+    }
+
+    /// Markers of nested conflicts, written as `graph::Writer` would.
+    fn push_marker(&mut self, marker: &str) {
+        let marker = if self.new_line { &marker[1..] } else { marker };
+        self.push(Line {
+            key: None,
+            contents: marker.as_bytes().to_vec(),
+        })
+    }
+}
+
+impl<'a, T: 'a + Transaction> LineBuffer<'a, T> for Regions {
+    fn output_line(&mut self, key: &Key<PatchId>, contents: Contents<'a>) -> Result<()> {
+        let line = contents.into_owned();
+        // The empty vertices at the beginning of files are not lines.
+        if !line.is_empty() {
+            self.new_line = line.ends_with(b"\n");
+            self.push(Line {
+                key: Some(*key),
+                contents: line,
+            })
+        }
+        Ok(())
+    }
+
+    fn output_conflict_marker(&mut self, s: &'a str) -> Result<()> {
+        self.push_marker(s);
+        Ok(())
+    }
+
+    fn begin_conflict(&mut self, side: &[Hash]) -> Result<()> {
+        if self.depth == 0 {
+            self.regions.push(Region::Conflict(vec![Vec::new()]))
+        } else {
+            let marker = self.markers.begin(side);
+            self.push_marker(&marker)
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn conflict_next(&mut self, side: &[Hash]) -> Result<()> {
+        if self.depth == 1 {
+            if let Some(&mut Region::Conflict(ref mut sides)) = self.regions.last_mut() {
+                sides.push(Vec::new())
+            }
+        } else {
+            let marker = self.markers.next(side);
+            self.push_marker(&marker)
+        }
+        Ok(())
+    }
+
+    fn end_conflict(&mut self) -> Result<()> {
+        if self.depth > 1 {
+            let marker = self.markers.end();
+            self.push_marker(&marker)
+        }
+        if self.depth > 0 {
+            self.depth -= 1
+        }
+        Ok(())
+    }
+}
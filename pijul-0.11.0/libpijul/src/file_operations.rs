@@ -12,7 +12,7 @@ use std::collections::BTreeMap;
 use std::iter::Iterator;
 use std::path::{Path, PathBuf};
 
-impl<'env, R: rand::Rng> MutTxn<'env, R> {
+impl<U: MutTransaction, R: rand::Rng> GenericTxn<U, R> {
     pub fn mark_inode_moved(&mut self, inode: Inode) {
         let mut header = None;
         if let Some(h) = self.get_inodes(inode) {
@@ -296,7 +296,7 @@ impl<A: Transaction, R> backend::GenericTxn<A, R> {
         &self,
         branch: &Branch,
         key: Key<PatchId>,
-    ) -> BTreeMap<Key<PatchId>, Vec<(FileMetadata, &str)>> {
+    ) -> BTreeMap<Key<PatchId>, Vec<(FileMetadata, String)>> {
         let mut result = BTreeMap::new();
 
         let e = Edge::zero(EdgeFlags::FOLDER_EDGE);
@@ -304,8 +304,7 @@ impl<A: Transaction, R> backend::GenericTxn<A, R> {
             |&(k, ref v)| k == key && v.flag <= EdgeFlags::FOLDER_EDGE | EdgeFlags::PSEUDO_EDGE,
         ) {
             let name = self.get_contents(child.dest).unwrap();
-            // This is supposed to be a small string anyway.
-            let (perms, basename) = name.as_slice().split_at(2);
+            let (perms, basename) = name.split_at(2);
             let perms = FileMetadata::from_contents(perms);
             let basename = std::str::from_utf8(basename).unwrap();
 
@@ -314,7 +313,7 @@ impl<A: Transaction, R> backend::GenericTxn<A, R> {
                     k == child.dest && v.flag <= EdgeFlags::FOLDER_EDGE | EdgeFlags::PSEUDO_EDGE
                 }) {
                 let names = result.entry(grandchild.dest.to_owned()).or_insert(vec![]);
-                names.push((perms, basename))
+                names.push((perms, basename.to_string()))
             }
         }
         result
@@ -371,7 +370,7 @@ impl<A: Transaction, R> backend::GenericTxn<A, R> {
         &self,
         branch: &Branch,
         key: Key<PatchId>,
-    ) -> Vec<(Key<PatchId>, FileMetadata, &str)> {
+    ) -> Vec<(Key<PatchId>, FileMetadata, String)> {
         let mut result = Vec::new();
         let e = Edge::zero(EdgeFlags::FOLDER_EDGE | EdgeFlags::PARENT_EDGE);
 
@@ -385,8 +384,7 @@ impl<A: Transaction, R> backend::GenericTxn<A, R> {
             debug!("file_names, parent {:?}", parent);
             match self.get_contents(parent.dest) {
                 Some(ref name) if name.len() >= 2 => {
-                    // This is supposed to be a small string anyway.
-                    let (perms, basename) = name.as_slice().split_at(2);
+                    let (perms, basename) = name.split_at(2);
                     let perms = FileMetadata::from_contents(perms);
                     let basename = std::str::from_utf8(basename).unwrap();
 
@@ -396,7 +394,7 @@ impl<A: Transaction, R> backend::GenericTxn<A, R> {
                             v.flag
                                 .contains(EdgeFlags::FOLDER_EDGE | EdgeFlags::PARENT_EDGE)
                         }) {
-                        result.push((grandparent.dest.to_owned(), perms, basename));
+                        result.push((grandparent.dest.to_owned(), perms, basename.to_string()));
                         break;
                     }
                 }
@@ -420,7 +418,7 @@ impl<A: Transaction, R> backend::GenericTxn<A, R> {
                 .filter(|&(_, ref v)| v.flag.contains(EdgeFlags::FOLDER_EDGE))
             {
                 let contents = self.get_contents(child.dest).unwrap();
-                if contents.into_cow().split_at(2).1 == comp.as_bytes() {
+                if contents.split_at(2).1 == comp.as_bytes() {
                     if !is_first {
                         return Err(Error::FileNameCount(current_key));
                     }
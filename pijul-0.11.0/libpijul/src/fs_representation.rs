@@ -6,13 +6,12 @@
 
 use Result;
 use backend::DEFAULT_BRANCH;
-use backend::{Hash, HashRef, MutTxn, ROOT_INODE};
+use backend::{GenericTxn, Hash, HashRef, Transaction, ROOT_INODE};
 use bs58;
 use flate2;
 use ignore::WalkBuilder;
 use ignore::overrides::OverrideBuilder;
 use patch::{Patch, PatchHeader};
-use rand;
 use rand::Rng;
 use rand::distributions::Alphanumeric;
 use std;
@@ -61,6 +60,42 @@ pub fn meta_file(p: &Path) -> PathBuf {
     p.join(PIJUL_DIR_NAME).join("meta.toml")
 }
 
+/// The manifest of an interrupted pull, listing the patches that
+/// remain to be applied.
+pub fn pull_manifest_file(p: &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("pull_manifest.toml")
+}
+
+/// The hashes of the patches recorded in this repository that were
+/// never pushed.
+pub fn local_patches_file(p: &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("local_patches.toml")
+}
+
+/// The tag registry, mapping tag names to the hashes of tag
+/// patches.
+pub fn tags_file(p: &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("tags.toml")
+}
+
+/// Directory of the stashes, i.e. of patches of unrecorded changes
+/// set aside by `pijul stash`, which are not on any branch.
+pub fn stash_dir(p: &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("stash")
+}
+
+/// Content-addressed store of the contents of large files (see the
+/// `blob` module).
+pub fn blobs_dir(p: &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("blobs")
+}
+
+/// The map between git commits and patches, maintained by `pijul git
+/// import` and `pijul git export`.
+pub fn git_map_file(p: &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("git_map")
+}
+
 /// The id file is used for remote operations, to identify a
 /// repository and save bandwidth when the remote state is partially
 /// known.
@@ -178,7 +213,10 @@ pub fn ignore_file(repo_root: &Path) -> PathBuf {
     repo_root.join(PIJUL_DIR_NAME).join("local").join("ignore")
 }
 
-pub fn untracked_files<T: rand::Rng>(txn: &MutTxn<T>, repo_root: &Path) -> HashSet<PathBuf> {
+pub fn untracked_files<A: Transaction, R>(
+    txn: &GenericTxn<A, R>,
+    repo_root: &Path,
+) -> HashSet<PathBuf> {
     let known_files = txn.list_files(ROOT_INODE).unwrap_or_else(|_| vec![]);
 
     let o = OverrideBuilder::new(repo_root)
@@ -0,0 +1,208 @@
+//! Garbage collection and compaction of the pristine.
+//!
+//! Sanakirja files never shrink: pages freed by a transaction are
+//! reused, but the file keeps the largest size it ever had. Moreover,
+//! deleting a branch leaves its patches registered, along with the
+//! contents of their lines. `collect_garbage` removes these entries,
+//! and `compact` copies all the tables into a fresh file, of the
+//! smallest size that fits them.
+
+use backend::*;
+use rand;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, remove_dir_all, rename};
+use std::path::Path;
+use tempdir::TempDir;
+use Result;
+
+/// Initial size of the compacted pristine. It is doubled until all
+/// the tables fit.
+const COMPACT_INITIAL_SIZE: u64 = 1 << 20;
+
+/// What `collect_garbage` removed from the pristine.
+#[derive(Debug, Default)]
+pub struct Garbage {
+    /// Patches that were not applied to any branch, and have been
+    /// unregistered.
+    pub patches: Vec<Hash>,
+    /// Number of lines whose contents have been deleted.
+    pub contents: usize,
+    /// Number of edges deleted from the cemetery.
+    pub cemetery: usize,
+}
+
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
+    /// Unregister the patches that are neither applied to any branch
+    /// nor in `roots`, and delete the contents and cemetery entries
+    /// that refer to them. `roots` are the patches the caller still
+    /// needs, such as the dependencies of a stash.
+    pub fn collect_garbage(&mut self, roots: &HashSet<Hash>) -> Result<Garbage> {
+        let mut garbage = Garbage::default();
+        let mut live = HashSet::new();
+        for branch in self.iter_branches(None) {
+            live.extend(self.iter_patches(&branch, None).map(|(p, _)| p))
+        }
+        live.extend(roots.iter().filter_map(|h| self.get_internal(h.as_ref())));
+        let is_live = |p: PatchId| p.is_root() || live.contains(&p);
+
+        let dead: Vec<(PatchId, Hash)> = self.iter_external(None)
+            .filter(|&(p, _)| !is_live(p))
+            .map(|(p, h)| (p, h.to_owned()))
+            .collect();
+        for &(p, ref h) in dead.iter() {
+            debug!("unregistering {:?}", h.to_base58());
+            self.del_external(p)?;
+            self.del_internal(h.as_ref())?;
+        }
+        let deps: Vec<_> = self.iter_dep(None)
+            .filter(|&(p, _)| !is_live(p))
+            .collect();
+        for (p, dep) in deps {
+            self.del_dep(p, Some(dep))?;
+        }
+        let revdeps: Vec<_> = self.iter_revdep(None)
+            .filter(|&(dep, p)| !is_live(dep) || !is_live(p))
+            .collect();
+        for (dep, p) in revdeps {
+            self.del_revdep(dep, Some(p))?;
+        }
+        // `ROOT_KEY` is the smallest key, so this iterates over the
+        // whole table.
+        let touched: Vec<_> = self.iter_touched(ROOT_KEY)
+            .filter(|&(_, p)| !is_live(p))
+            .collect();
+        for (file, p) in touched {
+            self.del_touched_file(file, p)?;
+        }
+        garbage.patches = dead.into_iter().map(|(_, h)| h).collect();
+
+        let contents: Vec<_> = self.iter_contents(None)
+            .map(|(k, _)| k)
+            .filter(|k| !is_live(k.patch))
+            .collect();
+        for k in contents {
+            self.del_contents(k)?;
+            garbage.contents += 1
+        }
+
+        let cemetery: Vec<_> = self.iter_cemetery(ROOT_KEY, Edge::zero(EdgeFlags::empty()))
+            .filter(|&((k, e), p)| !is_live(p) || !is_live(k.patch) || !is_live(e.dest.patch))
+            .collect();
+        for ((k, e), p) in cemetery {
+            self.del_cemetery(k, e, p)?;
+            garbage.cemetery += 1
+        }
+        Ok(garbage)
+    }
+
+    /// Copy all the tables of `from` into this transaction, which
+    /// should be on an empty repository.
+    fn copy_tables<V: Transaction, S>(&mut self, from: &GenericTxn<V, S>) -> Result<()> {
+        for (file_id, inode) in from.iter_tree(None) {
+            self.put_tree(&file_id, inode)?;
+        }
+        for (inode, file_id) in from.iter_revtree(None) {
+            self.put_revtree(inode, &file_id)?;
+        }
+        for (inode, header) in from.iter_inodes(None) {
+            self.replace_inodes(inode, header)?;
+        }
+        for (key, inode) in from.iter_revinodes(None) {
+            self.replace_revinodes(key, inode)?;
+        }
+        for (key, value) in from.iter_contents(None) {
+            self.put_contents(key, &value)?;
+        }
+        for (p, hash) in from.iter_external(None) {
+            self.put_external(p, hash)?;
+            self.put_internal(hash, p)?;
+        }
+        for (p, dep) in from.iter_dep(None) {
+            self.put_dep(p, dep)?;
+        }
+        for (dep, p) in from.iter_revdep(None) {
+            self.put_revdep(dep, p)?;
+        }
+        for ((key, edge), p) in from.iter_cemetery(ROOT_KEY, Edge::zero(EdgeFlags::empty())) {
+            self.put_cemetery(key, edge, p)?;
+        }
+        for (file, p) in from.iter_touched(ROOT_KEY) {
+            self.put_touched_file(file, p)?;
+        }
+        for (name, key) in from.iter_partials("") {
+            self.put_partials(name.as_str(), key)?;
+        }
+        for branch in from.iter_branches(None) {
+            let mut new_branch = self.open_branch(branch.name.as_str())?;
+            for (key, edge) in from.iter_nodes(&branch, None) {
+                self.put_edge_one_dir(&mut new_branch, key, edge)?;
+            }
+            for (p, time) in from.iter_patches(&branch, None) {
+                self.put_patches(&mut new_branch.patches, p, time)?;
+            }
+            for (time, p) in from.iter_applied(&branch, None) {
+                self.put_revpatches(&mut new_branch.revpatches, time, p)?;
+            }
+            new_branch.apply_counter = branch.apply_counter;
+            self.commit_branch(new_branch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy the pristine `from` into a new pristine at `to`, of size
+/// `size`.
+fn copy_pristine<U: Transaction, S>(from: &GenericTxn<U, S>, to: &Path, size: u64) -> Result<()> {
+    if to.exists() {
+        remove_dir_all(to)?;
+    }
+    create_dir_all(to)?;
+    let repo = Repository::open(to, Some(size))?;
+    let mut txn = repo.mut_txn_begin(rand::thread_rng())?;
+    txn.copy_tables(from)?;
+    txn.commit()
+}
+
+/// Rewrite the pristine in `pristine_dir` into a fresh file, just
+/// large enough for its contents. The pristine is only replaced if
+/// the new file is smaller, which might not be the case for instance
+/// if several branches share most of their graphs. Returns the size
+/// of the pristine before and after compaction.
+///
+/// A mutable transaction is held on the old pristine from the copy
+/// until the new one is in place, so that no other process commits
+/// to the old pristine in between. Processes that were waiting for
+/// that transaction notice that the pristine was replaced, and open
+/// the new one (see `Repository::with_mut_txn`).
+pub fn compact(pristine_dir: &Path) -> Result<(u64, u64)> {
+    let before = Repository::repository_size(pristine_dir)?;
+    let tmp = TempDir::new_in(pristine_dir.parent().unwrap_or(pristine_dir), "pijul_compact")?;
+    let new_pristine = tmp.path().join("pristine");
+    let old_pristine = tmp.path().join("old");
+    let repo = Repository::open(pristine_dir, None)?;
+    let txn = repo.mut_txn_begin(rand::thread_rng())?;
+    let mut size = COMPACT_INITIAL_SIZE;
+    loop {
+        match copy_pristine(&txn, &new_pristine, size) {
+            Err(ref e) if e.lacks_space() => size *= 2,
+            e => break e?,
+        }
+    }
+    let after = Repository::repository_size(&new_pristine)?;
+    debug!("compact: {:?} -> {:?}", before, after);
+    if after >= before {
+        return Ok((before, before));
+    }
+    rename(pristine_dir, &old_pristine)?;
+    if let Err(e) = rename(&new_pristine, pristine_dir) {
+        if rename(&old_pristine, pristine_dir).is_err() {
+            // Don't let `tmp` delete the only copy of the pristine.
+            error!("the pristine was left in {:?}", old_pristine);
+            tmp.into_path();
+        }
+        return Err(e.into());
+    }
+    // The transaction is dropped (without committing) before `tmp`,
+    // which deletes the old pristine.
+    Ok((before, after))
+}
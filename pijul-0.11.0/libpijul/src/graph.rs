@@ -4,6 +4,7 @@
 use Result;
 use backend::*;
 use conflict;
+use std::borrow::Cow;
 use std::cmp::min;
 use std::collections::{HashMap, HashSet};
 
@@ -108,7 +109,6 @@ impl Graph {
         for (line, i) in self.lines.iter().zip(0..) {
             let contents = {
                 if let Some(c) = txn.get_contents(line.key) {
-                    let c = c.into_cow();
                     if let Ok(c) = std::str::from_utf8(&c) {
                         c.split_at(std::cmp::min(50, c.len())).0.to_string()
                     } else {
@@ -200,16 +200,19 @@ impl Graph {
     }
 }
 
-use sanakirja::value::Value;
 /// A "line outputter" trait.
 pub trait LineBuffer<'a, T: 'a + Transaction> {
-    fn output_line(&mut self, key: &Key<PatchId>, contents: Value<'a, T>) -> Result<()>;
+    fn output_line(&mut self, key: &Key<PatchId>, contents: Contents<'a>) -> Result<()>;
 
     fn output_conflict_marker(&mut self, s: &'a str) -> Result<()>;
-    fn begin_conflict(&mut self) -> Result<()> {
+    /// Begin a conflict, whose first side contains lines introduced
+    /// by `side`.
+    fn begin_conflict(&mut self, _side: &[Hash]) -> Result<()> {
         self.output_conflict_marker(conflict::START_MARKER)
     }
-    fn conflict_next(&mut self) -> Result<()> {
+    /// Begin the next side of a conflict, containing lines introduced
+    /// by `side`.
+    fn conflict_next(&mut self, _side: &[Hash]) -> Result<()> {
         self.output_conflict_marker(conflict::SEPARATOR)
     }
     fn end_conflict(&mut self) -> Result<()> {
@@ -220,11 +223,34 @@ pub trait LineBuffer<'a, T: 'a + Transaction> {
 pub struct Writer<W: std::io::Write> {
     pub w: W,
     new_line: bool,
+    markers: conflict::Markers,
 }
 
 impl<W: std::io::Write> Writer<W> {
     pub fn new(w: W) -> Self {
-        Writer { w, new_line: true }
+        Self::with_markers(
+            w,
+            conflict::Markers::new(conflict::MarkerStyle::Plain, None),
+        )
+    }
+
+    /// A writer using `markers` for conflicts.
+    pub fn with_markers(w: W, markers: conflict::Markers) -> Self {
+        Writer {
+            w,
+            new_line: true,
+            markers,
+        }
+    }
+
+    fn write_marker(&mut self, s: &str) -> Result<()> {
+        debug!("output_conflict_marker {:?}", self.new_line);
+        if !self.new_line {
+            self.w.write_all(s.as_bytes())?;
+        } else {
+            self.w.write_all(&s.as_bytes()[1..])?;
+        }
+        Ok(())
     }
 }
 
@@ -242,31 +268,34 @@ impl<W: std::io::Write> std::ops::DerefMut for Writer<W> {
 }
 
 impl<'a, T: 'a + Transaction, W: std::io::Write> LineBuffer<'a, T> for Writer<W> {
-    fn output_line(&mut self, k: &Key<PatchId>, c: Value<T>) -> Result<()> {
-        let mut ends_with_newline = false;
-        let mut is_empty = true;
-        for chunk in c {
-            debug!("output line {:?} {:?}", k, std::str::from_utf8(chunk));
-            is_empty = is_empty && chunk.is_empty();
-            ends_with_newline = chunk.ends_with(b"\n");
-            self.w.write_all(chunk)?
-        }
-        if !is_empty {
+    fn output_line(&mut self, k: &Key<PatchId>, c: Contents) -> Result<()> {
+        debug!("output line {:?} {:?}", k, std::str::from_utf8(&c));
+        self.w.write_all(&c)?;
+        if !c.is_empty() {
             // empty "lines" (such as in the beginning of a file)
             // don't change the status of self.new_line.
-            self.new_line = ends_with_newline;
+            self.new_line = c.ends_with(b"\n");
         }
         Ok(())
     }
 
     fn output_conflict_marker(&mut self, s: &'a str) -> Result<()> {
-        debug!("output_conflict_marker {:?}", self.new_line);
-        if !self.new_line {
-            self.write(s.as_bytes())?;
-        } else {
-            self.write(&s.as_bytes()[1..])?;
-        }
-        Ok(())
+        self.write_marker(s)
+    }
+
+    fn begin_conflict(&mut self, side: &[Hash]) -> Result<()> {
+        let marker = self.markers.begin(side);
+        self.write_marker(&marker)
+    }
+
+    fn conflict_next(&mut self, side: &[Hash]) -> Result<()> {
+        let marker = self.markers.next(side);
+        self.write_marker(&marker)
+    }
+
+    fn end_conflict(&mut self) -> Result<()> {
+        let marker = self.markers.end();
+        self.write_marker(&marker)
     }
 }
 
@@ -696,8 +725,9 @@ struct ConflictMarkers<'b> {
 }
 
 impl<'b> ConflictMarkers<'b> {
-    fn output_zombie_markers_if_needed<'a, A: Transaction + 'a, B: LineBuffer<'a, A>>(
+    fn output_zombie_markers_if_needed<'a, A: Transaction + 'a, B: LineBuffer<'a, A>, R>(
         &mut self,
+        txn: &GenericTxn<A, R>,
         buf: &mut B,
         vertex: VertexId,
     ) -> Result<()> {
@@ -705,7 +735,12 @@ impl<'b> ConflictMarkers<'b> {
             if !self.current_is_zombie {
                 debug!("begin zombie conflict: vertex = {:?}", self.graph[vertex]);
                 self.current_is_zombie = true;
-                buf.begin_conflict()?;
+                let side: Vec<Hash> = txn
+                    .get_external(self.graph[vertex].key.patch)
+                    .map(|h| h.to_owned())
+                    .into_iter()
+                    .collect();
+                buf.begin_conflict(&side)?;
             }
         } else if self.current_is_zombie {
             // Zombie segment has ended
@@ -721,8 +756,9 @@ impl<'b> ConflictMarkers<'b> {
     fn begin_conflict<'a, A: Transaction + 'a, B: LineBuffer<'a, A>>(
         &mut self,
         buf: &mut B,
+        side: &[Hash],
     ) -> Result<()> {
-        buf.begin_conflict()?;
+        buf.begin_conflict(side)?;
         self.current_conflicts += 1;
         Ok(())
     }
@@ -775,17 +811,27 @@ impl<'a, A: Transaction + 'a, R> GenericTxn<A, R> {
         scc: &[Vec<VertexId>],
         conflict: &mut [Vec<ConflictLine>],
     ) -> Result<()> {
-        let mut is_first = true;
         let n_sides = conflict.len();
         debug!(target:"libpijul::graph::output_conflict", "n_sides = {:?}", n_sides);
+        let sides: Vec<Vec<Hash>> = if n_sides > 1 {
+            conflict
+                .iter()
+                .map(|side| {
+                    let mut patches = Vec::new();
+                    self.side_patches(graph, scc, side, &mut patches);
+                    patches
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
         if n_sides > 1 {
-            conflicts.begin_conflict(buf)?;
+            conflicts.begin_conflict(buf, &sides[0])?;
         }
-        for side in conflict {
-            if !is_first {
-                buf.conflict_next()?;
+        for (n, side) in conflict.iter_mut().enumerate() {
+            if n > 0 {
+                buf.conflict_next(&sides[n])?;
             }
-            is_first = false;
             debug!(target:"libpijul::graph::output_conflict", "side = {:?}", side);
             for i in side {
                 match *i {
@@ -804,6 +850,36 @@ impl<'a, A: Transaction + 'a, R> GenericTxn<A, R> {
         Ok(())
     }
 
+    /// The patches that introduced the lines of `side`, in order of
+    /// appearance.
+    fn side_patches(
+        &self,
+        graph: &Graph,
+        scc: &[Vec<VertexId>],
+        side: &[ConflictLine],
+        patches: &mut Vec<Hash>,
+    ) {
+        for line in side {
+            match *line {
+                ConflictLine::Line(i) => {
+                    for &v in scc[i].iter() {
+                        if let Some(h) = self.get_external(graph[v].key.patch) {
+                            let h = h.to_owned();
+                            if !patches.contains(&h) {
+                                patches.push(h)
+                            }
+                        }
+                    }
+                }
+                ConflictLine::Conflict(ref sides) => {
+                    for side in sides {
+                        self.side_patches(graph, scc, side, patches)
+                    }
+                }
+            }
+        }
+    }
+
     /// Output the database contents of the file into the buffer
     /// `buf`. The return value indicates whether there are any
     /// conflicts in the file that was output. If forward edges are
@@ -828,7 +904,7 @@ impl<'a, A: Transaction + 'a, R> GenericTxn<A, R> {
         graph.dfs(self, branch, &scc, &mut dfs, forward);
 
         debug!("dfs done");
-        buf.output_line(&graph.lines[1].key, Value::from_slice(b""))?;
+        buf.output_line(&graph.lines[1].key, Cow::Borrowed(&b""[..]))?;
         let conflict_tree = conflict_tree(graph, &scc, &mut dfs);
         debug!("conflict_tree = {:?}", conflict_tree);
         let mut conflicts = ConflictMarkers {
@@ -838,7 +914,7 @@ impl<'a, A: Transaction + 'a, R> GenericTxn<A, R> {
         };
         self.output_conflict(&mut conflicts, buf, graph, &scc, &mut [conflict_tree])?;
         // Close any remaining zombie part (if needed).
-        conflicts.output_zombie_markers_if_needed(buf, DUMMY_VERTEX)?;
+        conflicts.output_zombie_markers_if_needed(self, buf, DUMMY_VERTEX)?;
         debug!("/output_file");
         Ok(dfs.has_conflicts)
     }
@@ -851,7 +927,7 @@ impl<'a, A: Transaction + 'a, R> GenericTxn<A, R> {
         buf: &mut B,
     ) -> Result<()> {
         assert_eq!(scc.len(), 1);
-        conflicts.output_zombie_markers_if_needed(buf, scc[0])?;
+        conflicts.output_zombie_markers_if_needed(self, buf, scc[0])?;
         let key = graph[scc[0]].key;
         if let Some(cont) = self.get_contents(key) {
             debug!(target:"libpijul::graph::output_conflict", "outputting {:?}", cont);
@@ -1073,7 +1149,7 @@ fn conflict_tree(graph: &Graph, scc: &[Vec<VertexId>], dfs: &mut DFS) -> Vec<Con
 }
 
 /// Removes redundant forward edges, among those listed in `forward`.
-impl<'env, R: rand::Rng> MutTxn<'env, R> {
+impl<U: MutTransaction, R: rand::Rng> GenericTxn<U, R> {
     pub fn remove_redundant_edges(
         &mut self,
         branch: &mut Branch,
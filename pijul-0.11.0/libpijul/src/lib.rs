@@ -50,7 +50,9 @@ extern crate tempdir;
 extern crate thrussh_keys;
 extern crate toml;
 
-pub use sanakirja::Transaction;
+/// The transactions of sanakirja, which was exported as
+/// `Transaction` before the storage of the pristine was abstracted.
+pub use sanakirja::Transaction as SanakirjaTransaction;
 use std::collections::HashSet;
 use std::io::Write;
 use std::path::Path;
@@ -76,6 +78,12 @@ pub enum Error {
     BranchNameAlreadyExists(String),
     WrongFileHeader(Key<PatchId>),
     FileNameCount(Key<PatchId>),
+    NotInARepository(PathBuf),
+    InARepository(PathBuf),
+    NoSuchBranch(String),
+    PendingChanges,
+    BranchDoesNotHavePatch(String, Hash),
+    BlobChanged(PathBuf),
 }
 
 impl std::convert::From<std::io::Error> for Error {
@@ -149,6 +157,12 @@ impl std::fmt::Display for Error {
             Error::BranchNameAlreadyExists(ref name) => write!(fmt, "Branch {:?} already exists", name),
             Error::WrongFileHeader(ref h) => write!(fmt, "Wrong file header (possible branch corruption): {:?}", h),
             Error::FileNameCount(ref f) => write!(fmt, "Name {:?} doesn't have exactly one child", f),
+            Error::NotInARepository(ref p) => write!(fmt, "Not in a repository: {:?}", p),
+            Error::InARepository(ref p) => write!(fmt, "Already in a repository: {:?}", p),
+            Error::NoSuchBranch(ref name) => write!(fmt, "No such branch: {:?}", name),
+            Error::PendingChanges => write!(fmt, "There are pending changes in the repository"),
+            Error::BranchDoesNotHavePatch(ref name, ref h) => write!(fmt, "Branch {:?} does not have patch {}", name, h.to_base58()),
+            Error::BlobChanged(ref file) => write!(fmt, "Large file {:?} changed while being recorded", file),
         }
     }
 }
@@ -174,6 +188,12 @@ impl std::error::Error for Error {
             Error::BranchNameAlreadyExists(_) => "Branch name already exists",
             Error::WrongFileHeader(_) => "Wrong file header (possible branch corruption)",
             Error::FileNameCount(_) => "A file name doesn't have exactly one child",
+            Error::NotInARepository(_) => "Not in a repository",
+            Error::InARepository(_) => "Already in a repository",
+            Error::NoSuchBranch(_) => "No such branch",
+            Error::PendingChanges => "There are pending changes in the repository",
+            Error::BranchDoesNotHavePatch(..) => "Branch does not have patch",
+            Error::BlobChanged(_) => "Large file changed while being recorded",
         }
     }
 }
@@ -187,35 +207,57 @@ impl Error {
     }
 }
 
+/// Errors telling whether the pristine ran out of space, in which
+/// case `Repository::with_mut_txn` grows it and tries again.
+pub trait LacksSpace {
+    fn lacks_space(&self) -> bool;
+}
+
+impl LacksSpace for Error {
+    fn lacks_space(&self) -> bool {
+        Error::lacks_space(self)
+    }
+}
+
 #[macro_use]
 mod backend;
+pub mod blob;
+pub mod check;
+pub mod config;
 mod file_operations;
 pub mod fs_representation;
+pub mod gc;
 
 pub mod patch;
+pub mod repo;
 
 pub mod apply;
-mod conflict;
+pub mod conflict;
 pub mod graph;
 mod optimal_diff;
 mod output;
 mod record;
 mod unrecord;
 
-pub use backend::{ApplyTimestamp, Branch, Edge, EdgeFlags, FileId, FileMetadata, FileStatus,
-                  GenericTxn, Hash, HashRef, Inode, Key, LineId, MutTxn, OwnedFileId, PatchId,
-                  Repository, SmallStr, SmallString, Txn, DEFAULT_BRANCH, ROOT_INODE, ROOT_KEY};
+pub use backend::{ApplyTimestamp, Branch, Contents, Edge, EdgeFlags, FileId, FileMetadata,
+                  FileStatus, GenericTxn, Hash, HashRef, Inode, Key, LineId, Memory,
+                  MemoryMutTxn, MemoryTxn, MutTransaction, MutTxn, OwnedFileId, PatchId,
+                  Pristine, Repository, SmallStr, SmallString, Transaction, Txn,
+                  DEFAULT_BRANCH, ROOT_INODE, ROOT_KEY};
 
 use fs_representation::ID_LENGTH;
-pub use output::{Prefixes, ToPrefixes};
+pub use config::Config;
+pub use optimal_diff::DiffAlgorithm;
+pub use output::{NameConflict, Prefixes, ToPrefixes};
 pub use patch::{Patch, PatchHeader};
 use rand::Rng;
 use rand::distributions::Alphanumeric;
 pub use record::{InodeUpdate, RecordState};
+pub use repo::Repo;
 pub use sanakirja::value::Value;
 use std::io::Read;
 
-impl<'env, T: rand::Rng> backend::MutTxn<'env, T> {
+impl<U: MutTransaction, T: rand::Rng> backend::GenericTxn<U, T> {
     pub fn output_changes_file<P: AsRef<Path>>(&mut self, branch: &Branch, path: P) -> Result<()> {
         let changes_file =
             fs_representation::branch_changes_file(path.as_ref(), branch.name.as_str());
@@ -256,13 +298,7 @@ impl<'env, T: rand::Rng> backend::MutTxn<'env, T> {
         if branch.name.as_str() == new_name {
             Err(Error::BranchNameAlreadyExists(new_name.to_string()))
         } else {
-            Ok(Branch {
-                db: self.txn.fork(&mut self.rng, &branch.db)?,
-                patches: self.txn.fork(&mut self.rng, &branch.patches)?,
-                revpatches: self.txn.fork(&mut self.rng, &branch.revpatches)?,
-                name: SmallString::from_str(new_name),
-                apply_counter: branch.apply_counter,
-            })
+            self.fork_branch(branch, new_name)
         }
     }
     pub fn add_file<P: AsRef<Path>>(&mut self, path: P, is_dir: bool) -> Result<()> {
@@ -400,7 +436,7 @@ impl<T: Transaction, R> backend::GenericTxn<T, R> {
         &'a self,
         branch: &Branch,
         key: Key<PatchId>,
-    ) -> Vec<(Key<PatchId>, Vec<&'a str>)> {
+    ) -> Vec<(Key<PatchId>, Vec<String>)> {
         let mut names = vec![(key, Vec::new())];
         debug!("inode: {:?}", names);
         // Go back to the root.
@@ -468,14 +504,14 @@ fn make_remote<'a, I: Iterator<Item = &'a Hash>>(
     Ok((patches, size_increase))
 }
 
-/// Apply a number of patches, guessing the new repository size.  If
-/// this fails, the repository size is guaranteed to have been
-/// increased by at least some pages, and it is safe to call this
-/// function again.
+/// Apply a number of patches, growing the pristine as needed (see
+/// `Repository::with_mut_txn`), starting from a guess of the new
+/// repository size.
 ///
 /// Also, this function takes a file lock on the repository.
 pub fn apply_resize<'a, I, F, P: output::ToPrefixes>(
     target: &Path,
+    config: &Config,
     branch_name: &str,
     remote: I,
     partial_paths: P,
@@ -488,6 +524,7 @@ where
     let (patches, size_increase) = make_remote(target, remote)?;
     apply_resize_patches(
         target,
+        config,
         branch_name,
         &patches,
         size_increase,
@@ -499,34 +536,34 @@ where
 /// A version of `apply_resize` with the patches list already loaded.
 pub fn apply_resize_patches<'a, F, P: output::ToPrefixes>(
     target: &Path,
+    config: &Config,
     branch_name: &str,
     patches: &[(Hash, Patch)],
     size_increase: usize,
     partial_paths: P,
-    apply_cb: F,
+    mut apply_cb: F,
 ) -> Result<()>
 where
     F: FnMut(usize, &Hash),
 {
     use fs_representation::*;
     info!("applying patches with size_increase {:?}", size_increase);
-    let pristine_dir = pristine_dir(target).to_path_buf();
-    let repo = Repository::open(pristine_dir, Some(size_increase as u64))?;
-    let mut txn = repo.mut_txn_begin(rand::thread_rng())?;
-    let mut branch = txn.open_branch(branch_name)?;
-    txn.apply_patches(&mut branch, target, &patches, partial_paths, apply_cb)?;
-    txn.commit_branch(branch)?;
-    txn.commit().map_err(Into::into)
+    Repository::with_mut_txn(pristine_dir(target), config, size_increase as u64, |mut txn| {
+        let mut branch = txn.open_branch(branch_name)?;
+        txn.apply_patches(&mut branch, target, &patches, &partial_paths, &mut apply_cb)?;
+        txn.commit_branch(branch)?;
+        txn.commit()
+    })
 }
 
-/// Apply a number of patches, guessing the new repository size.  If
-/// this fails, the repository size is guaranteed to have been
-/// increased by at least some pages, and it is safe to call this
-/// function again.
+/// Apply a number of patches without outputting the repository,
+/// growing the pristine as needed, starting from a guess of the new
+/// repository size.
 ///
 /// Also, this function takes a file lock on the repository.
 pub fn apply_resize_no_output<'a, F, I>(
     target: &Path,
+    config: &Config,
     branch_name: &str,
     remote: I,
     apply_cb: F,
@@ -536,11 +573,19 @@ where
     F: FnMut(usize, &Hash),
 {
     let (patches, size_increase) = make_remote(target, remote)?;
-    apply_resize_patches_no_output(target, branch_name, &patches, size_increase, apply_cb)
+    apply_resize_patches_no_output(
+        target,
+        config,
+        branch_name,
+        &patches,
+        size_increase,
+        apply_cb,
+    )
 }
 
 pub fn apply_resize_patches_no_output<'a, F>(
     target: &Path,
+    config: &Config,
     branch_name: &str,
     patches: &[(Hash, Patch)],
     size_increase: usize,
@@ -551,52 +596,44 @@ where
 {
     use fs_representation::*;
     debug!("apply_resize_no_output: patches = {:?}", patches);
-    let pristine_dir = pristine_dir(target).to_path_buf();
-    let repo = try!(Repository::open(pristine_dir, Some(size_increase as u64)));
-    let mut txn = try!(repo.mut_txn_begin(rand::thread_rng()));
-    let mut branch = txn.open_branch(branch_name)?;
-    let mut new_patches_count = 0;
-    for &(ref p, ref patch) in patches.iter() {
-        debug!("apply_patches: {:?}", p);
-        txn.apply_patches_rec(&mut branch, &patches, p, patch, &mut new_patches_count)?;
-        apply_cb(new_patches_count, p);
-    }
-    info!("branch: {:?}", branch);
-    txn.commit_branch(branch)?;
-    txn.commit()?;
-    Ok(())
+    Repository::with_mut_txn(pristine_dir(target), config, size_increase as u64, |mut txn| {
+        let mut branch = txn.open_branch(branch_name)?;
+        let mut new_patches_count = 0;
+        for &(ref p, ref patch) in patches.iter() {
+            debug!("apply_patches: {:?}", p);
+            txn.apply_patches_rec(&mut branch, &patches, p, patch, &mut new_patches_count)?;
+            apply_cb(new_patches_count, p);
+        }
+        info!("branch: {:?}", branch);
+        txn.commit_branch(branch)?;
+        txn.commit()
+    })
 }
 
-/// Open the repository, and unrecord the patch, without increasing
-/// the size. If this fails, the repository file is guaranteed to have
-/// been increased by `increase` bytes.
-pub fn unrecord_no_resize(
+/// Open the repository, and unrecord the patches in `selected`,
+/// starting from the last one, growing the pristine as needed.
+pub fn unrecord_resize(
     repo_dir: &Path,
     repo_root: &Path,
+    config: &Config,
     branch_name: &str,
-    selected: &mut Vec<(Hash, Patch)>,
-    increase: u64,
+    selected: &[(Hash, Patch)],
+    size_increase: u64,
 ) -> Result<()> {
-    debug!("unrecord_no_resize: {:?}", repo_dir);
-    let repo = try!(Repository::open(repo_dir, Some(increase)));
-
-    let mut txn = try!(repo.mut_txn_begin(rand::thread_rng()));
-    let mut branch = txn.open_branch(branch_name)?;
-    let mut timestamps = Vec::new();
-    while let Some((hash, patch)) = selected.pop() {
-        let internal = txn.get_internal(hash.as_ref()).unwrap().to_owned();
-        debug!("Unrecording {:?}", hash);
-        if let Some(ts) = txn.get_patch(&branch.patches, internal) {
-            timestamps.push(ts);
+    debug!("unrecord_resize: {:?}", repo_dir);
+    Repository::with_mut_txn(repo_dir, config, size_increase, |mut txn| {
+        let mut branch = txn.open_branch(branch_name)?;
+        for &(ref hash, ref patch) in selected.iter().rev() {
+            let internal = txn.get_internal(hash.as_ref()).unwrap().to_owned();
+            debug!("Unrecording {:?}", hash);
+            txn.unrecord(&mut branch, internal, patch)?;
+            debug!("Done unrecording {:?}", hash);
         }
-        txn.unrecord(&mut branch, internal, &patch)?;
-        debug!("Done unrecording {:?}", hash);
-    }
 
-    if let Err(e) = txn.output_changes_file(&branch, repo_root) {
-        error!("no changes file: {:?}", e)
-    }
-    try!(txn.commit_branch(branch));
-    try!(txn.commit());
-    Ok(())
+        if let Err(e) = txn.output_changes_file(&branch, repo_root) {
+            error!("no changes file: {:?}", e)
+        }
+        txn.commit_branch(branch)?;
+        txn.commit()
+    })
 }
@@ -245,14 +245,14 @@ impl<A: Transaction, R: rand::Rng> GenericTxn<A, R> {
 /// Iterator over the first lines of sides of a conflict. This is
 /// non-trivial because conflicts can be nested. In such a case, this
 /// iterator returns the first lines of all sides of nested conflicts.
-struct ConflictSidesIter<'c, 'a: 'c, 'b: 'c, T: sanakirja::Transaction + 'a> {
+struct ConflictSidesIter<'c, 'a: 'c, 'b: 'c, T: Transaction + 'a> {
     level: usize,
     started: bool,
     current: usize,
     diff: &'c Diff<'a, 'b, T>,
 }
 
-impl<'c, 'a: 'c, 'b: 'c, T: sanakirja::Transaction + 'a> Iterator for ConflictSidesIter<'c, 'a, 'b, T> {
+impl<'c, 'a: 'c, 'b: 'c, T: Transaction + 'a> Iterator for ConflictSidesIter<'c, 'a, 'b, T> {
     type Item = usize;
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -0,0 +1,137 @@
+//! Myers' diff algorithm, in its linear space version: instead of
+//! remembering all the furthest reaching paths, we only look for the
+//! "middle snake" of the shortest edit script, and recurse on both
+//! sides of it.
+
+/// Push to `matches` the pairs of equal lines of a longest common
+/// subsequence of `a0..a1` and `b0..b1`, in increasing order. `eq(i,
+/// j)` tells whether line `i` of the first file is equal to line `j`
+/// of the second one.
+pub(crate) fn diff<F: Fn(usize, usize) -> bool>(
+    eq: &F,
+    mut a0: usize,
+    mut a1: usize,
+    mut b0: usize,
+    mut b1: usize,
+    matches: &mut Vec<(usize, usize)>,
+) {
+    while a0 < a1 && b0 < b1 && eq(a0, b0) {
+        matches.push((a0, b0));
+        a0 += 1;
+        b0 += 1;
+    }
+    let mut suffix = 0;
+    while a0 < a1 && b0 < b1 && eq(a1 - 1, b1 - 1) {
+        a1 -= 1;
+        b1 -= 1;
+        suffix += 1
+    }
+    if a0 < a1 && b0 < b1 {
+        if let Some((x, y)) = middle(eq, a0, a1, b0, b1) {
+            diff(eq, a0, x, b0, y, matches);
+            diff(eq, x, a1, y, b1, matches);
+        }
+    }
+    for k in 0..suffix {
+        matches.push((a1 + k, b1 + k))
+    }
+}
+
+/// Find a point of a shortest edit script from `(a0, b0)` to `(a1,
+/// b1)` that splits it into two scripts of roughly the same
+/// length. Returns `None` if the files have no common line, or if the
+/// split would not make the problem smaller.
+fn middle<F: Fn(usize, usize) -> bool>(
+    eq: &F,
+    a0: usize,
+    a1: usize,
+    b0: usize,
+    b1: usize,
+) -> Option<(usize, usize)> {
+    let n = (a1 - a0) as isize;
+    let m = (b1 - b0) as isize;
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d;
+    let len = 2 * max_d + 2;
+    // Furthest reaching x, on each diagonal, of the forward paths
+    // from the start, and of the backward paths from the end (the
+    // backward x is counted from the end).
+    let mut forward = vec![-1; len as usize];
+    let mut backward = vec![-1; len as usize];
+    forward[(offset + 1) as usize] = 0;
+    backward[(offset + 1) as usize] = 0;
+    let delta = n - m;
+    // If delta is odd, the forward paths meet the backward paths
+    // while extending the former, else while extending the latter.
+    let front = delta % 2 != 0;
+    // Diagonals that have gone out of the rectangle on each side.
+    let (mut k1start, mut k1end, mut k2start, mut k2end) = (0, 0, 0, 0);
+    let split = |x: isize, y: isize| {
+        if (x == 0 && y == 0) || (x == n && y == m) {
+            None
+        } else {
+            Some((a0 + x as usize, b0 + y as usize))
+        }
+    };
+    for d in 0..max_d {
+        let mut k1 = -d + k1start;
+        while k1 <= d - k1end {
+            let k1_offset = (offset + k1) as usize;
+            let mut x1 = if k1 == -d || (k1 != d && forward[k1_offset - 1] < forward[k1_offset + 1]) {
+                forward[k1_offset + 1]
+            } else {
+                forward[k1_offset - 1] + 1
+            };
+            let mut y1 = x1 - k1;
+            while x1 < n && y1 < m && eq(a0 + x1 as usize, b0 + y1 as usize) {
+                x1 += 1;
+                y1 += 1;
+            }
+            forward[k1_offset] = x1;
+            if x1 > n {
+                k1end += 2
+            } else if y1 > m {
+                k1start += 2
+            } else if front {
+                let k2_offset = offset + delta - k1;
+                if k2_offset >= 0 && k2_offset < len && backward[k2_offset as usize] != -1 {
+                    if x1 >= n - backward[k2_offset as usize] {
+                        return split(x1, y1);
+                    }
+                }
+            }
+            k1 += 2
+        }
+        let mut k2 = -d + k2start;
+        while k2 <= d - k2end {
+            let k2_offset = (offset + k2) as usize;
+            let mut x2 = if k2 == -d || (k2 != d && backward[k2_offset - 1] < backward[k2_offset + 1]) {
+                backward[k2_offset + 1]
+            } else {
+                backward[k2_offset - 1] + 1
+            };
+            let mut y2 = x2 - k2;
+            while x2 < n && y2 < m && eq(a1 - 1 - x2 as usize, b1 - 1 - y2 as usize) {
+                x2 += 1;
+                y2 += 1;
+            }
+            backward[k2_offset] = x2;
+            if x2 > n {
+                k2end += 2
+            } else if y2 > m {
+                k2start += 2
+            } else if !front {
+                let k1_offset = offset + delta - k2;
+                if k1_offset >= 0 && k1_offset < len && forward[k1_offset as usize] != -1 {
+                    let x1 = forward[k1_offset as usize];
+                    let y1 = offset + x1 - k1_offset;
+                    if x1 >= n - x2 {
+                        return split(x1, y1);
+                    }
+                }
+            }
+            k2 += 2
+        }
+    }
+    None
+}
@@ -0,0 +1,113 @@
+//! Patience diff: match the lines that appear exactly once on both
+//! sides, keep the longest increasing sequence of these matches, and
+//! recurse between them. Ranges without such lines are handed over to
+//! Myers' algorithm.
+
+use super::myers;
+use std::collections::HashMap;
+
+/// Push to `matches` the pairs of lines matched between `a0..a1` and
+/// `b0..b1`, in increasing order. `contents_a[i]` and `contents_b[j]`
+/// are used to find unique lines, and are `None` for lines that must
+/// not be used as anchors (such as conflict markers). Anchors are
+/// only kept if `eq` also considers them equal.
+pub(crate) fn diff<F: Fn(usize, usize) -> bool>(
+    eq: &F,
+    contents_a: &[Option<&[u8]>],
+    contents_b: &[&[u8]],
+    mut a0: usize,
+    mut a1: usize,
+    mut b0: usize,
+    mut b1: usize,
+    matches: &mut Vec<(usize, usize)>,
+) {
+    while a0 < a1 && b0 < b1 && eq(a0, b0) {
+        matches.push((a0, b0));
+        a0 += 1;
+        b0 += 1;
+    }
+    let mut suffix = 0;
+    while a0 < a1 && b0 < b1 && eq(a1 - 1, b1 - 1) {
+        a1 -= 1;
+        b1 -= 1;
+        suffix += 1
+    }
+    if a0 < a1 && b0 < b1 {
+        let anchors = unique_anchors(eq, contents_a, contents_b, a0, a1, b0, b1);
+        if anchors.is_empty() {
+            myers::diff(eq, a0, a1, b0, b1, matches)
+        } else {
+            let (mut i, mut j) = (a0, b0);
+            for (ai, bj) in anchors {
+                diff(eq, contents_a, contents_b, i, ai, j, bj, matches);
+                matches.push((ai, bj));
+                i = ai + 1;
+                j = bj + 1;
+            }
+            diff(eq, contents_a, contents_b, i, a1, j, b1, matches);
+        }
+    }
+    for k in 0..suffix {
+        matches.push((a1 + k, b1 + k))
+    }
+}
+
+/// The longest increasing sequence of pairs of lines that appear
+/// exactly once in both `a0..a1` and `b0..b1`.
+fn unique_anchors<F: Fn(usize, usize) -> bool>(
+    eq: &F,
+    contents_a: &[Option<&[u8]>],
+    contents_b: &[&[u8]],
+    a0: usize,
+    a1: usize,
+    b0: usize,
+    b1: usize,
+) -> Vec<(usize, usize)> {
+    // For each line, the number of occurrences and the last position
+    // on each side.
+    let mut occurrences: HashMap<&[u8], (usize, usize, usize, usize)> = HashMap::new();
+    for i in a0..a1 {
+        if let Some(line) = contents_a[i] {
+            let e = occurrences.entry(line).or_insert((0, 0, 0, 0));
+            e.0 += 1;
+            e.1 = i
+        }
+    }
+    for j in b0..b1 {
+        if let Some(e) = occurrences.get_mut(contents_b[j]) {
+            e.2 += 1;
+            e.3 = j
+        }
+    }
+    let mut pairs: Vec<(usize, usize)> = occurrences
+        .values()
+        .filter(|&&(na, i, nb, j)| na == 1 && nb == 1 && eq(i, j))
+        .map(|&(_, i, _, j)| (i, j))
+        .collect();
+    pairs.sort();
+
+    // Patience sorting: `tops[k]` is the index in `pairs` of the top
+    // card of pile `k`, and `previous[p]` the top of the previous
+    // pile when card `p` was added.
+    let mut tops: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = Vec::with_capacity(pairs.len());
+    for (p, &(_, j)) in pairs.iter().enumerate() {
+        let k = match tops.binary_search_by(|&t| pairs[t].1.cmp(&j)) {
+            Ok(k) | Err(k) => k,
+        };
+        previous.push(if k > 0 { Some(tops[k - 1]) } else { None });
+        if k < tops.len() {
+            tops[k] = p
+        } else {
+            tops.push(p)
+        }
+    }
+    let mut anchors = Vec::with_capacity(tops.len());
+    let mut p = tops.last().cloned();
+    while let Some(q) = p {
+        anchors.push(pairs[q]);
+        p = previous[q]
+    }
+    anchors.reverse();
+    anchors
+}
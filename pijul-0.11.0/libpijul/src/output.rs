@@ -1,13 +1,17 @@
 use backend::*;
+use blob;
 use patch::*;
-use record::InodeUpdate;
+use record::{is_text, InodeUpdate};
 use {Error, Result};
+use conflict;
+use fs_representation;
 use graph;
 use rand;
 use std;
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use tempdir;
 
@@ -32,6 +36,228 @@ fn set_permissions(_name: &Path, _permissions: u16) -> Result<()> {
     Ok(())
 }
 
+#[cfg(not(windows))]
+fn create_symlink(target: &[u8], name: &Path) -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(OsStr::from_bytes(target), name)?;
+    Ok(())
+}
+
+// Windows has different kinds of links for files and directories.
+// Creating them needs special privileges, hence this might fail.
+#[cfg(windows)]
+fn create_symlink(target: &[u8], name: &Path) -> Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+    let target = Path::new(std::str::from_utf8(target)?);
+    let is_dir = name
+        .parent()
+        .map(|parent| parent.join(target).is_dir())
+        .unwrap_or(false);
+    if is_dir {
+        symlink_dir(target, name)?
+    } else {
+        symlink_file(target, name)?
+    }
+    Ok(())
+}
+
+/// Keeps only one side of each conflict, which is how conflicting
+/// symbolic links and binary files are output, since they can't hold
+/// conflict markers.
+struct OneSide {
+    w: Vec<u8>,
+    /// The side to keep. Conflicts with fewer sides keep their last
+    /// one.
+    side: usize,
+    /// The sides of each of the current conflicts, the innermost last.
+    conflicts: Vec<Vec<Vec<u8>>>,
+    /// Largest number of sides of a conflict.
+    max_sides: usize,
+}
+
+impl OneSide {
+    fn new(side: usize) -> Self {
+        OneSide {
+            w: Vec::new(),
+            side,
+            conflicts: Vec::new(),
+            max_sides: 0,
+        }
+    }
+}
+
+impl<'a, T: 'a + Transaction> graph::LineBuffer<'a, T> for OneSide {
+    fn output_line(&mut self, _: &Key<PatchId>, c: Contents) -> Result<()> {
+        match self.conflicts.last_mut().and_then(|sides| sides.last_mut()) {
+            Some(side) => side.extend(&c[..]),
+            None => self.w.extend(&c[..]),
+        }
+        Ok(())
+    }
+
+    fn output_conflict_marker(&mut self, _: &'a str) -> Result<()> {
+        Ok(())
+    }
+
+    fn begin_conflict(&mut self, _: &[Hash]) -> Result<()> {
+        self.conflicts.push(vec![Vec::new()]);
+        Ok(())
+    }
+
+    fn conflict_next(&mut self, _: &[Hash]) -> Result<()> {
+        if let Some(sides) = self.conflicts.last_mut() {
+            sides.push(Vec::new())
+        }
+        Ok(())
+    }
+
+    fn end_conflict(&mut self) -> Result<()> {
+        if let Some(mut sides) = self.conflicts.pop() {
+            self.max_sides = std::cmp::max(self.max_sides, sides.len());
+            let side = std::cmp::min(self.side, sides.len() - 1);
+            let side = sides.swap_remove(side);
+            match self.conflicts.last_mut().and_then(|sides| sides.last_mut()) {
+                Some(outer) => outer.extend(side),
+                None => self.w.extend(side),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Remove `name` if it is a symbolic link, so that writing to it
+/// doesn't write to its target.
+fn remove_symlink(name: &Path) -> Result<()> {
+    if let Ok(meta) = std::fs::symlink_metadata(name) {
+        if meta.file_type().is_symlink() {
+            std::fs::remove_file(name)?
+        }
+    }
+    Ok(())
+}
+
+/// A change to the working copy, staged by the output functions.
+enum Staged {
+    /// Delete a dead file or directory.
+    Remove(PathBuf),
+    /// Move a file to its new name.
+    Rename { from: PathBuf, to: PathBuf },
+    CreateDir(PathBuf),
+    /// Move a file written in the staging directory to its place.
+    File { staged: PathBuf, path: PathBuf },
+    Symlink { target: Vec<u8>, path: PathBuf },
+    /// Replace the pointer in a file by the contents of its blob.
+    Materialize { repo_root: PathBuf, path: PathBuf },
+    SetPermissions { path: PathBuf, permissions: u16 },
+}
+
+/// The changes to the working copy made by outputting the
+/// repository. They are staged in the transaction, and only
+/// performed once it is committed: a transaction dropped without
+/// committing, for instance when the pristine runs out of space (see
+/// `Repository::with_mut_txn`), leaves the working copy untouched.
+#[derive(Default)]
+pub(crate) struct StagedOutput {
+    /// Where the files are written until the commit, created when
+    /// the first file is output.
+    dir: Option<tempdir::TempDir>,
+    changes: Vec<Staged>,
+}
+
+impl StagedOutput {
+    /// Create a file in the staging directory, to be moved to `path`
+    /// on commit.
+    fn create_file(&mut self, working_copy: &Path, path: &Path) -> Result<fs::File> {
+        if self.dir.is_none() {
+            // Stay on the file system of the working copy, so that
+            // files can be moved in place.
+            let repo_dir = fs_representation::repo_dir(working_copy);
+            let parent = if repo_dir.is_dir() {
+                repo_dir
+            } else {
+                working_copy.to_path_buf()
+            };
+            self.dir = Some(tempdir::TempDir::new_in(parent, "output")?)
+        }
+        let staged = self.dir
+            .as_ref()
+            .unwrap()
+            .path()
+            .join(self.changes.len().to_string());
+        let file = fs::File::create(&staged)?;
+        self.changes.push(Staged::File {
+            staged,
+            path: path.to_path_buf(),
+        });
+        Ok(file)
+    }
+
+    fn write_file(&mut self, working_copy: &Path, path: &Path, contents: &[u8]) -> Result<()> {
+        self.create_file(working_copy, path)?.write_all(contents)?;
+        Ok(())
+    }
+
+    /// Perform the staged changes, in order.
+    pub(crate) fn perform(self) -> Result<()> {
+        for change in self.changes {
+            match change {
+                Staged::Remove(name) => {
+                    debug!("deleting {:?}", name);
+                    if let Ok(meta) = fs::symlink_metadata(&name) {
+                        if let Err(e) = if meta.is_dir() {
+                            fs::remove_dir_all(&name)
+                        } else {
+                            fs::remove_file(&name)
+                        } {
+                            error!("while deleting {:?}: {:?}", name, e);
+                        }
+                    }
+                }
+                Staged::Rename { from, to } => {
+                    debug!("renaming {:?} to {:?}", from, to);
+                    if let Some(p) = to.parent() {
+                        fs::create_dir_all(p)?
+                    }
+                    if let Err(e) = fs::rename(&from, &to) {
+                        error!("while renaming {:?} to {:?}: {:?}", from, to, e)
+                    }
+                }
+                Staged::CreateDir(path) => fs::create_dir_all(&path)?,
+                Staged::File { staged, path } => {
+                    remove_symlink(&path)?;
+                    fs::rename(&staged, &path)?
+                }
+                Staged::Symlink { target, path } => {
+                    if let Ok(meta) = fs::symlink_metadata(&path) {
+                        if !meta.is_dir() {
+                            fs::remove_file(&path)?
+                        }
+                    }
+                    create_symlink(&target, &path)?
+                }
+                Staged::Materialize { repo_root, path } => {
+                    blob::materialize(&repo_root, &path)?;
+                }
+                Staged::SetPermissions { path, permissions } => {
+                    set_permissions(&path, permissions)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A name claimed by several files.
+#[derive(Debug)]
+pub struct NameConflict {
+    /// The name, relative to the root of the repository.
+    pub name: PathBuf,
+    /// The name under which each file was output, and the patch
+    /// that gave it that name.
+    pub candidates: Vec<(PathBuf, PatchId)>,
+}
+
 #[derive(Debug)]
 struct OutputItem {
     parent: Inode,
@@ -69,36 +295,10 @@ fn is_related(prefixes: &Prefixes, key: Key<PatchId>) -> Related {
     Related::No
 }
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
-    // Climb up the tree (using revtree).
-    fn filename_of_inode(&self, inode: Inode, working_copy: &Path) -> Option<PathBuf> {
-        let mut components = Vec::new();
-        let mut current = inode;
-        loop {
-            match self.get_revtree(current) {
-                Some(v) => {
-                    components.push(v.basename.to_owned());
-                    current = v.parent_inode.clone();
-                    if current == ROOT_INODE {
-                        break;
-                    }
-                }
-                None => {
-                    debug!("filename_of_inode: not in tree");
-                    return None;
-                }
-            }
-        }
-        let mut working_copy = working_copy.to_path_buf();
-        for c in components.iter().rev() {
-            working_copy.push(c.as_small_str().as_str());
-        }
-        Some(working_copy)
-    }
-
+impl<U: Transaction, T> GenericTxn<U, T> {
     /// Collect all the children of key `key` into `files`.
     fn collect_children(
-        &mut self,
+        &self,
         branch: &Branch,
         path: &Path,
         key: Key<PatchId>,
@@ -119,13 +319,11 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
                 .unwrap();
             let b_inode = self.get_revinodes(b_key.dest);
 
-            // This is supposed to be a small string, so we can do
-            // as_slice.
-            if cont_b.as_slice().len() < 2 {
+            if cont_b.len() < 2 {
                 error!("cont_b {:?} b.dest {:?}", cont_b, b.dest);
                 return Err(Error::WrongFileHeader(b.dest));
             }
-            let (perms, basename) = cont_b.as_slice().split_at(2);
+            let (perms, basename) = cont_b.split_at(2);
 
             let perms = FileMetadata::from_contents(perms);
             let basename = std::str::from_utf8(basename).unwrap();
@@ -165,13 +363,17 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
     /// As conflicts have an internal representation, it can be determined
     /// exactly which files contain conflicts.
     pub fn list_conflict_files(
-        &mut self,
+        &self,
         branch_name: &str,
         prefixes: &[&str],
     ) -> Result<Vec<PathBuf>> {
         let mut files = HashMap::new();
         let mut next_files = HashMap::new();
-        let branch = self.open_branch(branch_name)?;
+        let branch = if let Some(branch) = self.get_branch(branch_name) {
+            branch
+        } else {
+            return Ok(Vec::new());
+        };
         let mut base_path = PathBuf::new();
         let prefixes = prefixes.to_prefixes(self, &branch);
         self.collect_children(
@@ -221,6 +423,73 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
         Ok(ret)
     }
 
+    /// Collect the files whose name is claimed by several files,
+    /// which are output with the internal identifier of the patch
+    /// that introduced each name appended to it.
+    pub fn list_name_conflicts(
+        &self,
+        branch_name: &str,
+        prefixes: &[&str],
+    ) -> Result<Vec<NameConflict>> {
+        let mut files = HashMap::new();
+        let mut next_files = HashMap::new();
+        let branch = if let Some(branch) = self.get_branch(branch_name) {
+            branch
+        } else {
+            return Ok(Vec::new());
+        };
+        let mut base_path = PathBuf::new();
+        let prefixes = prefixes.to_prefixes(self, &branch);
+        self.collect_children(
+            &branch,
+            "".as_ref(),
+            ROOT_KEY,
+            ROOT_INODE,
+            &mut base_path,
+            &prefixes,
+            &mut files,
+        )?;
+
+        let mut ret = vec![];
+        while !files.is_empty() {
+            next_files.clear();
+            for (a, b) in files.drain() {
+                let b_len = b.len();
+                let mut candidates = Vec::new();
+                for (name_key, output_item) in b {
+                    if b_len > 1 {
+                        let mut name = a.clone();
+                        self.make_conflicting_name(&mut name, name_key);
+                        candidates.push((name, name_key.patch))
+                    }
+                    if let Some(inode) = output_item.inode {
+                        if output_item.meta.is_dir() {
+                            self.collect_children(
+                                &branch,
+                                &a,
+                                output_item.key,
+                                inode,
+                                &mut base_path,
+                                &prefixes,
+                                &mut next_files,
+                            )?;
+                        }
+                    }
+                }
+                if !candidates.is_empty() {
+                    candidates.sort();
+                    ret.push(NameConflict {
+                        name: a,
+                        candidates,
+                    })
+                }
+            }
+            std::mem::swap(&mut files, &mut next_files);
+        }
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(ret)
+    }
+
     fn make_conflicting_name(&self, name: &mut PathBuf, name_key: Key<PatchId>) {
         let basename = {
             let basename = name.file_name().unwrap().to_string_lossy();
@@ -228,6 +497,75 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
         };
         name.set_file_name(&basename);
     }
+}
+
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
+    // Climb up the tree (using revtree).
+    pub(crate) fn filename_of_inode(
+        &self,
+        inode: Inode,
+        working_copy: &Path,
+    ) -> Option<PathBuf> {
+        let mut components = Vec::new();
+        let mut current = inode;
+        loop {
+            match self.get_revtree(current) {
+                Some(v) => {
+                    components.push(v.basename.to_owned());
+                    current = v.parent_inode.clone();
+                    if current == ROOT_INODE {
+                        break;
+                    }
+                }
+                None => {
+                    debug!("filename_of_inode: not in tree");
+                    return None;
+                }
+            }
+        }
+        let mut working_copy = working_copy.to_path_buf();
+        for c in components.iter().rev() {
+            working_copy.push(c.as_small_str().as_str());
+        }
+        Some(working_copy)
+    }
+
+    /// Binary files don't get conflict markers, and a conflict between
+    /// versions of a binary file is on the whole file: output the
+    /// first version of the conflicting file `path` in place, and the
+    /// other ones next to it, as `path.1`, `path.2`, etc. Text files
+    /// are left unchanged.
+    fn output_binary_conflict(
+        &mut self,
+        branch: &Branch,
+        key: Key<PatchId>,
+        working_copy: &Path,
+        path: &Path,
+    ) -> Result<()> {
+        let mut side = 0;
+        loop {
+            let (w, max_sides) = {
+                let mut f = OneSide::new(side);
+                let mut l = self.retrieve(branch, key);
+                self.output_file(branch, &mut f, &mut l, &mut Vec::new())?;
+                (f.w, f.max_sides)
+            };
+            if side == 0 {
+                if is_text(&w) {
+                    return Ok(());
+                }
+                self.output.write_file(working_copy, path, &w)?
+            } else {
+                let mut name = path.as_os_str().to_owned();
+                name.push(format!(".{}", side));
+                self.output.write_file(working_copy, Path::new(&name), &w)?
+            }
+            side += 1;
+            if side >= max_sides {
+                return Ok(());
+            }
+        }
+    }
 
     fn output_alive_files(
         &mut self,
@@ -236,6 +574,7 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
         working_copy: &Path,
     ) -> Result<()> {
         debug!("working copy {:?}", working_copy);
+        let markers = self.config.conflict_markers;
         let mut files = HashMap::new();
         let mut next_files = HashMap::new();
         let mut base_path = PathBuf::new();
@@ -303,21 +642,15 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
                         if let Some(ref current_name) = self.filename_of_inode(inode, "".as_ref()) {
                             if current_name != name.as_ref() {
                                 let current_name = working_copy.join(current_name);
-                                debug!("renaming {:?} to {:?}", current_name, working_copy_name);
                                 let parent = self.get_revtree(inode).unwrap().to_owned();
                                 self.del_revtree(inode, None)?;
                                 self.del_tree(&parent.as_file_id(), None)?;
 
                                 debug!("file_id: {:?}", file_id);
-                                if let Some(p) = working_copy_name.parent() {
-                                    std::fs::create_dir_all(p)?
-                                }
-                                if let Err(e) = std::fs::rename(&current_name, &working_copy_name) {
-                                    error!(
-                                        "while renaming {:?} to {:?}: {:?}",
-                                        current_name, working_copy_name, e
-                                    )
-                                }
+                                self.output.changes.push(Staged::Rename {
+                                    from: current_name,
+                                    to: working_copy_name.clone(),
+                                });
                             }
                         }
                         self.put_tree(&file_id.as_file_id(), inode)?;
@@ -356,7 +689,9 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
                     };
                     if output_item.meta.is_dir() {
                         // This is a directory, register it in inodes/trees.
-                        std::fs::create_dir_all(&working_copy_name)?;
+                        self.output
+                            .changes
+                            .push(Staged::CreateDir(working_copy_name.clone()));
                         if let Related::Exact = output_item.related {
                             self.collect_children(
                                 branch,
@@ -378,21 +713,66 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
                                 &mut next_files,
                             )?
                         }
-                    } else {
-                        // Output file.
+                    } else if output_item.meta.is_symlink() {
                         debug!(
-                            "creating file {:?}, key {:?} {:?}",
+                            "creating symlink {:?}, key {:?} {:?}",
                             &name, output_item.key, working_copy_name
                         );
-                        let mut f = graph::Writer::new(std::fs::File::create(&working_copy_name).unwrap());
-                        debug!("done");
+                        // If several patches set different targets,
+                        // the link points to the first one. The
+                        // conflict is still in the graph, and
+                        // recording the link resolves it.
+                        let mut f = OneSide::new(0);
                         let mut l = self.retrieve(branch, output_item.key);
                         let mut forward = Vec::new();
                         self.output_file(branch, &mut f, &mut l, &mut forward)?;
-                        self.remove_redundant_edges(branch, &forward)?
+                        self.remove_redundant_edges(branch, &forward)?;
+                        self.output.changes.push(Staged::Symlink {
+                            target: f.w,
+                            path: working_copy_name.clone(),
+                        })
+                    } else {
+                        // Output file.
+                        debug!(
+                            "creating file {:?}, key {:?} {:?}",
+                            &name, output_item.key, working_copy_name
+                        );
+                        let has_conflicts = {
+                            let file = self.output.create_file(working_copy, &working_copy_name)?;
+                            let mut f = graph::Writer::with_markers(
+                                file,
+                                conflict::Markers::new(markers, Some(working_copy)),
+                            );
+                            debug!("done");
+                            let mut l = self.retrieve(branch, output_item.key);
+                            let mut forward = Vec::new();
+                            let has_conflicts =
+                                self.output_file(branch, &mut f, &mut l, &mut forward)?;
+                            self.remove_redundant_edges(branch, &forward)?;
+                            has_conflicts
+                        };
+                        if has_conflicts {
+                            self.output_binary_conflict(
+                                branch,
+                                output_item.key,
+                                working_copy,
+                                &working_copy_name,
+                            )?
+                        }
+                        // Large files are output as pointers, replace
+                        // them with their contents.
+                        self.output.changes.push(Staged::Materialize {
+                            repo_root: working_copy.to_path_buf(),
+                            path: working_copy_name.clone(),
+                        });
                     }
                     base_path.pop();
-                    set_permissions(&working_copy_name, output_item.meta.permissions())?
+                    if !output_item.meta.is_symlink() {
+                        self.output.changes.push(Staged::SetPermissions {
+                            path: working_copy_name.clone(),
+                            permissions: output_item.meta.permissions(),
+                        })
+                    }
                 }
             }
             std::mem::swap(&mut files, &mut next_files);
@@ -438,16 +818,7 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
             self.remove_inode_rec(inode)?;
             debug!("removed");
             if let Some(ref name) = *name {
-                debug!("deleting {:?}", name);
-                if let Ok(meta) = fs::metadata(name) {
-                    if let Err(e) = if meta.is_dir() {
-                        fs::remove_dir_all(name)
-                    } else {
-                        fs::remove_file(name)
-                    } {
-                        error!("while deleting {:?}: {:?}", name, e);
-                    }
-                }
+                self.output.changes.push(Staged::Remove(name.clone()))
             } else {
                 self.del_tree(&parent.as_file_id(), Some(inode))?;
                 self.del_revtree(inode, Some(&parent.as_file_id()))?;
@@ -567,11 +938,11 @@ impl Prefixes {
 }
 
 pub trait ToPrefixes {
-    fn to_prefixes<T>(&self, txn: &MutTxn<T>, branch: &Branch) -> Prefixes;
+    fn to_prefixes<U: Transaction, T>(&self, txn: &GenericTxn<U, T>, branch: &Branch) -> Prefixes;
 }
 
 impl<'a> ToPrefixes for &'a [&'a str] {
-    fn to_prefixes<T>(&self, txn: &MutTxn<T>, branch: &Branch) -> Prefixes {
+    fn to_prefixes<U: Transaction, T>(&self, txn: &GenericTxn<U, T>, branch: &Branch) -> Prefixes {
         Prefixes(
             self.iter()
                 .flat_map(|pref| txn.prefix_keys(&branch, pref))
@@ -581,7 +952,7 @@ impl<'a> ToPrefixes for &'a [&'a str] {
 }
 
 impl<'a> ToPrefixes for &'a [Inode] {
-    fn to_prefixes<T>(&self, txn: &MutTxn<T>, _: &Branch) -> Prefixes {
+    fn to_prefixes<U: Transaction, T>(&self, txn: &GenericTxn<U, T>, _: &Branch) -> Prefixes {
         Prefixes(
             self.iter()
                 .map(|pref| {
@@ -19,6 +19,7 @@ use serde_json;
 use {Error, Result};
 
 mod pretty;
+pub use self::pretty::{PrettyChange, PrettyNewEdge, PrettyPatch};
 
 bitflags! {
     #[derive(Serialize, Deserialize)]
@@ -224,6 +225,20 @@ impl<Context> Record<Context> {
             extra: None,
         }
     }
+
+    /// If this record adds a file, the metadata of that file.
+    pub fn added_metadata(&self) -> Option<::backend::FileMetadata> {
+        match *self {
+            Record::FileAdd {
+                add: Change::NewNodes { ref nodes, .. },
+                ..
+            } if nodes.len() > 0 && nodes[0].len() >= 2 =>
+            {
+                Some(::backend::FileMetadata::from_contents(&nodes[0][..2]))
+            }
+            _ => None,
+        }
+    }
 }
 
 impl<Context> Iterator for RecordIter<Record<Context>, Change<Context>> {
@@ -528,6 +543,24 @@ pub fn read_signature_file(r: &mut Read) -> Result<SignatureFile> {
 }
 
 impl SignatureFile {
+    /// Whether this file is about `hash`, and all its signatures are
+    /// valid signatures of `hash` by their keys.
+    pub fn verify(&self, hash: &Hash) -> bool {
+        if Hash::from_base58(&self.hash).as_ref() != Some(hash) {
+            return false;
+        }
+        let hash = hash.as_ref().to_binary();
+        self.signatures.iter().all(|(key, sig)| {
+            match (
+                thrussh_keys::parse_public_key_base64(key),
+                bs58::decode(sig).into_vec(),
+            ) {
+                (Ok(key), Ok(sig)) => key.verify_detached(&hash, &sig),
+                _ => false,
+            }
+        })
+    }
+
     pub fn write_signature_file(&self, w: &mut Write) -> Result<()> {
         serde_json::to_writer(w, self)?;
         Ok(())
@@ -767,9 +800,8 @@ impl<U: Transaction, R> GenericTxn<U, R> {
 }
 
 use backend::*;
-use sanakirja;
 
-impl<A: sanakirja::Transaction, R> GenericTxn<A, R> {
+impl<A: Transaction, R> GenericTxn<A, R> {
     /// Gets the external key corresponding to the given key, returning an
     /// owned vector. If the key is just a patch internal hash, it returns the
     /// corresponding external hash.
@@ -1,6 +1,10 @@
 use backend::*;
+use blob::{BlobPointer, LargeFiles};
 use graph;
 use optimal_diff;
+use optimal_diff::DiffAlgorithm;
+use conflict::MarkerStyle;
+use fs_representation;
 use patch::*;
 use {Error, Result};
 
@@ -8,7 +12,7 @@ use rand;
 use std;
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::fs::metadata;
+use std::fs::symlink_metadata;
 use std::io::BufRead;
 use std::io::Read;
 #[cfg(not(windows))]
@@ -25,14 +29,72 @@ fn permissions(_: &std::fs::Metadata) -> Option<usize> {
     None
 }
 
+#[cfg(not(windows))]
+fn link_target(path: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(std::fs::read_link(path)?.into_os_string().into_vec())
+}
+#[cfg(windows)]
+fn link_target(path: &Path) -> Result<Vec<u8>> {
+    Ok(std::fs::read_link(path)?
+        .to_string_lossy()
+        .into_owned()
+        .into_bytes())
+}
+
+/// Read the contents of a file, or the target of a symbolic link
+/// (symbolic links are not followed). The boolean tells whether the
+/// file is a symbolic link.
+fn read_contents(path: &Path) -> Result<(Vec<u8>, bool)> {
+    if symlink_metadata(path)?.file_type().is_symlink() {
+        return Ok((link_target(path)?, true));
+    }
+    let mut contents = Vec::new();
+    let mut f = std::fs::File::open(path)?;
+    f.read_to_end(&mut contents)?;
+    Ok((contents, false))
+}
+
+/// Like `read_contents`, but large files are replaced by a pointer to
+/// their contents, which are copied to the blob store. `is_blob`
+/// tells whether the version of the file in the pristine is already a
+/// pointer, in which case the file stays a blob whatever the
+/// configuration says.
+fn read_large_or_contents(
+    st: &RecordState,
+    path: &Path,
+    is_blob: bool,
+) -> Result<(Vec<u8>, bool)> {
+    if let Some(ref large_files) = st.large_files {
+        let is_file = symlink_metadata(path)?.is_file();
+        if (is_blob && is_file) || large_files.is_large(path) {
+            return Ok((large_files.record(path)?.to_bytes(), false));
+        }
+    }
+    read_contents(path)
+}
+
 fn file_metadata(path: &Path) -> Result<FileMetadata> {
-    let attr = metadata(&path)?;
+    let attr = symlink_metadata(&path)?;
+    if attr.file_type().is_symlink() {
+        return Ok(FileMetadata::new_symlink());
+    }
     let permissions = permissions(&attr).unwrap_or(0o755);
     debug!("permissions = {:?}", permissions);
     Ok(FileMetadata::new(permissions & 0o777, attr.is_dir()))
 }
 
 impl<U: Transaction, R> GenericTxn<U, R> {
+    /// Whether the file whose graph is `graph` is stored as a blob
+    /// pointer in the pristine.
+    fn is_blob(&self, graph: &graph::Graph) -> bool {
+        graph.lines.iter().any(|line| {
+            self.get_contents(line.key)
+                .map(|c| BlobPointer::parse(&c).is_some())
+                .unwrap_or(false)
+        })
+    }
+
     pub fn globalize_change(
         &self,
         change: Change<Rc<RefCell<ChangeContext<PatchId>>>>,
@@ -129,6 +191,9 @@ pub struct RecordState {
     updatables: HashSet<InodeUpdate>,
     actions: Vec<Record<Rc<RefCell<ChangeContext<PatchId>>>>>,
     redundant: Vec<(Key<PatchId>, Edge)>,
+    markers: MarkerStyle,
+    diff_algorithm: DiffAlgorithm,
+    large_files: Option<LargeFiles>,
 }
 
 /// An account of the files that have been added, moved or deleted, as
@@ -165,11 +230,11 @@ pub enum WorkingFileStatus {
     Zombie,
 }
 
-fn is_text(x: &[u8]) -> bool {
+pub(crate) fn is_text(x: &[u8]) -> bool {
     x.iter().take(8000).all(|&c| c != 0)
 }
 
-impl<'env, R: rand::Rng> MutTxn<'env, R> {
+impl<U: Transaction, R> GenericTxn<U, R> {
     /// Create appropriate NewNodes for adding a file.
     fn record_file_addition(
         &self,
@@ -235,11 +300,8 @@ impl<'env, R: rand::Rng> MutTxn<'env, R> {
         if !meta.is_dir() {
             nodes.clear();
 
-            let mut node = Vec::new();
-            {
-                let mut f = std::fs::File::open(realpath.as_path())?;
-                f.read_to_end(&mut node)?;
-            }
+            // The target of a symbolic link is a single line.
+            let (node, is_link) = read_large_or_contents(st, realpath.as_path(), false)?;
 
             let up_context = Key {
                 patch: None,
@@ -249,7 +311,7 @@ impl<'env, R: rand::Rng> MutTxn<'env, R> {
                 patch: None,
                 line: blank_line_num.clone(),
             };
-            if is_text(&node) {
+            if !is_link && is_text(&node) {
                 let mut line = Vec::new();
                 let mut f = &node[..];
                 loop {
@@ -282,19 +344,28 @@ impl<'env, R: rand::Rng> MutTxn<'env, R> {
                 }
                 st.line_num += len;
             } else {
+                let nodes: Vec<Vec<u8>> = if is_link {
+                    vec![node]
+                } else {
+                    optimal_diff::read_chunks(&node)
+                        .into_iter()
+                        .map(|c| c.to_vec())
+                        .collect()
+                };
+                let len = nodes.len();
                 st.actions.push(Record::Change {
                     change: Change::NewNodes {
                         up_context: Rc::new(RefCell::new(vec![up_context])),
                         line_num: st.line_num,
                         down_context: Rc::new(RefCell::new(vec![])),
-                        nodes: vec![node],
+                        nodes,
                         flag: EdgeFlags::empty(),
                         inode: up_context_ext,
                     },
                     file: Rc::new(realpath.clone()),
                     conflict_reordering: Vec::new(),
                 });
-                st.line_num += 1;
+                st.line_num += len;
             }
             Ok(None)
         } else {
@@ -302,9 +373,11 @@ impl<'env, R: rand::Rng> MutTxn<'env, R> {
         }
     }
 
-    /// Diff for binary files, doesn't both splitting the file in
-    /// lines. This is wasteful, but doesn't break the format, and
-    /// doesn't create conflicts inside binary files.
+    /// Diff a file with its version in the repository. Binary files
+    /// are split into content-defined chunks instead of lines, and
+    /// symbolic links are a single "line". This doesn't create
+    /// conflicts inside binary files: a conflict on a binary file is
+    /// output as whole versions of the file.
     fn diff_with_binary(
         &self,
         inode: Key<Option<Hash>>,
@@ -313,16 +386,17 @@ impl<'env, R: rand::Rng> MutTxn<'env, R> {
         ret: &mut graph::Graph,
         path: Rc<PathBuf>,
     ) -> Result<()> {
-        let mut lines_b = Vec::new();
-        {
-            debug!("opening file for diff: {:?}", path);
-            let mut f = std::fs::File::open(path.as_ref())?;
-            f.read_to_end(&mut lines_b)?;
-        }
-        let lines = if is_text(&lines_b) {
-            optimal_diff::read_lines(&lines_b)
+        debug!("opening file for diff: {:?}", path);
+        let is_blob = self.is_blob(ret);
+        let (lines_b, is_link) = read_large_or_contents(st, path.as_ref(), is_blob)?;
+        let (lines, algorithm) = if is_link {
+            (vec![&lines_b[..]], st.diff_algorithm)
+        } else if is_text(&lines_b) {
+            (optimal_diff::read_lines(&lines_b), st.diff_algorithm)
         } else {
-            vec![&lines_b[..]]
+            // Large binary files have many chunks, the optimal diff
+            // would take too much memory.
+            (optimal_diff::read_chunks(&lines_b), DiffAlgorithm::Myers)
         };
 
         self.diff(
@@ -334,6 +408,8 @@ impl<'env, R: rand::Rng> MutTxn<'env, R> {
             &mut st.redundant,
             ret,
             &lines,
+            st.markers,
+            algorithm,
         )
     }
 
@@ -358,10 +434,7 @@ impl<'env, R: rand::Rng> MutTxn<'env, R> {
         name.extend(basename.as_bytes());
         for parent in self.iter_parents(branch, current_node, EdgeFlags::FOLDER_EDGE) {
             debug!("iter_parents: {:?}", parent);
-            let previous_name: &[u8] = match self.get_contents(parent.dest) {
-                None => &[],
-                Some(n) => n.as_slice(),
-            };
+            let previous_name = self.get_contents(parent.dest).unwrap_or_default();
             let name_changed =
                 (&previous_name[2..] != &name[2..]) || (new_meta != old_meta && cfg!(not(windows)));
 
@@ -780,6 +853,9 @@ impl RecordState {
             actions: Vec::new(),
             updatables: HashSet::new(),
             redundant: Vec::new(),
+            markers: MarkerStyle::default(),
+            diff_algorithm: DiffAlgorithm::default(),
+            large_files: None,
         }
     }
 
@@ -793,15 +869,35 @@ impl RecordState {
     }
 }
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
-    pub fn record(
-        &mut self,
+impl<U: Transaction, R> GenericTxn<U, R> {
+    /// Same as `record`, in a transaction that may be read-only,
+    /// for instance to compute the status of the working copy. The
+    /// pristine is left unchanged, and still tracks the files that
+    /// were added but no longer exist.
+    pub fn record_changes(
+        &self,
         state: &mut RecordState,
         branch: &Branch,
         working_copy: &std::path::Path,
         prefix: Option<&std::path::Path>,
     ) -> Result<()> {
+        self.record_obsolete(state, branch, working_copy, prefix)?;
+        Ok(())
+    }
+
+    /// Record the changes to `state`, and return the inodes of the
+    /// files that were added but no longer exist.
+    fn record_obsolete(
+        &self,
+        state: &mut RecordState,
+        branch: &Branch,
+        working_copy: &std::path::Path,
+        prefix: Option<&std::path::Path>,
+    ) -> Result<Vec<Inode>> {
         let mut obsolete_inodes = Vec::new();
+        state.markers = self.config.conflict_markers;
+        state.diff_algorithm = self.config.diff_algorithm;
+        state.large_files = Some(LargeFiles::new(working_copy, &self.config));
         {
             let mut realpath = PathBuf::from(working_copy);
 
@@ -856,6 +952,19 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
             debug!("record done, {} changes", state.actions.len());
             debug!("changes: {:?}", state.actions);
         }
+        Ok(obsolete_inodes)
+    }
+}
+
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
+    pub fn record(
+        &mut self,
+        state: &mut RecordState,
+        branch: &Branch,
+        working_copy: &std::path::Path,
+        prefix: Option<&std::path::Path>,
+    ) -> Result<()> {
+        let obsolete_inodes = self.record_obsolete(state, branch, working_copy, prefix)?;
         // try!(self.remove_redundant_edges(&mut branch, &mut st.redundant));
         debug!("remove_redundant_edges done");
         for inode in obsolete_inodes {
@@ -0,0 +1,531 @@
+//! A high-level interface to a repository on disk.
+//!
+//! `Repo` bundles the steps the command-line tool goes through for
+//! its most common operations (finding the pristine, growing it when
+//! needed, saving patches, outputting the working copy), so that
+//! programs embedding Pijul do not have to reimplement them.
+//!
+//! ```ignore
+//! let repo = Repo::open("/path/to/repository")?;
+//! repo.add(&[Path::new("a")])?;
+//! let header = PatchHeader {
+//!     authors: vec!["me".to_string()],
+//!     name: "Add a".to_string(),
+//!     description: None,
+//!     timestamp: chrono::Utc::now(),
+//!     flag: PatchFlags::empty(),
+//! };
+//! if let Some(hash) = repo.record(&[], header)? {
+//!     println!("recorded {}", hash.to_base58())
+//! }
+//! ```
+
+use backend::*;
+use fs_representation::*;
+use output::ToPrefixes;
+use patch::{Patch, PatchHeader, Record, UnsignedPatch};
+use rand;
+use record::{InodeUpdate, RecordState};
+use std::collections::HashSet;
+use std::fs::{canonicalize, create_dir_all, metadata};
+use std::iter;
+use std::path::{Path, PathBuf};
+use {apply_resize_patches, unrecord_resize, Config, Error, Result};
+
+/// A repository, identified by its root directory.
+#[derive(Debug, Clone)]
+pub struct Repo {
+    root: PathBuf,
+    config: Config,
+}
+
+/// A patch applied to a branch, as returned by `Repo::log`.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub hash: Hash,
+    /// Position of the patch in the branch's application order.
+    pub applied: ApplyTimestamp,
+    pub header: PatchHeader,
+}
+
+/// The kind of change made to a file since the last record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    Added,
+    Modified,
+    Deleted,
+    Moved,
+}
+
+/// The state of the working copy, as returned by `Repo::status`. All
+/// paths are relative to the repository root.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub branch: String,
+    /// Files with unrecorded changes, in the order of the record.
+    pub changes: Vec<(PathBuf, FileChange)>,
+    /// Files not tracked by the repository, sorted.
+    pub untracked: Vec<PathBuf>,
+    /// Files with unresolved conflicts.
+    pub conflicts: Vec<PathBuf>,
+}
+
+impl Status {
+    /// Whether there is nothing to record and no conflict to resolve.
+    pub fn is_clean(&self) -> bool {
+        self.changes.is_empty() && self.conflicts.is_empty()
+    }
+}
+
+impl Repo {
+    /// Open the repository containing `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Repo> {
+        let path = canonicalize(path.as_ref())?;
+        if let Some(root) = find_repo_root(&path) {
+            Ok(Repo {
+                root,
+                config: Config::default(),
+            })
+        } else {
+            Err(Error::NotInARepository(path))
+        }
+    }
+
+    /// Create an empty repository in `path`, which is created if it
+    /// does not exist yet.
+    pub fn init<P: AsRef<Path>>(path: P) -> Result<Repo> {
+        create_dir_all(path.as_ref())?;
+        let root = canonicalize(path.as_ref())?;
+        if find_repo_root(&root).is_some() {
+            return Err(Error::InARepository(root));
+        }
+        create(&root, rand::thread_rng())?;
+        let repo = Repository::open(pristine_dir(&root), None)?;
+        repo.mut_txn_begin(rand::thread_rng())?.commit()?;
+        Ok(Repo {
+            root,
+            config: Config::default(),
+        })
+    }
+
+    /// The root directory of the working copy.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Use `config` to record and output the working copy, instead
+    /// of the default configuration.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config
+    }
+
+    fn pristine_dir(&self) -> PathBuf {
+        pristine_dir(&self.root)
+    }
+
+    /// Open the pristine for read-only transactions, which don't
+    /// need it to grow.
+    fn open_pristine(&self) -> Result<Repository> {
+        let mut repo = Repository::open(self.pristine_dir(), None)?;
+        repo.set_config(self.config.clone());
+        Ok(repo)
+    }
+
+    pub fn current_branch(&self) -> Result<String> {
+        get_current_branch(&self.root)
+    }
+
+    /// Start tracking `paths`, relative to the repository root.
+    pub fn add(&self, paths: &[&Path]) -> Result<()> {
+        Repository::with_mut_txn(self.pristine_dir(), &self.config, 0, |mut txn| {
+            for path in paths {
+                let is_dir = metadata(self.root.join(path))?.is_dir();
+                txn.add_file(path, is_dir)?
+            }
+            txn.commit()
+        })
+    }
+
+    /// Record the changes to the files under `paths` (relative to the
+    /// repository root, or the whole repository if `paths` is empty)
+    /// as a new patch on the current branch, described by
+    /// `header`. Returns the hash of the new patch, or `None` if there
+    /// was nothing to record.
+    pub fn record(&self, paths: &[&Path], header: PatchHeader) -> Result<Option<Hash>> {
+        let branch_name = self.current_branch()?;
+        let recorded = Repository::with_mut_txn(
+            self.pristine_dir(),
+            &self.config,
+            0,
+            |mut txn| -> Result<Option<(Patch, HashSet<InodeUpdate>)>> {
+                let mut record = RecordState::new();
+                let branch = txn.open_branch(&branch_name)?;
+                if paths.is_empty() {
+                    txn.record(&mut record, &branch, &self.root, None)?;
+                } else {
+                    for path in paths {
+                        txn.record(&mut record, &branch, &self.root, Some(*path))?;
+                    }
+                }
+                let (changes, syncs) = record.finish();
+                if changes.is_empty() {
+                    return Ok(None);
+                }
+                let changes = changes
+                    .into_iter()
+                    .flat_map(|x| txn.globalize_record(x).into_iter())
+                    .collect();
+                let patch = txn.new_patch(
+                    &branch,
+                    header.authors.clone(),
+                    header.name.clone(),
+                    header.description.clone(),
+                    header.timestamp,
+                    changes,
+                    iter::empty(),
+                    header.flag,
+                );
+                txn.commit_branch(branch)?;
+                txn.commit()?;
+                Ok(Some((patch, syncs)))
+            },
+        )?;
+        let (patch, syncs) = if let Some(recorded) = recorded {
+            recorded
+        } else {
+            return Ok(None);
+        };
+        let hash = patch.save(patches_dir(&self.root), None)?;
+        let size_hint = patch.size_upper_bound() as u64;
+        Repository::with_mut_txn(self.pristine_dir(), &self.config, size_hint, |mut txn| {
+            let mut branch = txn.open_branch(&branch_name)?;
+            txn.apply_local_patch(&mut branch, &self.root, &hash, &patch, &syncs, false)?;
+            txn.commit_branch(branch)?;
+            txn.commit()
+        })?;
+        Ok(Some(hash))
+    }
+
+    /// Apply `patches` to the current branch, and output the result
+    /// to the working copy. The dependencies of each patch must be
+    /// either already applied, or in `patches`. Returns the hashes of
+    /// the patches, in the same order.
+    pub fn apply(&self, patches: Vec<Patch>) -> Result<Vec<Hash>> {
+        let branch_name = self.current_branch()?;
+        let patches_dir = patches_dir(&self.root);
+        let mut hashed = Vec::with_capacity(patches.len());
+        let mut size_increase = 0;
+        for patch in patches {
+            let hash = patch.save(&patches_dir, None)?;
+            size_increase += patch.size_upper_bound();
+            hashed.push((hash, patch))
+        }
+        apply_resize_patches(
+            &self.root,
+            &self.config,
+            &branch_name,
+            &hashed,
+            size_increase,
+            &[] as &[&str],
+            |_, _| (),
+        )?;
+        Ok(hashed.into_iter().map(|(h, _)| h).collect())
+    }
+
+    /// Unrecord patch `hash` from the current branch, along with the
+    /// patches of that branch depending on it. The working copy is
+    /// left untouched, so the changes of these patches become
+    /// unrecorded changes. Returns the hashes of the patches that were
+    /// unrecorded.
+    pub fn unrecord(&self, hash: &Hash) -> Result<Vec<Hash>> {
+        let branch_name = self.current_branch()?;
+        let mut selected = {
+            let repo = self.open_pristine()?;
+            let txn = repo.txn_begin()?;
+            let branch = if let Some(branch) = txn.get_branch(&branch_name) {
+                branch
+            } else {
+                return Err(Error::NoSuchBranch(branch_name));
+            };
+            let mut stack = match txn.get_internal(hash.as_ref()) {
+                Some(internal) if txn.get_patch(&branch.patches, internal).is_some() => {
+                    vec![internal]
+                }
+                _ => return Err(Error::BranchDoesNotHavePatch(branch_name, hash.clone())),
+            };
+            let mut visited = HashSet::new();
+            let mut selected = Vec::new();
+            while let Some(p) = stack.pop() {
+                if !visited.insert(p) {
+                    continue;
+                }
+                if let Some(t) = txn.get_patch(&branch.patches, p) {
+                    let ext = txn.get_external(p).unwrap().to_owned();
+                    selected.push((t, ext));
+                    stack.extend(
+                        txn.iter_revdep(Some((p, None)))
+                            .take_while(|&(q, _)| q == p)
+                            .map(|(_, dependent)| dependent),
+                    )
+                }
+            }
+            selected
+        };
+        // Dependents are applied after their dependencies, and
+        // `unrecord_resize` unrecords from the last patch.
+        selected.sort_by_key(|&(t, _)| t);
+        let mut patches = Vec::with_capacity(selected.len());
+        let mut size_increase = 0;
+        for (_, h) in selected {
+            let patch = read_patch(&self.root, h.as_ref())?;
+            size_increase += patch.size_upper_bound() as u64;
+            patches.push((h, patch))
+        }
+        unrecord_resize(
+            &self.pristine_dir(),
+            &self.root,
+            &self.config,
+            &branch_name,
+            &patches,
+            size_increase,
+        )?;
+        Ok(patches.into_iter().map(|(h, _)| h).collect())
+    }
+
+    /// The patches applied to `branch`, most recent first.
+    pub fn log(&self, branch: &str) -> Result<Vec<LogEntry>> {
+        let repo = self.open_pristine()?;
+        let txn = repo.txn_begin()?;
+        let branch = if let Some(b) = txn.get_branch(branch) {
+            b
+        } else {
+            return Err(Error::NoSuchBranch(branch.to_string()));
+        };
+        let mut log = Vec::new();
+        for (applied, p) in txn.rev_iter_applied(&branch, None) {
+            let hash = txn.get_external(p).unwrap();
+            log.push(LogEntry {
+                hash: hash.to_owned(),
+                applied,
+                header: read_patch_nochanges(&self.root, hash)?,
+            })
+        }
+        Ok(log)
+    }
+
+    /// Switch to `branch`, and output it to the working copy. Fails
+    /// with `Error::PendingChanges` if there are unrecorded changes.
+    pub fn checkout(&self, branch: &str) -> Result<()> {
+        let current = self.current_branch()?;
+        {
+            let repo = self.open_pristine()?;
+            let txn = repo.txn_begin()?;
+            if !self.file_changes_of(&txn, &current)?.is_empty() {
+                return Err(Error::PendingChanges);
+            }
+        }
+        Repository::with_mut_txn(self.pristine_dir(), &self.config, 0, |mut txn| {
+            let mut b = if let Some(b) = txn.get_branch(branch) {
+                b
+            } else {
+                return Err(Error::NoSuchBranch(branch.to_string()));
+            };
+            let prefixes = (&[][..] as &[&str]).to_prefixes(&txn, &b);
+            txn.output_repository(
+                &mut b,
+                &self.root,
+                &prefixes,
+                &UnsignedPatch::empty().leave_unsigned(),
+                &HashSet::new(),
+            )?;
+            txn.commit_branch(b)?;
+            txn.commit()
+        })?;
+        set_current_branch(&self.root, branch)
+    }
+
+    /// The unrecorded changes, untracked files and conflicts of the
+    /// working copy.
+    pub fn status(&self) -> Result<Status> {
+        let branch_name = self.current_branch()?;
+        let (changes, untracked, conflicts) = {
+            let repo = self.open_pristine()?;
+            let txn = repo.txn_begin()?;
+            let changes = self.file_changes_of(&txn, &branch_name)?;
+            let untracked = untracked_files(&txn, &self.root);
+            let conflicts = txn.list_conflict_files(&branch_name, &[])?;
+            (changes, untracked, conflicts)
+        };
+        let mut untracked: Vec<_> = untracked.into_iter().map(|p| self.relative(&p)).collect();
+        untracked.sort();
+        Ok(Status {
+            branch: branch_name,
+            changes,
+            untracked,
+            conflicts,
+        })
+    }
+
+    /// The unrecorded changes to the working copy, compared to
+    /// `branch`, in a transaction that may be read-only.
+    fn file_changes_of<A: Transaction, R>(
+        &self,
+        txn: &GenericTxn<A, R>,
+        branch: &str,
+    ) -> Result<Vec<(PathBuf, FileChange)>> {
+        if let Some(branch) = txn.get_branch(branch) {
+            let mut record = RecordState::new();
+            txn.record_changes(&mut record, &branch, &self.root, None)?;
+            let (records, _) = record.finish();
+            Ok(self.file_changes(&records))
+        } else {
+            // Nothing was ever recorded on this branch, hence all the
+            // tracked files are new.
+            Ok(txn.list_files(ROOT_INODE)?
+                .into_iter()
+                .map(|file| (file, FileChange::Added))
+                .collect())
+        }
+    }
+
+    /// Summarise `records` as one entry per file.
+    fn file_changes<C>(&self, records: &[Record<C>]) -> Vec<(PathBuf, FileChange)> {
+        let mut changes: Vec<(PathBuf, FileChange)> = Vec::new();
+        for record in records {
+            let (file, change) = match *record {
+                Record::Change { ref file, .. } | Record::Replace { ref file, .. } => {
+                    (self.relative(file), FileChange::Modified)
+                }
+                Record::FileAdd { ref name, .. } => {
+                    (self.relative(Path::new(name)), FileChange::Added)
+                }
+                Record::FileDel { ref name, .. } => {
+                    (self.relative(Path::new(name)), FileChange::Deleted)
+                }
+                Record::FileMove { ref new_name, .. } => {
+                    (self.relative(Path::new(new_name)), FileChange::Moved)
+                }
+            };
+            // A file is modified after being added or moved: keep the
+            // first entry only.
+            if changes.iter().any(|&(ref f, _)| *f == file) {
+                continue;
+            }
+            changes.push((file, change))
+        }
+        changes
+    }
+
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root).unwrap_or(path).to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono;
+    use patch::PatchFlags;
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    fn header(name: &str) -> PatchHeader {
+        PatchHeader {
+            authors: vec!["me".to_string()],
+            name: name.to_string(),
+            description: None,
+            timestamp: chrono::Utc::now(),
+            flag: PatchFlags::empty(),
+        }
+    }
+
+    fn write(repo: &Repo, file: &str, contents: &str) {
+        File::create(repo.root().join(file))
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap()
+    }
+
+    #[test]
+    fn record_log_unrecord() {
+        let dir = TempDir::new("pijul_repo").unwrap();
+        let repo = Repo::init(dir.path()).unwrap();
+        assert_eq!(Repo::open(dir.path()).unwrap().root(), repo.root());
+        let branch = repo.current_branch().unwrap();
+
+        write(&repo, "a", "a\n");
+        write(&repo, "untracked", "u\n");
+        repo.add(&[Path::new("a")]).unwrap();
+        let status = repo.status().unwrap();
+        assert_eq!(status.changes, vec![(PathBuf::from("a"), FileChange::Added)]);
+        assert_eq!(status.untracked, vec![PathBuf::from("untracked")]);
+
+        let first = repo.record(&[], header("a")).unwrap().unwrap();
+        assert!(repo.status().unwrap().is_clean());
+        assert!(repo.record(&[], header("nothing")).unwrap().is_none());
+
+        write(&repo, "a", "a\nb\n");
+        let status = repo.status().unwrap();
+        assert_eq!(status.changes, vec![(PathBuf::from("a"), FileChange::Modified)]);
+        let second = repo.record(&[], header("b")).unwrap().unwrap();
+
+        let log: Vec<_> = repo.log(&branch)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.hash, entry.header.name))
+            .collect();
+        assert_eq!(
+            log,
+            vec![(second.clone(), "b".to_string()), (first.clone(), "a".to_string())]
+        );
+
+        // The working copy is left untouched.
+        assert_eq!(repo.unrecord(&second).unwrap(), vec![second]);
+        assert_eq!(repo.log(&branch).unwrap().len(), 1);
+        let status = repo.status().unwrap();
+        assert_eq!(status.changes, vec![(PathBuf::from("a"), FileChange::Modified)]);
+    }
+
+    #[test]
+    fn checkout() {
+        let dir = TempDir::new("pijul_repo").unwrap();
+        let repo = Repo::init(dir.path()).unwrap();
+        let master = repo.current_branch().unwrap();
+        write(&repo, "a", "a\n");
+        repo.add(&[Path::new("a")]).unwrap();
+        repo.record(&[], header("a")).unwrap().unwrap();
+        Repository::with_mut_txn(repo.pristine_dir(), &repo.config, 0, |mut txn| -> Result<()> {
+            let branch = txn.open_branch(&master)?;
+            let other = txn.fork(&branch, "other")?;
+            txn.commit_branch(branch)?;
+            txn.commit_branch(other)?;
+            txn.commit()
+        }).unwrap();
+
+        match repo.checkout("nope") {
+            Err(Error::NoSuchBranch(_)) => {}
+            r => panic!("expected NoSuchBranch, got {:?}", r),
+        }
+        repo.checkout("other").unwrap();
+        assert_eq!(repo.current_branch().unwrap(), "other");
+        write(&repo, "b", "b\n");
+        repo.add(&[Path::new("b")]).unwrap();
+        repo.record(&[], header("b")).unwrap().unwrap();
+        assert_eq!(repo.log("other").unwrap().len(), 2);
+        assert_eq!(repo.log(&master).unwrap().len(), 1);
+
+        repo.checkout(&master).unwrap();
+        assert!(!repo.root().join("b").exists());
+        assert!(repo.status().unwrap().is_clean());
+        repo.checkout("other").unwrap();
+        assert!(repo.root().join("b").exists());
+
+        write(&repo, "a", "a\nc\n");
+        match repo.checkout(&master) {
+            Err(Error::PendingChanges) => {}
+            r => panic!("expected PendingChanges, got {:?}", r),
+        }
+        assert_eq!(repo.current_branch().unwrap(), "other");
+    }
+}
@@ -3,7 +3,7 @@ use backend::*;
 use rand;
 use std::collections::HashMap;
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
     fn collect_up_context_repair(
         &self,
         branch: &Branch,
@@ -7,7 +7,7 @@ use rand;
 use std::collections::HashSet;
 use std::str::from_utf8;
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
     pub(in unrecord) fn unrecord_edges(
         &mut self,
         find_alive: &mut FindAlive,
@@ -163,7 +163,7 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
                 let (metadata, basename) = {
                     let source_contents = self.get_contents(source).unwrap();
                     assert!(source_contents.len() >= 2);
-                    let (a, b) = source_contents.as_slice().split_at(2);
+                    let (a, b) = source_contents.split_at(2);
                     let name = SmallString::from_str(from_utf8(b)?);
                     (FileMetadata::from_contents(a), name)
                 };
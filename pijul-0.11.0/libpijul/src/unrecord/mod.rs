@@ -14,7 +14,7 @@ struct Workspace {
     context_edges: HashMap<Key<PatchId>, Edge>,
 }
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
     pub fn unrecord(
         &mut self,
         branch: &mut Branch,
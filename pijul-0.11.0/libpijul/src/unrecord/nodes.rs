@@ -5,7 +5,7 @@ use rand;
 use std::collections::HashSet;
 use std::mem::swap;
 
-impl<'env, T: rand::Rng> MutTxn<'env, T> {
+impl<U: MutTransaction, T: rand::Rng> GenericTxn<U, T> {
     pub(in unrecord) fn unrecord_nodes(
         &mut self,
         branch: &mut Branch,
@@ -59,7 +59,7 @@ impl<'env, T: rand::Rng> MutTxn<'env, T> {
             if unused_in_other_branch {
                 // If this patch is unknown to any other branch,
                 // delete the contents of this node.
-                self.del_contents(k, None)?;
+                self.del_contents(k)?;
             }
 
             // Delete all edges adjacent to this node, which will also
@@ -0,0 +1,282 @@
+use chrono;
+use clap::{Arg, ArgMatches, SubCommand};
+use commands::hooks::run_hook;
+use commands::local_patches::LocalPatches;
+use commands::record::{
+    decide_authors, prefix, prepare_changes_template, record_args, select_changes,
+};
+use commands::{ask, default_explain, BasicOptions, StaticSubcommand};
+use error::Error;
+use libpijul::fs_representation::{patches_dir, read_patch};
+use libpijul::patch::{Change, ChangeContext, NewEdge};
+use libpijul::{Hash, InodeUpdate, Key, Patch};
+use meta::{load_global_or_local_signing_key, Global, Meta};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+pub fn invocation() -> StaticSubcommand {
+    return record_args(
+        SubCommand::with_name("amend")
+            .about("Fold unrecorded changes into the last patch of the branch")
+            .arg(
+                Arg::with_name("all")
+                    .short("a")
+                    .long("all")
+                    .help("Answer 'y' to all questions")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::with_name("add-new-files")
+                    .short("n")
+                    .long("add-new-files")
+                    .help("Offer to add files that have been created since the last record")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::with_name("force")
+                    .long("force")
+                    .help("Amend the patch even if other patches depend on it")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::with_name("prefix")
+                    .help("Prefix to start from")
+                    .takes_value(true)
+                    .multiple(true),
+            ),
+    );
+}
+
+pub fn run(args: &ArgMatches) -> Result<Option<Hash>, Error> {
+    let opts = BasicOptions::from_args(args)?;
+    run_hook(&opts.repo_root, "pre-record", None)?;
+    amend(args, &opts)
+}
+
+/// Replace the last patch of the branch by a patch with its changes
+/// and the selected unrecorded changes. Its name, description and
+/// authors are kept, unless new ones are given in `args`. Only
+/// patches recorded here and never pushed can be amended.
+pub fn amend(args: &ArgMatches, opts: &BasicOptions) -> Result<Option<Hash>, Error> {
+    let branch_name = opts.branch();
+    let yes_to_all = args.is_present("all");
+    let add_new_files = args.is_present("add-new-files");
+
+    // Find the last patch, and the files it added.
+    let (old_hash, file_adds) = {
+        let repo = opts.open_repo()?;
+        let txn = repo.txn_begin()?;
+        let branch = txn.get_branch(&branch_name).ok_or(Error::NoSuchBranch)?;
+        let internal = if let Some((_, internal)) = txn.rev_iter_applied(&branch, None).next() {
+            internal
+        } else {
+            return Err(Error::NothingToAmend);
+        };
+        let hash = txn.get_external(internal).unwrap().to_owned();
+        if !LocalPatches::load(&opts.repo_root)?.contains(&hash) {
+            return Err(Error::PatchNotLocal { hash });
+        }
+        if !args.is_present("force") {
+            if let Some((_, dependent)) = txn
+                .iter_revdep(Some((internal, None)))
+                .take_while(|&(p, _)| p == internal)
+                .next()
+            {
+                return Err(Error::PatchHasDependents {
+                    hash,
+                    dependent: txn.get_external(dependent).unwrap().to_owned(),
+                });
+            }
+        }
+        let file_adds: Vec<_> = txn
+            .iter_inodes(None)
+            .filter(|&(_, header)| header.key.patch == internal)
+            .map(|(inode, header)| InodeUpdate::Add {
+                line: header.key.line,
+                meta: header.metadata,
+                inode,
+            })
+            .collect();
+        (hash, file_adds)
+    };
+    let old = read_patch(&opts.repo_root, old_hash.as_ref())?;
+    let header = old.header().clone();
+
+    let (changes, syncs) = select_changes(
+        opts,
+        add_new_files,
+        &branch_name,
+        yes_to_all,
+        prefix(args, opts)?,
+    )?;
+
+    let meta = Meta::load(&opts.repo_root).unwrap_or(Meta::new());
+    let global = Global::load().unwrap_or(Global::new());
+    let authors = if args.is_present("author") {
+        decide_authors(args.values_of("author").map(|x| x.collect()), &meta, &global)?
+    } else {
+        header.authors.clone()
+    };
+    let date = args.value_of("date").map_or(Ok(chrono::Utc::now()), |x| {
+        chrono::DateTime::from_str(x).map_err(|_| Error::InvalidDate { date: String::from(x) })
+    })?;
+    let editor = if args.is_present("no-editor") {
+        None
+    } else {
+        meta.editor.as_ref().or(global.editor.as_ref())
+    };
+    let (name, description) = if let Some(name) = args.value_of("message") {
+        (
+            name.to_string(),
+            args.value_of("description").map(|x| String::from(x.trim())),
+        )
+    } else if editor.is_some() {
+        let message = if let Some(ref d) = header.description {
+            format!("{}\n\n{}", header.name, d)
+        } else {
+            header.name.clone()
+        };
+        let template = prepare_changes_template(&opts.repo_root, "", &changes);
+        ask::ask_patch_name(&opts.repo_root, editor, message + &template)?
+    } else {
+        (
+            header.name.clone(),
+            args.value_of("description")
+                .map(|x| String::from(x.trim()))
+                .or(header.description.clone()),
+        )
+    };
+    run_hook(&opts.repo_root, "patch-name", Some(&name))?;
+
+    let changes: Vec<_> = changes.into_iter().flat_map(|x| x.into_iter()).collect();
+    let (changes, mut syncs) = fold_changes(&old_hash, &old, changes, syncs);
+    syncs.extend(file_adds.into_iter());
+
+    let key = if let Ok(Some(key)) = meta.signing_key() {
+        Some(key)
+    } else {
+        load_global_or_local_signing_key(Some(&opts.repo_dir())).ok()
+    };
+
+    // Unrecord the old patch, and record its replacement, in the
+    // same transaction, so that the branch never loses the patch.
+    let size_hint = 2 * old.size_upper_bound() as u64;
+    let hash = opts.with_mut_txn(size_hint, |mut txn| {
+        let mut branch = txn.open_branch(&branch_name)?;
+        let internal = txn.get_internal(old_hash.as_ref()).unwrap().to_owned();
+        txn.unrecord(&mut branch, internal, &old)?;
+        let extra_deps: Vec<Hash> = old.dependencies().iter().cloned().collect();
+        let patch = txn.new_patch(
+            &branch,
+            authors.clone(),
+            name.clone(),
+            description.clone(),
+            date,
+            changes.clone(),
+            extra_deps.into_iter(),
+            header.flag,
+        );
+        let hash = patch.save(&patches_dir(&opts.repo_root), key.as_ref())?;
+        txn.apply_local_patch(&mut branch, &opts.repo_root, &hash, &patch, &syncs, false)?;
+        txn.commit_branch(branch)?;
+        txn.commit()?;
+        Ok(hash)
+    })?;
+    LocalPatches::register(&opts.repo_root, &hash)?;
+    LocalPatches::unregister(&opts.repo_root, Some(&old_hash))?;
+    println!("Recorded patch {}", hash.to_base58());
+    run_hook(&opts.repo_root, "post-record", None)?;
+    Ok(Some(hash))
+}
+
+/// Fold `changes`, recorded on top of patch `old`, into the changes
+/// of `old`. The lines added by `changes` are renumbered after the
+/// lines of `old`, and references to `old` become references to the
+/// new patch.
+fn fold_changes(
+    old_hash: &Hash,
+    old: &Patch,
+    changes: Vec<Change<ChangeContext<Hash>>>,
+    syncs: HashSet<InodeUpdate>,
+) -> (Vec<Change<ChangeContext<Hash>>>, HashSet<InodeUpdate>) {
+    let offset = old.changes()
+        .iter()
+        .filter_map(|c| match *c {
+            Change::NewNodes {
+                line_num,
+                ref nodes,
+                ..
+            } => Some((line_num + nodes.len()).0 as usize),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let key = |k: &Key<Option<Hash>>| match k.patch {
+        None if !k.line.is_root() => Key {
+            patch: None,
+            line: k.line + offset,
+        },
+        Some(ref h) if h == old_hash => Key {
+            patch: None,
+            line: k.line,
+        },
+        _ => k.clone(),
+    };
+    let mut folded = old.changes().to_vec();
+    for c in changes {
+        folded.push(match c {
+            Change::NewNodes {
+                up_context,
+                down_context,
+                flag,
+                line_num,
+                nodes,
+                inode,
+            } => Change::NewNodes {
+                up_context: up_context.iter().map(&key).collect(),
+                down_context: down_context.iter().map(&key).collect(),
+                flag,
+                line_num: line_num + offset,
+                nodes,
+                inode: key(&inode),
+            },
+            Change::NewEdges {
+                previous,
+                flag,
+                edges,
+                inode,
+            } => Change::NewEdges {
+                previous,
+                flag,
+                edges: edges
+                    .iter()
+                    .map(|e| NewEdge {
+                        from: key(&e.from),
+                        to: key(&e.to),
+                        introduced_by: match e.introduced_by {
+                            Some(ref h) if h == old_hash => None,
+                            ref i => i.clone(),
+                        },
+                    })
+                    .collect(),
+                inode: key(&inode),
+            },
+        })
+    }
+    let syncs = syncs
+        .into_iter()
+        .map(|s| match s {
+            InodeUpdate::Add { line, meta, inode } => InodeUpdate::Add {
+                line: line + offset,
+                meta,
+                inode,
+            },
+            s => s,
+        })
+        .collect();
+    (folded, syncs)
+}
+
+pub fn explain(res: Result<Option<Hash>, Error>) {
+    default_explain(res)
+}
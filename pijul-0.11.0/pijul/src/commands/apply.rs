@@ -1,3 +1,4 @@
+use super::tag_registry::TagRegistry;
 use super::{get_current_branch, validate_base58, BasicOptions};
 use clap::{Arg, ArgMatches, SubCommand};
 use commands::{default_explain, StaticSubcommand};
@@ -101,24 +102,25 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
     } else {
         false
     };
-    loop {
-        let result = if args.is_present("no-output") || !is_current_branch {
-            apply_resize_no_output(&opts.repo_root, &opts.branch(), remote.iter(), |_, _| ())
-        } else {
-            apply_resize(
-                &opts.repo_root,
-                &opts.branch(),
-                remote.iter(),
-                &[] as &[&str],
-                |_, _| {},
-            )
-        };
-        match result {
-            Err(ref e) if e.lacks_space() => {}
-            Ok(()) => return Ok(()),
-            Err(e) => return Err(From::from(e)),
-        }
+    if args.is_present("no-output") || !is_current_branch {
+        apply_resize_no_output(
+            &opts.repo_root,
+            &opts.config,
+            &opts.branch(),
+            remote.iter(),
+            |_, _| (),
+        )?
+    } else {
+        apply_resize(
+            &opts.repo_root,
+            &opts.config,
+            &opts.branch(),
+            remote.iter(),
+            &[] as &[&str],
+            |_, _| {},
+        )?
     }
+    TagRegistry::register_hashes(&opts.repo_root, &remote)
 }
 
 pub fn explain(res: Result<(), Error>) {
@@ -1,5 +1,5 @@
 use getch;
-use libpijul::patch::{Change, ChangeContext, Patch, PatchHeader, Record};
+use libpijul::patch::{Change, ChangeContext, NewEdge, Patch, PatchHeader, Record};
 use std::io::prelude::*;
 
 use std::collections::{HashMap, HashSet};
@@ -14,7 +14,7 @@ use libpijul::fs_representation::PIJUL_DIR_NAME;
 
 use error::Error;
 use isatty::stdout_isatty;
-use libpijul::{EdgeFlags, Hash, LineId, MutTxn, PatchId};
+use libpijul::{EdgeFlags, Hash, Key, LineId, MutTxn, PatchId};
 use rand;
 use std;
 use std::char::from_u32;
@@ -26,6 +26,7 @@ use term::{Attr, StdoutTerminal};
 
 use ignore::gitignore::GitignoreBuilder;
 use line;
+use meta::{Global, Meta};
 use relativize::relativize;
 
 const BINARY_CONTENTS: &'static str = "<binary contents>";
@@ -311,7 +312,11 @@ fn print_change<T: rand::Rng>(
             if let Some(ref mut term) = *term {
                 term.fg(term::color::CYAN).unwrap_or(());
             }
-            print!("added file ");
+            if c.added_metadata().map(|m| m.is_symlink()).unwrap_or(false) {
+                print!("added symlink ");
+            } else {
+                print!("added file ");
+            }
             if let Some(ref mut term) = *term {
                 term.reset().unwrap_or(());
             }
@@ -439,7 +444,6 @@ fn print_change<T: rand::Rng>(
                         if let Some(target) = target {
                             let internal = repo.internal_key_unwrap(target);
                             let l = repo.get_contents(internal).unwrap();
-                            let l = l.into_cow();
                             let s = str::from_utf8(&l).unwrap_or(BINARY_CONTENTS);
 
                             let mut file_changed = true;
@@ -519,6 +523,12 @@ fn display_help_changes(potential_new_ignore: Option<&str>, direction: ChangesDi
     println!("k: go back to the previous change");
     println!("a: {} all remaining changes", direction.verb());
     println!("d: skip all remaining changes");
+    println!("s: split this change into smaller changes");
+    if direction.is_record() {
+        println!("e: edit this change, and record the edited version");
+    }
+    println!("f: {} or skip all remaining changes to this file", direction.verb());
+    println!("/: search for a change matching a regular expression");
     match potential_new_ignore {
         Some(filename) => println!("i: ignore file {}", filename),
         None => (),
@@ -542,11 +552,11 @@ fn prompt_one_change<T: rand::Rng>(
     debug!("changes: {:?}", change);
     print_change(terminal, cwd, repository, current_file, &change)?;
     println!("");
-    let choices = if potential_new_ignore.is_some() {
-        "[ynkadi?]"
-    } else {
-        "[ynkad?]"
-    };
+    let choices = format!(
+        "[ynkads{}f/{}?]",
+        if direction.is_record() { "e" } else { "" },
+        if potential_new_ignore.is_some() { "i" } else { "" }
+    );
     if show_help {
         display_help_changes(potential_new_ignore, direction);
         print!(
@@ -622,26 +632,11 @@ fn add_to_ignore_file(
     }
 }
 
-pub fn ask_changes<T: rand::Rng>(
-    repository: &MutTxn<T>,
-    repo_root: &Path,
-    cwd: &Path,
+/// For each change, the changes it depends on, and the changes that
+/// depend on it.
+fn changes_dependencies(
     changes: &[Record<ChangeContext<Hash>>],
-    direction: ChangesDirection,
-    to_unadd: &mut HashSet<PathBuf>,
-) -> Result<(HashMap<usize, bool>, Vec<String>), Error> {
-    debug!("changes: {:?}", changes);
-    let mut terminal = if stdout_isatty() {
-        term::stdout()
-    } else {
-        None
-    };
-    let getch = getch::Getch::new();
-    let mut i = 0;
-    let mut choices: HashMap<usize, bool> = HashMap::new();
-    let mut new_ignored_patterns: Vec<String> = Vec::new();
-    let mut new_ignore_builder = GitignoreBuilder::new(repo_root);
-    let mut final_decision = None;
+) -> (HashMap<usize, Vec<usize>>, HashMap<usize, Vec<usize>>) {
     let mut provided_by = HashMap::new();
     let mut line_deps = Vec::with_capacity(changes.len());
     for i in 0..changes.len() {
@@ -662,12 +657,456 @@ pub fn ask_changes<T: rand::Rng>(
             e.push(i);
         }
     }
+    (deps, rev_deps)
+}
+
+/// The file touched by a change.
+fn change_file(change: &Record<ChangeContext<Hash>>) -> &Path {
+    match *change {
+        Record::FileAdd { ref name, .. } | Record::FileDel { ref name, .. } => Path::new(name),
+        Record::FileMove { ref new_name, .. } => Path::new(new_name),
+        Record::Change { ref file, .. } | Record::Replace { ref file, .. } => file.as_path(),
+    }
+}
+
+/// The line deleted by an edge of a `NewEdges` change.
+fn edge_target(flag: EdgeFlags, e: &NewEdge) -> &Key<Option<Hash>> {
+    if flag.contains(EdgeFlags::PARENT_EDGE) {
+        &e.from
+    } else {
+        &e.to
+    }
+}
+
+/// Group the edges of a `NewEdges` change by deleted line, in order.
+fn deleted_lines(flag: EdgeFlags, edges: &[NewEdge]) -> Vec<Vec<NewEdge>> {
+    let mut lines: Vec<Vec<NewEdge>> = Vec::new();
+    let mut index = HashMap::new();
+    for e in edges {
+        let n = *index.entry(edge_target(flag, e)).or_insert(lines.len());
+        if n == lines.len() {
+            lines.push(Vec::new())
+        }
+        lines[n].push(e.clone())
+    }
+    lines
+}
+
+fn line_contents<T: rand::Rng>(repo: &MutTxn<T>, key: &Key<Option<Hash>>) -> Vec<u8> {
+    let internal = repo.internal_key_unwrap(key);
+    if let Some(l) = repo.get_contents(internal) {
+        l.into_owned()
+    } else {
+        Vec::new()
+    }
+}
+
+/// The deletions and additions of lines in a change, if it is a
+/// change to the contents of a file.
+fn contents_change(
+    change: &Record<ChangeContext<Hash>>,
+) -> Option<(Option<&Change<ChangeContext<Hash>>>, Option<&Change<ChangeContext<Hash>>>)> {
+    match *change {
+        Record::Replace {
+            ref dels, ref adds, ..
+        } => Some((Some(dels), Some(adds))),
+        Record::Change { ref change, .. } => match *change {
+            Change::NewNodes { flag, .. } if flag.contains(EdgeFlags::FOLDER_EDGE) => None,
+            Change::NewEdges { flag, .. } if flag.contains(EdgeFlags::FOLDER_EDGE) => None,
+            Change::NewNodes { .. } => Some((None, Some(change))),
+            Change::NewEdges { .. } => Some((Some(change), None)),
+        },
+        _ => None,
+    }
+}
+
+/// Build a record from its deletions and additions.
+fn contents_record(
+    file: Rc<PathBuf>,
+    dels: Option<Change<ChangeContext<Hash>>>,
+    adds: Option<Change<ChangeContext<Hash>>>,
+    conflict_reordering: Vec<Change<ChangeContext<Hash>>>,
+) -> Option<Record<ChangeContext<Hash>>> {
+    match (dels, adds) {
+        (Some(dels), Some(adds)) => Some(Record::Replace {
+            file,
+            adds,
+            dels,
+            conflict_reordering,
+        }),
+        (Some(change), None) | (None, Some(change)) => Some(Record::Change {
+            file,
+            change,
+            conflict_reordering,
+        }),
+        (None, None) => None,
+    }
+}
+
+fn conflict_reordering(change: &Record<ChangeContext<Hash>>) -> Vec<Change<ChangeContext<Hash>>> {
+    match *change {
+        Record::Change {
+            ref conflict_reordering,
+            ..
+        }
+        | Record::Replace {
+            ref conflict_reordering,
+            ..
+        } => conflict_reordering.clone(),
+        _ => Vec::new(),
+    }
+}
+
+/// Split a change to the contents of a file into one change per
+/// deleted line, followed by one change per added line. Each added
+/// line is attached to the previous one, so that a prefix of the
+/// added lines can be selected.
+fn split_change(change: &Record<ChangeContext<Hash>>) -> Option<Vec<Record<ChangeContext<Hash>>>> {
+    let (dels, adds) = contents_change(change)?;
+    let file = Rc::new(change_file(change).to_path_buf());
+    let mut pieces = Vec::new();
+    if let Some(&Change::NewEdges {
+        previous,
+        flag,
+        ref edges,
+        ref inode,
+    }) = dels
+    {
+        for edges in deleted_lines(flag, edges) {
+            pieces.push(Change::NewEdges {
+                previous,
+                flag,
+                edges,
+                inode: inode.clone(),
+            })
+        }
+    }
+    if let Some(&Change::NewNodes {
+        ref up_context,
+        ref down_context,
+        flag,
+        line_num,
+        ref nodes,
+        ref inode,
+    }) = adds
+    {
+        for (i, node) in nodes.iter().enumerate() {
+            let up_context = if i == 0 {
+                up_context.clone()
+            } else {
+                vec![Key {
+                    patch: None,
+                    line: line_num + (i - 1),
+                }]
+            };
+            pieces.push(Change::NewNodes {
+                up_context,
+                down_context: down_context.clone(),
+                flag,
+                line_num: line_num + i,
+                nodes: vec![node.clone()],
+                inode: inode.clone(),
+            })
+        }
+    }
+    if pieces.len() < 2 {
+        return None;
+    }
+    let mut reordering = conflict_reordering(change);
+    Some(
+        pieces
+            .into_iter()
+            .map(|change| Record::Change {
+                file: file.clone(),
+                change,
+                conflict_reordering: std::mem::replace(&mut reordering, Vec::new()),
+            })
+            .collect(),
+    )
+}
+
+/// The first line identifier not used by any of `changes`.
+fn next_line_id(changes: &[Record<ChangeContext<Hash>>]) -> LineId {
+    let mut next = LineId::new() + 1;
+    for rec in changes {
+        for c in rec.iter().chain(conflict_reordering(rec).iter()) {
+            if let Change::NewNodes {
+                line_num,
+                ref nodes,
+                ..
+            } = *c
+            {
+                let end = line_num + nodes.len();
+                if end > next {
+                    next = end
+                }
+            }
+        }
+    }
+    next
+}
+
+fn editor(repo_root: &Path) -> String {
+    Meta::load(repo_root)
+        .ok()
+        .and_then(|meta| meta.editor)
+        .or_else(|| Global::load().ok().and_then(|global| global.editor))
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or("vi".to_string())
+}
+
+fn run_editor(repo_root: &Path, editor: &str, file: &Path) -> Result<(), Error> {
+    let mut editor_cmd = editor
+        .trim()
+        .split(" ")
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+
+    editor_cmd.push(file.as_os_str().to_owned());
+
+    process::Command::new(&editor_cmd[0])
+        .args(&editor_cmd[1..])
+        .current_dir(repo_root)
+        .status()
+        .map_err(|e| Error::CannotSpawnEditor { editor: editor.to_owned(), cause: e.to_string() })?;
+    Ok(())
+}
+
+/// The text of `line` shown in the editor, without its line ending.
+fn shown_line(line: &[u8]) -> String {
+    let mut s = String::from_utf8_lossy(line).into_owned();
+    if s.ends_with("\n") {
+        s.pop();
+        if s.ends_with("\r") {
+            s.pop();
+        }
+    }
+    s
+}
+
+enum EditedChange {
+    /// The edited change.
+    Edited(Record<ChangeContext<Hash>>),
+    /// Everything was removed from the change.
+    Empty,
+    /// The edited text could not be used.
+    Invalid(&'static str),
+}
+
+const EDIT_HELP: &'static str = "# Lines starting with '-' are deleted, and lines starting with '+' are added.
+# To keep a deleted line, replace its '-' with a space.
+# Added lines can be modified, removed, or new ones inserted.
+# Lines starting with '#' are ignored.
+";
+
+/// Edit a change to the contents of a file in an editor. If the
+/// number of added lines changes, the lines get identifiers starting
+/// at `fresh_line`, which is only possible if no other change depends
+/// on them (`depended_on` is false).
+fn edit_change<T: rand::Rng>(
+    repository: &MutTxn<T>,
+    repo_root: &Path,
+    change: &Record<ChangeContext<Hash>>,
+    fresh_line: LineId,
+    depended_on: bool,
+) -> Result<EditedChange, Error> {
+    let (dels, adds) = if let Some(c) = contents_change(change) {
+        c
+    } else {
+        return Ok(EditedChange::Invalid("Only changes to the contents of a file can be edited."));
+    };
+    let (del_flag, deleted) = match dels {
+        Some(&Change::NewEdges { flag, ref edges, .. }) => (flag, deleted_lines(flag, edges)),
+        _ => (EdgeFlags::empty(), Vec::new()),
+    };
+    let added: &[Vec<u8>] = match adds {
+        Some(&Change::NewNodes { ref nodes, .. }) => nodes,
+        _ => &[],
+    };
+
+    let mut text = format!("# Editing a change to {}\n", change_file(change).display());
+    text.push_str(EDIT_HELP);
+    for edges in deleted.iter() {
+        text.push('-');
+        let line = line_contents(repository, edge_target(del_flag, &edges[0]));
+        text.push_str(&String::from_utf8_lossy(&line));
+        if !text.ends_with("\n") {
+            text.push('\n')
+        }
+    }
+    for node in added {
+        text.push('+');
+        text.push_str(&String::from_utf8_lossy(node));
+        if !text.ends_with("\n") {
+            text.push('\n')
+        }
+    }
+
+    let mut change_file_path = repo_root.join(PIJUL_DIR_NAME);
+    change_file_path.push("CHANGE");
+    File::create(&change_file_path)?.write_all(text.as_bytes())?;
+    run_editor(repo_root, &editor(repo_root), &change_file_path)?;
+    let mut edited = String::new();
+    File::open(&change_file_path)?.read_to_string(&mut edited)?;
+    remove_file(&change_file_path)?;
+
+    // Added lines left unchanged keep their original bytes, which
+    // the text shown in the editor may not preserve (invalid UTF-8,
+    // or "\r\n" line endings).
+    let shown: Vec<String> = added.iter().map(|node| shown_line(node)).collect();
+    let mut next_original = 0;
+    let mut last_is_original = false;
+    let mut kept_deletions = Vec::new();
+    let mut nodes = Vec::new();
+    for line in edited.lines() {
+        if line.starts_with("#") || line.is_empty() {
+            continue;
+        } else if line.starts_with("+") {
+            let line = &line[1..];
+            if let Some(k) = (next_original..added.len()).find(|&k| shown[k] == line) {
+                nodes.push(added[k].clone());
+                next_original = k + 1;
+                last_is_original = true
+            } else {
+                nodes.push(format!("{}\n", line).into_bytes());
+                last_is_original = false
+            }
+        } else if line.starts_with("-") {
+            kept_deletions.push(true)
+        } else if line.starts_with(" ") {
+            kept_deletions.push(false)
+        } else {
+            return Ok(EditedChange::Invalid("Lines must start with '+', '-', ' ' or '#'."));
+        }
+    }
+    if kept_deletions.len() != deleted.len() {
+        return Ok(EditedChange::Invalid("Deleted lines cannot be added or removed."));
+    }
+    // Keep the absence of a final newline.
+    if !last_is_original && added.last().map(|l| !l.ends_with(b"\n")).unwrap_or(false) {
+        if let Some(last) = nodes.last_mut() {
+            last.pop();
+        }
+    }
+
+    let dels = match dels {
+        Some(&Change::NewEdges {
+            previous,
+            flag,
+            ref inode,
+            ..
+        }) if kept_deletions.iter().any(|&k| k) => Some(Change::NewEdges {
+            previous,
+            flag,
+            edges: deleted
+                .into_iter()
+                .zip(kept_deletions.into_iter())
+                .filter(|&(_, k)| k)
+                .flat_map(|(edges, _)| edges.into_iter())
+                .collect(),
+            inode: inode.clone(),
+        }),
+        _ => None,
+    };
+    let adds = match adds {
+        Some(&Change::NewNodes {
+            ref up_context,
+            ref down_context,
+            flag,
+            line_num,
+            ref inode,
+            ..
+        }) if !nodes.is_empty() => {
+            if nodes.len() != added.len() && depended_on {
+                return Ok(EditedChange::Invalid(
+                    "Other changes depend on the lines added by this change, their number cannot change.",
+                ));
+            }
+            Some(Change::NewNodes {
+                up_context: up_context.clone(),
+                down_context: down_context.clone(),
+                flag,
+                line_num: if nodes.len() > added.len() { fresh_line } else { line_num },
+                nodes,
+                inode: inode.clone(),
+            })
+        }
+        None if !nodes.is_empty() => {
+            return Ok(EditedChange::Invalid("Lines can only be added to changes that already add lines."));
+        }
+        _ => None,
+    };
+    let file = Rc::new(change_file(change).to_path_buf());
+    Ok(
+        match contents_record(file, dels, adds, conflict_reordering(change)) {
+            Some(rec) => EditedChange::Edited(rec),
+            None => EditedChange::Empty,
+        },
+    )
+}
+
+/// Does this change touch a file, or a line, matching `re`?
+fn change_matches<T: rand::Rng>(
+    repository: &MutTxn<T>,
+    change: &Record<ChangeContext<Hash>>,
+    re: &Regex,
+) -> bool {
+    if re.is_match(&change_file(change).to_string_lossy()) {
+        return true;
+    }
+    change.iter().any(|c| match *c {
+        Change::NewNodes { flag, ref nodes, .. } => {
+            !flag.contains(EdgeFlags::FOLDER_EDGE)
+                && nodes.iter().any(|n| re.is_match(&String::from_utf8_lossy(n)))
+        }
+        Change::NewEdges { flag, ref edges, .. } => {
+            !flag.contains(EdgeFlags::FOLDER_EDGE) && edges.iter().any(|e| {
+                re.is_match(&String::from_utf8_lossy(&line_contents(
+                    repository,
+                    edge_target(flag, e),
+                )))
+            })
+        }
+    })
+}
+
+pub fn ask_changes<T: rand::Rng>(
+    repository: &MutTxn<T>,
+    repo_root: &Path,
+    cwd: &Path,
+    changes: &mut Vec<Record<ChangeContext<Hash>>>,
+    direction: ChangesDirection,
+    to_unadd: &mut HashSet<PathBuf>,
+) -> Result<(HashMap<usize, bool>, Vec<String>), Error> {
+    debug!("changes: {:?}", changes);
+    let mut terminal = if stdout_isatty() {
+        term::stdout()
+    } else {
+        None
+    };
+    let getch = getch::Getch::new();
+    let mut i = 0;
+    let mut choices: HashMap<usize, bool> = HashMap::new();
+    let mut new_ignored_patterns: Vec<String> = Vec::new();
+    let mut new_ignore_builder = GitignoreBuilder::new(repo_root);
+    let mut final_decision = None;
+    // Decisions taken with "f", for all the changes to a file.
+    let mut file_decisions: HashMap<PathBuf, char> = HashMap::new();
+    let (mut deps, mut rev_deps) = changes_dependencies(changes);
 
     let empty_deps = Vec::new();
     let mut current_file = None;
     let mut show_help = false;
 
-    while i < changes.len() {
+    loop {
+        if i >= changes.len() {
+            // Come back to the changes skipped by a search, if any.
+            if let Some(j) = (0..changes.len()).find(|j| !choices.contains_key(j)) {
+                i = j
+            } else {
+                break;
+            }
+        }
         let decision=
             // If one of our dependencies has been unselected (with "n")
             if deps.get(&i)
@@ -694,19 +1133,22 @@ pub fn ask_changes<T: rand::Rng>(
             }
             _ => decision,
         };
-        let potential_new_ignore: Option<&str> = match direction {
+        let potential_new_ignore: Option<String> = match direction {
             ChangesDirection::Revert => None,
             ChangesDirection::Record => match changes[i] {
-                Record::FileAdd { ref name, .. } => Some(&name),
+                Record::FileAdd { ref name, .. } => Some(name.clone()),
                 _ => None,
             },
         };
+        let file_decision = file_decisions.get(change_file(&changes[i])).cloned();
         let (e, f) = match decision {
             Some(true) => ('Y', final_decision),
             Some(false) => ('N', final_decision),
             None => {
                 if let Some(d) = final_decision {
                     (d, Some(d))
+                } else if let Some(d) = file_decision {
+                    (d, None)
                 } else {
                     prompt_one_change(
                         repository,
@@ -715,7 +1157,7 @@ pub fn ask_changes<T: rand::Rng>(
                         changes.len(),
                         i,
                         direction,
-                        potential_new_ignore,
+                        potential_new_ignore.as_ref().map(|x| x.as_str()),
                         &mut terminal,
                         &getch,
                         cwd,
@@ -746,12 +1188,13 @@ pub fn ask_changes<T: rand::Rng>(
             }
             'K' if i > 0 => {
                 choices.remove(&i);
-                i -= 1
+                i -= 1;
+                file_decisions.remove(change_file(&changes[i]));
             }
             'I' => match potential_new_ignore {
                 Some(file) => {
                     add_to_ignore_file(
-                        file,
+                        &file,
                         repo_root,
                         &mut new_ignored_patterns,
                         &mut new_ignore_builder,
@@ -761,6 +1204,82 @@ pub fn ask_changes<T: rand::Rng>(
                 }
                 _ => {}
             },
+            'S' => match split_change(&changes[i]) {
+                Some(pieces) => {
+                    let n = pieces.len();
+                    let rest = changes.split_off(i + 1);
+                    changes.pop();
+                    changes.extend(pieces);
+                    changes.extend(rest);
+                    choices = choices
+                        .into_iter()
+                        .map(|(j, c)| if j > i { (j + n - 1, c) } else { (j, c) })
+                        .collect();
+                    let d = changes_dependencies(changes);
+                    deps = d.0;
+                    rev_deps = d.1;
+                }
+                None => println!("This change cannot be split.\n"),
+            },
+            'E' if direction.is_record() => {
+                let depended_on = rev_deps
+                    .get(&i)
+                    .map(|r| r.iter().any(|&j| j != i))
+                    .unwrap_or(false);
+                let fresh_line = next_line_id(changes);
+                match edit_change(repository, repo_root, &changes[i], fresh_line, depended_on)? {
+                    EditedChange::Edited(change) => {
+                        changes[i] = change;
+                        let d = changes_dependencies(changes);
+                        deps = d.0;
+                        rev_deps = d.1;
+                        choices.insert(i, true);
+                        i += 1
+                    }
+                    EditedChange::Empty => {
+                        choices.insert(i, false);
+                        i += 1
+                    }
+                    EditedChange::Invalid(msg) => println!("{}\n", msg),
+                }
+            }
+            'F' => {
+                print!(
+                    "Shall I {} all remaining changes to this file? [yn] ",
+                    direction.verb()
+                );
+                stdout().flush()?;
+                let d = getch
+                    .getch()
+                    .ok()
+                    .and_then(|x| from_u32(x as u32))
+                    .and_then(|x| x.to_uppercase().next());
+                println!("{}\n", d.unwrap_or(' '));
+                match d {
+                    Some(d) if d == 'Y' || d == 'N' => {
+                        file_decisions.insert(change_file(&changes[i]).to_path_buf(), d);
+                    }
+                    _ => {}
+                }
+            }
+            '/' => {
+                let pattern = read_line("Search for (regular expression): ");
+                match Regex::new(&pattern) {
+                    Ok(ref re) if !pattern.is_empty() => {
+                        let n = changes.len();
+                        if let Some(j) = (1..n)
+                            .map(|k| (i + k) % n)
+                            .find(|&j| change_matches(repository, &changes[j], re))
+                        {
+                            i = j
+                        } else {
+                            println!("No other change matches {:?}\n", pattern)
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("Invalid regular expression {:?}: {}\n", pattern, e),
+                }
+            }
             '?' => {
                 show_help = true;
             }
@@ -770,7 +1289,7 @@ pub fn ask_changes<T: rand::Rng>(
     Ok((choices, new_ignored_patterns))
 }
 
-fn read_line(s: &str) -> String {
+pub fn read_line(s: &str) -> String {
     print!("{}", s);
     if let Some(mut term) = line::Terminal::new() {
         term.read_line().unwrap()
@@ -813,19 +1332,7 @@ pub fn ask_patch_name(
         let _ = File::create(patch_name_file.as_path())?
             .write_all(template.into_bytes().as_slice())?;
 
-        let mut editor_cmd = editor
-            .trim()
-            .split(" ")
-            .map(OsString::from)
-            .collect::<Vec<_>>();
-
-        editor_cmd.push(patch_name_file.clone().into_os_string());
-
-        process::Command::new(&editor_cmd[0])
-            .args(&editor_cmd[1..])
-            .current_dir(repo_root)
-            .status()
-            .map_err(|e| Error::CannotSpawnEditor { editor: editor.to_owned(), cause: e.to_string() })?;
+        run_editor(repo_root, editor, &patch_name_file)?;
         // if we are here, it means the editor must have stopped and we can read
         // the content of PATCH_NAME.
 
@@ -1,10 +1,11 @@
 use clap::{Arg, ArgMatches, SubCommand};
 
+use super::porcelain::{format_args, is_json, print_json, BranchDescr};
 use super::{default_explain, get_current_branch, BasicOptions, StaticSubcommand};
 use error::Error;
 
 pub fn invocation() -> StaticSubcommand {
-    return SubCommand::with_name("branches")
+    return format_args(SubCommand::with_name("branches")
         .about("List all branches")
         .arg(
             Arg::with_name("repository")
@@ -14,7 +15,7 @@ pub fn invocation() -> StaticSubcommand {
                      current directory.",
                 )
                 .takes_value(true),
-        );
+        ));
 }
 
 pub fn run(args: &ArgMatches) -> Result<(), Error> {
@@ -22,6 +23,16 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let repo = opts.open_repo()?;
     let txn = repo.txn_begin()?;
     let current_branch = get_current_branch(&opts.repo_root)?;
+    if is_json(args) {
+        let branches: Vec<_> = txn
+            .iter_branches(None)
+            .map(|branch| BranchDescr {
+                current: branch.name.as_str() == current_branch,
+                name: branch.name.as_str().to_string(),
+            })
+            .collect();
+        return print_json(&branches);
+    }
     for branch in txn.iter_branches(None) {
         debug!("branch: {:?}", branch);
         if branch.name.as_str() == current_branch {
@@ -0,0 +1,394 @@
+//! Patch bundles, for exchanging patches without a network
+//! connection.
+//!
+//! A bundle is a single file containing:
+//!
+//! - the `MAGIC` string;
+//!
+//! - a `BundleHeader`, encoded with bincode, with the id of the
+//!   repository and the name of the branch the bundle was created
+//!   from, followed by the list of patches in the bundle along with
+//!   their dependencies. Patches are listed in the order in which
+//!   they were applied, hence dependencies come first. Headers
+//!   larger than `MAX_HEADER_SIZE` are refused;
+//!
+//! - the compressed patches, concatenated in the same order, each
+//!   one followed by its `SignatureFile` if it is signed.
+//!
+//! Bundles can be applied with `pijul bundle apply`, or pulled from
+//! like any other remote (`pijul pull file.bundle`).
+
+use bincode;
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use commands::local_patches::LocalPatches;
+use commands::remote::apply_patches_to_branch;
+use commands::smart_http::PatchEntry;
+use commands::{default_explain, BasicOptions, StaticSubcommand};
+use error::Error;
+use libpijul::fs_representation::{
+    id_file, patch_file_name, patches_dir, read_dependencies, ID_LENGTH,
+};
+use libpijul::Hash;
+use std::collections::HashSet;
+use std::fs::{metadata, rename, File};
+use std::io::{copy, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempdir::TempDir;
+
+/// First bytes of every bundle, including the version of the format.
+pub const MAGIC: &'static [u8] = b"pijul bundle 1\n";
+
+/// Largest header accepted when reading a bundle.
+pub const MAX_HEADER_SIZE: u64 = 1 << 26;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundleHeader {
+    pub repository_id: String,
+    pub branch: String,
+    pub patches: Vec<BundlePatch>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundlePatch {
+    pub hash: String,
+    pub dependencies: Vec<String>,
+    /// Size of the compressed patch, in bytes.
+    pub size: u64,
+    /// Size of the signature file, or 0 if the patch is not signed.
+    pub signature_size: u64,
+}
+
+/// A bundle opened for reading.
+pub struct Bundle {
+    pub header: BundleHeader,
+    file: File,
+    /// Offset of each patch in the file.
+    offsets: Vec<u64>,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBundle {
+        message: message.to_string(),
+    }
+}
+
+impl Bundle {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = vec![0; MAGIC.len()];
+        if file.read_exact(&mut magic).is_err() || &magic[..] != MAGIC {
+            return Err(invalid("not a Pijul bundle"));
+        }
+        let header: BundleHeader = bincode::config()
+            .limit(MAX_HEADER_SIZE)
+            .deserialize_from(&mut file)?;
+        if header.repository_id.len() != ID_LENGTH
+            || !header.repository_id.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(invalid("invalid repository id"));
+        }
+        let mut offset = file.seek(SeekFrom::Current(0))?;
+        let mut offsets = Vec::with_capacity(header.patches.len());
+        for p in header.patches.iter() {
+            offsets.push(offset);
+            offset = offset
+                .checked_add(p.size)
+                .and_then(|o| o.checked_add(p.signature_size))
+                .ok_or_else(|| invalid("truncated bundle"))?;
+        }
+        let file = file.into_inner();
+        if offset > file.metadata()?.len() {
+            return Err(invalid("truncated bundle"));
+        }
+        Ok(Bundle {
+            header,
+            file,
+            offsets,
+        })
+    }
+
+    /// Position of patch `hash` in the bundle.
+    pub fn position(&self, hash: &Hash) -> Option<usize> {
+        let hash = hash.to_base58();
+        self.header.patches.iter().position(|p| p.hash == hash)
+    }
+
+    /// Read the `n`th patch of the bundle, and its signature.
+    pub fn entry(&mut self, n: usize) -> Result<PatchEntry, Error> {
+        let p = &self.header.patches[n];
+        self.file.seek(SeekFrom::Start(self.offsets[n]))?;
+        let mut patch = vec![0; p.size as usize];
+        self.file
+            .read_exact(&mut patch)
+            .map_err(|_| invalid("truncated bundle"))?;
+        let signature = if p.signature_size > 0 {
+            let mut sig = vec![0; p.signature_size as usize];
+            self.file
+                .read_exact(&mut sig)
+                .map_err(|_| invalid("truncated bundle"))?;
+            Some(sig)
+        } else {
+            None
+        };
+        Ok(PatchEntry {
+            hash: p.hash.clone(),
+            patch,
+            signature,
+        })
+    }
+}
+
+/// Write a bundle of `patches` (which must be in the order in which
+/// they were applied) to `output`.
+pub fn write_bundle(
+    repo_root: &Path,
+    branch: &str,
+    patches: &[Hash],
+    output: &Path,
+) -> Result<(), Error> {
+    let mut repository_id = String::new();
+    File::open(id_file(repo_root))?.read_to_string(&mut repository_id)?;
+    let patches_dir = patches_dir(repo_root);
+    let mut header = BundleHeader {
+        repository_id: repository_id.trim().to_string(),
+        branch: branch.to_string(),
+        patches: Vec::new(),
+    };
+    let mut files = Vec::new();
+    for hash in patches {
+        let path = patches_dir.join(&patch_file_name(hash.as_ref()));
+        let sig = path.with_extension("sig");
+        let signature_size = metadata(&sig).map(|m| m.len()).unwrap_or(0);
+        header.patches.push(BundlePatch {
+            hash: hash.to_base58(),
+            dependencies: read_dependencies(repo_root, hash.as_ref())?
+                .iter()
+                .map(|d| d.to_base58())
+                .collect(),
+            size: metadata(&path)?.len(),
+            signature_size,
+        });
+        files.push((path, if signature_size > 0 { Some(sig) } else { None }))
+    }
+
+    // Write to a temporary file first, so that an interrupted
+    // command doesn't leave a truncated bundle behind.
+    let dir = match output.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let tmp_dir = TempDir::new_in(dir, "pijul_bundle")?;
+    let tmp = tmp_dir.path().join("bundle");
+    {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(MAGIC)?;
+        bincode::serialize_into(&mut w, &header)?;
+        for (patch, sig) in files {
+            copy(&mut File::open(&patch)?, &mut w)?;
+            if let Some(sig) = sig {
+                copy(&mut File::open(&sig)?, &mut w)?;
+            }
+        }
+        w.flush()?;
+    }
+    rename(&tmp, output)?;
+    Ok(())
+}
+
+pub fn invocation() -> StaticSubcommand {
+    return SubCommand::with_name("bundle")
+        .about("Exchange patches as a single file")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("Write patches of a branch to a bundle")
+                .arg(
+                    Arg::with_name("range")
+                        .help(
+                            "Patches to include, in base58. Each range is either a single patch \
+                             hash, or FROM..TO, meaning the patches applied after FROM, up to \
+                             TO (included). FROM and TO can be omitted. Defaults to all the \
+                             patches of the branch.",
+                        )
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("File to write the bundle to.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .long("repository")
+                        .help("The repository to take the patches from.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .long("branch")
+                        .help("The branch to take the patches from. Defaults to the current branch.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Apply all the patches of a bundle")
+                .arg(
+                    Arg::with_name("bundle")
+                        .help("The bundle to apply.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .long("repository")
+                        .help("The repository to apply the patches to.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .long("branch")
+                        .help("The branch to apply the patches to. Defaults to the current branch.")
+                        .takes_value(true),
+                ),
+        );
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    match args.subcommand() {
+        ("create", Some(args)) => create(args),
+        ("apply", Some(args)) => apply(args),
+        _ => Ok(()),
+    }
+}
+
+fn parse_hash(h: &str) -> Result<Hash, Error> {
+    Hash::from_base58(h).ok_or(Error::WrongHash)
+}
+
+/// Select the patches of `applied` (in the order in which they were
+/// applied) described by `ranges`.
+fn select(applied: &[Hash], branch: &str, ranges: &[&str]) -> Result<Vec<Hash>, Error> {
+    if ranges.is_empty() {
+        return Ok(applied.to_vec());
+    }
+    let position = |h: &str| -> Result<usize, Error> {
+        let hash = parse_hash(h)?;
+        applied
+            .iter()
+            .position(|x| x == &hash)
+            .ok_or_else(|| Error::BranchDoesNotHavePatch {
+                branch_name: branch.to_string(),
+                patch: hash.clone(),
+            })
+    };
+    let mut selected = vec![false; applied.len()];
+    for range in ranges {
+        let (start, end) = if let Some(i) = range.find("..") {
+            let (from, to) = (&range[..i], &range[i + 2..]);
+            let start = if from.is_empty() {
+                0
+            } else {
+                position(from)? + 1
+            };
+            let end = if to.is_empty() {
+                applied.len()
+            } else {
+                position(to)? + 1
+            };
+            (start, end)
+        } else {
+            let n = position(range)?;
+            (n, n + 1)
+        };
+        if start > end {
+            return Err(Error::InvalidRange {
+                range: range.to_string(),
+            });
+        }
+        for s in &mut selected[start..end] {
+            *s = true
+        }
+    }
+    Ok(applied
+        .iter()
+        .zip(selected)
+        .filter(|&(_, s)| s)
+        .map(|(h, _)| h.to_owned())
+        .collect())
+}
+
+fn create(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let branch_name = opts.branch();
+    let applied: Vec<Hash> = {
+        let repo = opts.open_repo()?;
+        let txn = repo.txn_begin()?;
+        let branch = txn.get_branch(&branch_name).ok_or(Error::NoSuchBranch)?;
+        txn.iter_applied(&branch, None)
+            .map(|(_, patchid)| txn.get_external(patchid).unwrap().to_owned())
+            .collect()
+    };
+    let ranges: Vec<&str> = args.values_of("range").map(|r| r.collect()).unwrap_or_default();
+    let patches = select(&applied, &branch_name, &ranges)?;
+    write_bundle(
+        &opts.repo_root,
+        &branch_name,
+        &patches,
+        Path::new(args.value_of("output").unwrap()),
+    )?;
+    LocalPatches::unregister(&opts.repo_root, &patches)
+}
+
+fn apply(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let branch_name = opts.branch();
+    let mut bundle = Bundle::open(Path::new(args.value_of("bundle").unwrap()))?;
+
+    // Check that all dependencies are either in the bundle or in the
+    // branch, before touching the repository.
+    {
+        let repo = opts.open_repo()?;
+        let txn = repo.txn_begin()?;
+        let branch = txn.get_branch(&branch_name);
+        let in_bundle: HashSet<&str> = bundle
+            .header
+            .patches
+            .iter()
+            .map(|p| p.hash.as_str())
+            .collect();
+        for p in bundle.header.patches.iter() {
+            for dep in p.dependencies.iter() {
+                if in_bundle.contains(dep.as_str()) {
+                    continue;
+                }
+                let dep = parse_hash(dep)?;
+                let on_branch = if let Some(ref branch) = branch {
+                    txn.get_internal(dep.as_ref())
+                        .and_then(|id| txn.get_patch(&branch.patches, id))
+                        .is_some()
+                } else {
+                    false
+                };
+                if !on_branch {
+                    return Err(Error::MissingDependency { hash: dep });
+                }
+            }
+        }
+    }
+
+    let patches_dir = opts.patches_dir();
+    let mut hashes = HashSet::new();
+    for n in 0..bundle.header.patches.len() {
+        hashes.insert(bundle.entry(n)?.save(&patches_dir)?);
+    }
+    apply_patches_to_branch(&opts.repo_root, &branch_name, &hashes)
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
@@ -0,0 +1,57 @@
+use clap::{Arg, ArgMatches, SubCommand};
+use error::Error;
+use libpijul::check::Problem;
+
+use super::{default_explain, BasicOptions, StaticSubcommand};
+
+pub fn invocation() -> StaticSubcommand {
+    return SubCommand::with_name("check")
+        .about("Check the integrity of the repository")
+        .arg(
+            Arg::with_name("repository")
+                .long("repository")
+                .help("Local repository.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .long("repair")
+                .help("Rebuild the tables of the pristine that can be recomputed from the patches")
+                .takes_value(false),
+        );
+}
+
+fn check(opts: &BasicOptions) -> Result<Vec<Problem>, Error> {
+    let repo = opts.open_repo()?;
+    let txn = repo.txn_begin()?;
+    Ok(txn.check(&opts.repo_root)?)
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let mut problems = check(&opts)?;
+    for p in problems.iter() {
+        println!("{}", p)
+    }
+    if args.is_present("repair") && problems.iter().any(|p| p.is_repairable()) {
+        let n = opts.with_mut_txn(0, |mut txn| {
+            let n = txn.repair(&opts.repo_root)?;
+            txn.commit()?;
+            Ok(n)
+        })?;
+        println!("Rebuilt the dependencies and touched files of {} patches", n);
+        problems = check(&opts)?;
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+        Ok(())
+    } else {
+        Err(Error::RepositoryCorrupted {
+            problems: problems.len(),
+        })
+    }
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
@@ -1,9 +1,10 @@
 use clap::{Arg, ArgMatches, SubCommand};
 
+use super::tag_registry::{dependency_closure, TagRegistry};
 use super::{default_explain, BasicOptions, StaticSubcommand};
-use libpijul::fs_representation::{get_current_branch, set_current_branch};
+use libpijul::fs_representation::{get_current_branch, read_patch, set_current_branch};
 use libpijul::patch::UnsignedPatch;
-use libpijul::{FileStatus, RecordState, ToPrefixes};
+use libpijul::{FileStatus, MutTxn, RecordState, ToPrefixes};
 use rand;
 use error::Error;
 use std::collections::HashSet;
@@ -19,7 +20,13 @@ pub fn invocation() -> StaticSubcommand {
         )
         .arg(
             Arg::with_name("branch")
-                .help("Branch to switch to.")
+                .help("Branch to switch to. With --tag, the name of the branch to create (defaults to the name of the tag).")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tag")
+                .long("tag")
+                .help("Create a new branch with the given tag and its dependencies, and switch to it.")
                 .takes_value(true),
         )
         .arg(
@@ -39,7 +46,16 @@ pub fn invocation() -> StaticSubcommand {
 
 pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let opts = BasicOptions::from_args(args)?;
-    if let Some(branch) = args.value_of("branch") {
+    if let Some(tag) = args.value_of("tag") {
+        let branch = args.value_of("branch").unwrap_or(tag);
+        branch_from_tag(&opts, tag, branch)?;
+        checkout(
+            &opts,
+            branch,
+            args.is_present("force"),
+            args.value_of("path"),
+        )
+    } else if let Some(branch) = args.value_of("branch") {
         checkout(
             &opts,
             branch,
@@ -51,36 +67,75 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
     }
 }
 
+/// Create branch `branch_name`, containing tag `tag` and its
+/// dependencies.
+fn branch_from_tag(opts: &BasicOptions, tag: &str, branch_name: &str) -> Result<(), Error> {
+    let hash = TagRegistry::load(&opts.repo_root)?.get(tag)?;
+    let mut patches = Vec::new();
+    for h in dependency_closure(&opts.repo_root, &hash) {
+        let patch = read_patch(&opts.repo_root, h.as_ref())?;
+        patches.push((h, patch))
+    }
+    let size_hint: u64 = patches.iter().map(|&(_, ref p)| p.size_upper_bound() as u64).sum();
+    opts.with_mut_txn(size_hint, |mut txn| {
+        if txn.get_branch(branch_name).is_some() {
+            return Err(Error::BranchAlreadyExists);
+        }
+        let mut branch = txn.open_branch(branch_name)?;
+        let mut count = 0;
+        for &(ref hash, ref patch) in patches.iter() {
+            txn.apply_patches_rec(&mut branch, &patches, hash, patch, &mut count)?;
+        }
+        txn.output_changes_file(&branch, &opts.repo_root)?;
+        txn.commit_branch(branch)?;
+        txn.commit()?;
+        Ok(())
+    })
+}
+
 pub fn checkout(
     opts: &BasicOptions,
-    branch: &str,
+    branch_name: &str,
     force: bool,
     partial_path: Option<&str>,
 ) -> Result<(), Error> {
-    let mut force = force;
-    let mut provision = 409600;
+    opts.with_mut_txn(0, |mut txn| {
+        check_pending_changes(opts, &mut txn, force)?;
 
-    loop {
-        match try_checkout(opts, branch, force, provision, partial_path) {
-            Err(ref e) if e.lacks_space() => {
-                provision = provision * 2;
-                force = true;
-            }
-            x => return x,
-        }
-    }
+        debug!("output repository");
+        let mut branch = if let Some(branch) = txn.get_branch(branch_name) {
+            branch
+        } else {
+            return Err(Error::NoSuchBranch);
+        };
+        let pref = if let Some(partial) = partial_path {
+            (&[partial][..]).to_prefixes(&txn, &branch)
+        } else {
+            (&[][..] as &[&str]).to_prefixes(&txn, &branch)
+        };
+        txn.output_repository(
+            &mut branch,
+            &opts.repo_root,
+            &pref,
+            &UnsignedPatch::empty().leave_unsigned(),
+            &HashSet::new(),
+        )?;
+        txn.commit_branch(branch)?;
+        txn.commit()?;
+        Ok(())
+    })?;
+
+    set_current_branch(&opts.repo_root, branch_name)?;
+
+    println!("Current branch: {:?}", get_current_branch(&opts.repo_root)?);
+    Ok(())
 }
 
-pub fn try_checkout(
+fn check_pending_changes(
     opts: &BasicOptions,
-    branch_name: &str,
+    txn: &mut MutTxn<rand::ThreadRng>,
     force: bool,
-    provision: u64,
-    partial_path: Option<&str>,
 ) -> Result<(), Error> {
-    let repo = opts.open_and_grow_repo(provision)?;
-    let mut txn = repo.mut_txn_begin(rand::thread_rng())?;
-    let current_branch = get_current_branch(&opts.repo_root)?;
     // We need to check at least that there are no file
     // moves/additions/deletions, because these would be
     // overwritten by the checkout, sometimes causing Pijul to
@@ -94,6 +149,7 @@ pub fn try_checkout(
         }
     } else {
         // Check whether there are more general changes.
+        let current_branch = get_current_branch(&opts.repo_root)?;
         let mut record = RecordState::new();
         let current_branch = txn.open_branch(&current_branch)?;
         txn.record(&mut record, &current_branch, &opts.repo_root, None)?;
@@ -104,33 +160,6 @@ pub fn try_checkout(
             return Err(Error::PendingChanges);
         }
     }
-
-    debug!("output repository");
-
-    let mut branch = if let Some(branch) = txn.get_branch(branch_name) {
-        branch
-    } else {
-        return Err(Error::NoSuchBranch);
-    };
-    let pref = if let Some(partial) = partial_path {
-        (&[partial][..]).to_prefixes(&txn, &branch)
-    } else {
-        (&[][..] as &[&str]).to_prefixes(&txn, &branch)
-    };
-    txn.output_repository(
-        &mut branch,
-        &opts.repo_root,
-        &pref,
-        &UnsignedPatch::empty().leave_unsigned(),
-        &HashSet::new(),
-    )?;
-    txn.commit_branch(branch)?;
-
-    txn.commit()?;
-
-    set_current_branch(&opts.repo_root, branch_name)?;
-
-    println!("Current branch: {:?}", get_current_branch(&opts.repo_root)?);
     Ok(())
 }
 
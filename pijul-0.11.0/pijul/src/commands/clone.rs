@@ -1,5 +1,6 @@
 use clap::{Arg, ArgMatches, SubCommand};
 
+use commands::pull_manifest::PullManifest;
 use commands::remote::{parse_remote, Remote};
 use commands::{assert_no_containing_repo, create_repo, default_explain, StaticSubcommand};
 use error::Error;
@@ -117,8 +118,27 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
                 .pullable_patches(args.from_branch, args.to_branch, path, &args.from_path)?
                 .iter()
                 .collect();
-            session.pull(path, args.to_branch, &mut pullable, &args.from_path, true)?;
-            set_current_branch(path, args.to_branch).map_err(|x| x.into())
+            let mut manifest =
+                PullManifest::new(&args.from, args.from_branch, args.to_branch, &args.from_path);
+            manifest.set_patches(&pullable);
+            manifest.save(path)?;
+            set_current_branch(path, args.to_branch)?;
+            if let Err(e) = session.pull(
+                path,
+                args.to_branch,
+                &mut pullable,
+                &args.from_path,
+                true,
+                Some(&mut manifest),
+            ) {
+                writeln!(
+                    stderr(),
+                    "Clone interrupted, run `pijul pull --resume` in {:?} to finish it.",
+                    path
+                )?;
+                return Err(e);
+            }
+            PullManifest::remove(path)
         }
         _ => {
             // Clone between remote repositories.
@@ -1,9 +1,15 @@
 use clap::{Arg, ArgMatches, SubCommand};
-use commands::{default_explain, BasicOptions, StaticSubcommand};
+use commands::{default_explain, validate_base58, BasicOptions, StaticSubcommand};
 use error::Error;
-use libpijul::fs_representation::read_patch_nochanges;
+use libpijul::fs_representation::{read_patch, read_patch_nochanges};
 use libpijul::graph::LineBuffer;
-use libpijul::{Key, PatchId, Txn, Value};
+use libpijul::patch::Patch;
+use libpijul::{
+    Branch, Contents, EdgeFlags, FileMetadata, GenericTxn, Hash, Key, Memory, PatchHeader,
+    PatchId, Transaction, ROOT_KEY,
+};
+use rand;
+use std::collections::{HashMap, HashSet};
 use std::fs::canonicalize;
 use std::io::{stdout, Stdout};
 use std::path::Path;
@@ -24,6 +30,24 @@ pub fn invocation() -> StaticSubcommand {
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("at")
+                .long("at")
+                .help(
+                    "Annotate the file as it was when this patch (for instance a tag) was \
+                     applied, i.e. with only this patch and its dependencies.",
+                )
+                .takes_value(true)
+                .validator(validate_base58),
+        )
+        .arg(
+            Arg::with_name("show-deleted")
+                .long("show-deleted")
+                .help(
+                    "Also show deleted lines, marked with '-' instead of '>', along with \
+                     the patch that deleted them. Conflicts are not marked in this mode.",
+                ),
+        )
         .arg(
             Arg::with_name("file")
                 .help("File to annotate.")
@@ -35,8 +59,33 @@ pub fn invocation() -> StaticSubcommand {
 pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let opts = BasicOptions::from_args(args)?;
     let file = Path::new(args.value_of("file").unwrap());
-    let p = canonicalize(opts.cwd.join(file))?;
-    if let Ok(file) = p.strip_prefix(&opts.repo_root) {
+    let at = args.value_of("at").map(|at| Hash::from_base58(at).unwrap());
+    let show_deleted = args.is_present("show-deleted");
+    let p = opts.cwd.join(file);
+    // With `--at`, the file might not be in the working copy anymore.
+    let p = match canonicalize(&p) {
+        Ok(p) => p,
+        Err(_) if at.is_some() => p,
+        Err(e) => return Err(e.into()),
+    };
+    let file = if let Ok(file) = p.strip_prefix(&opts.repo_root) {
+        file.to_path_buf()
+    } else {
+        return Ok(());
+    };
+    if let Some(at) = at {
+        let patches = patches_at(&opts.repo_root, at)?;
+        // Apply them in memory, leaving the pristine untouched.
+        let memory = Memory::new();
+        let mut txn = memory.mut_txn_begin(rand::thread_rng())?;
+        let mut branch = txn.open_branch(&opts.branch())?;
+        let mut count = 0;
+        for &(ref h, ref patch) in patches.iter() {
+            txn.apply_patches_rec(&mut branch, &patches, h, patch, &mut count)?;
+        }
+        let key = find_key(&txn, &branch, &file)?;
+        annotate(&txn, &branch, key, &opts.repo_root, show_deleted)
+    } else {
         let repo = opts.open_repo()?;
         let txn = repo.txn_begin()?;
         if let Some(branch) = txn.get_branch(&opts.branch()) {
@@ -45,56 +94,203 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
                 return Err(Error::IsDirectory);
             }
             let node = txn.get_inodes(inode).unwrap();
-            let mut graph = txn.retrieve(&branch, node.key);
-            let mut buf = OutBuffer {
-                stdout: stdout(),
-                txn: &txn,
-                target: &opts.repo_root,
-            };
-            super::setup_pager();
-            txn.output_file(&branch, &mut buf, &mut graph, &mut Vec::new())?;
+            annotate(&txn, &branch, node.key, &opts.repo_root, show_deleted)?;
+        }
+        Ok(())
+    }
+}
+
+fn annotate<T: Transaction, R>(
+    txn: &GenericTxn<T, R>,
+    branch: &Branch,
+    key: Key<PatchId>,
+    repo_root: &Path,
+    show_deleted: bool,
+) -> Result<(), Error> {
+    let mut buf = OutBuffer {
+        stdout: stdout(),
+        txn,
+        target: repo_root,
+        headers: HashMap::new(),
+    };
+    super::setup_pager();
+    if show_deleted {
+        output_with_deleted(txn, branch, key, &mut buf)?;
+    } else {
+        let mut graph = txn.retrieve(branch, key);
+        txn.output_file(branch, &mut buf, &mut graph, &mut Vec::new())?;
+    }
+    Ok(())
+}
+
+/// Patch `hash` and its dependencies.
+fn patches_at(repo_root: &Path, hash: Hash) -> Result<Vec<(Hash, Patch)>, Error> {
+    let mut patches = Vec::new();
+    let mut stack = vec![hash];
+    let mut seen = HashSet::new();
+    while let Some(h) = stack.pop() {
+        if !seen.insert(h.clone()) {
+            continue;
+        }
+        let patch = read_patch(repo_root, h.as_ref()).map_err(|_| Error::PatchNotFound {
+            repo_root: repo_root.to_string_lossy().into_owned(),
+            patch_hash: h.clone(),
+        })?;
+        stack.extend(patch.dependencies().iter().cloned());
+        patches.push((h, patch))
+    }
+    Ok(patches)
+}
+
+/// Find the key of `path` in `branch`, by following the names in the
+/// graph, since the tree of the working copy might not contain `path`
+/// in that version.
+fn find_key<T: Transaction, R>(
+    txn: &GenericTxn<T, R>,
+    branch: &Branch,
+    path: &Path,
+) -> Result<Key<PatchId>, Error> {
+    let not_found = || Error::FileNotInRepository {
+        path: path.to_string_lossy().into_owned(),
+    };
+    let names = EdgeFlags::FOLDER_EDGE | EdgeFlags::PSEUDO_EDGE | EdgeFlags::EPSILON_EDGE;
+    let mut key = ROOT_KEY;
+    let mut is_dir = true;
+    for c in path.components() {
+        let c = c.as_os_str().to_str().ok_or_else(&not_found)?;
+        let child = txn
+            .iter_adjacent(branch, key, EdgeFlags::empty(), names)
+            .filter_map(|e| {
+                let contents = txn.get_contents(e.dest)?;
+                if contents.len() < 2 || &contents[2..] != c.as_bytes() {
+                    return None;
+                }
+                let meta = FileMetadata::from_contents(&contents[..2]);
+                let child = txn
+                    .iter_adjacent(branch, e.dest, EdgeFlags::FOLDER_EDGE, names)
+                    .next()?;
+                Some((child.dest, meta.is_dir()))
+            })
+            .next();
+        if let Some((k, d)) = child {
+            key = k;
+            is_dir = d
+        } else {
+            return Err(not_found());
+        }
+    }
+    if is_dir {
+        return Err(Error::IsDirectory);
+    }
+    Ok(key)
+}
+
+/// Output all the lines of the file at `file_key`, alive or deleted,
+/// in an order compatible with the graph.
+fn output_with_deleted<'a, T: Transaction, R>(
+    txn: &'a GenericTxn<T, R>,
+    branch: &Branch,
+    file_key: Key<PatchId>,
+    buf: &mut OutBuffer<'a, T, R>,
+) -> Result<(), Error> {
+    // Depth-first search, collecting vertices in postorder.
+    let mut postorder = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![(file_key, false)];
+    while let Some((key, children_done)) = stack.pop() {
+        if children_done {
+            postorder.push(key);
+            continue;
+        }
+        if !seen.insert(key) {
+            continue;
+        }
+        stack.push((key, true));
+        for e in txn.iter_adjacent(branch, key, EdgeFlags::empty(), EdgeFlags::all()) {
+            if !e.flag.intersects(EdgeFlags::PARENT_EDGE | EdgeFlags::FOLDER_EDGE)
+                && !seen.contains(&e.dest)
+            {
+                stack.push((e.dest, false))
+            }
+        }
+    }
+
+    for key in postorder.into_iter().rev() {
+        if key == file_key {
+            continue;
+        }
+        let contents = if let Some(contents) = txn.get_contents(key) {
+            contents
+        } else {
+            continue;
+        };
+        if txn.is_alive(branch, key) {
+            buf.output_line(&key, contents)?;
+        } else {
+            let deleted_by = txn
+                .iter_adjacent(branch, key, EdgeFlags::PARENT_EDGE, EdgeFlags::all())
+                .find(|e| {
+                    e.flag.contains(EdgeFlags::PARENT_EDGE | EdgeFlags::DELETED_EDGE)
+                        && !e.flag.contains(EdgeFlags::PSEUDO_EDGE)
+                })
+                .map(|e| e.introduced_by);
+            if let Some(deleted_by) = deleted_by {
+                buf.write_line(deleted_by, "-", contents)?;
+            }
         }
     }
     Ok(())
 }
 
-struct OutBuffer<'a> {
+struct OutBuffer<'a, T: 'a, R: 'a> {
     stdout: Stdout,
-    txn: &'a Txn<'a>,
+    txn: &'a GenericTxn<T, R>,
     target: &'a Path,
+    headers: HashMap<PatchId, (Hash, PatchHeader)>,
 }
 
 use libpijul;
-use libpijul::Transaction;
 use std::io::Write;
 
-impl<'a, T: 'a + Transaction> LineBuffer<'a, T> for OutBuffer<'a> {
-    fn output_line(
+impl<'a, T: Transaction, R> OutBuffer<'a, T, R> {
+    /// Write a line, prefixed with the author and date of `patch`,
+    /// and `marker`.
+    fn write_line(
         &mut self,
-        key: &Key<PatchId>,
-        contents: Value<'a, T>,
+        patch: PatchId,
+        marker: &str,
+        contents: Contents<'a>,
     ) -> Result<(), libpijul::Error> {
-        let ext = self.txn.get_external(key.patch).unwrap();
-        let patch = read_patch_nochanges(self.target, ext)?;
+        if !self.headers.contains_key(&patch) {
+            let ext = self.txn.get_external(patch).unwrap();
+            let header = read_patch_nochanges(self.target, ext)?;
+            self.headers.insert(patch, (ext.to_owned(), header));
+        }
+        let &(ref hash, ref header) = self.headers.get(&patch).unwrap();
         write!(
             self.stdout,
-            "{} {} {} > ",
-            patch.authors[0],
-            patch.timestamp.format("%F %R %Z"),
-            key.patch.to_base58()
+            "{} {} {} {} ",
+            header.authors.get(0).map(|a| a.as_str()).unwrap_or("-"),
+            header.timestamp.format("%F %R %Z"),
+            hash.to_base58(),
+            marker
         )?;
-        let mut ends_with_eol = false;
-        for chunk in contents {
-            self.stdout.write_all(chunk)?;
-            if let Some(&c) = chunk.last() {
-                ends_with_eol = c == b'\n'
-            }
-        }
-        if !ends_with_eol {
+        self.stdout.write_all(&contents)?;
+        if contents.last() != Some(&b'\n') {
             writeln!(self.stdout, "")?;
         }
         Ok(())
     }
+}
+
+impl<'a, T: 'a + Transaction, R> LineBuffer<'a, T> for OutBuffer<'a, T, R> {
+    fn output_line(
+        &mut self,
+        key: &Key<PatchId>,
+        contents: Contents<'a>,
+    ) -> Result<(), libpijul::Error> {
+        self.write_line(key.patch, ">", contents)
+    }
 
     fn output_conflict_marker(&mut self, s: &'a str) -> Result<(), libpijul::Error> {
         write!(self.stdout, "{}", s)?;
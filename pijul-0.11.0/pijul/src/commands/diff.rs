@@ -1,4 +1,5 @@
 use clap::{Arg, ArgMatches, SubCommand};
+use commands::porcelain::{diff_records, format_args, is_json, print_json};
 use commands::{BasicOptions, StaticSubcommand};
 use error::Error;
 use libpijul::RecordState;
@@ -8,7 +9,7 @@ use std::io::{stderr, Write};
 use std::process::exit;
 
 pub fn invocation() -> StaticSubcommand {
-    return SubCommand::with_name("diff")
+    return format_args(SubCommand::with_name("diff")
         .about("Show what would be recorded if record were called")
         .arg(
             Arg::with_name("repository")
@@ -29,7 +30,7 @@ pub fn invocation() -> StaticSubcommand {
                 .help("Prefix to start from")
                 .takes_value(true)
                 .multiple(true),
-        );
+        ));
 }
 
 pub fn run(args: &ArgMatches) -> Result<(), Error> {
@@ -62,7 +63,11 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
         .into_iter()
         .map(|x| txn.globalize_record(x))
         .collect();
-    super::ask::print_status(&txn, &opts.cwd, &changes)?;
+    if is_json(args) {
+        print_json(&diff_records(&txn, &opts.repo_root, &changes))?
+    } else {
+        super::ask::print_status(&txn, &opts.cwd, &changes)?;
+    }
     Ok(())
 }
 
@@ -132,7 +132,7 @@ fn archive_rec<W: Write>(
         if names.len() > 1 {
             error!("file has several names: {:?}", names);
         }
-        current_path.push(names[0].1);
+        current_path.push(&names[0].1);
         if names[0].0.is_dir() {
             archive_rec(txn, branch, key, builder, buffer, forward, current_path)?;
         } else {
@@ -92,7 +92,7 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
 
             let deps = patch_dependencies(hash, &opts.repo_root)?;
 
-            apply_resize_no_output(&opts.repo_root, to, deps.iter(), |_, _| ())?;
+            apply_resize_no_output(&opts.repo_root, &opts.config, to, deps.iter(), |_, _| ())?;
 
             println!("Branch {:?} has been created.", to);
 
@@ -1,9 +1,9 @@
 use clap::ArgMatches;
 use commands::BasicOptions;
 use libpijul;
-use libpijul::Repository;
+use libpijul::MutTxn;
 use rand;
-use std::fs::{canonicalize, metadata, read_dir};
+use std::fs::{canonicalize, read_dir, symlink_metadata};
 use std::mem::swap;
 use std::path::{Path, PathBuf};
 use error::Error;
@@ -19,48 +19,55 @@ pub fn run(args: &ArgMatches, op: Operation) -> Result<(), Error> {
     let opts = BasicOptions::from_args(args)?;
 
     debug!("repo {:?}", opts.repo_root);
-    let mut extra_space = 409600;
     let recursive = args.is_present("recursive");
-    loop {
-        let touched_files = match args.values_of("files") {
-            Some(l) => l.map(|p| Path::new(p).to_owned()).collect(),
-            None => vec![],
-        };
-        match really_run(
-            &opts.pristine_dir(),
+    let touched_files: Vec<PathBuf> = match args.values_of("files") {
+        Some(l) => l.map(|p| Path::new(p).to_owned()).collect(),
+        None => vec![],
+    };
+    opts.with_mut_txn(0, |txn| {
+        really_run(
+            txn,
             &opts.cwd,
             &opts.repo_root,
-            touched_files,
+            touched_files.clone(),
             recursive,
             op,
-            extra_space,
-        ) {
-            Err(ref e) if e.lacks_space() => extra_space *= 2,
-            e => return e,
+        )
+    })
+}
+
+/// Canonicalize the parent of `path` only, so that symbolic links
+/// are tracked themselves rather than their targets.
+fn canonicalize_no_follow(path: &Path) -> Result<PathBuf, Error> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if symlink_metadata(path)?.file_type().is_symlink() => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            Ok(canonicalize(parent)?.join(name))
         }
+        _ => Ok(canonicalize(path)?),
     }
 }
 
 fn really_run(
-    repo_dir: &Path,
+    mut txn: MutTxn<rand::ThreadRng>,
     wd: &Path,
     r: &Path,
     mut files: Vec<PathBuf>,
     recursive: bool,
     op: Operation,
-    extra_space: u64,
 ) -> Result<(), Error> {
     debug!("files {:?}", files);
-    let mut rng = rand::thread_rng();
-    let repo = Repository::open(&repo_dir, Some(extra_space))?;
-    let mut txn = repo.mut_txn_begin(&mut rng)?;
     let mut files_ = Vec::new();
     match op {
         Operation::Add => {
             while !files.is_empty() {
                 for file_ in files.drain(..) {
-                    let p = canonicalize(wd.join(&file_))?;
-                    let m = metadata(&p)?;
+                    let p = canonicalize_no_follow(&wd.join(&file_))?;
+                    let m = symlink_metadata(&p)?;
                     if let Ok(file) = p.strip_prefix(r) {
                         match txn.add_file(&file, m.is_dir()) {
                             Ok(()) => {}
@@ -74,7 +81,7 @@ fn really_run(
                             if let Ok(dir) = read_dir(&file) {
                                 for file_ in dir.filter_map(|x| x.ok()) {
                                     match file_.file_type() {
-                                        Ok(f) if f.is_dir() || f.is_file() => {
+                                        Ok(f) if f.is_dir() || f.is_file() || f.is_symlink() => {
                                             files_.push(file_.path())
                                         }
                                         _ => {}
@@ -93,7 +100,7 @@ fn really_run(
         Operation::Remove => {
             for file in &files[..] {
                 debug!("file: {:?} {:?}", file, wd.join(file));
-                let p = wd.join(file).canonicalize()?;
+                let p = canonicalize_no_follow(&wd.join(file))?;
                 debug!("p: {:?}", p);
                 if let Ok(file) = p.strip_prefix(r) {
                     debug!("remove_file {:?}", file);
@@ -0,0 +1,166 @@
+use clap::{Arg, ArgMatches, SubCommand};
+use commands::pull_manifest::PullManifest;
+use commands::stash::stash_dependencies;
+use commands::tag_registry::{dependency_closure, TagRegistry};
+use error::Error;
+use libpijul::gc::compact;
+use libpijul::Hash;
+use std::collections::HashSet;
+use std::fs::{read_dir, remove_file};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::{default_explain, BasicOptions, StaticSubcommand};
+
+pub fn invocation() -> StaticSubcommand {
+    return SubCommand::with_name("gc")
+        .about("Remove the patches not applied to any branch, and compact the pristine")
+        .arg(
+            Arg::with_name("repository")
+                .long("repository")
+                .help("Local repository.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keep")
+                .long("keep")
+                .help("Keep this patch and its file, even if it is not applied to any branch.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("grace-period")
+                .long("grace-period")
+                .help("Keep the patches whose files were modified less than this number of days ago (default: 14).")
+                .takes_value(true)
+                .validator(|val| {
+                    let x: Result<u64, _> = val.parse();
+                    match x {
+                        Ok(_) => Ok(()),
+                        Err(_) => Err(val),
+                    }
+                }),
+        );
+}
+
+const DEFAULT_GRACE_PERIOD_DAYS: u64 = 14;
+
+/// Patches that must stay registered, along with their files, even
+/// though they are not applied to any branch: the ones given on the
+/// command line, the tags and the stashes with their dependencies,
+/// the patches of an interrupted pull, and the patches whose files
+/// were modified after `limit`.
+fn kept_patches(
+    args: &ArgMatches,
+    repo_root: &Path,
+    patches_dir: &Path,
+    limit: SystemTime,
+) -> Result<HashSet<Hash>, Error> {
+    let mut keep = HashSet::new();
+    if let Some(hashes) = args.values_of("keep") {
+        for h in hashes {
+            keep.insert(Hash::from_base58(h).ok_or(Error::WrongHash)?);
+        }
+    }
+    for hash in TagRegistry::load(repo_root)?.tags.values() {
+        if let Some(hash) = Hash::from_base58(hash) {
+            keep.extend(dependency_closure(repo_root, &hash))
+        }
+    }
+    if let Some(manifest) = PullManifest::load(repo_root)? {
+        keep.extend(manifest.patches()?.into_iter().map(|(h, _)| h))
+    }
+    keep.extend(stash_dependencies(repo_root)?);
+    for (hash, path) in patch_files(patches_dir)? {
+        if path.metadata()?.modified().map(|m| m > limit).unwrap_or(true) {
+            debug!("{:?} is too recent to be removed", path);
+            keep.insert(hash);
+        }
+    }
+    Ok(keep)
+}
+
+/// The patch files in `patches_dir`, with their hashes.
+fn patch_files(patches_dir: &Path) -> Result<Vec<(Hash, PathBuf)>, Error> {
+    let mut files = Vec::new();
+    for entry in read_dir(patches_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("gz") {
+            continue;
+        }
+        if let Some(hash) = path.file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(Hash::from_base58)
+        {
+            files.push((hash, path))
+        }
+    }
+    Ok(files)
+}
+
+/// Remove the files of the patches that are not registered, except
+/// for the ones in `keep`. Returns the number of patches removed, and
+/// the number of bytes freed.
+fn remove_patch_files(
+    patches_dir: &Path,
+    registered: &HashSet<Hash>,
+    keep: &HashSet<Hash>,
+) -> Result<(usize, u64), Error> {
+    let mut removed = 0;
+    let mut freed = 0;
+    for (hash, path) in patch_files(patches_dir)? {
+        if registered.contains(&hash) || keep.contains(&hash) {
+            continue;
+        }
+        let meta = path.metadata()?;
+        debug!("removing {:?}", path);
+        remove_file(&path)?;
+        freed += meta.len();
+        removed += 1;
+        let sig = path.with_extension("sig");
+        if let Ok(meta) = sig.metadata() {
+            remove_file(&sig)?;
+            freed += meta.len();
+        }
+    }
+    Ok((removed, freed))
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let grace_period = args.value_of("grace-period")
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_GRACE_PERIOD_DAYS);
+    let limit = SystemTime::now() - Duration::from_secs(grace_period * 24 * 3600);
+
+    let patches_dir = opts.patches_dir();
+    let keep = kept_patches(args, &opts.repo_root, &patches_dir, limit)?;
+    let (garbage, registered) = opts.with_mut_txn(0, |mut txn| {
+        let garbage = txn.collect_garbage(&keep)?;
+        let registered: HashSet<Hash> = txn.iter_external(None).map(|(_, h)| h.to_owned()).collect();
+        txn.commit()?;
+        Ok((garbage, registered))
+    })?;
+    let (removed, freed) = remove_patch_files(&patches_dir, &registered, &keep)?;
+    let (before, after) = compact(&opts.pristine_dir())?;
+
+    println!(
+        "Unregistered {} patches, deleted {} lines and {} cemetery edges",
+        garbage.patches.len(),
+        garbage.contents,
+        garbage.cemetery
+    );
+    println!("Removed {} patch files ({} bytes)", removed, freed);
+    println!(
+        "Pristine compacted from {} to {} bytes ({} bytes reclaimed)",
+        before,
+        after,
+        before - after
+    );
+    Ok(())
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
@@ -0,0 +1,573 @@
+//! A bridge between Pijul and Git.
+//!
+//! `pijul git import` records each commit of the first-parent history
+//! of a git branch as a patch on the current branch, and `pijul git
+//! export` writes the patches of a branch, in the order in which they
+//! were applied, as a linear sequence of git commits.
+//!
+//! Both commands are incremental: the correspondence between git
+//! commits and patches is kept in `.pijul/git_map`, and commits or
+//! patches that are already there are skipped.
+
+use chrono::{TimeZone, Utc};
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use commands::local_patches::LocalPatches;
+use commands::record::{changes_from_prefixes, record_resize};
+use commands::remote::apply_pulled_patches;
+use commands::{create_repo, default_explain, BasicOptions, StaticSubcommand};
+use error::Error;
+use git2;
+use libpijul;
+use libpijul::fs_representation::{
+    git_map_file, patches_dir, pristine_dir, read_dependencies, read_patch, PIJUL_DIR_NAME,
+};
+use libpijul::patch::PatchFlags;
+use libpijul::{Hash, Patch, Repository, DEFAULT_BRANCH};
+use meta::load_config;
+use rand;
+use std::collections::{HashMap, HashSet};
+use std::fs::{
+    create_dir_all, read_dir, read_link, remove_dir, remove_file, rename, symlink_metadata, File,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use tempdir::TempDir;
+
+pub fn invocation() -> StaticSubcommand {
+    let git_args = |sub: StaticSubcommand| {
+        sub.arg(
+            Arg::with_name("git")
+                .help("Path to the git repository.")
+                .takes_value(true)
+                .required(true),
+        ).arg(
+            Arg::with_name("git-branch")
+                .long("git-branch")
+                .help("The git branch. Defaults to master.")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("repository")
+                .long("repository")
+                .help("The Pijul repository. Defaults to the current repository.")
+                .takes_value(true),
+        )
+    };
+    return SubCommand::with_name("git")
+        .about("Exchange patches with a git repository")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(git_args(
+            SubCommand::with_name("import")
+                .about("Record the new commits of a git branch on the current branch"),
+        ))
+        .subcommand(
+            git_args(
+                SubCommand::with_name("export")
+                    .about("Write the new patches of a branch as commits of a git branch"),
+            ).arg(
+                Arg::with_name("branch")
+                    .long("branch")
+                    .help("The branch to export. Defaults to the current branch.")
+                    .takes_value(true),
+            ),
+        );
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    match args.subcommand() {
+        ("import", Some(args)) => import(args),
+        ("export", Some(args)) => export(args),
+        _ => Ok(()),
+    }
+}
+
+/// The correspondence between git commits and patches. Commits that
+/// didn't change anything when imported have no patch.
+struct GitMap {
+    commits: HashMap<git2::Oid, Option<Hash>>,
+    patches: HashMap<Hash, git2::Oid>,
+}
+
+impl GitMap {
+    /// Load the map, stored as one `<oid> <hash>` line per commit
+    /// (with `-` instead of the hash for commits without a patch).
+    fn load(repo_root: &Path) -> Result<Self, Error> {
+        let mut map = GitMap {
+            commits: HashMap::new(),
+            patches: HashMap::new(),
+        };
+        let file = if let Ok(f) = File::open(git_map_file(repo_root)) {
+            f
+        } else {
+            return Ok(map);
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            if let (Some(oid), Some(hash)) = (words.next(), words.next()) {
+                let oid = git2::Oid::from_str(oid)?;
+                let hash = if hash == "-" {
+                    None
+                } else {
+                    Some(Hash::from_base58(hash).ok_or(Error::WrongHash)?)
+                };
+                map.insert(oid, hash)
+            }
+        }
+        Ok(map)
+    }
+
+    fn insert(&mut self, oid: git2::Oid, hash: Option<Hash>) {
+        if let Some(ref hash) = hash {
+            self.patches.insert(hash.clone(), oid);
+        }
+        self.commits.insert(oid, hash);
+    }
+
+    fn save(&self, repo_root: &Path) -> Result<(), Error> {
+        let path = git_map_file(repo_root);
+        let tmp = path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            for (oid, hash) in self.commits.iter() {
+                match *hash {
+                    Some(ref hash) => writeln!(f, "{} {}", oid, hash.to_base58())?,
+                    None => writeln!(f, "{} -", oid)?,
+                }
+            }
+        }
+        rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+fn git_branch_ref(args: &ArgMatches) -> String {
+    format!(
+        "refs/heads/{}",
+        args.value_of("git-branch").unwrap_or(DEFAULT_BRANCH)
+    )
+}
+
+fn import(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let branch_name = opts.branch();
+    let git = git2::Repository::open(args.value_of("git").unwrap())?;
+    let head = git.refname_to_id(&git_branch_ref(args))?;
+    let mut map = GitMap::load(&opts.repo_root)?;
+
+    // Changes are recorded from the working copy, which must not
+    // contain anything else.
+    {
+        let repo = opts.open_repo()?;
+        let mut txn = repo.mut_txn_begin(rand::thread_rng())?;
+        let (changes, _) =
+            changes_from_prefixes(&opts.repo_root, &mut txn, &branch_name, None::<&Vec<&Path>>)?;
+        if !changes.is_empty() {
+            return Err(Error::PendingChanges);
+        }
+    }
+
+    let mut walk = git.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
+    walk.simplify_first_parent();
+    walk.push(head)?;
+    for oid in map.commits.keys() {
+        // Commits imported from other git repositories are unknown here.
+        walk.hide(*oid).unwrap_or(())
+    }
+    for oid in walk {
+        let commit = git.find_commit(oid?)?;
+        let hash = import_commit(&opts.repo_root, &branch_name, &git, &commit)?;
+        map.insert(commit.id(), hash);
+        map.save(&opts.repo_root)?;
+    }
+    Ok(())
+}
+
+/// Apply the changes made by `commit` (relative to its first parent)
+/// to the working copy, and record them.
+fn import_commit(
+    repo_root: &Path,
+    branch_name: &str,
+    git: &git2::Repository,
+    commit: &git2::Commit,
+) -> Result<Option<Hash>, Error> {
+    debug!("importing {:?}", commit.id());
+    let tree = commit.tree()?;
+    let parent_tree = if let Ok(parent) = commit.parent(0) {
+        Some(parent.tree()?)
+    } else {
+        None
+    };
+    let diff = git.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+    let mut added = Vec::new();
+    let mut deleted = Vec::new();
+    for delta in diff.deltas() {
+        match delta.status() {
+            git2::Delta::Deleted => {
+                if let Some(path) = delta.old_file().path() {
+                    remove_file(repo_root.join(path)).unwrap_or(());
+                    deleted.push(path.to_path_buf())
+                }
+            }
+            _ => {
+                if let Some(path) = delta.new_file().path() {
+                    let mode = tree.get_path(path)?.filemode();
+                    let is_symlink = mode & 0o170000 == 0o120000;
+                    if mode & 0o170000 != 0o100000 && !is_symlink {
+                        eprintln!("Skipping {:?}: submodules are not imported", path);
+                        continue;
+                    }
+                    let blob = git.find_blob(delta.new_file().id())?;
+                    let file = repo_root.join(path);
+                    if let Some(dir) = file.parent() {
+                        create_dir_all(dir)?
+                    }
+                    // The entry may have changed between a file and a
+                    // symbolic link.
+                    if let Ok(meta) = symlink_metadata(&file) {
+                        if !meta.is_dir() {
+                            remove_file(&file)?
+                        }
+                    }
+                    if is_symlink {
+                        if !create_symlink(blob.content(), &file)? {
+                            eprintln!("Skipping {:?}: symbolic links are not supported", path);
+                            continue;
+                        }
+                    } else {
+                        File::create(&file)?.write_all(blob.content())?;
+                        set_executable(&file, mode & 0o111 != 0)?;
+                    }
+                    if delta.status() == git2::Delta::Added {
+                        added.push(path.to_path_buf())
+                    }
+                }
+            }
+        }
+    }
+
+    // Update the tracked files. Git doesn't track directories, so
+    // remove the directories left empty.
+    let config = load_config(repo_root)?;
+    Repository::with_mut_txn(pristine_dir(repo_root), &config, 0, |mut txn| -> Result<(), Error> {
+        for path in added.iter() {
+            match txn.add_file(path, false) {
+                Ok(()) | Err(libpijul::Error::AlreadyAdded) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for path in deleted.iter() {
+            txn.remove_file(path).unwrap_or(());
+            let mut dir = path.parent();
+            while let Some(d) = dir {
+                let full = repo_root.join(d);
+                let is_empty = read_dir(&full)
+                    .map(|mut entries| entries.next().is_none())
+                    .unwrap_or(false);
+                if d.as_os_str().is_empty() || !is_empty {
+                    break;
+                }
+                remove_dir(&full)?;
+                txn.remove_file(d).unwrap_or(());
+                dir = d.parent();
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    })?;
+
+    let author = commit.author();
+    let author = match (author.name(), author.email()) {
+        (Some(name), Some(email)) if !email.is_empty() => format!("{} <{}>", name, email),
+        (Some(name), _) => name.to_string(),
+        (None, _) => String::new(),
+    };
+    let message = commit.message().unwrap_or("").trim();
+    let (name, description) = match message.find('\n') {
+        Some(i) => (
+            message[..i].trim().to_string(),
+            Some(message[i..].trim().to_string()),
+        ),
+        None => (message.to_string(), None),
+    };
+    record(
+        repo_root,
+        branch_name,
+        author,
+        name,
+        description,
+        Utc.timestamp(commit.time().seconds(), 0),
+    )
+}
+
+/// Record all the changes in the working copy.
+fn record(
+    repo_root: &Path,
+    branch_name: &str,
+    author: String,
+    name: String,
+    description: Option<String>,
+    date: ::chrono::DateTime<Utc>,
+) -> Result<Option<Hash>, Error> {
+    let pristine = pristine_dir(repo_root);
+    let config = load_config(repo_root)?;
+    let (changes, syncs) = Repository::with_mut_txn(&pristine, &config, 0, |mut txn| -> Result<_, Error> {
+        let (changes, syncs) =
+            changes_from_prefixes(repo_root, &mut txn, branch_name, None::<&Vec<&Path>>)?;
+        let changes: Vec<_> = changes
+            .into_iter()
+            .map(|x| txn.globalize_record(x))
+            .collect();
+        txn.commit()?;
+        Ok((changes, syncs))
+    })?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    let patch = {
+        let repo = Repository::open(&pristine, None)?;
+        let txn = repo.txn_begin()?;
+        let branch = txn.get_branch(branch_name).ok_or(Error::NoSuchBranch)?;
+        txn.new_patch(
+            &branch,
+            vec![author],
+            name,
+            description,
+            date,
+            changes.into_iter().flat_map(|x| x.into_iter()).collect(),
+            ::std::iter::empty(),
+            PatchFlags::empty(),
+        )
+    };
+    let hash = patch.save(&patches_dir(repo_root), None)?;
+    record_resize(&pristine, repo_root, branch_name, &hash, &patch, &syncs)
+}
+
+#[cfg(unix)]
+fn set_executable(file: &Path, executable: bool) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perm = file.metadata()?.permissions();
+    let mode = perm.mode();
+    perm.set_mode(if executable {
+        mode | 0o111
+    } else {
+        mode & !0o111
+    });
+    ::std::fs::set_permissions(file, perm)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_: &Path, _: bool) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Create a symbolic link to `target`, returning `false` if they are
+/// not supported on this platform.
+#[cfg(unix)]
+fn create_symlink(target: &[u8], file: &Path) -> Result<bool, Error> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    ::std::os::unix::fs::symlink(OsStr::from_bytes(target), file)?;
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_: &[u8], _: &Path) -> Result<bool, Error> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn symlink_target(file: &Path) -> Result<Vec<u8>, Error> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(read_link(file)?.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn symlink_target(file: &Path) -> Result<Vec<u8>, Error> {
+    Ok(read_link(file)?.to_string_lossy().into_owned().into_bytes())
+}
+
+#[cfg(unix)]
+fn file_mode(meta: &::std::fs::Metadata) -> i32 {
+    use std::os::unix::fs::PermissionsExt;
+    if meta.permissions().mode() & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+#[cfg(not(unix))]
+fn file_mode(_: &::std::fs::Metadata) -> i32 {
+    0o100644
+}
+
+/// Write the contents of directory `dir` (except `.pijul`) to the
+/// git object database, returning `None` if it has no files.
+fn write_tree(git: &git2::Repository, dir: &Path) -> Result<Option<git2::Oid>, Error> {
+    let mut builder = git.treebuilder(None)?;
+    let mut empty = true;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_str() == Some(PIJUL_DIR_NAME) {
+            continue;
+        }
+        let meta = symlink_metadata(entry.path())?;
+        if meta.is_dir() {
+            if let Some(oid) = write_tree(git, &entry.path())? {
+                builder.insert(name.as_os_str(), oid, 0o040000)?;
+                empty = false
+            }
+        } else if meta.is_file() {
+            let oid = git.blob_path(&entry.path())?;
+            builder.insert(name.as_os_str(), oid, file_mode(&meta))?;
+            empty = false
+        } else if meta.file_type().is_symlink() {
+            let oid = git.blob(&symlink_target(&entry.path())?)?;
+            builder.insert(name.as_os_str(), oid, 0o120000)?;
+            empty = false
+        }
+    }
+    if empty {
+        Ok(None)
+    } else {
+        Ok(Some(builder.write()?))
+    }
+}
+
+fn signature(author: &str, date: &::chrono::DateTime<Utc>) -> Result<git2::Signature<'static>, Error> {
+    let time = git2::Time::new(date.timestamp(), 0);
+    let (name, email) = match (author.find('<'), author.rfind('>')) {
+        (Some(i), Some(j)) if i < j => (author[..i].trim(), &author[i + 1..j]),
+        _ => (author.trim(), ""),
+    };
+    let name = if name.is_empty() { "unknown" } else { name };
+    Ok(git2::Signature::new(name, email, &time)?)
+}
+
+fn export(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let branch_name = opts.branch();
+    let git = git2::Repository::open(args.value_of("git").unwrap())?;
+    let git_ref = git_branch_ref(args);
+    let mut map = GitMap::load(&opts.repo_root)?;
+
+    // Exported commits go on top of the current head of the git
+    // branch, which must be known.
+    let mut parent = if let Ok(head) = git.refname_to_id(&git_ref) {
+        if !map.commits.contains_key(&head) {
+            return Err(Error::GitBranchNotImported { branch: git_ref });
+        }
+        Some(head)
+    } else {
+        None
+    };
+
+    let applied: Vec<Hash> = {
+        let repo = opts.open_repo()?;
+        let txn = repo.txn_begin()?;
+        let branch = txn.get_branch(&branch_name).ok_or(Error::NoSuchBranch)?;
+        txn.iter_applied(&branch, None)
+            .map(|(_, patchid)| txn.get_external(patchid).unwrap().to_owned())
+            .collect()
+    };
+
+    // The state of the git branch is given by the patches already
+    // there, along with their dependencies.
+    let mut base = HashSet::new();
+    let mut stack: Vec<Hash> = applied
+        .iter()
+        .filter(|h| map.patches.contains_key(h))
+        .cloned()
+        .collect();
+    while let Some(h) = stack.pop() {
+        if base.insert(h.clone()) {
+            stack.extend(read_dependencies(&opts.repo_root, h.as_ref())?)
+        }
+    }
+    if applied.iter().all(|h| base.contains(h)) {
+        println!("Nothing to export");
+        return Ok(());
+    }
+
+    // Rebuild these states in a scratch repository.
+    let tmp = TempDir::new("pijul_git")?;
+    create_repo(tmp.path())?;
+    let apply = |patches: &[&Hash]| -> Result<Vec<(Hash, Patch)>, Error> {
+        let mut p = Vec::new();
+        for h in patches {
+            p.push(((*h).clone(), read_patch(&opts.repo_root, h.as_ref())?))
+        }
+        apply_pulled_patches(tmp.path(), DEFAULT_BRANCH, DEFAULT_BRANCH, &p, &[], |_, _| {})?;
+        Ok(p)
+    };
+    let base_patches: Vec<&Hash> = applied.iter().filter(|h| base.contains(h)).collect();
+    let folded: Vec<(Hash, Patch)> = apply(&base_patches)?
+        .into_iter()
+        .filter(|&(ref h, _)| !map.patches.contains_key(h))
+        .collect();
+
+    // Dependencies of imported patches that were never exported are
+    // exported as a single commit.
+    if !folded.is_empty() {
+        let mut message = String::new();
+        for &(_, ref patch) in folded.iter() {
+            message.push_str(&patch.name);
+            message.push('\n')
+        }
+        let oid = commit(&git, tmp.path(), parent, &folded[0].1, &message)?;
+        for (hash, _) in folded {
+            map.insert(oid, Some(hash))
+        }
+        parent = Some(oid);
+    }
+
+    for hash in applied.iter().filter(|h| !base.contains(h)) {
+        let (hash, patch) = apply(&[hash])?.pop().unwrap();
+        let message = match patch.description {
+            Some(ref d) if !d.is_empty() => format!("{}\n\n{}\n", patch.name, d),
+            _ => format!("{}\n", patch.name),
+        };
+        let oid = commit(&git, tmp.path(), parent, &patch, &message)?;
+        map.insert(oid, Some(hash));
+        parent = Some(oid);
+    }
+
+    if let Some(oid) = parent {
+        git.reference(&git_ref, oid, true, "pijul git export")?;
+    }
+    map.save(&opts.repo_root)?;
+    LocalPatches::unregister(&opts.repo_root, &applied)
+}
+
+/// Commit the state of `dir`, authored by the authors of `patch`.
+fn commit(
+    git: &git2::Repository,
+    dir: &Path,
+    parent: Option<git2::Oid>,
+    patch: &Patch,
+    message: &str,
+) -> Result<git2::Oid, Error> {
+    let tree = match write_tree(git, dir)? {
+        Some(oid) => oid,
+        None => git.treebuilder(None)?.write()?,
+    };
+    let tree = git.find_tree(tree)?;
+    let author = signature(
+        patch.authors.get(0).map(|a| a.as_str()).unwrap_or(""),
+        &patch.timestamp,
+    )?;
+    let parent = if let Some(parent) = parent {
+        Some(git.find_commit(parent)?)
+    } else {
+        None
+    };
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    Ok(git.commit(None, &author, &author, message, &tree, &parents)?)
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
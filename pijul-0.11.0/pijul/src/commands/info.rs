@@ -1,5 +1,5 @@
 use clap::{AppSettings, Arg, ArgMatches, SubCommand};
-use libpijul::Inode;
+use libpijul::{Hash, Inode};
 use std::fs::File;
 
 use super::{default_explain, BasicOptions, StaticSubcommand};
@@ -29,11 +29,34 @@ pub fn invocation() -> StaticSubcommand {
                 .short("f")
                 .help("show only folder edges"),
         )
-        .arg(Arg::with_name("introduced_by").long("introducedby"));
+        .arg(Arg::with_name("introduced_by").long("introducedby"))
+        .arg(
+            Arg::with_name("drop-dependencies")
+                .long("drop-dependencies")
+                .help("Remove the dependencies of this patch from the pristine (to test pijul check).")
+                .takes_value(true),
+        );
 }
 
 pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let opts = BasicOptions::from_args(args)?;
+    if let Some(hash) = args.value_of("drop-dependencies") {
+        let hash = Hash::from_base58(hash).ok_or(Error::WrongHash)?;
+        opts.with_mut_txn(0, |mut txn| {
+            if let Some(id) = txn.get_internal(hash.as_ref()) {
+                let deps: Vec<_> = txn
+                    .iter_dep(Some((id, None)))
+                    .take_while(|&(p, _)| p == id)
+                    .map(|(_, dep)| dep)
+                    .collect();
+                for dep in deps {
+                    txn.del_dep(id, Some(dep))?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })?;
+    }
     if args.is_present("debug") {
         let repo = opts.open_repo()?;
         let txn = repo.txn_begin()?;
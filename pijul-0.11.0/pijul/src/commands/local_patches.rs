@@ -0,0 +1,80 @@
+//! The patches recorded in this repository that were never pushed,
+//! or written to a bundle. These are the only patches `pijul amend`
+//! may replace, since no other repository can depend on them.
+//!
+//! Patches pulled by other repositories from this one can't be
+//! detected, and are still considered local.
+
+use error::Error;
+use libpijul::fs_representation::local_patches_file;
+use libpijul::Hash;
+use std::collections::BTreeSet;
+use std::fs::{rename, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use toml;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LocalPatches {
+    /// Hashes in base58.
+    #[serde(default)]
+    pub patches: BTreeSet<String>,
+}
+
+impl LocalPatches {
+    /// Load the local patches of `repo_root`, which are empty if the
+    /// file doesn't exist.
+    pub fn load(repo_root: &Path) -> Result<Self, Error> {
+        let mut s = String::new();
+        if let Ok(mut f) = File::open(local_patches_file(repo_root)) {
+            f.read_to_string(&mut s)?;
+            Ok(toml::from_str(&s)?)
+        } else {
+            Ok(LocalPatches::default())
+        }
+    }
+
+    pub fn save(&self, repo_root: &Path) -> Result<(), Error> {
+        let path = local_patches_file(repo_root);
+        let mut tmp = path.clone();
+        tmp.set_extension("tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(toml::to_string(&self)?.as_bytes())?;
+            f.flush()?;
+        }
+        rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.patches.contains(&hash.to_base58())
+    }
+
+    /// Add `hash`, just recorded, to the local patches of
+    /// `repo_root`.
+    pub fn register(repo_root: &Path, hash: &Hash) -> Result<(), Error> {
+        let mut local = Self::load(repo_root)?;
+        if local.patches.insert(hash.to_base58()) {
+            local.save(repo_root)?
+        }
+        Ok(())
+    }
+
+    /// Remove `hashes`, which were published (or replaced), from the
+    /// local patches of `repo_root`.
+    pub fn unregister<'a, I: IntoIterator<Item = &'a Hash>>(
+        repo_root: &Path,
+        hashes: I,
+    ) -> Result<(), Error> {
+        let mut local = Self::load(repo_root)?;
+        let mut changed = false;
+        for hash in hashes {
+            changed |= local.patches.remove(&hash.to_base58())
+        }
+        if changed {
+            local.save(repo_root)?
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,20 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
 use clap::{Arg, ArgMatches, SubCommand};
-use commands::{ask, default_explain, BasicOptions, StaticSubcommand};
+use commands::porcelain::{format_args, is_json, print_json, PatchDescr};
+use commands::{ask, default_explain, validate_base58, BasicOptions, StaticSubcommand};
 use error::Error;
 use libpijul::fs_representation::{id_file, read_patch_nochanges};
-use libpijul::{HashRef, PatchId};
+use libpijul::patch::{PatchFlags, PatchHeader};
+use libpijul::{Hash, HashRef, PatchId, Txn};
 use regex::Regex;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 use term;
 
 pub fn invocation() -> StaticSubcommand {
-    SubCommand::with_name("log")
+    format_args(SubCommand::with_name("log")
         .about("List the patches applied to the given branch")
         .arg(
             Arg::with_name("repository")
@@ -50,6 +54,85 @@ pub fn invocation() -> StaticSubcommand {
                 .takes_value(true)
                 .help("Search patch name and description with a regular expression."),
         )
+        .arg(
+            Arg::with_name("author")
+                .long("author")
+                .multiple(true)
+                .takes_value(true)
+                .help("Only display patches with an author matching the given regular expression."),
+        )
+        .arg(
+            Arg::with_name("since")
+                .long("since")
+                .takes_value(true)
+                .help("Only display patches recorded at or after this date (RFC 3339, or YYYY-MM-DD)."),
+        )
+        .arg(
+            Arg::with_name("until")
+                .long("until")
+                .takes_value(true)
+                .help("Only display patches recorded at or before this date (RFC 3339, or YYYY-MM-DD)."),
+        )
+        .arg(
+            Arg::with_name("tags-only")
+                .long("tags-only")
+                .help("Only display tags."),
+        )
+        .arg(
+            Arg::with_name("dependencies-of")
+                .long("dependencies-of")
+                .multiple(true)
+                .takes_value(true)
+                .validator(validate_base58)
+                .help("Only display the given patches and their (direct or indirect) dependencies."),
+        )
+        .arg(
+            Arg::with_name("depends-on")
+                .long("depends-on")
+                .multiple(true)
+                .takes_value(true)
+                .validator(validate_base58)
+                .help("Only display patches that depend (directly or indirectly) on the given patches."),
+        )
+        .arg(
+            Arg::with_name("offset")
+                .long("offset")
+                .takes_value(true)
+                .validator(validate_usize)
+                .help("Skip this many matching patches."),
+        )
+        .arg(
+            Arg::with_name("limit")
+                .long("limit")
+                .takes_value(true)
+                .validator(validate_usize)
+                .help("Display at most this many patches."),
+        ))
+}
+
+fn validate_usize(x: String) -> Result<(), String> {
+    x.parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("\"{}\" is not a non-negative integer", x))
+}
+
+/// Parse a date given either in RFC 3339, or as YYYY-MM-DD (UTC). In
+/// the latter case, `end_of_day` tells whether to return the first
+/// or the last instant of that day.
+fn parse_date(date: &str, end_of_day: bool) -> Result<DateTime<Utc>, Error> {
+    if let Ok(d) = DateTime::<FixedOffset>::parse_from_rfc3339(date) {
+        return Ok(d.with_timezone(&Utc));
+    }
+    let invalid = || Error::InvalidDate {
+        date: date.to_string(),
+    };
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| invalid())?;
+    let t = if end_of_day {
+        day.and_hms_nano_opt(23, 59, 59, 999_999_999)
+    } else {
+        day.and_hms_opt(0, 0, 0)
+    };
+    Ok(Utc.from_utc_datetime(&t.ok_or_else(invalid)?))
 }
 
 struct Pager {
@@ -71,7 +154,16 @@ impl Pager {
 
 struct Settings<'a> {
     hash_only: bool,
+    json: bool,
     regex: Vec<Regex>,
+    authors: Vec<Regex>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    tags_only: bool,
+    dependencies_of: Vec<Hash>,
+    depends_on: Vec<Hash>,
+    offset: usize,
+    limit: Option<usize>,
     opts: BasicOptions<'a>,
     path: Vec<&'a str>,
 }
@@ -87,50 +179,199 @@ impl<'a> Settings<'a> {
                 regex.push(Regex::new(r)?)
             }
         }
+        let mut authors = Vec::new();
+        if let Some(author_args) = args.values_of("author") {
+            for r in author_args {
+                authors.push(Regex::new(r)?)
+            }
+        }
+        let hashes = |name: &str| -> Vec<Hash> {
+            args.values_of(name)
+                .map(|x| x.filter_map(Hash::from_base58).collect())
+                .unwrap_or(Vec::new())
+        };
         let path = args.values_of("path")
             .map(|x| x.collect())
             .unwrap_or(Vec::new());
         Ok(Settings {
             hash_only,
+            json: is_json(args),
             regex,
+            authors,
+            since: match args.value_of("since") {
+                Some(d) => Some(parse_date(d, false)?),
+                None => None,
+            },
+            until: match args.value_of("until") {
+                Some(d) => Some(parse_date(d, true)?),
+                None => None,
+            },
+            tags_only: args.is_present("tags-only"),
+            dependencies_of: hashes("dependencies-of"),
+            depends_on: hashes("depends-on"),
+            offset: args.value_of("offset").map_or(0, |x| x.parse().unwrap()),
+            limit: args.value_of("limit").map(|x| x.parse().unwrap()),
             opts: basic_opts,
             path,
         })
     }
-}
 
-fn display_patch(
-    pager: &mut Pager,
-    settings: &Settings,
-    nth: usize,
-    patchid: PatchId,
-    hash_ext: HashRef,
-) -> Result<(), Error> {
-    let (matches_regex, o_patch) = if settings.regex.is_empty() {
-        (true, None)
-    } else {
-        let patch = read_patch_nochanges(&settings.opts.repo_root, hash_ext)?;
-        let does_match = {
+    /// Whether this patch needs to be read to decide whether to
+    /// display it.
+    fn filters_header(&self) -> bool {
+        !self.regex.is_empty()
+            || !self.authors.is_empty()
+            || self.since.is_some()
+            || self.until.is_some()
+            || self.tags_only
+    }
+
+    fn matches_header(&self, patch: &PatchHeader) -> bool {
+        if !self.regex.is_empty() {
             let descr = match patch.description {
                 Some(ref d) => d,
                 None => "",
             };
-            settings
+            if !self
                 .regex
                 .iter()
                 .any(|ref r| r.is_match(&patch.name) || r.is_match(descr))
-        };
-        (does_match, Some(patch))
-    };
-    if !matches_regex {
-        return Ok(());
+            {
+                return false;
+            }
+        }
+        if !self.authors.is_empty()
+            && !patch
+                .authors
+                .iter()
+                .any(|a| self.authors.iter().any(|r| r.is_match(a)))
+        {
+            return false;
+        }
+        if let Some(ref since) = self.since {
+            if patch.timestamp < *since {
+                return false;
+            }
+        }
+        if let Some(ref until) = self.until {
+            if patch.timestamp > *until {
+                return false;
+            }
+        }
+        !self.tags_only || patch.flag.contains(PatchFlags::TAG)
+    }
+
+    /// The set of patches allowed by `--dependencies-of` and
+    /// `--depends-on`, or `None` if these options were not given.
+    fn patch_set(&self, txn: &Txn) -> Result<Option<HashSet<PatchId>>, Error> {
+        let mut result: Option<HashSet<PatchId>> = None;
+        for &(hashes, reverse) in &[(&self.dependencies_of, false), (&self.depends_on, true)] {
+            if hashes.is_empty() {
+                continue;
+            }
+            let mut set = HashSet::new();
+            for hash in hashes.iter() {
+                let id = match txn.get_internal(hash.as_ref()) {
+                    Some(id) => id.to_owned(),
+                    None => {
+                        return Err(Error::PatchNotFound {
+                            repo_root: self.opts.repo_root.to_string_lossy().into_owned(),
+                            patch_hash: hash.clone(),
+                        })
+                    }
+                };
+                let mut closure = HashSet::new();
+                dependency_closure(txn, id, reverse, &mut closure);
+                if reverse {
+                    // A patch doesn't depend on itself.
+                    closure.remove(&id);
+                }
+                set.extend(closure)
+            }
+            result = Some(match result {
+                Some(r) => r.intersection(&set).cloned().collect(),
+                None => set,
+            })
+        }
+        Ok(result)
+    }
+}
+
+/// Add `patch` and all its dependencies (or all the patches that
+/// depend on it, if `reverse` is true) to `set`.
+fn dependency_closure(txn: &Txn, patch: PatchId, reverse: bool, set: &mut HashSet<PatchId>) {
+    let mut stack = vec![patch];
+    while let Some(p) = stack.pop() {
+        if !set.insert(p) {
+            continue;
+        }
+        if reverse {
+            for (_, revdep) in txn.iter_revdep(Some((p, None))).take_while(|&(q, _)| q == p) {
+                stack.push(revdep)
+            }
+        } else {
+            for (_, dep) in txn.iter_dep(Some((p, None))).take_while(|&(q, _)| q == p) {
+                stack.push(dep)
+            }
+        }
+    }
+}
+
+/// Where the selected patches go, and how many of them were found so
+/// far.
+struct Output {
+    pager: Pager,
+    json: Vec<PatchDescr>,
+    matched: usize,
+}
+
+/// Display a patch if it matches the filters. Returns `false` if the
+/// limit has been reached, and no more patches need to be displayed.
+fn display_patch(
+    out: &mut Output,
+    settings: &Settings,
+    patch_set: &Option<HashSet<PatchId>>,
+    nth: usize,
+    patchid: PatchId,
+    hash_ext: HashRef,
+) -> Result<bool, Error> {
+    if let Some(limit) = settings.limit {
+        if out.matched >= settings.offset + limit {
+            return Ok(false);
+        }
+    }
+    if let Some(ref set) = *patch_set {
+        if !set.contains(&patchid) {
+            return Ok(true);
+        }
+    }
+    let o_patch = if settings.filters_header() {
+        let patch = read_patch_nochanges(&settings.opts.repo_root, hash_ext)?;
+        if !settings.matches_header(&patch) {
+            return Ok(true);
+        }
+        Some(patch)
+    } else {
+        None
     };
+    out.matched += 1;
+    if out.matched <= settings.offset {
+        return Ok(true);
+    }
 
-    pager.setup();
+    if settings.json {
+        let patch = match o_patch {
+            None => read_patch_nochanges(&settings.opts.repo_root, hash_ext)?,
+            Some(patch) => patch,
+        };
+        out.json.push(PatchDescr::new(&hash_ext.to_owned(), Some(patchid), &patch));
+        return Ok(true);
+    }
+
+    out.pager.setup();
 
     if settings.hash_only {
         println!("{}:{}", hash_ext.to_base58(), nth);
-        Ok(())
     } else {
         let patch = match o_patch {
             None => read_patch_nochanges(&settings.opts.repo_root, hash_ext)?,
@@ -138,8 +379,8 @@ fn display_patch(
         };
         let mut term = term::stdout();
         ask::print_patch_descr(&mut term, &hash_ext.to_owned(), Some(patchid), &patch);
-        Ok(())
     }
+    Ok(true)
 }
 
 pub fn run(args: &ArgMatches) -> Result<(), Error> {
@@ -147,7 +388,7 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
 
     let settings = Settings::parse(args)?;
 
-    if settings.hash_only {
+    if settings.hash_only && !settings.json {
         // If in binary form, start with this repository's id.
         let id_file = id_file(&settings.opts.repo_root);
         let mut f = File::open(&id_file)?;
@@ -156,7 +397,11 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
         println!("{}", s.trim());
     };
 
-    let mut pager = Pager::new();
+    let mut out = Output {
+        pager: Pager::new(),
+        json: Vec::new(),
+        matched: 0,
+    };
 
     let repo = settings.opts.open_repo()?;
     let txn = repo.txn_begin()?;
@@ -164,13 +409,16 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
         Some(b) => b,
         None => return Err(Error::NoSuchBranch),
     };
+    let patch_set = settings.patch_set(&txn)?;
     if let Some(v) = args.values_of("internal-id") {
         for (n, patchid) in v.filter_map(|x| PatchId::from_base58(x)).enumerate() {
             let hash_ext = txn.get_external(patchid).unwrap();
-            display_patch(&mut pager, &settings, n, patchid, hash_ext)?;
+            if !display_patch(&mut out, &settings, &patch_set, n, patchid, hash_ext)? {
+                break;
+            }
         }
     } else if !settings.path.is_empty() {
-        for (n, (applied, patchid)) in txn.rev_iter_applied(&branch, None).enumerate() {
+        'outer: for (n, (applied, patchid)) in txn.rev_iter_applied(&branch, None).enumerate() {
             for path in settings.path.iter() {
                 let inode = txn.find_inode(Path::new(path))?;
                 let key = txn.get_inodes(inode).unwrap().key;
@@ -179,7 +427,9 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
                     debug!("applied: {:?}", applied);
                     let hash_ext = txn.get_external(patchid).unwrap();
                     debug!("hash: {:?}", hash_ext.to_base58());
-                    display_patch(&mut pager, &settings, n, patchid, hash_ext)?;
+                    if !display_patch(&mut out, &settings, &patch_set, n, patchid, hash_ext)? {
+                        break 'outer;
+                    }
                     break;
                 }
             }
@@ -189,9 +439,14 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
             debug!("applied: {:?} {:?}", applied, patchid);
             let hash_ext = txn.get_external(patchid).unwrap();
             debug!("hash: {:?}", hash_ext.to_base58());
-            display_patch(&mut pager, &settings, n, patchid, hash_ext)?;
+            if !display_patch(&mut out, &settings, &patch_set, n, patchid, hash_ext)? {
+                break;
+            }
         }
     }
+    if settings.json {
+        print_json(&out.json)?
+    }
     Ok(())
 }
 
@@ -1,9 +1,10 @@
 use clap::{Arg, ArgMatches, SubCommand};
+use commands::porcelain::{format_args, is_json, print_json};
 use commands::{default_explain, BasicOptions, StaticSubcommand};
 use error;
 
 pub fn invocation() -> StaticSubcommand {
-    return SubCommand::with_name("ls")
+    return format_args(SubCommand::with_name("ls")
         .about("List tracked files")
         .arg(
             Arg::with_name("dir")
@@ -15,7 +16,7 @@ pub fn invocation() -> StaticSubcommand {
                 .long("repository")
                 .help("Repository to list.")
                 .takes_value(true),
-        );
+        ));
 }
 
 pub fn run(args: &ArgMatches) -> Result<(), error::Error> {
@@ -23,6 +24,10 @@ pub fn run(args: &ArgMatches) -> Result<(), error::Error> {
     let repo = opts.open_repo()?;
     let txn = repo.txn_begin()?;
     let files = txn.list_files(opts.dir_inode(&txn)?)?;
+    if is_json(args) {
+        let files: Vec<_> = files.iter().map(|f| f.to_string_lossy()).collect();
+        return print_json(&files);
+    }
     for f in files {
         println!("{}", f.display())
     }
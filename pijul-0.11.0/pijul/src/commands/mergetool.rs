@@ -0,0 +1,292 @@
+use clap::{Arg, ArgMatches, SubCommand};
+use commands::hooks::run_hook;
+use commands::record::{prefix, record_args, record_prefixes};
+use commands::resolve::{file_regions, has_unrecorded_changes};
+use commands::{default_explain, BasicOptions, StaticSubcommand};
+use error::Error;
+use libpijul::conflict::{Line, Region};
+use meta::{Global, Meta};
+use std;
+use std::ffi::OsString;
+use std::fs::{read, File};
+use std::io::Write;
+use std::mem::drop;
+use std::path::{Path, PathBuf};
+use std::process;
+use tempdir::TempDir;
+
+pub fn invocation() -> StaticSubcommand {
+    record_args(
+        SubCommand::with_name("mergetool")
+            .about("Resolve conflicts with an external merge tool, and record the resolution")
+            .arg(
+                Arg::with_name("tool")
+                    .long("tool")
+                    .help(
+                        "Merge tool to use (meld, kdiff3, vimdiff, or a command line \
+                         using $BASE, $LOCAL, $REMOTE and $MERGED).",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("prefix")
+                    .help("Only resolve the conflicts in these files or directories.")
+                    .takes_value(true)
+                    .multiple(true),
+            ),
+    )
+}
+
+/// Command lines of the tools we know about.
+const PRESETS: &'static [(&'static str, &'static str)] = &[
+    ("meld", "meld $LOCAL $BASE $REMOTE --output $MERGED"),
+    ("kdiff3", "kdiff3 $BASE $LOCAL $REMOTE -o $MERGED"),
+    ("vimdiff", "vimdiff $MERGED $LOCAL $BASE $REMOTE"),
+];
+
+/// The three versions of a conflicting file given to the merge
+/// tool. The first side of each conflict is "local", and the second
+/// one is "remote". Lines outside of conflicts, and lines of zombie
+/// conflicts, are in all three versions except that zombie lines are
+/// left out of the remote version.
+#[derive(Default)]
+struct Versions {
+    base: Vec<u8>,
+    local: Vec<u8>,
+    remote: Vec<u8>,
+}
+
+impl Versions {
+    /// Split `regions` into three versions, or return `None` if a
+    /// conflict has more than two sides, which merge tools can't
+    /// show.
+    fn new(regions: &[Region]) -> Option<Self> {
+        let mut v = Versions::default();
+        for region in regions {
+            match *region {
+                Region::Lines(ref lines) => {
+                    push_lines(&mut v.base, lines);
+                    push_lines(&mut v.local, lines);
+                    push_lines(&mut v.remote, lines);
+                }
+                Region::Conflict(ref sides) if region.is_zombie() => {
+                    push_lines(&mut v.base, &sides[0]);
+                    push_lines(&mut v.local, &sides[0]);
+                }
+                Region::Conflict(ref sides) if sides.len() > 2 => return None,
+                Region::Conflict(ref sides) => {
+                    push_lines(&mut v.local, &sides[0]);
+                    if let Some(side) = sides.get(1) {
+                        push_lines(&mut v.remote, side)
+                    }
+                }
+            }
+        }
+        Some(v)
+    }
+}
+
+fn push_lines(out: &mut Vec<u8>, lines: &[Line]) {
+    for line in lines {
+        out.extend_from_slice(&line.contents)
+    }
+}
+
+/// Build the command line for `tool`, replacing the placeholders.
+fn command_line(
+    tool: &str,
+    base: &Path,
+    local: &Path,
+    remote: &Path,
+    merged: &Path,
+) -> Vec<OsString> {
+    let template = PRESETS
+        .iter()
+        .find(|&&(name, _)| name == tool)
+        .map(|&(_, template)| template)
+        .unwrap_or(tool);
+    let vars = [
+        ("$BASE", base),
+        ("$LOCAL", local),
+        ("$REMOTE", remote),
+        ("$MERGED", merged),
+    ];
+    split_args(template)
+        .iter()
+        .map(|arg| expand(arg, &vars))
+        .collect()
+}
+
+/// Split a command line into arguments, as a shell would: arguments
+/// are separated by whitespace, quotes (single or double) group
+/// characters into a single argument, and a backslash escapes a
+/// quote, a backslash or a whitespace character.
+fn split_args(s: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quote != Some('\'') => {
+                in_arg = true;
+                match chars.peek() {
+                    Some(&next) if "\"'\\".contains(next) || next.is_whitespace() => {
+                        current.push(next);
+                        chars.next();
+                    }
+                    _ => current.push(c),
+                }
+            }
+            c if Some(c) == quote => quote = None,
+            c if quote.is_some() => current.push(c),
+            '"' | '\'' => {
+                in_arg = true;
+                quote = Some(c)
+            }
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::replace(&mut current, String::new()));
+                    in_arg = false
+                }
+            }
+            c => {
+                in_arg = true;
+                current.push(c)
+            }
+        }
+    }
+    if in_arg {
+        args.push(current)
+    }
+    args
+}
+
+/// Replace the placeholders in `arg` by their paths.
+fn expand(arg: &str, vars: &[(&str, &Path)]) -> OsString {
+    let mut result = OsString::new();
+    let mut rest = arg;
+    'outer: while let Some(i) = rest.find('$') {
+        result.push(&rest[..i]);
+        rest = &rest[i..];
+        for &(name, path) in vars {
+            if rest.starts_with(name) {
+                result.push(path);
+                rest = &rest[name.len()..];
+                continue 'outer;
+            }
+        }
+        result.push("$");
+        rest = &rest[1..];
+    }
+    result.push(rest);
+    result
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let branch_name = opts.branch();
+    let prefixes: Vec<String> = prefix(args, &opts)?
+        .unwrap_or(Vec::new())
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    let prefixes: Vec<&str> = prefixes.iter().map(|p| p.as_str()).collect();
+
+    let meta = Meta::load(&opts.repo_root).unwrap_or(Meta::new());
+    let global = Global::load().unwrap_or(Global::new());
+    let tool = if let Some(tool) = args.value_of("tool") {
+        tool.to_string()
+    } else if let Some(tool) = meta.merge_tool.or(global.merge_tool) {
+        tool
+    } else {
+        return Err(Error::NoMergeTool);
+    };
+
+    let repo = opts.open_repo()?;
+    let txn = repo.txn_begin()?;
+    let mut files = txn.list_conflict_files(&branch_name, &prefixes)?;
+    files.sort();
+    files.dedup();
+    if files.is_empty() {
+        println!("No conflicts to resolve.");
+        return Ok(());
+    }
+
+    let tmp = TempDir::new("pijul_mergetool")?;
+    let mut touched = Vec::new();
+    for file in files.iter() {
+        let regions = if let Some(regions) = file_regions(&txn, &opts.repo_root, &branch_name, file)? {
+            regions
+        } else {
+            continue;
+        };
+        if !regions.has_conflicts() {
+            continue;
+        }
+        if has_unrecorded_changes(&txn, &opts.repo_root, &branch_name, file)? {
+            println!("{}: skipped, it has unrecorded changes", file.display());
+            continue;
+        }
+        let versions = if let Some(versions) = Versions::new(&regions.regions) {
+            versions
+        } else {
+            println!(
+                "{}: skipped, it has conflicts with more than two sides (use pijul resolve)",
+                file.display()
+            );
+            continue;
+        };
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or(String::new());
+        let write_version = |version: &str, contents: &[u8]| -> Result<PathBuf, Error> {
+            let path = tmp.path().join(format!("{}.{}", version, name));
+            File::create(&path)?.write_all(contents)?;
+            Ok(path)
+        };
+        let base = write_version("BASE", &versions.base)?;
+        let local = write_version("LOCAL", &versions.local)?;
+        let remote = write_version("REMOTE", &versions.remote)?;
+        let merged = opts.repo_root.join(file);
+        let original = read(&merged)?;
+
+        let cmd = command_line(&tool, &base, &local, &remote, &merged);
+        if cmd.is_empty() {
+            return Err(Error::NoMergeTool);
+        }
+        println!("Merging {}", file.display());
+        let status = process::Command::new(&cmd[0])
+            .args(&cmd[1..])
+            .current_dir(&opts.repo_root)
+            .status()
+            .map_err(|e| Error::CannotSpawnEditor {
+                editor: tool.clone(),
+                cause: e.to_string(),
+            })?;
+        if !status.success() {
+            println!("{}: merge tool failed, leaving the conflict", file.display());
+            File::create(&merged)?.write_all(&original)?;
+            continue;
+        }
+        if read(&merged)? != original {
+            touched.push(file.clone())
+        }
+    }
+    drop(txn);
+    drop(repo);
+
+    if touched.is_empty() {
+        println!("Nothing to record");
+        return Ok(());
+    }
+    run_hook(&opts.repo_root, "pre-record", None)?;
+    record_prefixes(args, &opts, false, true, Some(touched))?;
+    Ok(())
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
@@ -4,13 +4,21 @@ pub type StaticSubcommand = clap::App<'static, 'static>;
 
 mod ask;
 mod fs_operation;
+mod local_patches;
+mod porcelain;
+mod pull_manifest;
 pub mod remote;
+mod smart_http;
 mod ssh_auth_attempts;
+mod tag_registry;
 
 pub mod add;
+pub mod amend;
 pub mod apply;
 pub mod branches;
+pub mod bundle;
 pub mod challenge;
+pub mod check;
 pub mod checkout;
 pub mod clone;
 pub mod credit;
@@ -18,23 +26,30 @@ pub mod delete_branch;
 pub mod diff;
 pub mod dist;
 pub mod fork;
+pub mod gc;
 pub mod generate_completions;
+pub mod git;
 pub mod hooks;
 pub mod info;
 pub mod init;
 pub mod key;
 pub mod log;
+pub mod mergetool;
 pub mod ls;
 pub mod mv;
 pub mod patch;
+pub mod protocol;
 pub mod pull;
 pub mod push;
 pub mod record;
 pub mod remove;
+pub mod resolve;
 pub mod revert;
 pub mod rollback;
+pub mod serve_http;
 pub mod show_dependencies;
 pub mod sign;
+pub mod stash;
 pub mod status;
 pub mod tag;
 pub mod unrecord;
@@ -47,7 +62,8 @@ use std::env;
 use libpijul::Hash;
 use error::Error;
 use libpijul::fs_representation::get_current_branch;
-use libpijul::{fs_representation, Inode, Repository, Txn, DEFAULT_BRANCH};
+use libpijul::{fs_representation, Config, Inode, MutTxn, Repository, Txn, DEFAULT_BRANCH};
+use meta::load_config;
 use rand;
 use std::borrow::Cow;
 use std::env::current_dir;
@@ -63,6 +79,7 @@ pub fn all_command_invocations() -> Vec<StaticSubcommand> {
         info::invocation(),
         init::invocation(),
         record::invocation(),
+        amend::invocation(),
         unrecord::invocation(),
         add::invocation(),
         pull::invocation(),
@@ -73,10 +90,15 @@ pub fn all_command_invocations() -> Vec<StaticSubcommand> {
         mv::invocation(),
         ls::invocation(),
         revert::invocation(),
+        stash::invocation(),
+        resolve::invocation(),
+        mergetool::invocation(),
         patch::invocation(),
         fork::invocation(),
         branches::invocation(),
         delete_branch::invocation(),
+        check::invocation(),
+        gc::invocation(),
         checkout::invocation(),
         diff::invocation(),
         credit::invocation(),
@@ -88,6 +110,10 @@ pub fn all_command_invocations() -> Vec<StaticSubcommand> {
         tag::invocation(),
         sign::invocation(),
         challenge::invocation(),
+        serve_http::invocation(),
+        protocol::invocation(),
+        bundle::invocation(),
+        git::invocation(),
         generate_completions::invocation(),
     ];
 }
@@ -158,6 +184,8 @@ pub struct BasicOptions<'a> {
     /// running `pijul`.
     pub cwd: PathBuf,
     pub repo_root: PathBuf,
+    /// Settings read from the meta file of the repository.
+    pub config: Config,
     args: &'a ArgMatches<'a>,
 }
 
@@ -175,9 +203,11 @@ impl<'a> BasicOptions<'a> {
         } else {
             return Err(Error::NotInARepository);
         };
+        let config = load_config(&repo_root)?;
         Ok(BasicOptions {
             cwd: wd,
             repo_root: repo_root,
+            config: config,
             args: args,
         })
     }
@@ -202,11 +232,19 @@ impl<'a> BasicOptions<'a> {
     }
 
     pub fn open_repo(&self) -> Result<Repository, Error> {
-        Repository::open(self.pristine_dir(), None).map_err(|e| e.into())
+        let mut repo = Repository::open(self.pristine_dir(), None)?;
+        repo.set_config(self.config.clone());
+        Ok(repo)
     }
 
-    pub fn open_and_grow_repo(&self, increase: u64) -> Result<Repository, Error> {
-        Repository::open(self.pristine_dir(), Some(increase)).map_err(|e| e.into())
+    /// Call `f` on a mutable transaction, growing the pristine and
+    /// calling `f` again if it runs out of space (see
+    /// `Repository::with_mut_txn`).
+    pub fn with_mut_txn<A, F>(&self, size_hint: u64, f: F) -> Result<A, Error>
+    where
+        F: for<'env> FnMut(MutTxn<'env, rand::ThreadRng>) -> Result<A, Error>,
+    {
+        Repository::with_mut_txn(self.pristine_dir(), &self.config, size_hint, f)
     }
 
     pub fn pristine_dir(&self) -> PathBuf {
@@ -1,12 +1,15 @@
 use super::validate_base58;
 use clap::{Arg, ArgGroup, ArgMatches, SubCommand};
+use commands::porcelain::{format_args, is_json, print_json, PatchContents, PatchDescr};
 use commands::{default_explain, BasicOptions, StaticSubcommand};
 use isatty::stdout_isatty;
 use libpijul;
 use libpijul::fs_representation::patches_dir;
 use libpijul::graph::LineBuffer;
 use libpijul::patch::{Change, NewEdge, Patch};
-use libpijul::{Branch, EdgeFlags, Hash, Key, LineId, PatchId, Transaction, Txn, Value, ROOT_KEY};
+use libpijul::{
+    Branch, Contents, EdgeFlags, Hash, Key, LineId, PatchId, Transaction, Txn, ROOT_KEY,
+};
 use serde_json;
 use std::cmp::max;
 use std::collections::{HashMap, HashSet};
@@ -18,7 +21,7 @@ use term::StdoutTerminal;
 use error::Error;
 
 pub fn invocation() -> StaticSubcommand {
-    SubCommand::with_name("patch")
+    format_args(SubCommand::with_name("patch"))
         .about("Output a patch")
         .arg(
             Arg::with_name("repository")
@@ -108,6 +111,19 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
         let (hash, _, patch) = Patch::from_reader_compressed(&mut f)?;
 
         match v {
+            _ if is_json(args) => {
+                let internal = {
+                    let repo = opts.open_repo()?;
+                    let txn = repo.txn_begin()?;
+                    txn.get_internal(hash.as_ref()).map(|i| i.to_owned())
+                };
+                let pretty = patch.to_pretty();
+                print_json(&PatchContents {
+                    descr: PatchDescr::new(&hash, internal, patch.header()),
+                    dependencies: pretty.dependencies,
+                    changes: pretty.changes,
+                })?
+            }
             View::AuthorsOnly => print!("{:?}", patch.authors),
             View::DescrOnly => print!("{}", patch.description.clone().unwrap_or("".into())),
             View::DateOnly => print!("{:?}", patch.timestamp),
@@ -298,7 +314,7 @@ struct LineNumbers {
 }
 
 impl<'a, T: 'a + Transaction> LineBuffer<'a, T> for LineNumbers {
-    fn output_line(&mut self, key: &Key<PatchId>, _: Value<'a, T>) -> libpijul::Result<()> {
+    fn output_line(&mut self, key: &Key<PatchId>, _: Contents<'a>) -> libpijul::Result<()> {
         self.numbers
             .insert(key.clone(), (self.current_file.clone(), self.n));
         self.n += 1;
@@ -397,18 +413,7 @@ fn render_new_edges(
                     term.reset().unwrap_or(());
                 }
 
-                let mut is_valid = true;
-                let mut cont = String::new();
-                for chunk in contents {
-                    let c = from_utf8(chunk);
-                    if let Ok(c) = c {
-                        cont.push_str(c)
-                    } else {
-                        is_valid = false;
-                        break;
-                    }
-                }
-                if is_valid {
+                if let Ok(cont) = from_utf8(&contents) {
                     print!("{}", cont);
                     if !cont.ends_with("\n") {
                         println!("");
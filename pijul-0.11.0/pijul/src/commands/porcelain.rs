@@ -0,0 +1,243 @@
+//! Machine-readable output, selected with `--format json` (or its
+//! shorthand `--porcelain`) on `log`, `status`, `diff`, `patch`,
+//! `branches`, `ls` and `show-dependencies`.
+//!
+//! Each command writes a single JSON document on its standard
+//! output. Paths are relative to the repository root, hashes are in
+//! base58, and timestamps in RFC 3339. The schema is:
+//!
+//! - `log`: an array of patch objects, newest first, each with
+//!   fields `hash`, `internal_id`, `authors` (array of strings),
+//!   `name`, `description` (string or null), `timestamp` and `tag`
+//!   (boolean).
+//!
+//! - `patch`: a patch object as in `log`, with two more fields:
+//!   `dependencies` (array of hashes) and `changes`, the changes of
+//!   the patch in the format of `pijul apply --json`. In `changes`,
+//!   vertices are written `<n>.<line>`, where `<n>` is the position
+//!   (starting at 1) in `dependencies` of the patch that introduced
+//!   the vertex, or just `<line>` for vertices introduced by this
+//!   patch.
+//!
+//! - `status`: an object with fields `branch`, `conflicts` (array of
+//!   paths), `changes` (array of objects with fields `path` and
+//!   `type`, one of `"modified"`, `"added"`, `"deleted"` and
+//!   `"moved"`) and `untracked` (array of paths).
+//!
+//! - `diff`: an array of objects with fields `type` (one of
+//!   `"add_file"`, `"delete_file"`, `"move_file"` and `"edit"`),
+//!   `path`, `added` and `deleted` (arrays of lines, including their
+//!   final newline if any). Lines that are not valid UTF-8 are
+//!   converted lossily.
+//!
+//! - `branches`: an array of objects with fields `name` and `current`
+//!   (boolean).
+//!
+//! - `ls`: an array of paths.
+//!
+//! - `show-dependencies`: an array of objects with fields `hash`,
+//!   `name`, `tag` (boolean) and `dependencies` (array of hashes, not
+//!   including transitive dependencies).
+//!
+//! Fields may be added in future versions, but existing fields will
+//! keep their meaning.
+
+use clap::{Arg, ArgMatches};
+use commands::StaticSubcommand;
+use error::Error;
+use libpijul::patch::{Change, ChangeContext, PatchFlags, PrettyChange, Record};
+use libpijul::{EdgeFlags, Hash, MutTxn, PatchHeader, PatchId};
+use rand;
+use serde;
+use serde_json;
+use std::collections::HashSet;
+use std::io::{stdout, Write};
+use std::path::Path;
+
+/// Add the `--format` and `--porcelain` arguments to a command.
+pub fn format_args(sub: StaticSubcommand) -> StaticSubcommand {
+    sub.arg(
+        Arg::with_name("format")
+            .long("format")
+            .help("Output format.")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .takes_value(true),
+    ).arg(
+        Arg::with_name("porcelain")
+            .long("porcelain")
+            .help("Output in a stable, machine-readable format (same as --format json)."),
+    )
+}
+
+pub fn is_json(args: &ArgMatches) -> bool {
+    args.is_present("porcelain") || args.value_of("format") == Some("json")
+}
+
+pub fn print_json<T: serde::Serialize>(value: &T) -> Result<(), Error> {
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer_pretty(&mut stdout, value).map_err(::std::io::Error::from)?;
+    writeln!(stdout, "")?;
+    Ok(())
+}
+
+/// `path` relative to `repo_root`.
+pub fn repo_path(repo_root: &Path, path: &Path) -> String {
+    path.strip_prefix(repo_root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[derive(Debug, Serialize)]
+pub struct PatchDescr {
+    pub hash: String,
+    pub internal_id: Option<String>,
+    pub authors: Vec<String>,
+    pub name: String,
+    pub description: Option<String>,
+    pub timestamp: String,
+    pub tag: bool,
+}
+
+impl PatchDescr {
+    pub fn new(hash: &Hash, internal: Option<PatchId>, header: &PatchHeader) -> Self {
+        PatchDescr {
+            hash: hash.to_base58(),
+            internal_id: internal.map(|i| i.to_base58()),
+            authors: header.authors.clone(),
+            name: header.name.clone(),
+            description: header.description.clone(),
+            timestamp: header.timestamp.to_rfc3339(),
+            tag: header.flag.contains(PatchFlags::TAG),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PatchContents {
+    #[serde(flatten)]
+    pub descr: PatchDescr,
+    pub dependencies: Vec<String>,
+    pub changes: Vec<PrettyChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub branch: String,
+    pub conflicts: Vec<String>,
+    pub changes: Vec<StatusChange>,
+    pub untracked: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusChange {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffRecord {
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+    pub path: String,
+    pub added: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BranchDescr {
+    pub name: String,
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Dependencies {
+    pub hash: String,
+    pub name: String,
+    pub tag: bool,
+    pub dependencies: Vec<String>,
+}
+
+/// Convert the records of `pijul diff` (as in `ask::print_status`).
+pub fn diff_records<T: rand::Rng>(
+    txn: &MutTxn<T>,
+    repo_root: &Path,
+    records: &[Record<ChangeContext<Hash>>],
+) -> Vec<DiffRecord> {
+    let mut result = Vec::new();
+    for r in records {
+        let (type_, path) = match *r {
+            Record::FileAdd { ref name, .. } => ("add_file", Path::new(name)),
+            Record::FileDel { ref name, .. } => ("delete_file", Path::new(name)),
+            Record::FileMove { ref new_name, .. } => ("move_file", Path::new(new_name)),
+            Record::Change { ref file, .. } | Record::Replace { ref file, .. } => {
+                ("edit", file.as_path())
+            }
+        };
+        let mut record = DiffRecord {
+            type_,
+            path: repo_path(repo_root, path),
+            added: Vec::new(),
+            deleted: Vec::new(),
+        };
+        match *r {
+            Record::Change { ref change, .. } => add_lines(txn, change, &mut record),
+            Record::Replace {
+                ref adds, ref dels, ..
+            } => {
+                add_lines(txn, dels, &mut record);
+                add_lines(txn, adds, &mut record)
+            }
+            _ => {}
+        }
+        result.push(record)
+    }
+    result
+}
+
+fn add_lines<T: rand::Rng>(
+    txn: &MutTxn<T>,
+    change: &Change<ChangeContext<Hash>>,
+    record: &mut DiffRecord,
+) {
+    match *change {
+        Change::NewNodes {
+            ref flag,
+            ref nodes,
+            ..
+        } => {
+            if !flag.contains(EdgeFlags::FOLDER_EDGE) {
+                for n in nodes {
+                    record.added.push(String::from_utf8_lossy(n).into_owned())
+                }
+            }
+        }
+        Change::NewEdges {
+            ref edges, flag, ..
+        } => {
+            let mut targets = HashSet::with_capacity(edges.len());
+            for e in edges {
+                let target = if flag.contains(EdgeFlags::PARENT_EDGE) {
+                    &e.from
+                } else {
+                    &e.to
+                };
+                if !targets.insert(target) {
+                    continue;
+                }
+                let internal = txn.internal_key_unwrap(target);
+                if let Some(l) = txn.get_contents(internal) {
+                    let l = String::from_utf8_lossy(&l).into_owned();
+                    if flag.contains(EdgeFlags::DELETED_EDGE) {
+                        record.deleted.push(l)
+                    } else {
+                        record.added.push(l)
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,545 @@
+//! The protocol spoken by `pijul protocol`, which is run on the
+//! remote end of SSH connections.
+//!
+//! The client writes requests to the standard input of the command,
+//! and reads one response per request on its standard output. Each
+//! message is encoded with bincode and prefixed by its length, as a
+//! 32-bit big-endian integer. The first request must be a `Hello`,
+//! carrying the client's protocol version: the server answers with
+//! the smaller of its version and the client's, which both sides
+//! then speak. Errors are reported with a code (see the `ERROR_*`
+//! constants) and a message, and do not end the session.
+//!
+//! Frames larger than `MAX_FRAME_SIZE` are refused, and never sent.
+//! From version 4, patches are sent in batches of at most
+//! `PATCH_BATCH_SIZE` bytes, and patches larger than that are sent
+//! in parts of at most `PART_SIZE` bytes, as are blobs.
+//!
+//! Remotes running older versions of Pijul don't have this command;
+//! `SshSession` then falls back to running `pijul log`, `pijul patch`
+//! and `pijul apply` on the remote side.
+
+use bincode;
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use commands::remote::{apply_patches_to_branch, Remote};
+use commands::smart_http::PatchEntry;
+use commands::tag_registry::TagRegistry;
+use commands::{default_explain, get_wd, StaticSubcommand};
+use error::Error;
+use libpijul::blob::{is_blob_hash, BlobPointer, BlobWriter};
+use libpijul::fs_representation::{find_repo_root, patch_file_name, patches_dir};
+use libpijul::{ApplyTimestamp, Hash};
+use std;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{stdin, stdout, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Version of the protocol implemented by this version of Pijul.
+/// Version 2 added `Tags`, version 3 the blob requests, and version
+/// 4 the requests sending patches in batches and parts.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Largest frame accepted by both sides.
+pub const MAX_FRAME_SIZE: usize = 1 << 28;
+
+/// Largest part of a blob or patch sent in a single frame.
+pub const PART_SIZE: usize = 1 << 20;
+
+/// Largest total size of the patches sent in a single frame.
+pub const PATCH_BATCH_SIZE: u64 = 1 << 24;
+
+pub const ERROR_UNSUPPORTED_VERSION: u16 = 1;
+pub const ERROR_BAD_REQUEST: u16 = 2;
+pub const ERROR_NO_SUCH_BRANCH: u16 = 3;
+pub const ERROR_PATCH_NOT_FOUND: u16 = 4;
+pub const ERROR_FILE_NOT_IN_REPOSITORY: u16 = 5;
+pub const ERROR_WRONG_HASH: u16 = 6;
+pub const ERROR_INTERNAL: u16 = 7;
+pub const ERROR_BLOB_NOT_FOUND: u16 = 8;
+pub const ERROR_TOO_LARGE: u16 = 9;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Hello { version: u32 },
+    /// List the patches applied to `branch`, restricted to those
+    /// touching `paths` if `paths` is not empty.
+    Changes { branch: String, paths: Vec<String> },
+    /// Send the patches with the given (base58-encoded) hashes. From
+    /// version 4, the response only contains the first of these
+    /// patches that fit in `PATCH_BATCH_SIZE` bytes: the client asks
+    /// for the others again, and downloads a patch that doesn't fit
+    /// alone (in which case the response is empty) with `Patch`.
+    Patches { hashes: Vec<String> },
+    /// Apply the given patches to `branch`.
+    Apply {
+        branch: String,
+        patches: Vec<PatchEntry>,
+    },
+    /// List the tags of the repository.
+    Tags,
+    /// Send the part of the blob with the given hash and size that
+    /// starts at `offset`.
+    Blob { hash: String, size: u64, offset: u64 },
+    /// Start adding the blob with the given hash and size to the
+    /// store. Its contents follow in `BlobPart` requests.
+    PutBlob { hash: String, size: u64 },
+    /// The next part of the blob started by the last `PutBlob`. The
+    /// blob is checked and added to the store after its last part.
+    BlobPart { data: Vec<u8> },
+    /// Send the part of patch `hash` that starts at `offset`.
+    Patch { hash: String, offset: u64 },
+    /// Add the given patches to the patches directory, without
+    /// applying them.
+    PutPatches { patches: Vec<PatchEntry> },
+    /// Start adding patch `hash`, of `size` bytes, to the patches
+    /// directory. Its contents follow in `PatchPart` requests.
+    PutPatch {
+        hash: String,
+        size: u64,
+        signature: Option<Vec<u8>>,
+    },
+    /// The next part of the patch started by the last `PutPatch`.
+    /// The patch is checked and added after its last part.
+    PatchPart { data: Vec<u8> },
+    /// Apply the patches with the given hashes, sent before with
+    /// `PutPatches` or `PutPatch`, to `branch`.
+    ApplyPatches { branch: String, hashes: Vec<String> },
+}
+
+impl Request {
+    /// The first version of the protocol with this request.
+    fn version(&self) -> u32 {
+        match *self {
+            Request::Hello { .. }
+            | Request::Changes { .. }
+            | Request::Patches { .. }
+            | Request::Apply { .. } => 1,
+            Request::Tags => 2,
+            Request::Blob { .. } | Request::PutBlob { .. } | Request::BlobPart { .. } => 3,
+            Request::Patch { .. }
+            | Request::PutPatches { .. }
+            | Request::PutPatch { .. }
+            | Request::PatchPart { .. }
+            | Request::ApplyPatches { .. } => 4,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Hello { version: u32 },
+    Changes { changes: Vec<(String, ApplyTimestamp)> },
+    Patches { patches: Vec<PatchEntry> },
+    Applied,
+    /// Tag names and hashes.
+    Tags { tags: Vec<(String, String)> },
+    Error { code: u16, message: String },
+    /// At most `PART_SIZE` bytes of a blob.
+    BlobPart { data: Vec<u8> },
+    /// At most `PART_SIZE` bytes of a patch of `size` bytes. The
+    /// first part also carries the signature file of the patch.
+    PatchPart {
+        size: u64,
+        data: Vec<u8>,
+        signature: Option<Vec<u8>>,
+    },
+}
+
+impl Response {
+    /// Turn error responses into errors.
+    pub fn into_result(self) -> Result<Response, Error> {
+        match self {
+            Response::Error { code, message } => Err(Error::Remote { code, message }),
+            r => Ok(r),
+        }
+    }
+}
+
+/// Prefix `payload` with its length, refusing payloads that the
+/// other side would refuse.
+fn frame(payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(frame_too_large(payload.len()));
+    }
+    let len = payload.len() as u32;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    frame.extend(payload);
+    Ok(frame)
+}
+
+fn frame_len(header: &[u8]) -> usize {
+    ((header[0] as usize) << 24)
+        | ((header[1] as usize) << 16)
+        | ((header[2] as usize) << 8)
+        | (header[3] as usize)
+}
+
+pub fn request_frame(req: &Request) -> Result<Vec<u8>, Error> {
+    frame(bincode::serialize(req)?)
+}
+
+pub fn response_frame(resp: &Response) -> Result<Vec<u8>, Error> {
+    frame(bincode::serialize(resp)?)
+}
+
+/// Whether `buf` starts with a complete frame, or with the header of
+/// a frame that is too large (which `take_frame` reports as an
+/// error).
+pub fn has_frame(buf: &[u8]) -> bool {
+    buf.len() >= 4 && {
+        let len = frame_len(&buf[..4]);
+        len > MAX_FRAME_SIZE || buf.len() >= 4 + len
+    }
+}
+
+/// If `buf` starts with a complete frame, remove it from `buf` and
+/// return its payload.
+pub fn take_frame(buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+    if !has_frame(buf) {
+        return Ok(None);
+    }
+    let len = frame_len(&buf[..4]);
+    if len > MAX_FRAME_SIZE {
+        return Err(frame_too_large(len));
+    }
+    let payload = buf[4..4 + len].to_vec();
+    buf.drain(..4 + len);
+    Ok(Some(payload))
+}
+
+fn frame_too_large(len: usize) -> Error {
+    Error::RemoteProtocol {
+        message: format!("frame too large ({} bytes)", len),
+    }
+}
+
+/// Read a frame, returning `None` if the stream ends before the
+/// frame starts.
+fn read_frame<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut header = [0; 4];
+    let mut n = 0;
+    while n < 4 {
+        let m = r.read(&mut header[n..])?;
+        if m == 0 {
+            if n == 0 {
+                return Ok(None);
+            } else {
+                return Err(Error::RemoteProtocol {
+                    message: "truncated frame".to_string(),
+                });
+            }
+        }
+        n += m
+    }
+    let len = frame_len(&header);
+    if len > MAX_FRAME_SIZE {
+        return Err(frame_too_large(len));
+    }
+    // Don't trust the length before the data is actually there.
+    let mut payload = Vec::new();
+    r.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(Error::RemoteProtocol {
+            message: "truncated frame".to_string(),
+        });
+    }
+    Ok(Some(payload))
+}
+
+pub fn invocation() -> StaticSubcommand {
+    return SubCommand::with_name("protocol")
+        .setting(AppSettings::Hidden)
+        .about("Serve a repository on the standard input and output (used over SSH)")
+        .arg(
+            Arg::with_name("repository")
+                .long("repository")
+                .help("Repository to serve.")
+                .takes_value(true),
+        );
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    let wd = get_wd(args.value_of("repository").map(Path::new))?;
+    let repo_root = if let Some(r) = find_repo_root(&wd) {
+        r
+    } else {
+        return Err(Error::NotInARepository);
+    };
+    let stdin = stdin();
+    let mut stdin = stdin.lock();
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
+    // The version agreed on with the client, once it said `Hello`.
+    let mut agreed = None;
+    let mut upload = None;
+    while let Some(payload) = read_frame(&mut stdin)? {
+        let response = match (bincode::deserialize(&payload), agreed) {
+            (Ok(Request::Hello { version }), _) => {
+                if version >= 1 {
+                    let version = std::cmp::min(version, PROTOCOL_VERSION);
+                    agreed = Some(version);
+                    Response::Hello { version }
+                } else {
+                    Response::Error {
+                        code: ERROR_UNSUPPORTED_VERSION,
+                        message: format!("unsupported protocol version {}", version),
+                    }
+                }
+            }
+            (Ok(_), None) => Response::Error {
+                code: ERROR_BAD_REQUEST,
+                message: "expected Hello".to_string(),
+            },
+            (Ok(ref req), Some(version)) if req.version() > version => Response::Error {
+                code: ERROR_BAD_REQUEST,
+                message: format!("request not in protocol version {}", version),
+            },
+            (Ok(req), Some(version)) => answer(&repo_root, version, &mut upload, req)
+                .unwrap_or_else(|e| Response::Error {
+                    code: error_code(&e),
+                    message: format!("{}", e),
+                }),
+            (Err(e), _) => Response::Error {
+                code: ERROR_BAD_REQUEST,
+                message: format!("{}", e),
+            },
+        };
+        debug!("response: {:?}", response);
+        let frame = match response_frame(&response) {
+            Ok(frame) => frame,
+            Err(e) => response_frame(&Response::Error {
+                code: ERROR_TOO_LARGE,
+                message: format!("{}", e),
+            })?,
+        };
+        stdout.write_all(&frame)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// An upload started by `PutBlob` or `PutPatch`, and continued by
+/// the following `BlobPart` or `PatchPart` requests.
+enum Upload {
+    Blob(BlobWriter),
+    Patch { entry: PatchEntry, size: u64 },
+}
+
+/// Answer `req`, in protocol version `version`. `upload` is the blob
+/// or patch being added by the last `PutBlob` or `PutPatch` request,
+/// if any.
+fn answer(
+    repo_root: &Path,
+    version: u32,
+    upload: &mut Option<Upload>,
+    req: Request,
+) -> Result<Response, Error> {
+    let not_found = |hash: &Hash| Error::PatchNotFound {
+        repo_root: repo_root.to_string_lossy().into_owned(),
+        patch_hash: hash.clone(),
+    };
+    match req {
+        Request::Hello { .. } => unreachable!(),
+        Request::Changes { branch, paths } => {
+            let remote = Remote::Local {
+                path: repo_root.to_path_buf(),
+            };
+            let mut session = remote.session()?;
+            let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+            let changes = session
+                .changes(&branch, &paths)?
+                .into_iter()
+                .map(|(h, t)| (h.to_base58(), t))
+                .collect();
+            Ok(Response::Changes { changes })
+        }
+        Request::Patches { hashes } => {
+            let pdir = patches_dir(repo_root);
+            let mut patches = Vec::new();
+            let mut batch_size = 0;
+            for hash in hashes {
+                let hash = Hash::from_base58(&hash).ok_or(Error::WrongHash)?;
+                if version >= 4 {
+                    batch_size += PatchEntry::size_of(&pdir, &hash).map_err(|_| not_found(&hash))?;
+                    if batch_size > PATCH_BATCH_SIZE {
+                        break;
+                    }
+                }
+                patches.push(PatchEntry::load(&pdir, &hash).map_err(|_| not_found(&hash))?)
+            }
+            Ok(Response::Patches { patches })
+        }
+        Request::Apply { branch, patches } => {
+            let pdir = patches_dir(repo_root);
+            let mut hashes = HashSet::new();
+            for entry in patches.iter() {
+                hashes.insert(entry.save(&pdir)?);
+            }
+            apply_patches_to_branch(repo_root, &branch, &hashes)?;
+            Ok(Response::Applied)
+        }
+        Request::Tags => Ok(Response::Tags {
+            tags: TagRegistry::load(repo_root)?.tags.into_iter().collect(),
+        }),
+        Request::Blob { hash, size, offset } => {
+            if !is_blob_hash(&hash) {
+                return Err(Error::WrongHash);
+            }
+            let pointer = BlobPointer { hash, size };
+            if !pointer.is_available(repo_root) {
+                return Ok(Response::Error {
+                    code: ERROR_BLOB_NOT_FOUND,
+                    message: format!("blob {} not found", pointer.hash),
+                });
+            }
+            let mut f = File::open(pointer.path(repo_root))?;
+            f.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::new();
+            f.take(PART_SIZE as u64).read_to_end(&mut data)?;
+            Ok(Response::BlobPart { data })
+        }
+        Request::PutBlob { hash, size } => {
+            if !is_blob_hash(&hash) {
+                return Err(Error::WrongHash);
+            }
+            *upload = Some(Upload::Blob(BlobWriter::new(
+                repo_root,
+                &BlobPointer { hash, size },
+            )?));
+            finish_upload(repo_root, upload)
+        }
+        Request::BlobPart { data } => {
+            let written = match *upload {
+                Some(Upload::Blob(ref mut writer)) => writer.write(&data)?,
+                _ => {
+                    return Ok(Response::Error {
+                        code: ERROR_BAD_REQUEST,
+                        message: "expected PutBlob".to_string(),
+                    })
+                }
+            };
+            if !written {
+                *upload = None;
+                return Err(Error::WrongHash);
+            }
+            finish_upload(repo_root, upload)
+        }
+        Request::Patch { hash, offset } => {
+            let pdir = patches_dir(repo_root);
+            let hash = Hash::from_base58(&hash).ok_or(Error::WrongHash)?;
+            let mut f = File::open(pdir.join(&patch_file_name(hash.as_ref())))
+                .map_err(|_| not_found(&hash))?;
+            let size = f.metadata()?.len();
+            f.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::new();
+            f.take(PART_SIZE as u64).read_to_end(&mut data)?;
+            let signature = if offset == 0 {
+                PatchEntry::load_signature(&pdir, &hash)?
+            } else {
+                None
+            };
+            Ok(Response::PatchPart {
+                size,
+                data,
+                signature,
+            })
+        }
+        Request::PutPatches { patches } => {
+            let pdir = patches_dir(repo_root);
+            for entry in patches.iter() {
+                entry.save(&pdir)?;
+            }
+            Ok(Response::Applied)
+        }
+        Request::PutPatch {
+            hash,
+            size,
+            signature,
+        } => {
+            Hash::from_base58(&hash).ok_or(Error::WrongHash)?;
+            *upload = Some(Upload::Patch {
+                entry: PatchEntry {
+                    hash,
+                    patch: Vec::new(),
+                    signature,
+                },
+                size,
+            });
+            finish_upload(repo_root, upload)
+        }
+        Request::PatchPart { data } => {
+            let too_long = match *upload {
+                Some(Upload::Patch {
+                    ref mut entry,
+                    size,
+                }) => {
+                    entry.patch.extend(&data);
+                    entry.patch.len() as u64 > size
+                }
+                _ => {
+                    return Ok(Response::Error {
+                        code: ERROR_BAD_REQUEST,
+                        message: "expected PutPatch".to_string(),
+                    })
+                }
+            };
+            if too_long {
+                *upload = None;
+                return Err(Error::WrongHash);
+            }
+            finish_upload(repo_root, upload)
+        }
+        Request::ApplyPatches { branch, hashes } => {
+            let pdir = patches_dir(repo_root);
+            let mut patches = HashSet::new();
+            for hash in hashes {
+                let hash = Hash::from_base58(&hash).ok_or(Error::WrongHash)?;
+                if !pdir.join(&patch_file_name(hash.as_ref())).exists() {
+                    return Err(not_found(&hash));
+                }
+                patches.insert(hash);
+            }
+            apply_patches_to_branch(repo_root, &branch, &patches)?;
+            Ok(Response::Applied)
+        }
+    }
+}
+
+/// Add the blob or patch being uploaded to the repository once it
+/// has all its contents.
+fn finish_upload(repo_root: &Path, upload: &mut Option<Upload>) -> Result<Response, Error> {
+    let complete = match *upload {
+        Some(Upload::Blob(ref writer)) => writer.is_complete(),
+        Some(Upload::Patch { ref entry, size }) => entry.patch.len() as u64 == size,
+        None => false,
+    };
+    if complete {
+        match upload.take().unwrap() {
+            Upload::Blob(writer) => {
+                if !writer.finish()? {
+                    return Err(Error::WrongHash);
+                }
+            }
+            Upload::Patch { entry, .. } => {
+                entry.save(&patches_dir(repo_root))?;
+            }
+        }
+    }
+    Ok(Response::Applied)
+}
+
+fn error_code(e: &Error) -> u16 {
+    match *e {
+        Error::NoSuchBranch => ERROR_NO_SUCH_BRANCH,
+        Error::PatchNotFound { .. } => ERROR_PATCH_NOT_FOUND,
+        Error::FileNotInRepository { .. } => ERROR_FILE_NOT_IN_REPOSITORY,
+        Error::WrongHash => ERROR_WRONG_HASH,
+        _ => ERROR_INTERNAL,
+    }
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
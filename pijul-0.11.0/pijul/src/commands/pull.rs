@@ -6,12 +6,15 @@ use std::fs::File;
 use std::path::Path;
 
 use commands::ask::{ask_patches, Command};
+use commands::pull_manifest::PullManifest;
 use commands::remote;
+use commands::remote::parse_remote;
+use libpijul::fs_representation::{patch_file_name, patches_dir, read_dependencies};
 use libpijul::patch::Patch;
 use libpijul::{ApplyTimestamp, Hash, PatchId, DEFAULT_BRANCH};
 use meta::{Meta, Repository, DEFAULT_REMOTE};
 use progrs;
-use rand;
+use std::collections::{HashMap, HashSet};
 use std::env::current_dir;
 use std::io::BufReader;
 
@@ -61,6 +64,27 @@ pub fn invocation() -> StaticSubcommand {
                 .help("Only pull patches relative to that patch.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("tag")
+                .long("tag")
+                .help("Only pull the given tag of the remote branch, and its dependencies.")
+                .takes_value(true)
+                .conflicts_with("remote_path"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Finish an interrupted pull or clone.")
+                .conflicts_with_all(&[
+                    "remote",
+                    "remote_branch",
+                    "local_branch",
+                    "remote_path",
+                    "tag",
+                    "set-remote",
+                    "set-default",
+                ]),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
@@ -87,6 +111,8 @@ pub struct Params<'a> {
     pub local_branch: Option<&'a str>,
     pub remote_branch: &'a str,
     pub remote_paths: Vec<&'a str>,
+    pub tag: Option<&'a str>,
+    pub resume: bool,
 }
 
 fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
@@ -103,6 +129,8 @@ fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
         } else {
             Vec::new()
         },
+        tag: args.value_of("tag"),
+        resume: args.is_present("resume"),
     }
 }
 
@@ -112,11 +140,16 @@ fn fetch_pullable_patches(
     r: &Path,
 ) -> Result<Vec<(Hash, Option<PatchId>, Patch)>, Error> {
     let mut patches = Vec::new();
+    {
+        let hashes: Vec<_> = pullable.iter().map(|&(ref h, _)| h.to_owned()).collect();
+        session.download_patches(r, &hashes, &HashSet::new())?;
+    }
 
+    let pdir = patches_dir(r);
     let (mut p, mut n) = (progrs::start("Pulling patches", pullable.len() as u64), 0);
     for &(ref i, _) in pullable {
         let (hash, _, patch) = {
-            let filename = session.download_patch(r, i)?;
+            let filename = pdir.join(&patch_file_name(i.as_ref()));
             debug!("filename {:?}", filename);
             let file = File::open(&filename)?;
             let mut file = BufReader::new(file);
@@ -158,6 +191,34 @@ pub fn select_patches(
     }
 }
 
+/// The patches of `pullable` needed to get tag `name` of the remote,
+/// i.e. the tag and its dependencies.
+fn select_tag(
+    session: &mut remote::Session,
+    r: &Path,
+    name: &str,
+    pullable: Vec<(Hash, ApplyTimestamp)>,
+) -> Result<Vec<(Hash, ApplyTimestamp)>, Error> {
+    let tag = session.tags()?.get(name)?;
+    let pullable: HashMap<Hash, ApplyTimestamp> = pullable.into_iter().collect();
+    let mut selected = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![tag];
+    while let Some(hash) = stack.pop() {
+        if !seen.insert(hash.clone()) {
+            continue;
+        }
+        // Patches we already have are not in `pullable`, and neither
+        // are their dependencies.
+        if let Some(&t) = pullable.get(&hash) {
+            session.download_patch(r, &hash)?;
+            stack.extend(read_dependencies(r, hash.as_ref())?);
+            selected.push((hash, t))
+        }
+    }
+    Ok(selected)
+}
+
 pub fn run(arg_matches: &ArgMatches) -> Result<(), Error> {
     let opts = BasicOptions::from_args(arg_matches)?;
     let args = parse_args(arg_matches);
@@ -170,28 +231,56 @@ pub fn run(arg_matches: &ArgMatches) -> Result<(), Error> {
         opts.branch()
     };
     let repo_root = opts.repo_root();
+    if args.resume {
+        return resume(&repo_root);
+    }
     {
         let remote = meta.pull(args.remote_id, args.port, Some(&cwd), Some(&repo_root))?;
         let mut session = remote.session()?;
-        let mut pullable = select_patches(
-            !args.yes_to_all,
-            &mut session,
-            args.remote_branch,
-            &local_branch,
-            &opts.repo_root,
-            &args.remote_paths,
-        )?;
+        let mut pullable = if let Some(tag) = args.tag {
+            let pullable = session
+                .pullable_patches(args.remote_branch, &local_branch, &opts.repo_root, &[])?
+                .iter()
+                .collect();
+            select_tag(&mut session, &opts.repo_root, tag, pullable)?
+        } else {
+            select_patches(
+                !args.yes_to_all,
+                &mut session,
+                args.remote_branch,
+                &local_branch,
+                &opts.repo_root,
+                &args.remote_paths,
+            )?
+        };
 
         // Pulling and applying
         info!("Pulling patch {:?}", pullable);
         if !pullable.is_empty() {
+            let mut manifest = PullManifest::new(
+                &remote,
+                args.remote_branch,
+                &local_branch,
+                &args.remote_paths,
+            );
+            manifest.set_patches(&pullable);
+            if !args.yes_to_all || args.tag.is_some() {
+                // Selecting the patches has already downloaded and
+                // checked them.
+                for &(ref h, _) in pullable.iter() {
+                    manifest.add_downloaded(h)
+                }
+            }
+            manifest.save(&repo_root)?;
             session.pull(
                 &opts.repo_root,
                 &local_branch,
                 &mut pullable,
                 &args.remote_paths,
                 false,
+                Some(&mut manifest),
             )?;
+            PullManifest::remove(&repo_root)?;
         } else {
             println!("No new patches to pull.");
         }
@@ -215,7 +304,7 @@ pub fn run(arg_matches: &ArgMatches) -> Result<(), Error> {
         meta.save(&opts.repo_root)?;
     }
     let repo = opts.open_repo()?;
-    let mut txn = repo.mut_txn_begin(rand::thread_rng())?;
+    let txn = repo.txn_begin()?;
     let conflicts = txn.list_conflict_files(&local_branch, &args.remote_paths)?;
     if !conflicts.is_empty() {
         println!("There are pending conflicts waiting to be solved:");
@@ -227,6 +316,33 @@ pub fn run(arg_matches: &ArgMatches) -> Result<(), Error> {
     Ok(())
 }
 
+/// Finish the pull described in the manifest of `repo_root`.
+fn resume(repo_root: &Path) -> Result<(), Error> {
+    let mut manifest = if let Some(manifest) = PullManifest::load(repo_root)? {
+        manifest
+    } else {
+        return Err(Error::NothingToResume);
+    };
+    let mut pullable = manifest.patches()?;
+    let address = manifest.remote.clone();
+    let to_branch = manifest.to_branch.clone();
+    let paths = manifest.paths.clone();
+    let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+    {
+        let remote = parse_remote(&address, manifest.port, None, Some(repo_root));
+        let mut session = remote.session()?;
+        session.pull(
+            repo_root,
+            &to_branch,
+            &mut pullable,
+            &paths,
+            true,
+            Some(&mut manifest),
+        )?;
+    }
+    PullManifest::remove(repo_root)
+}
+
 pub fn explain(res: Result<(), Error>) {
     default_explain(res)
 }
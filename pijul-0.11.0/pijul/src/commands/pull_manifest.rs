@@ -0,0 +1,114 @@
+use commands::remote::Remote;
+use error::Error;
+use libpijul::fs_representation::pull_manifest_file;
+use libpijul::{ApplyTimestamp, Hash};
+use std::collections::HashSet;
+use std::fs::{remove_file, rename, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use toml;
+
+/// A pull in progress. This is saved before downloading anything,
+/// and updated as patches get applied, so that `pijul pull --resume`
+/// can finish an interrupted pull (or clone) without asking the
+/// remote again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PullManifest {
+    /// Address of the remote, as returned by `Remote::address`.
+    pub remote: String,
+    pub port: Option<u16>,
+    pub from_branch: String,
+    pub to_branch: String,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub patches: Vec<ManifestPatch>,
+    /// Patches already downloaded and checked, which don't need to
+    /// be checked again when resuming.
+    #[serde(default)]
+    pub downloaded: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestPatch {
+    pub hash: String,
+    pub timestamp: ApplyTimestamp,
+}
+
+impl PullManifest {
+    pub fn new(remote: &Remote, from_branch: &str, to_branch: &str, paths: &[&str]) -> Self {
+        let (remote, port) = remote.address();
+        PullManifest {
+            remote,
+            port,
+            from_branch: from_branch.to_string(),
+            to_branch: to_branch.to_string(),
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            patches: Vec::new(),
+            downloaded: Vec::new(),
+        }
+    }
+
+    pub fn set_patches(&mut self, patches: &[(Hash, ApplyTimestamp)]) {
+        self.patches = patches
+            .iter()
+            .map(|&(ref h, t)| ManifestPatch {
+                hash: h.to_base58(),
+                timestamp: t,
+            }).collect()
+    }
+
+    pub fn patches(&self) -> Result<Vec<(Hash, ApplyTimestamp)>, Error> {
+        self.patches
+            .iter()
+            .map(|p| {
+                Hash::from_base58(&p.hash)
+                    .map(|h| (h, p.timestamp))
+                    .ok_or(Error::WrongHash)
+            }).collect()
+    }
+
+    pub fn add_downloaded(&mut self, hash: &Hash) {
+        self.downloaded.push(hash.to_base58())
+    }
+
+    pub fn downloaded(&self) -> HashSet<Hash> {
+        self.downloaded
+            .iter()
+            .filter_map(|h| Hash::from_base58(h))
+            .collect()
+    }
+
+    /// Load the manifest of `repo_root`, if there is one.
+    pub fn load(repo_root: &Path) -> Result<Option<Self>, Error> {
+        let mut s = String::new();
+        if let Ok(mut f) = File::open(pull_manifest_file(repo_root)) {
+            f.read_to_string(&mut s)?;
+            Ok(Some(toml::from_str(&s)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn save(&self, repo_root: &Path) -> Result<(), Error> {
+        let path = pull_manifest_file(repo_root);
+        let mut tmp = path.clone();
+        tmp.set_extension("tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(toml::to_string(&self)?.as_bytes())?;
+            f.flush()?;
+        }
+        rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Remove the manifest of `repo_root`, once the pull is complete.
+    pub fn remove(repo_root: &Path) -> Result<(), Error> {
+        let path = pull_manifest_file(repo_root);
+        if path.exists() {
+            remove_file(&path)?
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 use clap::{Arg, ArgMatches, SubCommand};
 
 use super::ask;
+use commands::local_patches::LocalPatches;
+use commands::tag_registry::{dependency_closure, TagRegistry};
 use commands::{default_explain, BasicOptions, StaticSubcommand};
 use error::Error;
 use libpijul::fs_representation::read_patch;
@@ -55,6 +57,12 @@ pub fn invocation() -> StaticSubcommand {
                 .takes_value(true)
                 .multiple(true),
         )
+        .arg(
+            Arg::with_name("tag")
+                .long("tag")
+                .help("Only push the given tag, and its dependencies.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
@@ -81,6 +89,7 @@ pub struct Params<'a> {
     pub local_branch: Option<&'a str>,
     pub remote_branch: &'a str,
     pub remote_path: Vec<&'a str>,
+    pub tag: Option<&'a str>,
 }
 
 pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
@@ -96,6 +105,7 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
             .values_of("remote_path")
             .map(|x| x.collect())
             .unwrap_or(Vec::new()),
+        tag: args.value_of("tag"),
     }
 }
 
@@ -114,13 +124,18 @@ pub fn run(arg_matches: &ArgMatches) -> Result<(), Error> {
         let remote = meta.push(args.remote_id, args.port, Some(&cwd), Some(&repo_root))?;
         debug!("remote: {:?}", remote);
         let mut session = remote.session()?;
-        let pushable = session.pushable_patches(
+        let mut pushable = session.pushable_patches(
             &local_branch,
             args.remote_branch,
             &opts.repo_root,
             &args.remote_path,
         )?;
-        let pushable = if !args.yes_to_all {
+        if let Some(tag) = args.tag {
+            let tag = TagRegistry::load(&opts.repo_root)?.get(tag)?;
+            let closure = dependency_closure(&opts.repo_root, &tag);
+            pushable.retain(|&(ref h, _, _)| closure.contains(h));
+        }
+        let pushable = if !args.yes_to_all && args.tag.is_none() {
             let mut patches = Vec::new();
             let mut pushable: Vec<_> = pushable.into_iter().collect();
             pushable.sort_by(|&(_, _, a), &(_, _, b)| a.cmp(&b));
@@ -136,7 +151,8 @@ pub fn run(arg_matches: &ArgMatches) -> Result<(), Error> {
             pushable.into_iter().map(|(h, _, _)| h).collect()
         };
         if !pushable.is_empty() {
-            session.push(&opts.repo_root, args.remote_branch, pushable)?;
+            session.push(&opts.repo_root, args.remote_branch, pushable.clone())?;
+            LocalPatches::unregister(&opts.repo_root, &pushable)?;
         }
     }
 
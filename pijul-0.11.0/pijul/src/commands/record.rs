@@ -3,12 +3,13 @@ use super::default_explain;
 use chrono;
 use clap::{Arg, ArgMatches, SubCommand};
 use commands::hooks::run_hook;
-use commands::{ask, BasicOptions, StaticSubcommand};
+use commands::local_patches::LocalPatches;
+use commands::{amend, ask, BasicOptions, StaticSubcommand};
 use libpijul;
 use libpijul::fs_representation::{ignore_file, patches_dir, untracked_files};
 use libpijul::patch::{PatchFlags, Record};
 use libpijul::{Hash, InodeUpdate, Key, MutTxn, Patch, PatchId, RecordState, Repository};
-use meta::{load_global_or_local_signing_key, Global, Meta};
+use meta::{load_config, load_global_or_local_signing_key, Global, Meta};
 use rand;
 use std::cell::RefCell;
 use std::collections::HashSet;
@@ -76,6 +77,13 @@ pub fn invocation() -> StaticSubcommand {
                     .help("Offer to add files that have been created since the last record")
                     .takes_value(false),
             )
+            .arg(
+                Arg::with_name("amend")
+                    .long("amend")
+                    .help("Fold the changes into the last patch of the branch, as with `pijul amend`")
+                    .conflicts_with("depends-on")
+                    .takes_value(false),
+            )
             .arg(
                 Arg::with_name("depends-on")
                     .help("Add a dependency to this patch (internal id or hash accepted)")
@@ -124,63 +132,87 @@ fn append_to_ignore_file(repo_root: &Path, lines: &Vec<String>) -> Result<(), Er
     Ok(())
 }
 
-fn select_changes(
+pub fn select_changes(
     opts: &BasicOptions,
     add_new_files: bool,
     branch_name: &str,
     yes_to_all: bool,
     prefix: Option<Vec<PathBuf>>,
 ) -> Result<(Vec<Record<Vec<Key<Option<Hash>>>>>, HashSet<InodeUpdate>), Error> {
-    // Increase by 100 pages. The most things record can write is one
-    // write in the branches table, affecting at most O(log n) blocks.
-    let repo = opts.open_and_grow_repo(409600)?;
-    let mut txn = repo.mut_txn_begin(rand::thread_rng())?;
-    let mut to_unadd = if add_new_files {
-        add_untracked_files(&mut txn, &opts.repo_root)?
-    } else {
-        HashSet::<PathBuf>::new()
-    };
-    let (changes, syncs) =
-        changes_from_prefixes(&opts.repo_root, &mut txn, &branch_name, prefix.as_ref())?;
-    let changes: Vec<_> = changes
-        .into_iter()
-        .map(|x| txn.globalize_record(x))
-        .collect();
-    if !yes_to_all {
-        let (c, i) = ask_changes(
-            &txn,
-            &opts.repo_root,
-            &opts.cwd,
-            &changes,
-            ChangesDirection::Record,
-            &mut to_unadd,
-        )?;
+    let mut answer = None;
+    let (changes, syncs, ignored) = opts.with_mut_txn(0, |mut txn| {
+        let mut to_unadd = if add_new_files {
+            add_untracked_files(&mut txn, &opts.repo_root)?
+        } else {
+            HashSet::<PathBuf>::new()
+        };
+        let (changes, syncs) =
+            changes_from_prefixes(&opts.repo_root, &mut txn, &branch_name, prefix.as_ref())?;
+        let mut changes: Vec<_> = changes
+            .into_iter()
+            .map(|x| txn.globalize_record(x))
+            .collect();
+        if yes_to_all {
+            txn.commit()?;
+            return Ok((changes, syncs, Vec::new()));
+        }
+        // Only ask once, even if the pristine runs out of space and
+        // this is called again.
+        if answer.is_none() {
+            let (c, i) = ask_changes(
+                &txn,
+                &opts.repo_root,
+                &opts.cwd,
+                &mut changes,
+                ChangesDirection::Record,
+                &mut to_unadd,
+            )?;
+            answer = Some((c, i, to_unadd))
+        }
+        let &(ref c, ref i, ref to_unadd) = answer.as_ref().unwrap();
         let selected = changes
             .into_iter()
             .enumerate()
             .filter(|&(i, _)| *(c.get(&i).unwrap_or(&false)))
             .map(|(_, x)| x)
             .collect();
-        for file in to_unadd {
-            txn.remove_file(&file)?
+        for file in to_unadd.iter() {
+            txn.remove_file(file)?
         }
         txn.commit()?;
-        append_to_ignore_file(&opts.repo_root, &i)?;
-        Ok((selected, syncs))
-    } else {
-        txn.commit()?;
-        Ok((changes, syncs))
-    }
+        Ok((selected, syncs, i.clone()))
+    })?;
+    append_to_ignore_file(&opts.repo_root, &ignored)?;
+    Ok((changes, syncs))
 }
 
 pub fn run(args: &ArgMatches) -> Result<Option<Hash>, Error> {
     let opts = BasicOptions::from_args(args)?;
     let yes_to_all = args.is_present("all");
+    let add_new_files = args.is_present("add-new-files");
+    run_hook(&opts.repo_root, "pre-record", None)?;
+    if args.is_present("amend") {
+        return amend::amend(args, &opts);
+    }
+
+    debug!("prefix {:?}", args.value_of("prefix"));
+    let prefix = prefix(args, &opts)?;
+    record_prefixes(args, &opts, add_new_files, yes_to_all, prefix)
+}
+
+/// Record the changes under `prefix` (or in the whole repository if
+/// `prefix` is `None`), using the options of `record_args`.
+pub fn record_prefixes(
+    args: &ArgMatches,
+    opts: &BasicOptions,
+    add_new_files: bool,
+    yes_to_all: bool,
+    prefix: Option<Vec<PathBuf>>,
+) -> Result<Option<Hash>, Error> {
     let patch_name_arg = args.value_of("message");
     let patch_descr_arg = args.value_of("description");
     let authors_arg = args.values_of("author").map(|x| x.collect::<Vec<_>>());
     let branch_name = opts.branch();
-    let add_new_files = args.is_present("add-new-files");
 
     let patch_date = args.value_of("date").map_or(Ok(chrono::Utc::now()), |x| {
         chrono::DateTime::from_str(x).map_err(|_| Error::InvalidDate { date: String::from(x) })
@@ -203,11 +235,6 @@ pub fn run(args: &ArgMatches) -> Result<Option<Hash>, Error> {
         }
     };
 
-    run_hook(&opts.repo_root, "pre-record", None)?;
-
-    debug!("prefix {:?}", args.value_of("prefix"));
-    let prefix = prefix(args, &opts)?;
-
     let (changes, syncs) = select_changes(&opts, add_new_files, &branch_name, yes_to_all, prefix)?;
 
     if changes.is_empty() {
@@ -314,22 +341,14 @@ pub fn run(args: &ArgMatches) -> Result<Option<Hash>, Error> {
         let patches_dir = patches_dir(&opts.repo_root);
         let hash = patch.save(&patches_dir, key.as_ref())?;
 
-        let pristine_dir = opts.pristine_dir();
-        let mut increase = 409600;
-        let res = loop {
-            match record_no_resize(
-                &pristine_dir,
-                &opts.repo_root,
-                &branch_name,
-                &hash,
-                &patch,
-                &syncs,
-                increase,
-            ) {
-                Err(ref e) if e.lacks_space() => increase *= 2,
-                e => break e,
-            }
-        };
+        let res = record_resize(
+            &opts.pristine_dir(),
+            &opts.repo_root,
+            &branch_name,
+            &hash,
+            &patch,
+            &syncs,
+        );
 
         run_hook(&opts.repo_root, "post-record", None)?;
 
@@ -337,27 +356,27 @@ pub fn run(args: &ArgMatches) -> Result<Option<Hash>, Error> {
     }
 }
 
-pub fn record_no_resize(
+/// Apply the newly recorded `patch` to branch `branch_name`, growing
+/// the pristine as needed.
+pub fn record_resize(
     pristine_dir: &Path,
     r: &Path,
     branch_name: &str,
     hash: &Hash,
     patch: &Patch,
     syncs: &HashSet<InodeUpdate>,
-    increase: u64,
 ) -> Result<Option<Hash>, Error> {
-    let size_increase = increase + patch.size_upper_bound() as u64;
-    let repo = match Repository::open(&pristine_dir, Some(size_increase)) {
-        Ok(repo) => repo,
-        Err(x) => return Err(Error::Repository(x)),
-    };
-    let mut txn = repo.mut_txn_begin(rand::thread_rng())?;
-    // save patch
-    debug!("syncs: {:?}", syncs);
-    let mut branch = txn.open_branch(branch_name)?;
-    txn.apply_local_patch(&mut branch, r, &hash, &patch, &syncs, false)?;
-    txn.commit_branch(branch)?;
-    txn.commit()?;
+    let size_hint = patch.size_upper_bound() as u64;
+    let config = load_config(r)?;
+    Repository::with_mut_txn(pristine_dir, &config, size_hint, |mut txn| -> Result<(), Error> {
+        debug!("syncs: {:?}", syncs);
+        let mut branch = txn.open_branch(branch_name)?;
+        txn.apply_local_patch(&mut branch, r, &hash, &patch, &syncs, false)?;
+        txn.commit_branch(branch)?;
+        txn.commit()?;
+        Ok(())
+    })?;
+    LocalPatches::register(r, hash)?;
     println!("Recorded patch {}", hash.to_base58());
     Ok(Some(hash.clone()))
 }
@@ -453,7 +472,7 @@ pub fn decide_patch_message(
     })
 }
 
-fn prepare_changes_template(
+pub fn prepare_changes_template(
     repo_root: &Path,
     descr: &str,
     changes: &[Record<Vec<Key<Option<Hash>>>>],
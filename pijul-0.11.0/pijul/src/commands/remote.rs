@@ -1,10 +1,12 @@
+use libpijul::blob::{materialize_all, patch_blobs, BlobPointer, BlobWriter};
 use libpijul::fs_representation::{
-    branch_changes_base_path, patch_file_name, patches_dir, pristine_dir, PIJUL_DIR_NAME,
+    blobs_dir, branch_changes_base_path, patch_file_name, patches_dir, pristine_dir, read_patch,
+    PIJUL_DIR_NAME,
 };
 use libpijul::patch::read_changes;
 use libpijul::{
     apply_resize, apply_resize_no_output, apply_resize_patches, apply_resize_patches_no_output,
-    ApplyTimestamp, Hash, Patch, PatchId, Repository,
+    ApplyTimestamp, Hash, Patch, PatchId, Prefixes, Repository,
 };
 use regex::Regex;
 use reqwest;
@@ -13,16 +15,24 @@ use reqwest::async as reqwest_async;
 use error::Error;
 use std;
 use std::collections::hash_set::HashSet;
-use std::collections::HashMap;
-use std::fs::{copy, hard_link, metadata, rename, File};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{copy, create_dir_all, hard_link, metadata, remove_file, rename, File};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use bincode;
+use commands::bundle::Bundle;
+use commands::protocol;
+use commands::pull_manifest::PullManifest;
+use commands::smart_http::{self, PatchEntry};
+use commands::tag_registry::TagRegistry;
 use commands::{ask, assert_no_containing_repo, create_repo};
+use meta::{load_config, Global};
 use cryptovec;
 use dirs;
 use futures;
-use futures::{Async, Future, Poll, Stream};
+use futures::{Future, Stream};
+use hyper;
 use progrs;
 use shell_escape::unix::escape;
 use std::borrow::Cow;
@@ -55,12 +65,16 @@ pub enum Remote<'a> {
     Local {
         path: PathBuf,
     },
+    Bundle {
+        path: PathBuf,
+    },
 }
 
 pub enum Session<'a> {
     Ssh(SshSession<'a>),
     Uri(UriSession<'a>),
     Local(LocalSession<'a>),
+    Bundle(BundleSession),
 }
 
 pub struct SshSession<'a> {
@@ -68,21 +82,40 @@ pub struct SshSession<'a> {
     path: &'a str,
     pijul_cmd: &'a str,
     session: Option<thrussh::client::Connection<thrussh_config::Stream, Client>>,
+    /// Channel running `pijul protocol` on the remote side, opened on
+    /// the first request.
+    protocol: Option<thrussh::ChannelId>,
+    /// Whether the remote Pijul is too old to have `pijul protocol`,
+    /// in which case we run its other commands instead.
+    legacy: bool,
+    /// Version of the protocol agreed on with the remote.
+    version: u32,
 }
 
 pub struct UriSession<'a> {
     l: tokio::runtime::Runtime,
     uri: &'a str,
     client: reqwest_async::Client,
+    /// Whether the server speaks the smart HTTP protocol. This is
+    /// assumed until the server answers otherwise.
+    smart: bool,
 }
 
 pub struct LocalSession<'a> {
     path: &'a Path,
 }
 
+pub struct BundleSession {
+    path: PathBuf,
+    bundle: Bundle,
+}
+
 impl<'a> Drop for SshSession<'a> {
     fn drop(&mut self) {
         if let Some(mut session) = self.session.take() {
+            if let Some(chan) = self.protocol.take() {
+                session.channel_eof(chan);
+            }
             debug!("disconnecting");
             session.disconnect(thrussh::Disconnect::ByApplication, "finished", "EN");
             if let Err(e) = self.l.block_on(session) {
@@ -145,65 +178,27 @@ impl Client {
 
 enum State {
     None,
-    Changes {
-        changes: HashMap<Hash, ApplyTimestamp>,
+    /// Accumulating the output of `pijul protocol` on channel `chan`.
+    Protocol {
+        chan: thrussh::ChannelId,
+        buf: Vec<u8>,
     },
-    DownloadPatch {
-        file: File,
+    /// Accumulating the output of another command, run on a remote
+    /// that doesn't have `pijul protocol`.
+    Output {
+        chan: thrussh::ChannelId,
+        buf: Vec<u8>,
     },
 }
 
-enum SendFileState {
-    Read(thrussh::client::Connection<thrussh_config::Stream, Client>),
-    Wait(thrussh::client::Data<thrussh_config::Stream, Client, Vec<u8>>),
-}
-
-struct SendFile {
-    f: File,
-    buf: Option<Vec<u8>>,
-    chan: thrussh::ChannelId,
-    state: Option<SendFileState>,
-}
-
-impl Future for SendFile {
-    type Item = (
-        thrussh::client::Connection<thrussh_config::Stream, Client>,
-        Vec<u8>,
-    );
-    type Error = Error;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        debug!("SendFile loop starting");
-        loop {
-            debug!("sendfile loop");
-            match self.state.take() {
-                Some(SendFileState::Read(c)) => {
-                    debug!("read");
-                    let mut buf = self.buf.take().unwrap();
-                    buf.resize(BUFFER_SIZE, 0);
-                    let len = self.f.read(&mut buf)?;
-                    if len == 0 {
-                        // If nothing has been read, return.
-                        return Ok(Async::Ready((c, buf)));
-                    }
-                    buf.truncate(len);
-                    debug!("sending {:?} bytes, {:?}", len, buf.len());
-                    self.state = Some(SendFileState::Wait(c.data(self.chan, None, buf)));
-                }
-                Some(SendFileState::Wait(mut c)) => {
-                    debug!("wait");
-                    match c.poll()? {
-                        Async::Ready((c, buf)) => {
-                            self.buf = Some(buf);
-                            self.state = Some(SendFileState::Read(c))
-                        }
-                        Async::NotReady => {
-                            self.state = Some(SendFileState::Wait(c));
-                            return Ok(Async::NotReady);
-                        }
-                    }
-                }
-                None => unreachable!(),
-            }
+impl Client {
+    /// Whether a complete response from `pijul protocol` has been
+    /// received.
+    fn has_frame(&self) -> bool {
+        if let State::Protocol { ref buf, .. } = self.state {
+            protocol::has_frame(buf)
+        } else {
+            false
         }
     }
 }
@@ -260,22 +255,13 @@ impl thrussh::client::Handler for Client {
             std::io::stderr().write(data).unwrap();
         } else if stream == None {
             match self.state {
-                State::None => {
-                    std::io::stdout().write(data).unwrap();
-                }
-                State::Changes { ref mut changes } => {
-                    let data = std::str::from_utf8(data).unwrap();
-                    for l in data.lines() {
-                        let mut spl = l.split(':');
-                        if let (Some(h), Some(s)) = (spl.next(), spl.next()) {
-                            if let (Some(h), Ok(s)) = (Hash::from_base58(h), s.parse()) {
-                                changes.insert(h, s);
-                            }
-                        }
-                    }
+                State::Protocol { chan, ref mut buf } | State::Output { chan, ref mut buf }
+                    if chan == channel =>
+                {
+                    buf.extend(data)
                 }
-                State::DownloadPatch { ref mut file, .. } => {
-                    file.write_all(data).unwrap();
+                _ => {
+                    std::io::stdout().write(data).unwrap();
                 }
             }
         } else {
@@ -346,10 +332,158 @@ impl thrussh::client::Handler for Client {
     }
 }
 
-const BUFFER_SIZE: usize = 1 << 14; // 16 kb.
-
 impl<'a> SshSession<'a> {
-    pub fn changes(
+    /// Start `pijul protocol` on the remote side, if this is not
+    /// already done, and agree on a protocol version.
+    fn protocol_channel(&mut self) -> Result<thrussh::ChannelId, Error> {
+        if let Some(chan) = self.protocol {
+            return Ok(chan);
+        }
+        let esc_path = escape(Cow::Borrowed(self.path));
+        let cmd = format!("{} protocol --repository {}", self.pijul_cmd, esc_path);
+        let (session, chan) = self.l.block_on(
+            self.session
+                .take()
+                .unwrap()
+                .channel_open_session()
+                .map(move |(mut connection, chan)| {
+                    debug!("exec: {:?}", cmd);
+                    connection.handler_mut().exit_status.remove(&chan);
+                    connection.handler_mut().channel = Some(chan);
+                    connection.handler_mut().state = State::Protocol {
+                        chan,
+                        buf: Vec::new(),
+                    };
+                    connection.exec(chan, false, &cmd);
+                    (connection, chan)
+                }),
+        )?;
+        self.session = Some(session);
+        self.protocol = Some(chan);
+        let mut version = protocol::PROTOCOL_VERSION;
+        loop {
+            match self.request(&protocol::Request::Hello { version }) {
+                Ok(protocol::Response::Hello { version: agreed }) if agreed >= 1 => {
+                    self.version = std::cmp::min(agreed, version);
+                    debug!("protocol version {:?}", self.version);
+                    return Ok(chan);
+                }
+                Ok(protocol::Response::Hello { version }) => {
+                    return Err(Error::UnsupportedProtocolVersion { version })
+                }
+                // Remotes from before the version negotiation only
+                // accept their own version.
+                Err(Error::Remote { code, .. })
+                    if code == protocol::ERROR_UNSUPPORTED_VERSION && version > 1 =>
+                {
+                    version -= 1
+                }
+                Ok(r) => return Err(unexpected_response(r)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send a request to `pijul protocol`, and wait for its response.
+    fn request(&mut self, req: &protocol::Request) -> Result<protocol::Response, Error> {
+        let chan = self.protocol_channel()?;
+        let frame = protocol::request_frame(req)?;
+        let mut session = self.session.take().unwrap();
+        session.handler_mut().channel = Some(chan);
+        let mut session = self.l.block_on(
+            session
+                .data(chan, None, frame)
+                .map_err(Error::from)
+                .and_then(move |(session, _)| {
+                    session
+                        .wait(move |session| {
+                            session.handler().has_frame()
+                                || session.handler().exit_status.contains_key(&chan)
+                                || !session.is_channel_open(chan)
+                        }).map_err(Error::from)
+                }),
+        )?;
+        let payload = if let State::Protocol { ref mut buf, .. } = session.handler_mut().state {
+            protocol::take_frame(buf)
+        } else {
+            Ok(None)
+        };
+        let status = session.handler().exit_status.get(&chan).map(|x| *x);
+        self.session = Some(session);
+        if let Some(payload) = payload? {
+            let resp: protocol::Response = bincode::deserialize(&payload)?;
+            resp.into_result()
+        } else {
+            self.protocol = None;
+            Err(Error::RemoteProtocol {
+                message: format!("remote pijul exited (status {:?})", status),
+            })
+        }
+    }
+
+    /// Whether the remote doesn't have `pijul protocol`, which we
+    /// find out by starting it. If the remote command exits without
+    /// answering our `Hello`, the remote Pijul is assumed to be older
+    /// than the protocol.
+    fn is_legacy(&mut self) -> Result<bool, Error> {
+        if !self.legacy && self.protocol.is_none() {
+            match self.protocol_channel() {
+                Ok(_) => {}
+                Err(Error::RemoteProtocol { message }) => {
+                    debug!("falling back to remote commands: {:?}", message);
+                    self.legacy = true
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.legacy)
+    }
+
+    /// Run `cmd` on the remote side, with `input` on its standard
+    /// input, and return its exit status and standard output. This is
+    /// only used with remotes that don't have `pijul protocol`.
+    fn exec(&mut self, cmd: String, input: Vec<u8>) -> Result<(Option<u32>, Vec<u8>), Error> {
+        let (mut session, chan) = self.l.block_on(
+            self.session
+                .take()
+                .unwrap()
+                .channel_open_session()
+                .map_err(Error::from)
+                .and_then(move |(mut session, chan)| {
+                    debug!("exec: {:?}", cmd);
+                    session.handler_mut().exit_status.remove(&chan);
+                    session.handler_mut().channel = Some(chan);
+                    session.handler_mut().state = State::Output {
+                        chan,
+                        buf: Vec::new(),
+                    };
+                    session.exec(chan, false, &cmd);
+                    session
+                        .data(chan, None, input)
+                        .map_err(Error::from)
+                        .and_then(move |(mut session, _)| {
+                            session.channel_eof(chan);
+                            session
+                                .wait(move |session| {
+                                    session.handler().exit_status.contains_key(&chan)
+                                        || !session.is_channel_open(chan)
+                                }).map_err(Error::from)
+                        }).map(move |session| (session, chan))
+                }),
+        )?;
+        if session.is_channel_open(chan) {
+            session.channel_close(chan);
+        }
+        let status = session.handler().exit_status.get(&chan).map(|x| *x);
+        let output = match std::mem::replace(&mut session.handler_mut().state, State::None) {
+            State::Output { buf, .. } => buf,
+            _ => Vec::new(),
+        };
+        self.session = Some(session);
+        Ok((status, output))
+    }
+
+    fn legacy_changes(
         &mut self,
         branch: &str,
         path: &[&str],
@@ -362,71 +496,25 @@ impl<'a> SshSession<'a> {
         for p in path {
             cmd.push_str(&format!(" --path {}", p))
         }
-
-        if let Some(ref mut session) = self.session {
-            session.handler_mut().state = State::Changes {
-                changes: HashMap::new(),
-            }
+        let mut changes = HashMap::new();
+        let (status, output) = self.exec(cmd, Vec::new())?;
+        // As in older versions, a branch that cannot be listed (for
+        // instance because it doesn't exist yet) has no patches.
+        if status != Some(0) {
+            return Ok(changes);
         }
-        let mut channel = None;
-        self.session = Some(
-            self.l
-                .block_on(
-                    self.session
-                        .take()
-                        .unwrap()
-                        .channel_open_session()
-                        .and_then(move |(mut connection, chan)| {
-                            debug!("exec: {:?}", cmd);
-                            channel = Some(chan);
-                            connection.handler_mut().exit_status.remove(&chan);
-                            connection.handler_mut().channel = Some(chan);
-                            connection.exec(chan, false, &cmd);
-                            connection.channel_eof(chan);
-                            // Wait until channel close.
-                            debug!("waiting channel close");
-                            connection
-                                .wait(move |session| {
-                                    session.handler().exit_status.get(&chan).is_some()
-                                }).and_then(move |mut session| {
-                                    if session.is_channel_open(chan) {
-                                        session.channel_close(chan);
-                                    }
-                                    session.wait(move |session| !session.is_channel_open(chan))
-                                })
-                        }),
-                ).unwrap(),
-        );
-
-        if let Some(ref session) = self.session {
-            if let Some(channel) = channel {
-                if let Some(&exit_code) = session.handler().exit_status.get(&channel) {
-                    debug!("exit_code = {:?}", exit_code);
-                    if exit_code != 0 {
-                        return Ok(HashMap::new());
-                    }
-                }
-            }
-        }
-        if let Some(ref mut session) = self.session {
-            match std::mem::replace(&mut session.handler_mut().state, State::None) {
-                State::Changes { changes } => {
-                    debug!("changes: {:?}", changes);
-                    Ok(changes)
+        for l in String::from_utf8_lossy(&output).lines() {
+            let mut spl = l.split(':');
+            if let (Some(h), Some(s)) = (spl.next(), spl.next()) {
+                if let (Some(h), Ok(s)) = (Hash::from_base58(h), s.parse()) {
+                    changes.insert(h, s);
                 }
-                _ => unreachable!(),
             }
-        } else {
-            unreachable!()
         }
+        Ok(changes)
     }
 
-    pub fn fetch_patch(
-        &mut self,
-        patch_hash: &Hash,
-        local_file: PathBuf,
-        local_tmp_file: PathBuf,
-    ) -> Result<PathBuf, Error> {
+    fn legacy_fetch_patch(&mut self, patch_hash: &Hash, patches_dir: &Path) -> Result<(), Error> {
         let esc_path = escape(Cow::Borrowed(self.path));
         let cmd = format!(
             "{} patch --repository {} --bin {}",
@@ -434,131 +522,491 @@ impl<'a> SshSession<'a> {
             esc_path,
             patch_hash.to_base58()
         );
-        debug!("cmd {:?} {:?}", cmd, local_file);
-        if let Some(ref mut session) = self.session {
-            session.handler_mut().state = State::DownloadPatch {
-                file: File::create(&local_tmp_file)?,
-            };
-            session.handler_mut().channel = None;
+        let (status, patch) = self.exec(cmd, Vec::new())?;
+        if status != Some(0) {
+            return Err(Error::PatchNotFound {
+                repo_root: self.path.to_string(),
+                patch_hash: patch_hash.to_owned(),
+            });
         }
-        self.session = Some(
-            self.l
-                .block_on(
-                    self.session
-                        .take()
-                        .unwrap()
-                        .channel_open_session()
-                        .and_then(move |(mut connection, chan)| {
-                            connection.handler_mut().exit_status.remove(&chan);
-                            connection.handler_mut().channel = Some(chan);
-                            connection.exec(chan, false, &cmd);
-                            connection.channel_eof(chan);
-                            connection
-                                .wait(move |session| {
-                                    session.handler().exit_status.get(&chan).is_some()
-                                }).and_then(move |mut session| {
-                                    if session.is_channel_open(chan) {
-                                        session.channel_close(chan);
-                                    }
-                                    session.wait(move |session| !session.is_channel_open(chan))
-                                })
-                        }),
-                ).unwrap(),
+        PatchEntry {
+            hash: patch_hash.to_base58(),
+            patch,
+            signature: None,
+        }.save(patches_dir)?;
+        Ok(())
+    }
+
+    fn legacy_remote_apply(
+        &mut self,
+        repo_root: &Path,
+        remote_branch: &str,
+        patch_hashes: HashSet<Hash>,
+    ) -> Result<(), Error> {
+        let pdir = patches_dir(repo_root);
+        let mut patches = Vec::new();
+        let mut signatures = Vec::new();
+        for hash in patch_hashes.iter() {
+            let entry = PatchEntry::load(&pdir, hash)?;
+            patches.extend(entry.patch);
+            if let Some(sig) = entry.signature {
+                signatures.extend(sig)
+            }
+        }
+        let esc_path = escape(Cow::Borrowed(self.path));
+        let cmd = format!(
+            "{} apply --repository {} --branch {:?}",
+            self.pijul_cmd, esc_path, remote_branch
         );
+        let (status, _) = self.exec(cmd, patches)?;
+        if status != Some(0) {
+            return Err(Error::RemoteProtocol {
+                message: format!("remote pijul apply failed (status {:?})", status),
+            });
+        }
+        if !signatures.is_empty() {
+            let cmd = format!("{} sign --repository {}", self.pijul_cmd, esc_path);
+            self.exec(cmd, signatures)?;
+        }
+        Ok(())
+    }
 
-        if let Some(ref mut session) = self.session {
-            if let State::DownloadPatch { mut file, .. } =
-                std::mem::replace(&mut session.handler_mut().state, State::None)
-            {
-                file.flush()?;
-                rename(&local_tmp_file, &local_file)?;
+    pub fn changes(
+        &mut self,
+        branch: &str,
+        path: &[&str],
+    ) -> Result<HashMap<Hash, ApplyTimestamp>, Error> {
+        if self.is_legacy()? {
+            return self.legacy_changes(branch, path);
+        }
+        let req = protocol::Request::Changes {
+            branch: branch.to_string(),
+            paths: path.iter().map(|p| p.to_string()).collect(),
+        };
+        match self.request(&req)? {
+            protocol::Response::Changes { changes } => {
+                let mut result = HashMap::new();
+                for (h, t) in changes {
+                    result.insert(Hash::from_base58(&h).ok_or(Error::WrongHash)?, t);
+                }
+                debug!("changes: {:?}", result);
+                Ok(result)
             }
+            r => Err(unexpected_response(r)),
+        }
+    }
+
+    pub fn tags(&mut self) -> Result<TagRegistry, Error> {
+        if self.is_legacy()? || self.version < 2 {
+            // Older versions of Pijul have no tag registry.
+            return Ok(TagRegistry::default());
+        }
+        match self.request(&protocol::Request::Tags)? {
+            protocol::Response::Tags { tags } => Ok(TagRegistry {
+                tags: tags.into_iter().collect(),
+            }),
+            r => Err(unexpected_response(r)),
         }
+    }
+
+    pub fn fetch_patch(&mut self, patch_hash: &Hash, local_file: PathBuf) -> Result<PathBuf, Error> {
+        let patches_dir = local_file.parent().unwrap().to_path_buf();
+        self.fetch_patches(&[patch_hash.to_owned()], &patches_dir)?;
         Ok(local_file)
     }
 
+    /// Download several patches in a single request.
+    pub fn fetch_patches(&mut self, patch_hashes: &[Hash], patches_dir: &Path) -> Result<(), Error> {
+        if patch_hashes.is_empty() {
+            return Ok(());
+        }
+        if self.is_legacy()? {
+            for hash in patch_hashes {
+                self.legacy_fetch_patch(hash, patches_dir)?
+            }
+            return Ok(());
+        }
+        // The remote sends as many of the patches asked as fit in a
+        // frame, starting from the first one.
+        let mut remaining = patch_hashes;
+        while !remaining.is_empty() {
+            let req = protocol::Request::Patches {
+                hashes: remaining.iter().map(|h| h.to_base58()).collect(),
+            };
+            let received = match self.request(&req)? {
+                protocol::Response::Patches { patches } => {
+                    for entry in patches.iter() {
+                        let hash = entry.save(patches_dir)?;
+                        debug!("downloaded {:?}", hash.to_base58());
+                    }
+                    patches.len()
+                }
+                r => return Err(unexpected_response(r)),
+            };
+            if received > 0 {
+                remaining = remaining.get(received..).unwrap_or(&[])
+            } else {
+                self.fetch_patch_parts(&remaining[0], patches_dir)?;
+                remaining = &remaining[1..]
+            }
+        }
+        Ok(())
+    }
+
+    /// Download a patch too large to be sent in a single frame.
+    fn fetch_patch_parts(&mut self, patch_hash: &Hash, patches_dir: &Path) -> Result<(), Error> {
+        if self.version < 4 {
+            return Err(Error::RemoteProtocol {
+                message: format!("patch {} too large for the remote", patch_hash.to_base58()),
+            });
+        }
+        let mut entry = PatchEntry {
+            hash: patch_hash.to_base58(),
+            patch: Vec::new(),
+            signature: None,
+        };
+        loop {
+            let req = protocol::Request::Patch {
+                hash: entry.hash.clone(),
+                offset: entry.patch.len() as u64,
+            };
+            match self.request(&req)? {
+                protocol::Response::PatchPart {
+                    size,
+                    data,
+                    signature,
+                } => {
+                    if add_patch_part(&mut entry, size, data, signature)? {
+                        entry.save(patches_dir)?;
+                        return Ok(());
+                    }
+                }
+                r => return Err(unexpected_response(r)),
+            }
+        }
+    }
+
     pub fn remote_apply(
         &mut self,
         repo_root: &Path,
         remote_branch: &str,
         patch_hashes: HashSet<Hash>,
     ) -> Result<(), Error> {
+        if self.is_legacy()? {
+            return self.legacy_remote_apply(repo_root, remote_branch, patch_hashes);
+        }
         let pdir = patches_dir(repo_root);
-        let mut exit_status = None;
-        let esc_path = escape(Cow::Borrowed(&self.path));
-        let apply_cmd = format!(
-            "{} apply --repository {} --branch {:?}",
-            self.pijul_cmd, esc_path, remote_branch
-        );
-        let sign_cmd = format!(
-            "{} sign --repository {}",
-            self.pijul_cmd, esc_path
-        );
+        if self.version < 4 {
+            // All the patches in a single frame, which fails if they
+            // don't fit.
+            let mut patches = Vec::new();
+            for hash in patch_hashes.iter() {
+                patches.push(PatchEntry::load(&pdir, hash)?)
+            }
+            let req = protocol::Request::Apply {
+                branch: remote_branch.to_string(),
+                patches,
+            };
+            return match self.request(&req)? {
+                protocol::Response::Applied => Ok(()),
+                r => Err(unexpected_response(r)),
+            };
+        }
+        // Send the patches first, in batches of bounded size, and
+        // then apply them all at once, so that the remote doesn't
+        // need to apply them in the order they are sent.
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        for hash in patch_hashes.iter() {
+            let entry = PatchEntry::load(&pdir, hash)?;
+            let size = entry.size();
+            if size > protocol::PATCH_BATCH_SIZE {
+                self.put_patch_parts(entry)?;
+                continue;
+            }
+            if batch_size + size > protocol::PATCH_BATCH_SIZE {
+                self.put_patches(std::mem::replace(&mut batch, Vec::new()))?;
+                batch_size = 0
+            }
+            batch_size += size;
+            batch.push(entry)
+        }
+        if !batch.is_empty() {
+            self.put_patches(batch)?
+        }
+        let req = protocol::Request::ApplyPatches {
+            branch: remote_branch.to_string(),
+            hashes: patch_hashes.iter().map(|h| h.to_base58()).collect(),
+        };
+        match self.request(&req)? {
+            protocol::Response::Applied => Ok(()),
+            r => Err(unexpected_response(r)),
+        }
+    }
 
-        let session = self.session.take().unwrap();
-        self.session = Some(self.l.block_on(
-            session.channel_open_session()
-                .and_then(move |(session, chan0)| session.channel_open_session().and_then(move |(mut session, chan1)| {
-                    session.handler_mut().exit_status.remove(&chan0);
-                    session.handler_mut().channel = Some(chan0);
-                    debug!("exec {:?}", apply_cmd);
-                    session.exec(chan0, false, &apply_cmd);
-                    debug!("exec {:?}", sign_cmd);
-                    session.exec(chan1, false, &sign_cmd);
-                    futures::stream::iter_ok(patch_hashes.into_iter())
-                        .fold((session, Vec::new()), move |(session, buf), hash| {
-                            let mut pdir = pdir.clone();
-                            pdir.push(hash.to_base58());
-                            pdir.set_extension("gz");
-                            let f = std::fs::File::open(&pdir).unwrap();
-                            pdir.set_extension("sig");
-                            if let Ok(sig) = std::fs::File::open(&pdir) {
-                                futures::future::Either::A((SendFile {
-                                    f: f,
-                                    buf: Some(buf),
-                                    chan: chan0,
-                                    state: Some(SendFileState::Read(session)),
-                                }).and_then(move |(session, mut buf)| {
-                                    buf.clear();
-                                    SendFile {
-                                        f: sig,
-                                        buf: Some(buf),
-                                        chan: chan1,
-                                        state: Some(SendFileState::Read(session)),
+    fn put_patches(&mut self, patches: Vec<PatchEntry>) -> Result<(), Error> {
+        match self.request(&protocol::Request::PutPatches { patches })? {
+            protocol::Response::Applied => Ok(()),
+            r => Err(unexpected_response(r)),
+        }
+    }
+
+    /// Upload a patch too large to be sent in a single frame.
+    fn put_patch_parts(&mut self, entry: PatchEntry) -> Result<(), Error> {
+        let PatchEntry {
+            hash,
+            patch,
+            signature,
+        } = entry;
+        let mut req = protocol::Request::PutPatch {
+            hash,
+            size: patch.len() as u64,
+            signature,
+        };
+        let mut parts = patch.chunks(protocol::PART_SIZE);
+        loop {
+            match self.request(&req)? {
+                protocol::Response::Applied => {}
+                r => return Err(unexpected_response(r)),
+            }
+            if let Some(data) = parts.next() {
+                req = protocol::Request::PatchPart {
+                    data: data.to_vec(),
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Download the blobs of `pointers` that the remote has.
+    pub fn fetch_blobs(&mut self, repo_root: &Path, pointers: &[BlobPointer]) -> Result<(), Error> {
+        if self.is_legacy()? || self.version < 3 {
+            // Older versions of Pijul have no blob store.
+            return Ok(());
+        }
+        'blobs: for pointer in pointers {
+            let mut writer = BlobWriter::new(repo_root, pointer)?;
+            while !writer.is_complete() {
+                let req = protocol::Request::Blob {
+                    hash: pointer.hash.clone(),
+                    size: pointer.size,
+                    offset: writer.written(),
+                };
+                match self.request(&req) {
+                    Ok(protocol::Response::BlobPart { ref data }) if !data.is_empty() => {
+                        if !writer.write(data)? {
+                            return Err(Error::WrongHash);
+                        }
+                    }
+                    Ok(r) => return Err(unexpected_response(r)),
+                    Err(Error::Remote { code, .. }) if code == protocol::ERROR_BLOB_NOT_FOUND => {
+                        continue 'blobs
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if !writer.finish()? {
+                return Err(Error::WrongHash);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn push_blobs(&mut self, repo_root: &Path, pointers: &[BlobPointer]) -> Result<(), Error> {
+        if pointers.is_empty() {
+            return Ok(());
+        }
+        if self.is_legacy()? || self.version < 3 {
+            return Err(Error::RemoteProtocol {
+                message: "the remote Pijul is too old to store large files".to_string(),
+            });
+        }
+        for pointer in pointers {
+            let mut f = File::open(pointer.path(repo_root))?;
+            let mut req = protocol::Request::PutBlob {
+                hash: pointer.hash.clone(),
+                size: pointer.size,
+            };
+            loop {
+                match self.request(&req)? {
+                    protocol::Response::Applied => {}
+                    r => return Err(unexpected_response(r)),
+                }
+                let mut data = Vec::new();
+                (&mut f)
+                    .take(protocol::PART_SIZE as u64)
+                    .read_to_end(&mut data)?;
+                if data.is_empty() {
+                    break;
+                }
+                req = protocol::Request::BlobPart { data };
+            }
+        }
+        Ok(())
+    }
+
+    /// Download patches in the background, keeping up to
+    /// `concurrency` requests in flight on the `pijul protocol`
+    /// channel. The connection is given back through the returned
+    /// receiver once all the patches have been downloaded. On remotes
+    /// without `pijul protocol`, the patches are downloaded one by one
+    /// before returning, and `None` is returned.
+    fn spawn_downloads(
+        &mut self,
+        patch_hashes: Vec<Hash>,
+        patches_dir: PathBuf,
+        concurrency: usize,
+        sender: DownloadSender,
+    ) -> Result<Option<futures::sync::oneshot::Receiver<SshConnection>>, Error> {
+        if self.is_legacy()? {
+            for hash in patch_hashes {
+                self.legacy_fetch_patch(&hash, &patches_dir)?;
+                sender.send(Ok(hash)).unwrap_or(())
+            }
+            return Ok(None);
+        }
+        let chan = self.protocol_channel()?;
+        let mut connection = self.session.take().unwrap();
+        connection.handler_mut().channel = Some(chan);
+        let version = self.version;
+        let downloads: VecDeque<Download> = patch_hashes
+            .chunks(DOWNLOAD_BATCH_SIZE)
+            .map(|c| Download::Batch(c.to_vec()))
+            .collect();
+        let (connection_sender, connection_receiver) = futures::sync::oneshot::channel();
+        let error_sender = sender.clone();
+        // Responses come in the order of the requests, which are
+        // kept in `in_flight` until then.
+        let downloads = futures::future::loop_fn(
+            (connection, downloads, VecDeque::new()),
+            move |(connection, mut downloads, mut in_flight): (
+                SshConnection,
+                VecDeque<Download>,
+                VecDeque<Download>,
+            )| {
+                let mut frames = Vec::new();
+                while in_flight.len() < concurrency {
+                    if let Some(download) = downloads.pop_front() {
+                        match protocol::request_frame(&download.request()) {
+                            Ok(frame) => frames.push(frame),
+                            Err(e) => return futures::future::Either::A(futures::future::err(e)),
+                        }
+                        in_flight.push_back(download)
+                    } else {
+                        break;
+                    }
+                }
+                let patches_dir = patches_dir.clone();
+                let sender = sender.clone();
+                futures::future::Either::B(
+                    futures::stream::iter_ok::<_, Error>(frames)
+                        .fold(connection, move |connection, frame| {
+                            connection
+                                .data(chan, None, frame)
+                                .map(|(connection, _)| connection)
+                                .map_err(Error::from)
+                        }).and_then(move |connection| {
+                            connection
+                                .wait(move |connection| {
+                                    connection.handler().has_frame()
+                                        || connection.handler().exit_status.contains_key(&chan)
+                                        || !connection.is_channel_open(chan)
+                                }).map_err(Error::from)
+                        }).and_then(move |mut connection| {
+                            let mut received = false;
+                            while let Some(payload) = match connection.handler_mut().state {
+                                State::Protocol { ref mut buf, .. } => protocol::take_frame(buf)?,
+                                _ => None,
+                            } {
+                                received = true;
+                                let resp: protocol::Response = bincode::deserialize(&payload)?;
+                                match (in_flight.pop_front(), resp.into_result()?) {
+                                    (
+                                        Some(Download::Batch(batch)),
+                                        protocol::Response::Patches { patches },
+                                    ) => {
+                                        for entry in patches.iter() {
+                                            let hash = entry.save(&patches_dir)?;
+                                            sender.send(Ok(hash)).unwrap_or(())
+                                        }
+                                        // Ask again for the patches that
+                                        // didn't fit in the response.
+                                        let mut rest = batch
+                                            .get(patches.len()..)
+                                            .unwrap_or(&[])
+                                            .to_vec();
+                                        if patches.is_empty() && !rest.is_empty() {
+                                            if version < 4 {
+                                                return Err(Error::RemoteProtocol {
+                                                    message: "no patches received".to_string(),
+                                                });
+                                            }
+                                            let hash = rest.remove(0);
+                                            if !rest.is_empty() {
+                                                downloads.push_front(Download::Batch(rest))
+                                            }
+                                            downloads.push_front(Download::Part(PatchEntry {
+                                                hash: hash.to_base58(),
+                                                patch: Vec::new(),
+                                                signature: None,
+                                            }))
+                                        } else if !rest.is_empty() {
+                                            downloads.push_front(Download::Batch(rest))
+                                        }
                                     }
-                                }))
-                            } else {
-                                futures::future::Either::B(SendFile {
-                                    f: f,
-                                    buf: Some(buf),
-                                    chan: chan0,
-                                    state: Some(SendFileState::Read(session)),
+                                    (
+                                        Some(Download::Part(mut entry)),
+                                        protocol::Response::PatchPart {
+                                            size,
+                                            data,
+                                            signature,
+                                        },
+                                    ) => {
+                                        if add_patch_part(&mut entry, size, data, signature)? {
+                                            let hash = entry.save(&patches_dir)?;
+                                            sender.send(Ok(hash)).unwrap_or(())
+                                        } else {
+                                            downloads.push_front(Download::Part(entry))
+                                        }
+                                    }
+                                    (_, r) => return Err(unexpected_response(r)),
+                                }
+                            }
+                            if !received {
+                                Err(Error::RemoteProtocol {
+                                    message: "remote pijul exited".to_string(),
                                 })
+                            } else if in_flight.is_empty() && downloads.is_empty() {
+                                Ok(futures::future::Loop::Break(connection))
+                            } else {
+                                Ok(futures::future::Loop::Continue((
+                                    connection,
+                                    downloads,
+                                    in_flight,
+                                )))
                             }
-                        }).and_then(move |(mut session, _)| {
-                            session.channel_eof(chan0);
-                            session
-                                .wait(move |session| {
-                                    session.handler().exit_status.get(&chan0).is_some()
-                                }).map(
-                                    move |mut session| {
-                                        exit_status = session
-                                            .handler()
-                                            .exit_status
-                                            .get(&chan0)
-                                            .map(|x| *x);
-                                        session.channel_close(chan0);
-                                        session
-                                    },
-                                )
-                        }).map_err(From::from)
-                })),
-        ).unwrap());
-
-        if let Some(ref session) = self.session {
-            debug!("exit status = {:?}", session.handler().exit_status);
+                        }),
+                )
+            },
+        );
+        self.l.spawn(downloads.then(move |result| {
+            match result {
+                Ok(connection) => connection_sender.send(connection).unwrap_or(()),
+                Err(e) => error_sender.send(Err(e)).unwrap_or(()),
+            }
+            Ok::<(), ()>(())
+        }));
+        Ok(Some(connection_receiver))
+    }
+
+    /// Get the connection back after `spawn_downloads`.
+    fn finish_downloads(&mut self, connection: futures::sync::oneshot::Receiver<SshConnection>) {
+        if let Ok(connection) = self.l.block_on(connection) {
+            self.session = Some(connection)
+        } else {
+            self.protocol = None
         }
-        Ok(())
     }
 
     pub fn remote_init(&mut self) -> Result<(), Error> {
@@ -597,21 +1045,50 @@ impl<'a> SshSession<'a> {
 }
 
 impl<'a> UriSession<'a> {
+    fn smart_uri(&self, endpoint: &str) -> String {
+        self.uri.to_string() + smart_http::PREFIX + endpoint
+    }
+
+    fn execute(
+        &mut self,
+        req: reqwest_async::RequestBuilder,
+    ) -> Result<(reqwest::StatusCode, Vec<u8>), Error> {
+        self.l.block_on(read_response(req))
+    }
+
     pub fn changes(
         &mut self,
         branch: &str,
         path: &[&str],
     ) -> Result<HashMap<Hash, ApplyTimestamp>, Error> {
+        if self.smart {
+            let mut query = vec![("branch", branch)];
+            for p in path {
+                query.push(("path", *p))
+            }
+            let uri = self.smart_uri(smart_http::CHANGES);
+            let req = self.client.get(&uri[..]).query(&query);
+            let (status, body) = self.execute(req)?;
+            debug!("smart http: {:?}", status);
+            if status == reqwest::StatusCode::OK {
+                return Ok(read_changes(&mut &body[..])?);
+            } else if status == reqwest::StatusCode::NOT_FOUND {
+                // This is a static server, serving the `.pijul`
+                // directory directly.
+                self.smart = false
+            } else {
+                return Err(Error::HttpStatus {
+                    status: status.as_u16(),
+                    message: String::from_utf8_lossy(&body).into_owned(),
+                });
+            }
+        }
         if !path.is_empty() {
             return Err(Error::PartialPullOverHttp);
         }
         let mut uri = self.uri.to_string();
         uri = uri + "/" + PIJUL_DIR_NAME + "/" + &branch_changes_base_path(branch);
-        let mut req = reqwest_async::Request::new(reqwest::Method::GET, uri.parse().unwrap());
-        req.headers_mut().insert(
-            reqwest::header::CONNECTION,
-            reqwest::header::HeaderValue::from_static("close"),
-        );
+        let req = reqwest_async::Request::new(reqwest::Method::GET, uri.parse().unwrap());
         let res: Vec<u8> = self.l.block_on(self.client.execute(req).and_then(
             |resp: reqwest_async::Response| {
                 let res = Vec::new();
@@ -627,6 +1104,24 @@ impl<'a> UriSession<'a> {
         Ok(changes)
     }
 
+    /// The tag registry of the remote, served as a static file by
+    /// both smart and static servers.
+    pub fn tags(&mut self) -> Result<TagRegistry, Error> {
+        let uri = self.uri.to_string() + "/" + PIJUL_DIR_NAME + "/tags.toml";
+        let req = self.client.get(&uri[..]);
+        let (status, body) = self.execute(req)?;
+        if status == reqwest::StatusCode::OK {
+            TagRegistry::parse(&String::from_utf8(body)?)
+        } else if status == reqwest::StatusCode::NOT_FOUND {
+            Ok(TagRegistry::default())
+        } else {
+            Err(Error::HttpStatus {
+                status: status.as_u16(),
+                message: String::from_utf8_lossy(&body).into_owned(),
+            })
+        }
+    }
+
     pub fn fetch_patch(
         &mut self,
         patch_hash: &Hash,
@@ -639,20 +1134,12 @@ impl<'a> UriSession<'a> {
             self.uri.to_string() + "/" + PIJUL_DIR_NAME + "/patches/" + &patch_hash.to_base58() + ".gz";
         debug!("downloading uri {:?}", uri);
 
-        let mut req = reqwest_async::Request::new(reqwest::Method::GET, uri.parse().unwrap());
-        req.headers_mut().insert(
-            reqwest::header::CONNECTION,
-            reqwest::header::HeaderValue::from_static("close"),
-        );
+        let req = reqwest_async::Request::new(reqwest::Method::GET, uri.parse().unwrap());
 
         let uri_sig =
             self.uri.to_string() + "/" + PIJUL_DIR_NAME + "/patches/" + &patch_hash.to_base58() + ".sig";
         debug!("{:?}", uri_sig);
         let req_sig = reqwest_async::Request::new(reqwest::Method::GET, uri_sig.parse().unwrap());
-        req.headers_mut().insert(
-            reqwest::header::CONNECTION,
-            reqwest::header::HeaderValue::from_static("close"),
-        );
         let mut local_sig_file = local_file.clone();
         let mut local_tmp_sig_file = local_tmp_file.clone();
         local_sig_file.set_extension("sig");
@@ -714,6 +1201,132 @@ impl<'a> UriSession<'a> {
             })
         }
     }
+
+    /// Download patches in the background, with at most `concurrency`
+    /// requests in flight. The client keeps its connections alive
+    /// between requests.
+    fn spawn_downloads(
+        &mut self,
+        patch_hashes: Vec<Hash>,
+        patches_dir: PathBuf,
+        concurrency: usize,
+        sender: DownloadSender,
+    ) {
+        let client = self.client.clone();
+        let uri = self.uri.to_string();
+        let smart = self.smart;
+        let batches: Vec<Vec<Hash>> = if smart {
+            patch_hashes
+                .chunks(DOWNLOAD_BATCH_SIZE)
+                .map(|c| c.to_vec())
+                .collect()
+        } else {
+            patch_hashes.into_iter().map(|h| vec![h]).collect()
+        };
+        let error_sender = sender.clone();
+        let downloads = futures::stream::iter_ok::<_, Error>(batches)
+            .map(move |batch| download_http_batch(&client, &uri, smart, batch))
+            .buffer_unordered(concurrency)
+            .for_each(move |entries| {
+                for entry in entries {
+                    let hash = entry.save(&patches_dir)?;
+                    sender.send(Ok(hash)).unwrap_or(())
+                }
+                Ok(())
+            }).map_err(move |e| error_sender.send(Err(e)).unwrap_or(()));
+        self.l.spawn(downloads);
+    }
+
+    pub fn remote_apply(
+        &mut self,
+        repo_root: &Path,
+        remote_branch: &str,
+        patch_hashes: HashSet<Hash>,
+    ) -> Result<(), Error> {
+        let pdir = patches_dir(repo_root);
+        let mut body = Vec::new();
+        for hash in patch_hashes.iter() {
+            smart_http::write_entry(&mut body, &PatchEntry::load(&pdir, hash)?)?;
+        }
+        let uri = self.smart_uri(smart_http::APPLY);
+        let mut req = self.client
+            .post(&uri[..])
+            .query(&[("branch", remote_branch)])
+            .body(body);
+        if let Ok(token) = std::env::var(smart_http::TOKEN_VAR) {
+            req = req.header(
+                reqwest::header::AUTHORIZATION,
+                &format!("Bearer {}", token)[..],
+            );
+        }
+        let (status, body) = self.execute(req)?;
+        if status == reqwest::StatusCode::OK {
+            Ok(())
+        } else {
+            Err(Error::HttpStatus {
+                status: status.as_u16(),
+                message: String::from_utf8_lossy(&body).into_owned(),
+            })
+        }
+    }
+
+    /// Download the blobs of `pointers` that the remote has. Blobs
+    /// are static files, so this doesn't need the smart protocol.
+    pub fn fetch_blobs(&mut self, repo_root: &Path, pointers: &[BlobPointer]) -> Result<(), Error> {
+        for pointer in pointers {
+            let uri = self.uri.to_string() + "/" + PIJUL_DIR_NAME + "/blobs/" + &pointer.hash;
+            debug!("downloading blob {:?}", uri);
+            let writer = BlobWriter::new(repo_root, pointer)?;
+            let req = self.client.get(&uri[..]);
+            if let Some(writer) = self.l.block_on(download_blob(req, writer))? {
+                if !writer.finish()? {
+                    return Err(Error::WrongHash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload the blobs of `pointers`, one request per blob, reading
+    /// each blob as it is sent.
+    pub fn push_blobs(&mut self, repo_root: &Path, pointers: &[BlobPointer]) -> Result<(), Error> {
+        for pointer in pointers {
+            let mut f = File::open(pointer.path(repo_root))?;
+            let parts = futures::stream::poll_fn(move || -> futures::Poll<_, std::io::Error> {
+                let mut data = Vec::new();
+                (&mut f)
+                    .take(protocol::PART_SIZE as u64)
+                    .read_to_end(&mut data)?;
+                Ok(futures::Async::Ready(if data.is_empty() {
+                    None
+                } else {
+                    Some(data)
+                }))
+            });
+            let uri = self.smart_uri(smart_http::BLOBS);
+            let size = pointer.size.to_string();
+            let query = [("hash", &pointer.hash[..]), ("size", &size[..])];
+            let mut req = self
+                .client
+                .post(&uri[..])
+                .query(&query)
+                .body(reqwest_async::Body::from(hyper::Body::wrap_stream(parts)));
+            if let Ok(token) = std::env::var(smart_http::TOKEN_VAR) {
+                req = req.header(
+                    reqwest::header::AUTHORIZATION,
+                    &format!("Bearer {}", token)[..],
+                );
+            }
+            let (status, body) = self.execute(req)?;
+            if status != reqwest::StatusCode::OK {
+                return Err(Error::HttpStatus {
+                    status: status.as_u16(),
+                    message: String::from_utf8_lossy(&body).into_owned(),
+                });
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a> LocalSession<'a> {
@@ -727,15 +1340,21 @@ impl<'a> LocalSession<'a> {
         let txn = repo.txn_begin()?;
         if let Some(branch) = txn.get_branch(&branch) {
             if !path.is_empty() {
+                let mut keys = Vec::new();
+                for path in path {
+                    let inode = txn.find_inode(Path::new(path))?;
+                    if let Some(header) = txn.get_inodes(inode) {
+                        keys.push(header.key)
+                    } else {
+                        return Err(Error::FileNotInRepository {
+                            path: path.to_string(),
+                        });
+                    }
+                }
                 let mut patches = HashMap::new();
                 for (hash, s) in txn.iter_patches(&branch, None) {
-                    for path in path {
-                        let inode = txn.find_inode(Path::new(path)).unwrap();
-                        let key = txn.get_inodes(inode).unwrap().key;
-                        if txn.get_touched(key, hash) {
-                            patches.insert(txn.get_external(hash).unwrap().to_owned(), s);
-                            break;
-                        }
+                    if keys.iter().any(|&key| txn.get_touched(key, hash)) {
+                        patches.insert(txn.get_external(hash).unwrap().to_owned(), s);
                     }
                 }
                 Ok(patches)
@@ -768,7 +1387,6 @@ impl<'a> LocalSession<'a> {
     ) -> Result<(), Error> {
         let mut remote_path = patches_dir(self.path);
         let mut local_path = patches_dir(repo_root);
-        let remote_current_branch = get_current_branch(&self.path)?;
 
         for hash in patch_hashes {
             remote_path.push(&hash.to_base58());
@@ -797,27 +1415,140 @@ impl<'a> LocalSession<'a> {
             remote_path.pop();
         }
 
-        loop {
-            let app = if remote_current_branch != remote_branch {
-                apply_resize_no_output(&self.path, &remote_branch, patch_hashes.iter(), |_, _| {})
-            } else {
-                apply_resize(
-                    &self.path,
-                    &remote_branch,
-                    patch_hashes.iter(),
-                    &[] as &[&str],
-                    |_, _| {},
-                )
-            };
-            match app {
-                Err(ref e) if e.lacks_space() => debug!("lacks space"),
-                Ok(()) => return Ok(()),
-                Err(e) => return Err(From::from(e)),
+        apply_patches_to_branch(&self.path, remote_branch, patch_hashes)
+    }
+
+    /// Copy the blobs of `pointers` from the store of `from` to the
+    /// store of `to`, if `from` has them.
+    fn copy_blobs(from: &Path, to: &Path, pointers: &[BlobPointer]) -> Result<(), Error> {
+        for pointer in pointers {
+            if pointer.is_available(from) && !pointer.is_available(to) {
+                create_dir_all(blobs_dir(to))?;
+                let (from, to) = (pointer.path(from), pointer.path(to));
+                debug!("hard linking {:?} to {:?}", from, to);
+                if hard_link(&from, &to).is_err() {
+                    copy(&from, &to)?;
+                }
             }
         }
+        Ok(())
+    }
+
+    pub fn fetch_blobs(&mut self, repo_root: &Path, pointers: &[BlobPointer]) -> Result<(), Error> {
+        Self::copy_blobs(self.path, repo_root, pointers)
+    }
+
+    pub fn push_blobs(&mut self, repo_root: &Path, pointers: &[BlobPointer]) -> Result<(), Error> {
+        Self::copy_blobs(repo_root, self.path, pointers)
     }
 }
 
+impl BundleSession {
+    /// The patches of the bundle. Since a bundle contains patches
+    /// from a single branch, `branch` is ignored.
+    pub fn changes(
+        &mut self,
+        _branch: &str,
+        path: &[&str],
+    ) -> Result<HashMap<Hash, ApplyTimestamp>, Error> {
+        if !path.is_empty() {
+            return Err(Error::PartialPullFromBundle);
+        }
+        let mut changes = HashMap::new();
+        for (n, p) in self.bundle.header.patches.iter().enumerate() {
+            let hash = Hash::from_base58(&p.hash).ok_or(Error::WrongHash)?;
+            changes.insert(hash, n as ApplyTimestamp);
+        }
+        Ok(changes)
+    }
+
+    /// The tags contained in the bundle.
+    pub fn tags(&mut self) -> Result<TagRegistry, Error> {
+        let mut registry = TagRegistry::default();
+        for n in 0..self.bundle.header.patches.len() {
+            let entry = self.bundle.entry(n)?;
+            let (hash, _, patch) = Patch::from_reader_compressed(&mut &entry.patch[..])?;
+            if patch.is_tag() {
+                registry.insert(&patch.header().name, &hash);
+            }
+        }
+        Ok(registry)
+    }
+
+    pub fn fetch_patch(&mut self, patch_hash: &Hash, patches_dir: &Path) -> Result<(), Error> {
+        debug!("extracting {:?} from {:?}", patch_hash, self.path);
+        if let Some(n) = self.bundle.position(patch_hash) {
+            self.bundle.entry(n)?.save(patches_dir)?;
+            Ok(())
+        } else {
+            Err(Error::PatchNotFound {
+                repo_root: self.path.to_string_lossy().into_owned(),
+                patch_hash: patch_hash.to_owned(),
+            })
+        }
+    }
+}
+
+/// Apply downloaded patches to `to_branch`, outputting the repository
+/// if `to_branch` is the current branch.
+pub fn apply_pulled_patches<F: FnMut(usize, &Hash)>(
+    target: &Path,
+    to_branch: &str,
+    current_branch: &str,
+    patches: &[(Hash, Patch)],
+    partial_paths: &[&str],
+    mut apply_cb: F,
+) -> Result<(), Error> {
+    let size_increase: usize = patches.iter().map(|&(_, ref p)| p.size_upper_bound()).sum();
+    let config = load_config(target)?;
+    if current_branch != to_branch {
+        apply_resize_patches_no_output(
+            target,
+            &config,
+            &to_branch,
+            patches,
+            size_increase,
+            |c, h| apply_cb(c, h),
+        )?
+    } else {
+        apply_resize_patches(
+            target,
+            &config,
+            &to_branch,
+            patches,
+            size_increase,
+            partial_paths,
+            |c, h| apply_cb(c, h),
+        )?
+    }
+    TagRegistry::register(target, patches.iter().map(|&(ref h, ref p)| (h, p.header())))
+}
+
+/// Apply patches already present in the patches directory of
+/// `repo_root`, outputting the repository if `branch` is the current
+/// branch.
+pub fn apply_patches_to_branch(
+    repo_root: &Path,
+    branch: &str,
+    patch_hashes: &HashSet<Hash>,
+) -> Result<(), Error> {
+    let current_branch = get_current_branch(repo_root)?;
+    let config = load_config(repo_root)?;
+    if current_branch != branch {
+        apply_resize_no_output(repo_root, &config, branch, patch_hashes.iter(), |_, _| {})?
+    } else {
+        apply_resize(
+            repo_root,
+            &config,
+            branch,
+            patch_hashes.iter(),
+            &[] as &[&str],
+            |_, _| {},
+        )?
+    }
+    TagRegistry::register_hashes(repo_root, patch_hashes)
+}
+
 impl<'a> Session<'a> {
     pub fn changes(
         &mut self,
@@ -828,33 +1559,142 @@ impl<'a> Session<'a> {
             Session::Ssh(ref mut ssh_session) => ssh_session.changes(branch, remote_path),
             Session::Local(ref mut local_session) => local_session.changes(branch, remote_path),
             Session::Uri(ref mut uri_session) => uri_session.changes(branch, remote_path),
+            Session::Bundle(ref mut bundle_session) => {
+                bundle_session.changes(branch, remote_path)
+            }
+        }
+    }
+    /// The tag registry of the remote repository.
+    pub fn tags(&mut self) -> Result<TagRegistry, Error> {
+        match *self {
+            Session::Ssh(ref mut ssh_session) => ssh_session.tags(),
+            Session::Local(ref mut local_session) => TagRegistry::load(local_session.path),
+            Session::Uri(ref mut uri_session) => uri_session.tags(),
+            Session::Bundle(ref mut bundle_session) => bundle_session.tags(),
         }
     }
+
     pub fn download_patch(&mut self, repo_root: &Path, patch_hash: &Hash) -> Result<PathBuf, Error> {
         let patches_dir_ = patches_dir(repo_root);
         let local_file = patches_dir_.join(&patch_file_name(patch_hash.as_ref()));
 
-        if !metadata(&local_file).is_ok() {
+        if !has_valid_patch(&patches_dir_, patch_hash) {
             match *self {
                 Session::Local(ref mut local_session) => {
                     local_session.fetch_patch(patch_hash, local_file)
                 }
                 Session::Ssh(ref mut ssh_session) => {
-                    let tmp_dir = TempDir::new_in(&patches_dir_, "pijul_patch")?;
-                    let local_tmp_file = tmp_dir.path().join("patch");
-                    ssh_session.fetch_patch(patch_hash, local_file, local_tmp_file)
+                    ssh_session.fetch_patch(patch_hash, local_file)
                 }
                 Session::Uri(ref mut uri_session) => {
                     let tmp_dir = TempDir::new_in(&patches_dir_, "pijul_patch")?;
                     let local_tmp_file = tmp_dir.path().join("patch");
                     uri_session.fetch_patch(patch_hash, local_file, local_tmp_file)
                 }
+                Session::Bundle(ref mut bundle_session) => {
+                    bundle_session.fetch_patch(patch_hash, &patches_dir_)?;
+                    Ok(local_file)
+                }
             }
         } else {
             Ok(local_file)
         }
     }
 
+    /// Start downloading the patches that are not already in the
+    /// patches directory of `repo_root`. Downloads run in the
+    /// background; each patch is announced on the returned `Downloads`
+    /// once it is in the patches directory (patches already there are
+    /// announced immediately).
+    ///
+    /// The patches of `trusted`, and the ones known to the pristine,
+    /// have already been checked, and are not read again.
+    pub fn start_downloads(
+        &mut self,
+        repo_root: &Path,
+        patch_hashes: &[Hash],
+        trusted: &HashSet<Hash>,
+    ) -> Result<Downloads, Error> {
+        let patches_dir_ = patches_dir(repo_root);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut missing = Vec::new();
+        {
+            let repo = Repository::open(&pristine_dir(repo_root), None)?;
+            let txn = repo.txn_begin()?;
+            for h in patch_hashes {
+                let known = trusted.contains(h) || txn.get_internal(h.as_ref()).is_some();
+                if known && patches_dir_.join(&patch_file_name(h.as_ref())).exists()
+                    || has_valid_patch(&patches_dir_, h)
+                {
+                    sender.send(Ok(h.to_owned())).unwrap_or(())
+                } else {
+                    missing.push(h.to_owned())
+                }
+            }
+        }
+        let mut connection = None;
+        if !missing.is_empty() {
+            let concurrency = download_concurrency();
+            debug!("downloading {} patches, concurrency {}", missing.len(), concurrency);
+            match *self {
+                Session::Local(ref mut local_session) => {
+                    for h in missing {
+                        let local_file = patches_dir_.join(&patch_file_name(h.as_ref()));
+                        let result = local_session.fetch_patch(&h, local_file).map(|_| h);
+                        sender.send(result).unwrap_or(())
+                    }
+                }
+                Session::Ssh(ref mut ssh_session) => {
+                    connection = ssh_session.spawn_downloads(
+                        missing,
+                        patches_dir_,
+                        concurrency,
+                        sender,
+                    )?
+                }
+                Session::Uri(ref mut uri_session) => {
+                    uri_session.spawn_downloads(missing, patches_dir_, concurrency, sender)
+                }
+                Session::Bundle(ref mut bundle_session) => {
+                    for h in missing {
+                        let result = bundle_session.fetch_patch(&h, &patches_dir_).map(|_| h);
+                        sender.send(result).unwrap_or(())
+                    }
+                }
+            }
+        }
+        Ok(Downloads {
+            receiver,
+            connection,
+        })
+    }
+
+    /// Release the resources used by `start_downloads`, once all
+    /// patches have been received.
+    fn finish_downloads(&mut self, downloads: Downloads) {
+        if let Some(connection) = downloads.connection {
+            if let Session::Ssh(ref mut ssh_session) = *self {
+                ssh_session.finish_downloads(connection)
+            }
+        }
+    }
+
+    /// Download the patches that are not already in the patches
+    /// directory of `repo_root`, and wait until they are all there.
+    pub fn download_patches(
+        &mut self,
+        repo_root: &Path,
+        patch_hashes: &[Hash],
+        trusted: &HashSet<Hash>,
+    ) -> Result<(), Error> {
+        let downloads = self.start_downloads(repo_root, patch_hashes, trusted)?;
+        for _ in patch_hashes {
+            downloads.next()?;
+        }
+        self.finish_downloads(downloads);
+        Ok(())
+    }
+
     fn remote_apply(
         &mut self,
         repo_root: &Path,
@@ -870,7 +1710,11 @@ impl<'a> Session<'a> {
                 local_session.remote_apply(repo_root, remote_branch, &patch_hashes)
             }
 
-            _ => panic!("upload to URI impossible"),
+            Session::Uri(ref mut uri_session) => {
+                uri_session.remote_apply(repo_root, remote_branch, patch_hashes)
+            }
+
+            Session::Bundle(_) => Err(Error::CannotPushToBundle),
         }
     }
 
@@ -924,6 +1768,115 @@ impl<'a> Session<'a> {
         pullable: &mut Vec<(Hash, ApplyTimestamp)>,
         partial_paths: &[&str],
         display_progress: bool,
+        mut manifest: Option<&mut PullManifest>,
+    ) -> Result<(), Error> {
+        if !partial_paths.is_empty() {
+            return self.pull_partial(
+                target,
+                to_branch,
+                pullable,
+                partial_paths,
+                display_progress,
+                manifest,
+            );
+        }
+        // The remote applied these patches in this order, hence
+        // dependencies come first.
+        pullable.sort_by(|&(_, ref a), &(_, ref b)| a.cmp(&b));
+        let order: Vec<(Hash, ApplyTimestamp)> = pullable.drain(..).collect();
+        let mut p = if display_progress && !order.is_empty() {
+            Some(progrs::start("Pulling patches", order.len() as u64))
+        } else {
+            None
+        };
+        let hashes: Vec<Hash> = order.iter().map(|&(ref h, _)| h.to_owned()).collect();
+        let trusted = manifest.as_ref().map(|m| m.downloaded()).unwrap_or_default();
+        let downloads = self.start_downloads(target, &hashes, &trusted)?;
+        let current_branch = get_current_branch(target)?;
+        let mut downloaded = HashSet::new();
+        // Apply the patches as soon as they and all the patches
+        // before them are downloaded, while the following ones are
+        // still downloading. Everything happens in a single
+        // transaction, so that the working copy is output only once,
+        // at the end.
+        let config = load_config(target)?;
+        let result = Repository::with_mut_txn(
+            pristine_dir(target),
+            &config,
+            0,
+            |mut txn| -> Result<Vec<(Hash, Patch)>, Error> {
+                let mut branch = txn.open_branch(to_branch)?;
+                let pending = if current_branch == to_branch {
+                    Some(txn.pending_patch(&branch, target)?)
+                } else {
+                    None
+                };
+                let mut patches: Vec<(Hash, Patch)> = Vec::with_capacity(order.len());
+                let mut new_patches_count = 0;
+                for &(ref hash, _) in order.iter() {
+                    while !downloaded.contains(hash) {
+                        let h = downloads.next()?;
+                        if let Some(ref mut manifest) = manifest {
+                            if !trusted.contains(&h) {
+                                manifest.add_downloaded(&h)
+                            }
+                        }
+                        downloaded.insert(h);
+                    }
+                    let patch = read_patch(target, hash.as_ref())?;
+                    txn.apply_patches_rec(
+                        &mut branch,
+                        &patches,
+                        hash,
+                        &patch,
+                        &mut new_patches_count,
+                    )?;
+                    patches.push((hash.to_owned(), patch));
+                    p.as_mut().map(|p| p.display(patches.len() as u64));
+                }
+                debug!("{} patches applied", new_patches_count);
+                if new_patches_count > 0 {
+                    txn.output_changes_file(&branch, target)?;
+                    if let Some((ref pending, ref local_pending)) = pending {
+                        txn.output_repository(
+                            &mut branch,
+                            target,
+                            &Prefixes::empty(),
+                            pending,
+                            local_pending,
+                        )?;
+                    }
+                }
+                txn.commit_branch(branch)?;
+                txn.commit()?;
+                Ok(patches)
+            },
+        );
+        // Even if the pull failed, save the patches downloaded so
+        // far, so that resuming doesn't check them again.
+        if let Some(ref mut manifest) = manifest {
+            if result.is_ok() {
+                manifest.set_patches(&[]);
+            }
+            manifest.save(target)?
+        }
+        let patches = result?;
+        TagRegistry::register(target, patches.iter().map(|&(ref h, ref p)| (h, p.header())))?;
+        self.finish_downloads(downloads);
+        p.map(|p| p.stop("done"));
+        self.fetch_blobs(target)
+    }
+
+    /// Pull only the patches touching some paths, along with their
+    /// dependencies.
+    fn pull_partial(
+        &mut self,
+        target: &Path,
+        to_branch: &str,
+        pullable: &mut Vec<(Hash, ApplyTimestamp)>,
+        partial_paths: &[&str],
+        display_progress: bool,
+        mut manifest: Option<&mut PullManifest>,
     ) -> Result<(), Error> {
         let mut p = if display_progress && !pullable.is_empty() {
             Some((progrs::start("Pulling patches", pullable.len() as u64), 0))
@@ -932,13 +1885,32 @@ impl<'a> Session<'a> {
         };
         let mut pullable_plus_deps = Vec::new();
         let mut pulled = HashSet::new();
+        let mut downloaded = manifest.as_ref().map(|m| m.downloaded()).unwrap_or_default();
+        let hashes: Vec<_> = pullable.iter().map(|&(ref h, _)| h.to_owned()).collect();
+        let result = self.download_patches(target, &hashes, &downloaded);
+        if let Some(ref mut manifest) = manifest {
+            if result.is_ok() {
+                for h in hashes.iter() {
+                    if downloaded.insert(h.to_owned()) {
+                        manifest.add_downloaded(h)
+                    }
+                }
+            }
+            manifest.save(target)?
+        }
+        result?;
 
         while let Some((hash, _)) = pullable.pop() {
             if pulled.contains(&hash) {
                 continue;
             }
             debug!("hash = {:?}", hash);
-            let path = self.download_patch(&target, &hash)?;
+            // Dependencies were not in the initial download.
+            let path = if downloaded.contains(&hash) {
+                patches_dir(target).join(&patch_file_name(hash.as_ref()))
+            } else {
+                self.download_patch(&target, &hash)?
+            };
 
             let patch = {
                 let file = File::open(&path)?;
@@ -947,13 +1919,11 @@ impl<'a> Session<'a> {
             };
             pulled.insert(hash.clone());
 
-            // If the apply is partial, we might not have all the
+            // Since the apply is partial, we might not have all the
             // dependencies. Add them to this list.
-            if !partial_paths.is_empty() {
-                for dep in patch.dependencies() {
-                    if !pulled.contains(dep) {
-                        pullable.push((dep.to_owned(), 0));
-                    }
+            for dep in patch.dependencies() {
+                if !pulled.contains(dep) {
+                    pullable.push((dep.to_owned(), 0));
                 }
             }
 
@@ -969,41 +1939,70 @@ impl<'a> Session<'a> {
         p.map(|(p, _)| p.stop("done"));
         debug!("patches downloaded");
 
-        let p = std::cell::RefCell::new(progrs::start(
-            "Applying patches",
-            pullable_plus_deps.len() as u64,
-        ));
-        let mut size_increase = 4096;
+        let mut p = progrs::start("Applying patches", pullable_plus_deps.len() as u64);
         let current_branch = get_current_branch(target)?;
-        loop {
-            let app = if current_branch != to_branch {
-                apply_resize_patches_no_output(
-                    target,
-                    &to_branch,
-                    &pullable_plus_deps,
-                    size_increase,
-                    |c, _| p.borrow_mut().display(c as u64),
-                )
-            } else {
-                apply_resize_patches(
-                    target,
-                    &to_branch,
-                    &pullable_plus_deps,
-                    size_increase,
-                    partial_paths,
-                    |c, _| p.borrow_mut().display(c as u64),
-                )
-            };
-            match app {
-                Ok(()) => break,
-                Err(ref e) if e.lacks_space() => size_increase *= 2,
-                Err(e) => return Err(e.into()),
-            }
+        apply_pulled_patches(
+            target,
+            to_branch,
+            &current_branch,
+            &pullable_plus_deps,
+            partial_paths,
+            |c, _| p.display(c as u64),
+        )?;
+        p.stop("done");
+        if let Some(ref mut manifest) = manifest {
+            manifest.set_patches(&[]);
+            manifest.save(target)?
+        }
+        self.fetch_blobs(target)
+    }
+
+    /// Download the blobs of the large files of the working copy of
+    /// `target` that are not in its store yet, and replace their
+    /// pointers by their contents.
+    fn fetch_blobs(&mut self, target: &Path) -> Result<(), Error> {
+        let repo = Repository::open(&pristine_dir(target), None)?;
+        let txn = repo.txn_begin()?;
+        let missing: Vec<BlobPointer> = materialize_all(&txn, target)?.into_iter().collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        debug!("fetching {} blobs", missing.len());
+        match *self {
+            Session::Ssh(ref mut ssh_session) => ssh_session.fetch_blobs(target, &missing)?,
+            Session::Local(ref mut local_session) => local_session.fetch_blobs(target, &missing)?,
+            Session::Uri(ref mut uri_session) => uri_session.fetch_blobs(target, &missing)?,
+            Session::Bundle(_) => return Ok(()),
+        }
+        let still_missing = materialize_all(&txn, target)?;
+        if !still_missing.is_empty() {
+            return Err(Error::MissingBlobs {
+                count: still_missing.len(),
+            });
         }
-        p.into_inner().stop("done");
         Ok(())
     }
 
+    /// Send the blobs referenced by the patches in `pushable`, so
+    /// that the remote can output them.
+    fn push_blobs(&mut self, source: &Path, pushable: &HashSet<Hash>) -> Result<(), Error> {
+        let mut blobs = HashSet::new();
+        for hash in pushable {
+            blobs.extend(patch_blobs(&read_patch(source, hash.as_ref())?).into_iter());
+        }
+        let blobs: Vec<BlobPointer> = blobs.into_iter().filter(|b| b.is_available(source)).collect();
+        if blobs.is_empty() {
+            return Ok(());
+        }
+        debug!("pushing {} blobs", blobs.len());
+        match *self {
+            Session::Ssh(ref mut ssh_session) => ssh_session.push_blobs(source, &blobs),
+            Session::Local(ref mut local_session) => local_session.push_blobs(source, &blobs),
+            Session::Uri(ref mut uri_session) => uri_session.push_blobs(source, &blobs),
+            Session::Bundle(_) => Err(Error::CannotPushToBundle),
+        }
+    }
+
     pub fn pushable_patches(
         &mut self,
         from_branch: &str,
@@ -1050,6 +2049,7 @@ impl<'a> Session<'a> {
         debug!("push, remote_applying");
         debug!("pushable: {:?}", pushable);
         if pushable.len() > 0 {
+            self.push_blobs(source, &pushable)?;
             self.remote_apply(source, remote_branch, pushable)?;
         }
         Ok(())
@@ -1095,12 +2095,233 @@ pub fn ssh_connect(user: &Option<&str>, host: &str, port: Option<u16>) -> Result
     Ok((ssh_config, stream))
 }
 
+type SshConnection = thrussh::client::Connection<thrussh_config::Stream, Client>;
+
+type DownloadSender = std::sync::mpsc::Sender<Result<Hash, Error>>;
+
+/// Number of patches downloaded concurrently, if not set in the
+/// global configuration.
+pub const DEFAULT_DOWNLOAD_CONCURRENCY: usize = 8;
+
+/// Number of patches asked in a single request, on remotes that
+/// support it.
+const DOWNLOAD_BATCH_SIZE: usize = 16;
+
+/// A request of `SshSession::spawn_downloads`.
+enum Download {
+    /// Patches asked in a single `Patches` request.
+    Batch(Vec<Hash>),
+    /// The next part of a patch too large for a batch, with the parts
+    /// received so far.
+    Part(PatchEntry),
+}
+
+impl Download {
+    fn request(&self) -> protocol::Request {
+        match *self {
+            Download::Batch(ref batch) => protocol::Request::Patches {
+                hashes: batch.iter().map(|h| h.to_base58()).collect(),
+            },
+            Download::Part(ref entry) => protocol::Request::Patch {
+                hash: entry.hash.clone(),
+                offset: entry.patch.len() as u64,
+            },
+        }
+    }
+}
+
+/// Add a part of a patch, received in a `PatchPart` response, to
+/// `entry`. Returns whether the patch is complete.
+fn add_patch_part(
+    entry: &mut PatchEntry,
+    size: u64,
+    data: Vec<u8>,
+    signature: Option<Vec<u8>>,
+) -> Result<bool, Error> {
+    if entry.patch.is_empty() {
+        entry.signature = signature
+    }
+    if data.is_empty() {
+        return Err(Error::RemoteProtocol {
+            message: format!("truncated patch {}", entry.hash),
+        });
+    }
+    entry.patch.extend(data);
+    if entry.patch.len() as u64 > size {
+        return Err(Error::WrongHash);
+    }
+    Ok(entry.patch.len() as u64 == size)
+}
+
+/// Whether `patches_dir` contains a complete copy of patch `hash`.
+/// Other copies (for instance truncated by an interrupted download)
+/// are removed, so that the patch gets downloaded again.
+fn has_valid_patch(patches_dir: &Path, hash: &Hash) -> bool {
+    let mut path = patches_dir.join(&patch_file_name(hash.as_ref()));
+    if let Ok(f) = File::open(&path) {
+        match Patch::from_reader_compressed(&mut BufReader::new(f)) {
+            Ok((ref h, _, _)) if h == hash => true,
+            _ => {
+                debug!("removing invalid patch {:?}", path);
+                remove_file(&path).unwrap_or(());
+                path.set_extension("sig");
+                remove_file(&path).unwrap_or(());
+                false
+            }
+        }
+    } else {
+        false
+    }
+}
+
+fn download_concurrency() -> usize {
+    Global::load()
+        .ok()
+        .and_then(|global| global.download_concurrency)
+        .map(|c| std::cmp::max(c, 1))
+        .unwrap_or(DEFAULT_DOWNLOAD_CONCURRENCY)
+}
+
+/// Patches being downloaded in the background, see
+/// `Session::start_downloads`.
+pub struct Downloads {
+    receiver: std::sync::mpsc::Receiver<Result<Hash, Error>>,
+    /// For SSH sessions, the connection is moved to the background
+    /// task, and given back here at the end.
+    connection: Option<futures::sync::oneshot::Receiver<SshConnection>>,
+}
+
+impl Downloads {
+    /// Wait until the next patch is downloaded.
+    fn next(&self) -> Result<Hash, Error> {
+        match self.receiver.recv() {
+            Ok(h) => h,
+            Err(_) => Err(Error::RemoteProtocol {
+                message: "download interrupted".to_string(),
+            }),
+        }
+    }
+}
+
+/// Send a request, and read the whole response.
+fn read_response(
+    req: reqwest_async::RequestBuilder,
+) -> impl Future<Item = (reqwest::StatusCode, Vec<u8>), Error = Error> {
+    req.send()
+        .and_then(|resp| {
+            let status = resp.status();
+            resp.into_body()
+                .fold(Vec::new(), |mut res, x| {
+                    res.extend(x.iter());
+                    futures::finished::<_, reqwest::Error>(res)
+                }).map(move |body| (status, body))
+        }).map_err(Error::from)
+}
+
+/// Download a blob into `writer` as it arrives. Returns `None` if the
+/// server doesn't have the blob.
+fn download_blob(
+    req: reqwest_async::RequestBuilder,
+    writer: BlobWriter,
+) -> impl Future<Item = Option<BlobWriter>, Error = Error> {
+    req.send().map_err(Error::from).and_then(|resp| {
+        if resp.status() != reqwest::StatusCode::OK {
+            return futures::future::Either::A(futures::future::ok(None));
+        }
+        futures::future::Either::B(
+            resp.into_body()
+                .map_err(Error::from)
+                .fold(writer, |mut writer, chunk| -> Result<_, Error> {
+                    if writer.write(&chunk)? {
+                        Ok(writer)
+                    } else {
+                        Err(Error::WrongHash)
+                    }
+                }).map(Some),
+        )
+    })
+}
+
+/// Download a batch of patches, in one request if the server speaks
+/// the smart protocol, or else by getting the patch and its
+/// signature (in which case the batch must contain a single patch).
+fn download_http_batch(
+    client: &reqwest_async::Client,
+    uri: &str,
+    smart: bool,
+    batch: Vec<Hash>,
+) -> Box<Future<Item = Vec<PatchEntry>, Error = Error> + Send> {
+    if smart {
+        let body: String = batch.iter().map(|h| h.to_base58() + "\n").collect();
+        let url = uri.to_string() + smart_http::PREFIX + smart_http::PATCHES;
+        Box::new(
+            read_response(client.post(&url[..]).body(body)).and_then(|(status, body)| {
+                if status == reqwest::StatusCode::OK {
+                    smart_http::read_entries(&body)
+                } else {
+                    Err(Error::HttpStatus {
+                        status: status.as_u16(),
+                        message: String::from_utf8_lossy(&body).into_owned(),
+                    })
+                }
+            }),
+        )
+    } else {
+        let hash = batch.into_iter().next().unwrap();
+        let url = uri.to_string() + "/" + PIJUL_DIR_NAME + "/patches/" + &hash.to_base58();
+        debug!("downloading uri {:?}", url);
+        let patch = read_response(client.get(&(url.clone() + ".gz")[..]));
+        let sig = read_response(client.get(&(url + ".sig")[..]));
+        let uri = uri.to_string();
+        Box::new(patch.join(sig).and_then(move |((status, patch), (sig_status, sig))| {
+            if status != reqwest::StatusCode::OK {
+                return Err(Error::PatchNotFound {
+                    repo_root: uri,
+                    patch_hash: hash,
+                });
+            }
+            Ok(vec![PatchEntry {
+                hash: hash.to_base58(),
+                patch,
+                signature: if sig_status == reqwest::StatusCode::OK {
+                    Some(sig)
+                } else {
+                    None
+                },
+            }])
+        }))
+    }
+}
+
+fn unexpected_response(r: protocol::Response) -> Error {
+    Error::RemoteProtocol {
+        message: format!("unexpected response {:?}", r),
+    }
+}
+
 impl<'a> Remote<'a> {
+    /// An address (and port) from which `parse_remote` gives this
+    /// remote back, independently of the current directory.
+    pub fn address(&self) -> (String, Option<u16>) {
+        match *self {
+            Remote::Ssh { id, port, .. } => (id.to_string(), port),
+            Remote::Uri { uri } => (uri.to_string(), None),
+            Remote::Local { ref path } | Remote::Bundle { ref path } => {
+                let path = std::fs::canonicalize(path).unwrap_or(path.clone());
+                (path.to_string_lossy().into_owned(), None)
+            }
+        }
+    }
+
     pub fn session(&'a self) -> Result<Session<'a>, Error> {
         match *self {
             Remote::Local { ref path } => Ok(Session::Local(LocalSession {
                 path: path.as_path(),
             })),
+            Remote::Bundle { ref path } => Ok(Session::Bundle(BundleSession {
+                path: path.clone(),
+                bundle: Bundle::open(path)?,
+            })),
             Remote::Uri { uri } => {
                 let l = tokio::runtime::Runtime::new().unwrap();
                 let proxy_url = std::env::var("http_proxy");
@@ -1117,6 +2338,7 @@ impl<'a> Remote<'a> {
                     l,
                     uri: uri,
                     client: c,
+                    smart: true,
                 }))
             }
             Remote::Ssh {
@@ -1163,6 +2385,9 @@ impl<'a> Remote<'a> {
                     session: Some(session),
                     path,
                     pijul_cmd: &pijul_cmd,
+                    protocol: None,
+                    legacy: false,
+                    version: protocol::PROTOCOL_VERSION,
                 }))
             }
         }
@@ -1182,11 +2407,9 @@ pub fn parse_remote<'a>(
         let cap = uri.captures(remote_id).unwrap();
         if &cap[1] == "file" {
             if let Some(a) = base_path {
-                let path = a.join(&cap[2]);
-                Remote::Local { path: path }
+                local_remote(a.join(&cap[2]))
             } else {
-                let path = Path::new(&cap[2]).to_path_buf();
-                Remote::Local { path: path }
+                local_remote(Path::new(&cap[2]).to_path_buf())
             }
         } else {
             Remote::Uri { uri: remote_id }
@@ -1218,15 +2441,22 @@ pub fn parse_remote<'a>(
         }
     } else {
         if let Some(a) = base_path {
-            let path = a.join(remote_id);
-            Remote::Local { path: path }
+            local_remote(a.join(remote_id))
         } else {
-            let path = Path::new(remote_id).to_path_buf();
-            Remote::Local { path: path }
+            local_remote(Path::new(remote_id).to_path_buf())
         }
     }
 }
 
+/// Local remotes are either repositories or bundle files.
+fn local_remote<'a>(path: PathBuf) -> Remote<'a> {
+    if path.is_file() {
+        Remote::Bundle { path }
+    } else {
+        Remote::Local { path }
+    }
+}
+
 #[derive(Debug)]
 pub struct Pullable {
     pub local: HashSet<Hash>,
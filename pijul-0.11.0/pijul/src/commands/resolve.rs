@@ -0,0 +1,607 @@
+use clap::{Arg, ArgMatches, SubCommand};
+use commands::ask::read_line;
+use commands::hooks::run_hook;
+use commands::record::{prefix, record_args, record_prefixes};
+use commands::{default_explain, BasicOptions, StaticSubcommand};
+use error::Error;
+use libpijul::conflict::{Line, Markers, MarkerStyle, Region, Regions};
+use libpijul::fs_representation::{read_patch_nochanges, PIJUL_DIR_NAME};
+use libpijul::graph::Writer;
+use libpijul::{FileStatus, GenericTxn, Hash, MutTxn, NameConflict, PatchId, Transaction};
+use meta::{Global, Meta};
+use rand;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{read, remove_dir_all, remove_file, rename, symlink_metadata, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub fn invocation() -> StaticSubcommand {
+    record_args(
+        SubCommand::with_name("resolve")
+            .about("Resolve conflicts interactively, and record the resolution")
+            .arg(
+                Arg::with_name("list")
+                    .long("list")
+                    .help("List the conflicts, without resolving them."),
+            )
+            .arg(
+                Arg::with_name("prefix")
+                    .help("Only resolve the conflicts in these files or directories.")
+                    .takes_value(true)
+                    .multiple(true),
+            ),
+    )
+}
+
+/// What to do with a conflict region.
+enum Choice {
+    /// Keep one of the sides.
+    Side(usize),
+    /// Keep all sides, in order.
+    All,
+    /// Keep none of the sides.
+    Nothing,
+    /// Edit the region, with its markers, in an editor.
+    Edit,
+    /// Leave the conflict markers.
+    Skip,
+    /// Leave this conflict and all the following ones.
+    Quit,
+}
+
+/// The contents of a file being resolved. Conflict markers are
+/// written exactly as `libpijul::graph::Writer` would, so that
+/// skipped conflicts are left unchanged.
+struct Output<'a> {
+    contents: Vec<u8>,
+    new_line: bool,
+    markers: &'a mut Markers,
+}
+
+impl<'a> Output<'a> {
+    fn lines(&mut self, lines: &[Line]) {
+        for line in lines {
+            self.raw(&line.contents)
+        }
+    }
+
+    fn raw(&mut self, s: &[u8]) {
+        self.contents.extend_from_slice(s);
+        if !s.is_empty() {
+            self.new_line = s.ends_with(b"\n")
+        }
+    }
+
+    fn marker(&mut self, marker: &str) {
+        if self.new_line {
+            self.contents.extend_from_slice(&marker.as_bytes()[1..])
+        } else {
+            self.contents.extend_from_slice(marker.as_bytes())
+        }
+    }
+
+    /// Write a conflict, where `patches[i]` are the patches that
+    /// introduced side `i`.
+    fn conflict(&mut self, sides: &[Vec<Line>], patches: &[Vec<Hash>]) {
+        let marker = self.markers.begin(&patches[0]);
+        self.marker(&marker);
+        for (i, side) in sides.iter().enumerate() {
+            if i > 0 {
+                let marker = self.markers.next(&patches[i]);
+                self.marker(&marker)
+            }
+            self.lines(side)
+        }
+        let marker = self.markers.end();
+        self.marker(&marker)
+    }
+}
+
+struct Resolver<'a> {
+    repo_root: &'a Path,
+    editor: String,
+    markers: MarkerStyle,
+    /// Names of the patches, by internal id.
+    names: HashMap<PatchId, String>,
+    /// Files to record.
+    touched: Vec<PathBuf>,
+    quit: bool,
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let branch_name = opts.branch();
+    let prefixes: Vec<String> = prefix(args, &opts)?
+        .unwrap_or(Vec::new())
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    let prefixes: Vec<&str> = prefixes.iter().map(|p| p.as_str()).collect();
+
+    let meta = Meta::load(&opts.repo_root).unwrap_or(Meta::new());
+    let global = Global::load().unwrap_or(Global::new());
+    let editor = meta
+        .editor
+        .or(global.editor)
+        .or(env::var("EDITOR").ok())
+        .unwrap_or("vi".to_string());
+
+    let mut resolver = Resolver {
+        repo_root: &opts.repo_root,
+        editor,
+        markers: opts.config.conflict_markers,
+        names: HashMap::new(),
+        touched: Vec::new(),
+        quit: false,
+    };
+
+    let list = args.is_present("list");
+    let resolving = opts.with_mut_txn(0, |mut txn| {
+        let name_conflicts = txn.list_name_conflicts(&branch_name, &prefixes)?;
+        let mut files = txn.list_conflict_files(&branch_name, &prefixes)?;
+        files.sort();
+        files.dedup();
+
+        if list {
+            for conflict in name_conflicts.iter() {
+                resolver.list_name_conflict(&txn, conflict)?
+            }
+            for file in files.iter() {
+                resolver.list_file(&txn, &branch_name, file)?
+            }
+            return Ok(false);
+        }
+
+        if name_conflicts.is_empty() && files.is_empty() {
+            println!("No conflicts to resolve.");
+            return Ok(false);
+        }
+        for conflict in name_conflicts.iter() {
+            if resolver.quit {
+                break;
+            }
+            resolver.resolve_name_conflict(&mut txn, conflict)?
+        }
+        for file in files.iter() {
+            if resolver.quit {
+                break;
+            }
+            resolver.resolve_file(&mut txn, &branch_name, file)?
+        }
+        txn.commit()?;
+        Ok(true)
+    })?;
+    if !resolving {
+        return Ok(());
+    }
+
+    let mut touched = resolver.touched;
+    if touched.is_empty() {
+        println!("Nothing to record");
+        return Ok(());
+    }
+    touched.sort();
+    touched.dedup();
+    run_hook(&opts.repo_root, "pre-record", None)?;
+    record_prefixes(args, &opts, false, true, Some(touched))?;
+    Ok(())
+}
+
+impl<'a> Resolver<'a> {
+    fn patch_name<T: rand::Rng>(&mut self, txn: &MutTxn<T>, id: PatchId) -> Result<String, Error> {
+        if let Some(name) = self.names.get(&id) {
+            return Ok(name.clone());
+        }
+        let name = if let Some(hash) = txn.get_external(id) {
+            read_patch_nochanges(self.repo_root, hash)?.name
+        } else {
+            id.to_base58()
+        };
+        self.names.insert(id, name.clone());
+        Ok(name)
+    }
+
+    /// The patches that introduced the lines of `side`, in order of
+    /// appearance, as in the labels of conflict markers.
+    fn side_hashes<T: rand::Rng>(&self, txn: &MutTxn<T>, side: &[Line]) -> Vec<Hash> {
+        let mut hashes = Vec::new();
+        for key in side.iter().filter_map(|l| l.key) {
+            if let Some(h) = txn.get_external(key.patch) {
+                let h = h.to_owned();
+                if !hashes.contains(&h) {
+                    hashes.push(h)
+                }
+            }
+        }
+        hashes
+    }
+
+    /// The names of the patches that introduced the lines of `side`,
+    /// in order of appearance.
+    fn side_patches<T: rand::Rng>(
+        &mut self,
+        txn: &MutTxn<T>,
+        side: &[Line],
+    ) -> Result<Vec<String>, Error> {
+        let mut patches = Vec::new();
+        for key in side.iter().filter_map(|l| l.key) {
+            if !patches.contains(&key.patch) {
+                patches.push(key.patch)
+            }
+        }
+        patches
+            .into_iter()
+            .map(|p| self.patch_name(txn, p).map(|n| format!("{:?}", n)))
+            .collect()
+    }
+
+    fn list_name_conflict<T: rand::Rng>(
+        &mut self,
+        txn: &MutTxn<T>,
+        conflict: &NameConflict,
+    ) -> Result<(), Error> {
+        println!("name conflict: {}", conflict.name.display());
+        for &(ref name, patch) in conflict.candidates.iter() {
+            println!("    {} (from {:?})", name.display(), self.patch_name(txn, patch)?)
+        }
+        Ok(())
+    }
+
+    fn list_file<T: rand::Rng>(
+        &mut self,
+        txn: &MutTxn<T>,
+        branch_name: &str,
+        file: &Path,
+    ) -> Result<(), Error> {
+        let is_zombie = if let Ok(inode) = txn.find_inode(file) {
+            txn.get_inodes(inode).map(|h| h.status == FileStatus::Zombie).unwrap_or(false)
+        } else {
+            false
+        };
+        if is_zombie {
+            println!("deleted file: {}", file.display())
+        }
+        if let Some(regions) = file_regions(txn, self.repo_root, branch_name, file)? {
+            let n = regions.regions.iter().filter(|r| !is_lines(r)).count();
+            if n > 0 {
+                println!("conflicts: {} ({} regions)", file.display(), n)
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_name_conflict<T: rand::Rng>(
+        &mut self,
+        txn: &mut MutTxn<T>,
+        conflict: &NameConflict,
+    ) -> Result<(), Error> {
+        println!("Several files are named {}:", conflict.name.display());
+        for (i, &(ref name, patch)) in conflict.candidates.iter().enumerate() {
+            println!(
+                "  {}. {} (from {:?})",
+                i + 1,
+                name.display(),
+                self.patch_name(txn, patch)?
+            )
+        }
+        let n = conflict.candidates.len();
+        let keep = loop {
+            let prompt = format!("Which one gets the name? [1-{}, s(kip), q(uit)] ", n);
+            match read_line(&prompt).trim() {
+                "" | "s" => return Ok(()),
+                "q" => {
+                    self.quit = true;
+                    return Ok(());
+                }
+                x => match x.parse::<usize>() {
+                    Ok(i) if i >= 1 && i <= n => break i - 1,
+                    _ => {}
+                },
+            }
+        };
+        for (i, &(ref name, _)) in conflict.candidates.iter().enumerate() {
+            let path = self.repo_root.join(name);
+            let is_dir = symlink_metadata(&path)?.is_dir();
+            if i == keep {
+                txn.move_file(name, &conflict.name, is_dir)?;
+                rename(&path, self.repo_root.join(&conflict.name))?;
+                self.touched.push(conflict.name.clone());
+                continue;
+            }
+            let prompt = format!(
+                "Keep {} under that name, or delete it? [k(eep), d(elete)] ",
+                name.display()
+            );
+            loop {
+                match read_line(&prompt).trim() {
+                    "" | "k" => {
+                        // Record the new name.
+                        let inode = txn.find_inode(name)?;
+                        txn.mark_inode_moved(inode);
+                        break;
+                    }
+                    "d" => {
+                        txn.remove_file(name)?;
+                        if is_dir {
+                            remove_dir_all(&path)?
+                        } else {
+                            remove_file(&path)?
+                        }
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            self.touched.push(name.clone())
+        }
+        Ok(())
+    }
+
+    fn resolve_file<T: rand::Rng>(
+        &mut self,
+        txn: &mut MutTxn<T>,
+        branch_name: &str,
+        file: &Path,
+    ) -> Result<(), Error> {
+        let inode = if let Ok(inode) = txn.find_inode(file) {
+            inode
+        } else {
+            // This file is probably involved in a name conflict that
+            // has not been resolved.
+            println!("Skipping {}, which is not in the repository", file.display());
+            return Ok(());
+        };
+        let header = txn.get_inodes(inode).unwrap().clone();
+
+        if header.status == FileStatus::Zombie {
+            println!(
+                "{} was deleted, but also edited by a patch unaware of the deletion.",
+                file.display()
+            );
+            loop {
+                match read_line("Keep it? [k(eep), d(elete), s(kip), q(uit)] ").trim() {
+                    "k" => {
+                        txn.add_file(file, header.metadata.is_dir())?;
+                        self.touched.push(file.to_path_buf());
+                        break;
+                    }
+                    "d" => {
+                        let path = self.repo_root.join(file);
+                        txn.remove_file(file)?;
+                        if header.metadata.is_dir() {
+                            remove_dir_all(&path)?
+                        } else {
+                            remove_file(&path)?
+                        }
+                        self.touched.push(file.to_path_buf());
+                        return Ok(());
+                    }
+                    "" | "s" => return Ok(()),
+                    "q" => {
+                        self.quit = true;
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let regions = if let Some(regions) = file_regions(txn, self.repo_root, branch_name, file)? {
+            regions
+        } else {
+            return Ok(());
+        };
+        if !regions.has_conflicts() {
+            return Ok(());
+        }
+        if has_unrecorded_changes(txn, self.repo_root, branch_name, file)? {
+            println!("Skipping {}, which has unrecorded changes", file.display());
+            return Ok(());
+        }
+
+        let n = regions.regions.iter().filter(|r| !is_lines(r)).count();
+        let mut markers = Markers::new(self.markers, Some(self.repo_root));
+        let mut out = Output {
+            contents: Vec::new(),
+            new_line: true,
+            markers: &mut markers,
+        };
+        let mut changed = false;
+        let mut i = 0;
+        for region in regions.regions.iter() {
+            let sides = match *region {
+                Region::Lines(ref lines) => {
+                    out.lines(lines);
+                    continue;
+                }
+                Region::Conflict(ref sides) => sides,
+            };
+            i += 1;
+            let patches: Vec<_> = sides.iter().map(|s| self.side_hashes(txn, s)).collect();
+            if self.quit {
+                out.conflict(sides, &patches);
+                continue;
+            }
+            println!("\nConflict {}/{} in {}:", i, n, file.display());
+            let choice = self.ask_region(txn, region, sides)?;
+            match choice {
+                Choice::Side(j) => out.lines(&sides[j]),
+                Choice::All => {
+                    for side in sides.iter() {
+                        out.lines(side)
+                    }
+                }
+                Choice::Nothing => {}
+                Choice::Edit => {
+                    let mut region_markers = Markers::new(self.markers, Some(self.repo_root));
+                    let contents = {
+                        let mut region = Output {
+                            contents: Vec::new(),
+                            new_line: out.new_line,
+                            markers: &mut region_markers,
+                        };
+                        region.conflict(sides, &patches);
+                        region.contents
+                    };
+                    let edited = self.edit(&contents)?;
+                    out.raw(&edited)
+                }
+                Choice::Skip | Choice::Quit => {
+                    out.conflict(sides, &patches);
+                    if let Choice::Quit = choice {
+                        self.quit = true
+                    }
+                    continue;
+                }
+            }
+            changed = true
+        }
+        if changed {
+            let mut f = File::create(self.repo_root.join(file))?;
+            f.write_all(&out.contents)?;
+            self.touched.push(file.to_path_buf())
+        }
+        Ok(())
+    }
+
+    fn ask_region<T: rand::Rng>(
+        &mut self,
+        txn: &MutTxn<T>,
+        region: &Region,
+        sides: &[Vec<Line>],
+    ) -> Result<Choice, Error> {
+        let prompt = if region.is_zombie() {
+            let patches = self.side_patches(txn, &sides[0])?;
+            println!(
+                "Lines deleted by a patch, but with their context edited by {}:",
+                patches.join(", ")
+            );
+            print_side(&sides[0]);
+            "Keep these lines? [y(es), n(o), e(dit), s(kip), q(uit)] ".to_string()
+        } else {
+            for (i, side) in sides.iter().enumerate() {
+                let patches = self.side_patches(txn, side)?;
+                if patches.is_empty() {
+                    println!("Side {} (empty):", i + 1)
+                } else {
+                    println!("Side {}, from {}:", i + 1, patches.join(", "))
+                }
+                print_side(side)
+            }
+            format!(
+                "Which side to keep? [1-{}, a(ll), n(one), e(dit), s(kip), q(uit)] ",
+                sides.len()
+            )
+        };
+        loop {
+            match read_line(&prompt).trim() {
+                "y" if region.is_zombie() => return Ok(Choice::Side(0)),
+                "a" => return Ok(Choice::All),
+                "n" => return Ok(Choice::Nothing),
+                "e" => return Ok(Choice::Edit),
+                "" | "s" => return Ok(Choice::Skip),
+                "q" => return Ok(Choice::Quit),
+                x => match x.parse::<usize>() {
+                    Ok(i) if i >= 1 && i <= sides.len() => return Ok(Choice::Side(i - 1)),
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    /// Edit `contents` in the editor, and return the result.
+    fn edit(&self, contents: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut conflict_file = self.repo_root.to_path_buf();
+        conflict_file.push(PIJUL_DIR_NAME);
+        conflict_file.push("CONFLICT");
+        File::create(&conflict_file)?.write_all(contents)?;
+
+        let mut editor_cmd = self.editor
+            .trim()
+            .split(" ")
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        editor_cmd.push(conflict_file.clone().into_os_string());
+        process::Command::new(&editor_cmd[0])
+            .args(&editor_cmd[1..])
+            .current_dir(self.repo_root)
+            .status()
+            .map_err(|e| Error::CannotSpawnEditor {
+                editor: self.editor.clone(),
+                cause: e.to_string(),
+            })?;
+        let edited = read(&conflict_file)?;
+        remove_file(&conflict_file)?;
+        Ok(edited)
+    }
+}
+
+/// The regions of `file`, or `None` if it is a directory or not in
+/// the repository.
+pub fn file_regions<T: Transaction, R>(
+    txn: &GenericTxn<T, R>,
+    repo_root: &Path,
+    branch_name: &str,
+    file: &Path,
+) -> Result<Option<Regions>, Error> {
+    let branch = if let Some(branch) = txn.get_branch(branch_name) {
+        branch
+    } else {
+        return Ok(None);
+    };
+    let inode = if let Ok(inode) = txn.find_inode(file) {
+        inode
+    } else {
+        return Ok(None);
+    };
+    let key = match txn.get_inodes(inode) {
+        Some(header) if !header.metadata.is_dir() => header.key,
+        _ => return Ok(None),
+    };
+    let mut graph = txn.retrieve(&branch, key);
+    let markers = Markers::new(txn.config.conflict_markers, Some(repo_root));
+    let mut regions = Regions::with_markers(markers);
+    txn.output_file(&branch, &mut regions, &mut graph, &mut Vec::new())?;
+    Ok(Some(regions))
+}
+
+/// Whether `file` was changed since the last output of the
+/// repository, in which case it must not be overwritten.
+pub fn has_unrecorded_changes<T: Transaction, R>(
+    txn: &GenericTxn<T, R>,
+    repo_root: &Path,
+    branch_name: &str,
+    file: &Path,
+) -> Result<bool, Error> {
+    let branch = txn.get_branch(branch_name).unwrap();
+    let inode = txn.find_inode(file)?;
+    let key = txn.get_inodes(inode).unwrap().key;
+    let mut graph = txn.retrieve(&branch, key);
+    let markers = Markers::new(txn.config.conflict_markers, Some(repo_root));
+    let mut w = Writer::with_markers(Vec::new(), markers);
+    txn.output_file(&branch, &mut w, &mut graph, &mut Vec::new())?;
+    let current = read(repo_root.join(file)).unwrap_or(Vec::new());
+    Ok(current != w.w)
+}
+
+fn is_lines(region: &Region) -> bool {
+    if let Region::Lines(_) = *region {
+        true
+    } else {
+        false
+    }
+}
+
+fn print_side(side: &[Line]) {
+    for line in side {
+        let s = String::from_utf8_lossy(&line.contents);
+        println!("  | {}", s.trim_right_matches('\n'))
+    }
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
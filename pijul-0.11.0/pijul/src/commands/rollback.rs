@@ -37,8 +37,7 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let patches: Option<HashSet<Hash>> = args.values_of("patch")
         .map(|ps| ps.map(|x| Hash::from_base58(x).unwrap()).collect());
 
-    let mut increase = 409600;
-    let repo = opts.open_and_grow_repo(increase)?;
+    let repo = opts.open_repo()?;
     let branch_name = opts.branch();
 
     let mut patches: HashMap<_, _> = if let Some(ref patches) = patches {
@@ -95,8 +94,7 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
 
     let mut selected = Vec::new();
     loop {
-        let hash = if let Some((hash, patch)) = patches.iter().next() {
-            increase += patch.size_upper_bound() as u64;
+        let hash = if let Some((hash, _)) = patches.iter().next() {
             hash.to_owned()
         } else {
             break;
@@ -170,29 +168,25 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
     };
 
     // Apply the inverse patch.
-    loop {
-        let app = if !is_current_branch {
-            apply_resize_no_output(
-                &opts.repo_root,
-                &opts.branch(),
-                iter::once(&hash),
-                |_, _| (),
-            )
-        } else {
-            apply_resize(
-                &opts.repo_root,
-                &opts.branch(),
-                iter::once(&hash),
-                &[] as &[&str],
-                |_, _| (),
-            )
-        };
-        match app {
-            Err(ref e) if e.lacks_space() => {}
-            Ok(()) => return Ok(()),
-            Err(e) => return Err(From::from(e)),
-        }
+    if !is_current_branch {
+        apply_resize_no_output(
+            &opts.repo_root,
+            &opts.config,
+            &opts.branch(),
+            iter::once(&hash),
+            |_, _| (),
+        )?
+    } else {
+        apply_resize(
+            &opts.repo_root,
+            &opts.config,
+            &opts.branch(),
+            iter::once(&hash),
+            &[] as &[&str],
+            |_, _| (),
+        )?
     }
+    Ok(())
 }
 
 fn load_patch(repo_root: &Path, ext: HashRef) -> Patch {
@@ -0,0 +1,445 @@
+use clap::{Arg, ArgMatches, SubCommand};
+use commands::remote::{apply_patches_to_branch, Remote};
+use commands::protocol;
+use commands::smart_http::{self, PatchEntry};
+use commands::{default_explain, get_wd, StaticSubcommand};
+use error::Error;
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Sink, Stream};
+use hyper;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use libpijul::blob::{is_blob_hash, BlobPointer, BlobWriter};
+use libpijul::fs_representation::{blobs_dir, find_repo_root, patches_dir, repo_dir, PIJUL_DIR_NAME};
+use libpijul::DEFAULT_BRANCH;
+use reqwest::Url;
+use std;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempdir::TempDir;
+
+pub fn invocation() -> StaticSubcommand {
+    return SubCommand::with_name("serve-http")
+        .about("Serve a repository over HTTP, including partial pulls and pushes")
+        .arg(
+            Arg::with_name("repository")
+                .long("repository")
+                .help("Repository to serve. Defaults to the current repository.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("address")
+                .long("address")
+                .help("Address to listen on.")
+                .default_value("127.0.0.1:8080")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .help(
+                    "Token allowed to push to this repository (can be given several times). \
+                     Pushes are refused if no token is given.",
+                )
+                .multiple(true)
+                .takes_value(true),
+        );
+}
+
+struct ServerState {
+    repo_root: PathBuf,
+    tokens: HashSet<String>,
+}
+
+impl ServerState {
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        if let Some(auth) = headers.get(AUTHORIZATION).and_then(|h| h.to_str().ok()) {
+            if auth.starts_with("Bearer ") {
+                return self.tokens.contains(auth["Bearer ".len()..].trim());
+            }
+        }
+        false
+    }
+}
+
+type ResponseFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    let wd = get_wd(args.value_of("repository").map(Path::new))?;
+    let repo_root = if let Some(r) = find_repo_root(&wd) {
+        r
+    } else {
+        return Err(Error::NotInARepository);
+    };
+    let address = args.value_of("address").unwrap();
+    let addr: SocketAddr = if let Ok(addr) = address.parse() {
+        addr
+    } else {
+        return Err(Error::InvalidAddress {
+            address: address.to_string(),
+        });
+    };
+    let mut tokens: HashSet<String> = args
+        .values_of("token")
+        .map(|t| t.map(|t| t.to_string()).collect())
+        .unwrap_or_else(HashSet::new);
+    if let Ok(token) = std::env::var(smart_http::TOKEN_VAR) {
+        tokens.insert(token);
+    }
+    let state = Arc::new(ServerState { repo_root, tokens });
+
+    let server = Server::try_bind(&addr)?.serve(move || {
+        let state = state.clone();
+        service_fn(move |req| handle(state.clone(), req))
+    });
+    println!("Serving on http://{}", addr);
+    hyper::rt::run(server.map_err(|e| error!("server error: {}", e)));
+    Ok(())
+}
+
+fn handle(state: Arc<ServerState>, req: Request<Body>) -> ResponseFuture {
+    debug!("{:?} {:?}", req.method(), req.uri());
+    let path = req.uri().path().to_string();
+    let query = parse_query(req.uri().query());
+    if path.starts_with(smart_http::PREFIX) {
+        let endpoint = path[smart_http::PREFIX.len()..].to_string();
+        match (req.method(), &endpoint[..]) {
+            (&Method::GET, smart_http::CHANGES) => {
+                blocking(move || respond(changes(&state.repo_root, &query)))
+            }
+            (&Method::POST, smart_http::PATCHES) => {
+                with_body(req.into_body(), move |body| {
+                    respond(patches(&state.repo_root, &body))
+                })
+            }
+            (&Method::POST, smart_http::APPLY) => {
+                if !state.is_authorized(req.headers()) {
+                    return Box::new(future::ok(status(
+                        StatusCode::UNAUTHORIZED,
+                        "Unauthorized",
+                    )));
+                }
+                let repo_root = state.repo_root.clone();
+                with_body_file(&state.repo_root, req.into_body(), move |body| {
+                    respond(apply(&repo_root, &query, body))
+                })
+            }
+            (&Method::POST, smart_http::BLOBS) => {
+                if !state.is_authorized(req.headers()) {
+                    return Box::new(future::ok(status(
+                        StatusCode::UNAUTHORIZED,
+                        "Unauthorized",
+                    )));
+                }
+                if let Some(pointer) = blob_of(&query) {
+                    receive_blob(&state.repo_root, pointer, req.into_body())
+                } else {
+                    Box::new(future::ok(status(StatusCode::BAD_REQUEST, "Bad request")))
+                }
+            }
+            _ => Box::new(future::ok(status(StatusCode::NOT_FOUND, "Not found"))),
+        }
+    } else if req.method() == &Method::GET {
+        blocking(move || match static_file(&state.repo_root, &path) {
+            Ok(file) => send_file(file),
+            Err(e) => respond(Err(e)),
+        })
+    } else {
+        Box::new(future::ok(status(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+        )))
+    }
+}
+
+/// Run `f` in its own thread, so that reading patches and working on
+/// the pristine don't block the event loop.
+fn blocking<F>(f: F) -> ResponseFuture
+where
+    F: FnOnce() -> Response<Body> + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || sender.send(f()).unwrap_or(()));
+    Box::new(
+        receiver.or_else(|_| -> Result<Response<Body>, hyper::Error> {
+            Ok(status(StatusCode::INTERNAL_SERVER_ERROR, "Internal error"))
+        }),
+    )
+}
+
+/// Read the body of a request, and then run `f` on it with
+/// `blocking`. Bodies larger than `smart_http::MAX_BODY_SIZE` are
+/// refused.
+fn with_body<F>(body: Body, f: F) -> ResponseFuture
+where
+    F: FnOnce(Vec<u8>) -> Response<Body> + Send + 'static,
+{
+    Box::new(
+        body.fold(Some(Vec::new()), |body, chunk| -> Result<_, hyper::Error> {
+            Ok(body.and_then(|mut body: Vec<u8>| {
+                if body.len() + chunk.len() > smart_http::MAX_BODY_SIZE {
+                    None
+                } else {
+                    body.extend(&chunk[..]);
+                    Some(body)
+                }
+            }))
+        }).and_then(move |body| -> ResponseFuture {
+            if let Some(body) = body {
+                blocking(move || f(body))
+            } else {
+                Box::new(future::ok(status(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "Payload too large",
+                )))
+            }
+        }),
+    )
+}
+
+/// Write the body of a request to a temporary file as it arrives,
+/// and then run `f` on that file with `blocking`. Unlike `with_body`,
+/// this doesn't bound the size of the body, which is never held in
+/// memory.
+fn with_body_file<F>(repo_root: &Path, body: Body, f: F) -> ResponseFuture
+where
+    F: FnOnce(BufReader<File>) -> Response<Body> + Send + 'static,
+{
+    let tmp_dir = match TempDir::new_in(repo_dir(repo_root), "body") {
+        Ok(tmp_dir) => tmp_dir,
+        Err(e) => return Box::new(future::ok(respond(Err(e.into())))),
+    };
+    let path = tmp_dir.path().join("body");
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(e) => return Box::new(future::ok(respond(Err(e.into())))),
+    };
+    Box::new(
+        body.map_err(Error::from)
+            .fold(file, |mut file, chunk| -> Result<_, Error> {
+                file.write_all(&chunk)?;
+                Ok(file)
+            }).then(move |result| -> ResponseFuture {
+                if let Err(e) = result {
+                    return Box::new(future::ok(respond(Err(e))));
+                }
+                blocking(move || {
+                    // The directory is removed after `f` returns.
+                    let _tmp_dir = tmp_dir;
+                    match File::open(&path) {
+                        Ok(file) => f(BufReader::new(file)),
+                        Err(e) => respond(Err(e.into())),
+                    }
+                })
+            }),
+    )
+}
+
+fn parse_query(query: Option<&str>) -> Vec<(String, String)> {
+    if let Some(query) = query {
+        if let Ok(url) = Url::parse(&format!("http://localhost/?{}", query)) {
+            return url.query_pairs().into_owned().collect();
+        }
+    }
+    Vec::new()
+}
+
+fn branch_of(query: &[(String, String)]) -> &str {
+    query
+        .iter()
+        .filter(|&&(ref k, _)| k == "branch")
+        .map(|&(_, ref v)| v.as_str())
+        .next()
+        .unwrap_or(DEFAULT_BRANCH)
+}
+
+fn changes(repo_root: &Path, query: &[(String, String)]) -> Result<Vec<u8>, Error> {
+    let paths: Vec<&str> = query
+        .iter()
+        .filter(|&&(ref k, _)| k == "path")
+        .map(|&(_, ref v)| v.as_str())
+        .collect();
+    let remote = Remote::Local {
+        path: repo_root.to_path_buf(),
+    };
+    let mut session = remote.session()?;
+    let mut changes: Vec<_> = session
+        .changes(branch_of(query), &paths)?
+        .into_iter()
+        .collect();
+    changes.sort_by_key(|&(_, t)| t);
+    let mut body = Vec::new();
+    for (hash, t) in changes {
+        body.extend(format!("{}:{}\n", hash.to_base58(), t).as_bytes())
+    }
+    Ok(body)
+}
+
+fn patches(repo_root: &Path, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let pdir = patches_dir(repo_root);
+    let mut response = Vec::new();
+    for hash in smart_http::read_hashes(body)? {
+        let entry = PatchEntry::load(&pdir, &hash).map_err(|_| Error::PatchNotFound {
+            repo_root: repo_root.to_string_lossy().into_owned(),
+            patch_hash: hash.clone(),
+        })?;
+        smart_http::write_entry(&mut response, &entry)?
+    }
+    Ok(response)
+}
+
+fn apply<R: BufRead>(
+    repo_root: &Path,
+    query: &[(String, String)],
+    body: R,
+) -> Result<Vec<u8>, Error> {
+    let pdir = patches_dir(repo_root);
+    let mut hashes = HashSet::new();
+    smart_http::for_each_entry(body, |entry| {
+        hashes.insert(entry.save(&pdir)?);
+        Ok(())
+    })?;
+    apply_patches_to_branch(repo_root, branch_of(query), &hashes)?;
+    Ok(Vec::new())
+}
+
+/// The blob named by the `hash` and `size` parameters of a request.
+fn blob_of(query: &[(String, String)]) -> Option<BlobPointer> {
+    let param = |name: &str| {
+        query
+            .iter()
+            .filter(|&&(ref k, _)| k == name)
+            .map(|&(_, ref v)| v.as_str())
+            .next()
+    };
+    let hash = param("hash")?;
+    if !is_blob_hash(hash) {
+        return None;
+    }
+    Some(BlobPointer {
+        hash: hash.to_lowercase(),
+        size: param("size")?.parse().ok()?,
+    })
+}
+
+/// Write the blob of `pointer` to the store as its contents arrive
+/// in `body`, refusing bodies longer than the blob.
+fn receive_blob(repo_root: &Path, pointer: BlobPointer, body: Body) -> ResponseFuture {
+    let writer = match BlobWriter::new(repo_root, &pointer) {
+        Ok(writer) => writer,
+        Err(e) => return Box::new(future::ok(respond(Err(e.into())))),
+    };
+    Box::new(
+        body.map_err(Error::from)
+            .fold(writer, |mut writer, chunk| -> Result<_, Error> {
+                if writer.write(&chunk)? {
+                    Ok(writer)
+                } else {
+                    Err(Error::WrongHash)
+                }
+            }).and_then(|writer| -> Result<_, Error> {
+                if writer.finish()? {
+                    Ok(Vec::new())
+                } else {
+                    Err(Error::WrongHash)
+                }
+            }).then(|result| -> Result<Response<Body>, hyper::Error> { Ok(respond(result)) }),
+    )
+}
+
+/// Serve the files needed by clients that don't know about the
+/// smart protocol (or by older versions of Pijul), i.e. patches,
+/// signatures, changes files and the tag registry, as well as the
+/// blobs of large files.
+fn static_file(repo_root: &Path, path: &str) -> Result<File, Error> {
+    let not_found = || Error::InvalidPath {
+        path: path.to_string(),
+    };
+    let prefix = format!("/{}/", PIJUL_DIR_NAME);
+    if !path.starts_with(&prefix) {
+        return Err(not_found());
+    }
+    let rel = &path[prefix.len()..];
+    let file = if rel.starts_with("patches/") {
+        let name = &rel["patches/".len()..];
+        if name.contains('/') || !(name.ends_with(".gz") || name.ends_with(".sig")) {
+            return Err(not_found());
+        }
+        patches_dir(repo_root).join(name)
+    } else if rel.starts_with("blobs/") {
+        let name = &rel["blobs/".len()..];
+        if !is_blob_hash(name) {
+            return Err(not_found());
+        }
+        blobs_dir(repo_root).join(name)
+    } else if (rel.starts_with("changes.") && !rel.contains('/')) || rel == "tags.toml" {
+        repo_dir(repo_root).join(rel)
+    } else {
+        return Err(not_found());
+    };
+    if file.components().any(|c| c == std::path::Component::ParentDir) {
+        return Err(not_found());
+    }
+    File::open(&file).map_err(|_| not_found())
+}
+
+/// Send `file` in parts, read by another thread as the client
+/// consumes them, so that blobs aren't held in memory.
+fn send_file(mut file: File) -> Response<Body> {
+    let (sender, receiver) = mpsc::channel(1);
+    std::thread::spawn(move || {
+        let mut sender = sender;
+        loop {
+            let mut data = Vec::new();
+            if (&mut file)
+                .take(protocol::PART_SIZE as u64)
+                .read_to_end(&mut data)
+                .is_err() || data.is_empty()
+            {
+                // A read error truncates the body, which clients
+                // detect.
+                break;
+            }
+            match sender.send(data).wait() {
+                Ok(s) => sender = s,
+                Err(_) => break,
+            }
+        }
+    });
+    Response::new(Body::wrap_stream(
+        receiver.map_err(|()| std::io::Error::from(std::io::ErrorKind::Other)),
+    ))
+}
+
+fn status(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+fn respond(result: Result<Vec<u8>, Error>) -> Response<Body> {
+    match result {
+        Ok(body) => Response::new(Body::from(body)),
+        Err(e) => {
+            debug!("error: {:?}", e);
+            let code = match e {
+                Error::PatchNotFound { .. } | Error::InvalidPath { .. } => StatusCode::NOT_FOUND,
+                Error::WrongHash
+                | Error::FileNotInRepository { .. }
+                | Error::Bincode(_)
+                | Error::NoSuchBranch => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            status(code, &format!("{}", e))
+        }
+    }
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
@@ -7,10 +7,11 @@ use std::string::String;
 use libpijul::fs_representation::{find_repo_root, pristine_dir, read_patch};
 use libpijul::{Hash, Repository, DEFAULT_BRANCH};
 use error::Error;
+use super::porcelain::{format_args, is_json, print_json, Dependencies};
 use super::{default_explain, get_current_branch, get_wd, StaticSubcommand, validate_base58};
 
 pub fn invocation() -> StaticSubcommand {
-    return SubCommand::with_name("show-dependencies")
+    return format_args(SubCommand::with_name("show-dependencies"))
         .about("Print the patch dependencies using the DOT syntax in stdout")
         .arg(
             Arg::with_name("hash")
@@ -59,6 +60,7 @@ enum Target<'a> {
 pub struct Params<'a> {
     pub repository: Option<&'a Path>,
     target: Target<'a>,
+    json: bool,
 }
 
 pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<Params<'a>, Error> {
@@ -76,6 +78,7 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<Params<'a>, Error> {
     Ok(Params {
         repository: args.value_of("repository").map(|x| Path::new(x)),
         target: target,
+        json: is_json(args),
     })
 }
 
@@ -110,6 +113,51 @@ fn label_sanitize(str: String) -> String {
     res
 }
 
+/// Either prints the graph in DOT, or collects it for JSON output.
+struct Output {
+    json: Option<Vec<Dependencies>>,
+}
+
+impl Output {
+    fn start(&self) {
+        if self.json.is_none() {
+            println!("digraph dependencies {{");
+            println!("  graph [rankdir=LR];");
+        }
+    }
+
+    /// Output a node and, if `edges` is true, its edges to `deps`
+    /// (in the JSON output, dependencies are always included).
+    fn node(&mut self, hash: String, name: String, is_tag: bool, deps: &HashSet<Hash>, edges: bool) {
+        if let Some(ref mut json) = self.json {
+            let mut dependencies: Vec<_> = deps.iter().map(|d| d.to_base58()).collect();
+            dependencies.sort();
+            json.push(Dependencies {
+                hash,
+                name,
+                tag: is_tag,
+                dependencies,
+            });
+            return;
+        }
+        patch_node(hash.clone(), name, is_tag);
+        if edges {
+            for hash_dep in deps {
+                println!("  N{} -> N{}", hash, hash_dep.to_base58());
+            }
+        }
+    }
+
+    fn end(&self) -> Result<(), Error> {
+        if let Some(ref json) = self.json {
+            print_json(json)
+        } else {
+            println!("}}");
+            Ok(())
+        }
+    }
+}
+
 pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let args = parse_args(args)?;
     let wd = get_wd(args.repository)?;
@@ -121,6 +169,9 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let repo_dir = pristine_dir(&target);
     let repo = Repository::open(&repo_dir, None)?;
     let txn = repo.txn_begin()?;
+    let mut out = Output {
+        json: if args.json { Some(Vec::new()) } else { None },
+    };
 
     match args.target {
         Target::Branch(branch_arg) => {
@@ -133,25 +184,22 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
             };
 
             if let Some(branch) = txn.get_branch(&branch_name) {
-                println!("digraph dependencies {{");
-                println!("  graph [rankdir=LR];");
+                out.start();
 
                 for (_, hash) in txn.rev_iter_applied(&branch, None) {
                     let hash_ext = txn.get_external(hash).unwrap();
                     let patch = read_patch(&target, hash_ext)?;
+                    let deps = txn.minimize_deps(patch.dependencies());
 
-                    patch_node(
+                    out.node(
                         hash_ext.to_base58(),
                         patch.header().name.clone(),
                         patch.is_tag(),
+                        &deps,
+                        true,
                     );
-
-                    let deps = txn.minimize_deps(patch.dependencies());
-                    for hash_dep in deps {
-                        println!("  N{} -> N{}", hash_ext.to_base58(), hash_dep.to_base58());
-                    }
                 }
-                println!("}}");
+                out.end()?;
             }
         }
         Target::Hash(hashes, depth) => {
@@ -162,8 +210,7 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
                 .collect();
             let mut next = Vec::new();
 
-            println!("digraph dependencies {{");
-            println!("  graph [rankdir=LR];");
+            out.start();
 
             for _ in 0..depth {
                 for hash in vec.drain(..) {
@@ -171,17 +218,18 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
                     seen.insert(hash.clone());
                     let hash_ext = hash.as_ref();
                     let patch = read_patch(&target, hash_ext)?;
+                    let deps = txn.minimize_deps(patch.dependencies());
 
-                    patch_node(
+                    out.node(
                         hash_ext.to_base58(),
                         patch.header().name.clone(),
                         patch.is_tag(),
+                        &deps,
+                        true,
                     );
 
-                    let deps = txn.minimize_deps(patch.dependencies());
                     for hash_dep in deps.iter() {
                         debug!("dep: {:?}", hash_dep);
-                        println!("  N{} -> N{}", hash_ext.to_base58(), hash_dep.to_base58());
 
                         let h = hash_dep.to_owned();
 
@@ -203,16 +251,19 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
             for hash in vec.drain(..) {
                 let hash_ext = hash.as_ref();
                 let patch = read_patch(&target, hash_ext)?;
+                let deps = txn.minimize_deps(patch.dependencies());
 
-                patch_node(
+                out.node(
                     hash_ext.to_base58(),
                     patch.header().name.clone(),
                     patch.is_tag(),
+                    &deps,
+                    false,
                 );
             }
 
             // and we are done
-            println!("}}");
+            out.end()?;
         }
     }
 
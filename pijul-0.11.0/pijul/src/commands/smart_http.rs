@@ -0,0 +1,181 @@
+//! Definitions shared by the smart HTTP client (`UriSession`) and the
+//! `pijul serve-http` server.
+//!
+//! All requests live under `<repository>/.pijul/v1/`:
+//!
+//! - `GET changes?branch=<branch>&path=<path>…` returns the patches
+//!   applied to a branch, in the format of the changes file (one
+//!   `<hash>:<apply timestamp>` per line). If `path` is given, only
+//!   the patches touching one of the paths are listed.
+//!
+//! - `POST patches`, with a body containing one base58 patch hash per
+//!   line, returns a stream of `PatchEntry`s, one per requested patch.
+//!
+//! - `POST apply?branch=<branch>`, with a stream of `PatchEntry`s as
+//!   its body, applies these patches to the branch. This request must
+//!   carry an `Authorization: Bearer <token>` header. The body is
+//!   written to a temporary file as it arrives, and the entries are
+//!   then read one at a time, so pushes aren't bounded in size.
+//!
+//! - `POST blobs?hash=<hash>&size=<size>`, with the contents of a
+//!   large file as its body, adds them to the blob store. This
+//!   request must also be authorized. The body is written to the
+//!   store as it arrives, and can't be longer than `size`. Blobs are
+//!   downloaded as static files, from `.pijul/blobs/<hash>`.
+//!
+//! A `PatchEntry` is encoded with bincode, and streams are just
+//! concatenations of entries. The server refuses `patches` request
+//! bodies larger than `MAX_BODY_SIZE`.
+
+use bincode;
+use error::Error;
+use libpijul::fs_representation::patch_file_name;
+use libpijul::patch::read_signature_file;
+use libpijul::{Hash, Patch};
+use std::fs::{metadata, rename, File};
+use std::io::{BufRead, Read, Write};
+use serde::Serialize;
+use std::path::Path;
+use tempdir::TempDir;
+
+/// Prefix of all smart HTTP requests, relative to the repository URI.
+pub const PREFIX: &'static str = "/.pijul/v1/";
+pub const CHANGES: &'static str = "changes";
+pub const PATCHES: &'static str = "patches";
+pub const APPLY: &'static str = "apply";
+pub const BLOBS: &'static str = "blobs";
+
+/// Largest request body accepted by `pijul serve-http`.
+pub const MAX_BODY_SIZE: usize = 1 << 28;
+
+/// Environment variable read by the client to authenticate pushes.
+pub const TOKEN_VAR: &'static str = "PIJUL_HTTP_TOKEN";
+
+/// A patch, as sent over the wire, along with its signature file (if
+/// any). This is also used by `pijul protocol`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatchEntry {
+    pub hash: String,
+    pub patch: Vec<u8>,
+    pub signature: Option<Vec<u8>>,
+}
+
+impl PatchEntry {
+    /// Read patch `hash` and its signature from `patches_dir`.
+    pub fn load(patches_dir: &Path, hash: &Hash) -> Result<Self, Error> {
+        let path = patches_dir.join(&patch_file_name(hash.as_ref()));
+        let mut patch = Vec::new();
+        File::open(&path)?.read_to_end(&mut patch)?;
+        Ok(PatchEntry {
+            hash: hash.to_base58(),
+            patch,
+            signature: Self::load_signature(patches_dir, hash)?,
+        })
+    }
+
+    /// Read the signature file of patch `hash`, if there is one.
+    pub fn load_signature(patches_dir: &Path, hash: &Hash) -> Result<Option<Vec<u8>>, Error> {
+        let mut path = patches_dir.join(&patch_file_name(hash.as_ref()));
+        path.set_extension("sig");
+        if let Ok(mut f) = File::open(&path) {
+            let mut sig = Vec::new();
+            f.read_to_end(&mut sig)?;
+            Ok(Some(sig))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Size of the entry of patch `hash`, without reading it.
+    pub fn size_of(patches_dir: &Path, hash: &Hash) -> Result<u64, Error> {
+        let mut path = patches_dir.join(&patch_file_name(hash.as_ref()));
+        let mut size = metadata(&path)?.len();
+        path.set_extension("sig");
+        if let Ok(meta) = metadata(&path) {
+            size += meta.len()
+        }
+        Ok(size)
+    }
+
+    /// Size of the patch and of its signature.
+    pub fn size(&self) -> u64 {
+        (self.patch.len() + self.signature.as_ref().map(|s| s.len()).unwrap_or(0)) as u64
+    }
+
+    /// Check the hash of this patch, and that its signatures are
+    /// valid signatures of that hash, and then write it to
+    /// `patches_dir`.
+    pub fn save(&self, patches_dir: &Path) -> Result<Hash, Error> {
+        let hash = if let Some(hash) = Hash::from_base58(&self.hash) {
+            hash
+        } else {
+            return Err(Error::WrongHash);
+        };
+        let (actual_hash, _, _) = Patch::from_reader_compressed(&mut &self.patch[..])?;
+        if actual_hash != hash {
+            return Err(Error::WrongHash);
+        }
+        if let Some(ref signature) = self.signature {
+            // `read_signature_file` verifies each signature against
+            // the hash written in the file, which must be this
+            // patch's.
+            let signatures = read_signature_file(&mut &signature[..])?;
+            if Hash::from_base58(&signatures.hash).as_ref() != Some(&hash) {
+                return Err(Error::WrongHash);
+            }
+        }
+        let tmp_dir = TempDir::new_in(patches_dir, "pijul_patch")?;
+        let tmp_file = tmp_dir.path().join("patch");
+        let mut local_file = patches_dir.join(&patch_file_name(hash.as_ref()));
+        {
+            let mut f = File::create(&tmp_file)?;
+            f.write_all(&self.patch)?;
+            f.flush()?;
+        }
+        rename(&tmp_file, &local_file)?;
+        if let Some(ref signature) = self.signature {
+            local_file.set_extension("sig");
+            let mut f = File::create(&local_file)?;
+            f.write_all(signature)?;
+        }
+        Ok(hash)
+    }
+}
+
+/// Append an entry to a stream.
+pub fn write_entry<W: Write, E: Serialize>(w: &mut W, entry: &E) -> Result<(), Error> {
+    bincode::serialize_into(w, entry)?;
+    Ok(())
+}
+
+/// Decode a stream of entries.
+pub fn read_entries(body: &[u8]) -> Result<Vec<PatchEntry>, Error> {
+    let mut entries = Vec::new();
+    for_each_entry(body, |entry| {
+        entries.push(entry);
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Decode a stream of entries one at a time, calling `f` on each of
+/// them, so that the whole stream doesn't need to be in memory.
+pub fn for_each_entry<R, F>(mut body: R, mut f: F) -> Result<(), Error>
+where
+    R: BufRead,
+    F: FnMut(PatchEntry) -> Result<(), Error>,
+{
+    while !body.fill_buf()?.is_empty() {
+        f(bincode::deserialize_from(&mut body)?)?
+    }
+    Ok(())
+}
+
+/// Parse the body of a `patches` request.
+pub fn read_hashes(body: &[u8]) -> Result<Vec<Hash>, Error> {
+    let body = ::std::str::from_utf8(body).map_err(|_| Error::WrongHash)?;
+    body.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| Hash::from_base58(l.trim()).ok_or(Error::WrongHash))
+        .collect()
+}
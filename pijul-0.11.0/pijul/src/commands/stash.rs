@@ -0,0 +1,328 @@
+use super::record;
+use super::revert::output_repository;
+use chrono;
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use commands::tag_registry::dependency_closure;
+use commands::{default_explain, BasicOptions, StaticSubcommand};
+use error::Error;
+use libpijul::blob::store_records;
+use libpijul::fs_representation::stash_dir;
+use libpijul::patch::{PatchFlags, Record, UnsignedPatch};
+use libpijul::{Hash, Patch, Prefixes, RecordState};
+use std;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, read_dir, remove_dir, remove_file, symlink_metadata, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+pub fn invocation() -> StaticSubcommand {
+    return SubCommand::with_name("stash")
+        .about("Set unrecorded changes aside, and apply them later")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("save")
+                .about("Save the unrecorded changes to a new stash, and revert them")
+                .arg(
+                    Arg::with_name("message")
+                        .short("m")
+                        .long("message")
+                        .help("Description of the stash.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .long("repository")
+                        .help("Local repository.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .long("branch")
+                        .help("The branch to compare the working copy with. Defaults to the current branch.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the stashes, the most recent first")
+                .arg(
+                    Arg::with_name("repository")
+                        .long("repository")
+                        .help("Local repository.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Apply a stash to the working copy, as unrecorded changes")
+                .arg(
+                    Arg::with_name("stash")
+                        .help("The stash to apply, by number or hash. Defaults to the most recent one.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .long("repository")
+                        .help("Local repository.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .long("branch")
+                        .help("The branch to apply the stash on. Defaults to the current branch.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("drop")
+                .about("Delete a stash")
+                .arg(
+                    Arg::with_name("stash")
+                        .help("The stash to delete, by number or hash. Defaults to the most recent one.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .long("repository")
+                        .help("Local repository.")
+                        .takes_value(true),
+                ),
+        );
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Error> {
+    match args.subcommand() {
+        ("save", Some(args)) => save(args),
+        ("list", Some(args)) => list(args),
+        ("apply", Some(args)) => apply(args),
+        ("drop", Some(args)) => drop_stash(args),
+        _ => Ok(()),
+    }
+}
+
+/// A stash, i.e. a patch saved under `.pijul/stash`.
+struct Stash {
+    hash: Hash,
+    path: PathBuf,
+    patch: Patch,
+}
+
+/// The stashes of the repository, the most recent first.
+fn stashes(repo_root: &Path) -> Result<Vec<Stash>, Error> {
+    let mut stashes = Vec::new();
+    if let Ok(dir) = read_dir(stash_dir(repo_root)) {
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("gz") {
+                continue;
+            }
+            let mut f = BufReader::new(File::open(&path)?);
+            let (hash, _, patch) = Patch::from_reader_compressed(&mut f)?;
+            stashes.push(Stash { hash, path, patch })
+        }
+    }
+    stashes.sort_by(|a, b| b.patch.header().timestamp.cmp(&a.patch.header().timestamp));
+    Ok(stashes)
+}
+
+/// The patches the stashes depend on, and their dependencies, which
+/// `pijul gc` must keep for the stashes to remain applicable.
+pub fn stash_dependencies(repo_root: &Path) -> Result<HashSet<Hash>, Error> {
+    let mut deps = HashSet::new();
+    for stash in stashes(repo_root)? {
+        for dep in stash.patch.dependencies().iter() {
+            if !deps.contains(dep) {
+                deps.extend(dependency_closure(repo_root, dep))
+            }
+        }
+    }
+    Ok(deps)
+}
+
+/// Find a stash by number (0 is the most recent) or by a prefix of
+/// its hash.
+fn find_stash(repo_root: &Path, name: Option<&str>) -> Result<Stash, Error> {
+    let name = name.unwrap_or("0");
+    let mut stashes = stashes(repo_root)?;
+    let n = if let Ok(n) = name.parse::<usize>() {
+        if n < stashes.len() {
+            Some(n)
+        } else {
+            None
+        }
+    } else {
+        stashes
+            .iter()
+            .position(|s| s.hash.to_base58().starts_with(name))
+    };
+    if let Some(n) = n {
+        Ok(stashes.swap_remove(n))
+    } else {
+        Err(Error::NoSuchStash {
+            name: name.to_string(),
+        })
+    }
+}
+
+fn save(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let branch_name = opts.branch();
+    let name = args
+        .value_of("message")
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| format!("On {}", branch_name));
+    let saved = opts.with_mut_txn(0, |mut txn| {
+        let (changes, _) =
+            record::changes_from_prefixes(&opts.repo_root, &mut txn, &branch_name, None)?;
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        // Files added since the last record, to be removed from the
+        // working copy along with the other changes.
+        let added: Vec<PathBuf> = changes
+            .iter()
+            .filter_map(|c| match *c {
+                Record::FileAdd { ref name, .. } => Some(opts.repo_root.join(name)),
+                _ => None,
+            })
+            .collect();
+        // The stash is never applied, so the contents of its large
+        // files must be stored now, before they are reverted.
+        store_records(&opts.repo_root, &changes)?;
+        let changes = changes
+            .into_iter()
+            .map(|x| txn.globalize_record(x))
+            .flat_map(|x| x.into_iter())
+            .collect();
+        let branch = txn.get_branch(&branch_name).unwrap();
+        let patch = txn.new_patch(
+            &branch,
+            Vec::new(),
+            name.clone(),
+            None,
+            chrono::Utc::now(),
+            changes,
+            std::iter::empty(),
+            PatchFlags::empty(),
+        );
+        txn.commit()?;
+        Ok(Some((patch, added)))
+    })?;
+    let (patch, added) = if let Some(saved) = saved {
+        saved
+    } else {
+        println!("No changes to stash");
+        return Ok(());
+    };
+
+    let dir = stash_dir(&opts.repo_root);
+    create_dir_all(&dir)?;
+    let hash = patch.save(&dir, None)?;
+
+    revert(&opts, &branch_name)?;
+    for path in added.iter().rev() {
+        if let Ok(meta) = symlink_metadata(path) {
+            if meta.is_dir() {
+                // Only remove directories left empty.
+                remove_dir(path).unwrap_or(())
+            } else {
+                remove_file(path)?
+            }
+        }
+    }
+    println!("Saved stash {}", hash.to_base58());
+    Ok(())
+}
+
+/// Revert all the unrecorded changes.
+fn revert(opts: &BasicOptions, branch_name: &str) -> Result<(), Error> {
+    let pending = UnsignedPatch::empty().leave_unsigned();
+    output_repository(
+        &opts.repo_root,
+        &opts.pristine_dir(),
+        branch_name,
+        None,
+        &pending,
+        &HashSet::new(),
+    )
+}
+
+fn list(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    for (n, stash) in stashes(&opts.repo_root)?.iter().enumerate() {
+        let header = stash.patch.header();
+        println!(
+            "{}: {} {} {}",
+            n,
+            stash.hash.to_base58(),
+            header.timestamp.to_rfc2822(),
+            header.name
+        );
+    }
+    Ok(())
+}
+
+fn apply(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let branch_name = opts.branch();
+    let stash = find_stash(&opts.repo_root, args.value_of("stash"))?;
+
+    // The stash is output over the working copy, which must not
+    // have unrecorded changes. Then, apply the stash to the branch,
+    // output the working copy, and unrecord the stash. If the branch
+    // changed since the stash was saved, conflicts are output with
+    // markers, as unrecorded changes.
+    let size_hint = stash.patch.size_upper_bound() as u64;
+    let (conflicts_before, conflicts) = opts.with_mut_txn(size_hint, |mut txn| {
+        let mut record = RecordState::new();
+        let branch = txn.open_branch(&branch_name)?;
+        txn.record(&mut record, &branch, &opts.repo_root, None)?;
+        txn.commit_branch(branch)?;
+        let (changes, _) = record.finish();
+        if !changes.is_empty() {
+            return Err(Error::PendingChanges);
+        }
+
+        let conflicts_before = txn.list_conflict_files(&branch_name, &[])?;
+        let mut branch = txn.open_branch(&branch_name)?;
+        for dep in stash.patch.dependencies().iter() {
+            match txn.get_internal(dep.as_ref()) {
+                Some(internal) if txn.get_patch(&branch.patches, internal).is_some() => {}
+                _ => return Err(Error::MissingDependency { hash: dep.clone() }),
+            }
+        }
+        let internal = txn.apply_local_patch(
+            &mut branch,
+            &opts.repo_root,
+            &stash.hash,
+            &stash.patch,
+            &HashSet::new(),
+            true,
+        )?;
+        txn.commit_branch(branch)?;
+        let conflicts = txn.list_conflict_files(&branch_name, &[])?;
+        let mut branch = txn.open_branch(&branch_name)?;
+        txn.output_repository_no_pending(&mut branch, &opts.repo_root, &Prefixes::empty())?;
+        txn.unrecord(&mut branch, internal, &stash.patch)?;
+        txn.commit_branch(branch)?;
+        txn.commit()?;
+        Ok((conflicts_before, conflicts))
+    })?;
+
+    for file in conflicts.iter().filter(|f| !conflicts_before.contains(f)) {
+        println!("Conflict in {}", file.display())
+    }
+    Ok(())
+}
+
+fn drop_stash(args: &ArgMatches) -> Result<(), Error> {
+    let opts = BasicOptions::from_args(args)?;
+    let stash = find_stash(&opts.repo_root, args.value_of("stash"))?;
+    remove_file(&stash.path)?;
+    println!("Dropped stash {}", stash.hash.to_base58());
+    Ok(())
+}
+
+pub fn explain(res: Result<(), Error>) {
+    default_explain(res)
+}
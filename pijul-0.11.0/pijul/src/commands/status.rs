@@ -1,13 +1,14 @@
 use clap::{Arg, ArgMatches, SubCommand};
+use commands::porcelain::{self, format_args, is_json, print_json, repo_path, StatusChange};
 use commands::{default_explain, BasicOptions, StaticSubcommand};
 use error::Error;
 use libpijul::fs_representation::untracked_files;
 use libpijul::patch::Record;
-use libpijul::{MutTxn, RecordState};
-use rand;
+use libpijul::{GenericTxn, RecordState, Transaction, ROOT_INODE};
 use relativize::relativize;
 
 use std::collections::HashSet;
+use std::fs::symlink_metadata;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
@@ -27,7 +28,7 @@ Unresolved conflicts:
 "#;
 
 pub fn invocation() -> StaticSubcommand {
-    SubCommand::with_name("status")
+    format_args(SubCommand::with_name("status")
         .about("Show working tree status")
         .arg(
             Arg::with_name("repository")
@@ -40,7 +41,7 @@ pub fn invocation() -> StaticSubcommand {
                 .long("short")
                 .short("s")
                 .help("Output in short format"),
-        )
+        ))
 }
 
 pub fn explain(r: Result<(), Error>) {
@@ -50,19 +51,42 @@ pub fn explain(r: Result<(), Error>) {
 pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let opts = BasicOptions::from_args(args)?;
     let current_branch = opts.branch();
-    let repo = opts.open_and_grow_repo(409600)?;
+    let repo = opts.open_repo()?;
     let short = args.is_present("short");
 
     let (unrecorded, untracked, conflicts) = {
-        let mut txn = repo.mut_txn_begin(rand::thread_rng())?;
-        let unrecorded = unrecorded_changes(&mut txn, &opts.repo_root, &current_branch)?;
+        let txn = repo.txn_begin()?;
+        let unrecorded = unrecorded_changes(&txn, &opts.repo_root, &current_branch)?;
 
         let untracked = untracked_files(&txn, &opts.repo_root);
         let conflicts = txn.list_conflict_files(&current_branch, &[])?;
         (unrecorded, untracked, conflicts)
     };
 
-    if short {
+    if is_json(args) {
+        print_json(&porcelain::Status {
+            branch: current_branch,
+            conflicts: conflicts
+                .iter()
+                .map(|f| f.to_string_lossy().into_owned())
+                .collect(),
+            changes: unrecorded
+                .iter()
+                .map(|&(ref f, ref t)| StatusChange {
+                    path: repo_path(&opts.repo_root, f),
+                    type_: t.name(),
+                })
+                .collect(),
+            untracked: {
+                let mut u: Vec<_> = untracked
+                    .iter()
+                    .map(|f| repo_path(&opts.repo_root, f))
+                    .collect();
+                u.sort();
+                u
+            },
+        })?
+    } else if short {
         print_shortstatus(&opts.cwd, &opts.repo_root, unrecorded, untracked, conflicts);
     } else {
         print_longstatus(
@@ -144,6 +168,7 @@ fn print_shortstatus(
 enum ChangeType {
     Modified,
     New,
+    NewSymlink,
     Del,
     Move(Rc<PathBuf>),
 }
@@ -152,31 +177,62 @@ impl ChangeType {
     fn short(&self) -> &str {
         match *self {
             ChangeType::Modified => "M",
-            ChangeType::New => "A",
+            ChangeType::New | ChangeType::NewSymlink => "A",
             ChangeType::Del => "D",
             ChangeType::Move(_) => "→",
         }
     }
 
+    /// Name of this type in the machine-readable format.
+    fn name(&self) -> &'static str {
+        match *self {
+            ChangeType::Modified => "modified",
+            ChangeType::New | ChangeType::NewSymlink => "added",
+            ChangeType::Del => "deleted",
+            ChangeType::Move(_) => "moved",
+        }
+    }
+
     fn long(&self) -> &str {
         match *self {
             ChangeType::Modified => "modified:",
             ChangeType::New => "new file:",
+            ChangeType::NewSymlink => "new symlink:",
             ChangeType::Del => "deleted:",
             ChangeType::Move(_) => "moved:",
         }
     }
 }
 
-fn unrecorded_changes<T: rand::Rng>(
-    txn: &mut MutTxn<T>,
+fn unrecorded_changes<A: Transaction, R>(
+    txn: &GenericTxn<A, R>,
     repo_root: &PathBuf,
     branch: &String,
 ) -> Result<Vec<(Rc<PathBuf>, ChangeType)>, Error> {
+    let branch = if let Some(branch) = txn.get_branch(branch) {
+        branch
+    } else {
+        // Nothing was ever recorded on this branch, hence all the
+        // tracked files are new.
+        return Ok(txn
+            .list_files(ROOT_INODE)?
+            .into_iter()
+            .map(|file| {
+                let file = repo_root.join(file);
+                let is_symlink = symlink_metadata(&file)
+                    .map(|m| m.file_type().is_symlink())
+                    .unwrap_or(false);
+                let t = if is_symlink {
+                    ChangeType::NewSymlink
+                } else {
+                    ChangeType::New
+                };
+                (Rc::new(file), t)
+            })
+            .collect());
+    };
     let mut record = RecordState::new();
-    let branch = txn.open_branch(branch)?;
-    txn.record(&mut record, &branch, repo_root, None)?;
-    txn.commit_branch(branch)?;
+    txn.record_changes(&mut record, &branch, repo_root, None)?;
     let (changes, _) = record.finish();
 
     let mut ret = vec![];
@@ -193,7 +249,11 @@ fn unrecorded_changes<T: rand::Rng>(
             Record::FileAdd { ref name, .. } => {
                 let file = Rc::new(PathBuf::from(name.clone()));
                 current_file = Some(file.clone());
-                ret.push((file.clone(), ChangeType::New));
+                if change.added_metadata().map(|m| m.is_symlink()).unwrap_or(false) {
+                    ret.push((file.clone(), ChangeType::NewSymlink));
+                } else {
+                    ret.push((file.clone(), ChangeType::New));
+                }
             }
             Record::FileDel { ref name, .. } => {
                 let file = Rc::new(PathBuf::from(name.clone()));
@@ -1,7 +1,8 @@
 use chrono;
-use clap::{ArgMatches, SubCommand};
+use clap::{Arg, ArgMatches, SubCommand};
 use commands::hooks::run_hook;
 use commands::record::{decide_authors, decide_patch_message, record_args};
+use commands::tag_registry::TagRegistry;
 use commands::{BasicOptions, StaticSubcommand};
 use libpijul::fs_representation::patches_dir;
 use libpijul::patch::PatchFlags;
@@ -15,11 +16,23 @@ use meta::{load_global_or_local_signing_key, Global, Meta};
 
 pub fn invocation() -> StaticSubcommand {
     record_args(SubCommand::with_name("tag")
-        .about("Create a patch (a \"tag\") with no changes, and all currently applied patches as dependencies"))
+        .about("Create a patch (a \"tag\") with no changes, and all currently applied patches as dependencies")
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .help("List the tags known to this repository, with their hashes."),
+        ))
 }
 
 pub fn run(args: &ArgMatches) -> Result<Option<Hash>, Error> {
     let opts = BasicOptions::from_args(args)?;
+    let mut registry = TagRegistry::load(&opts.repo_root)?;
+    if args.is_present("list") {
+        for (name, hash) in registry.tags.iter() {
+            println!("{} {}", hash, name);
+        }
+        return Ok(None);
+    }
     let patch_name_arg = args.value_of("message");
     let patch_descr_arg = args.value_of("description");
     let authors_arg = args.values_of("author").map(|x| x.collect::<Vec<_>>());
@@ -131,22 +144,17 @@ pub fn run(args: &ArgMatches) -> Result<Option<Hash>, Error> {
     let patches_dir = patches_dir(&opts.repo_root);
     let hash = patch.save(&patches_dir, key.as_ref())?;
 
-    let pristine_dir = opts.pristine_dir();
-    let mut increase = 40960;
-    loop {
-        match record::record_no_resize(
-            &pristine_dir,
-            &opts.repo_root,
-            &branch_name,
-            &hash,
-            &patch,
-            &HashSet::new(),
-            increase,
-        ) {
-            Err(ref e) if e.lacks_space() => increase *= 2,
-            _ => break,
-        }
-    }
+    record::record_resize(
+        &opts.pristine_dir(),
+        &opts.repo_root,
+        &branch_name,
+        &hash,
+        &patch,
+        &HashSet::new(),
+    )?;
+    // A new tag takes the name from older tags with the same name.
+    registry.tags.insert(patch.header().name.clone(), hash.to_base58());
+    registry.save(&opts.repo_root)?;
     Ok(Some(hash))
 }
 
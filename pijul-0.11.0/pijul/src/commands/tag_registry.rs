@@ -0,0 +1,119 @@
+//! The tag registry, mapping tag names to the hashes of tag patches.
+//!
+//! The registry is updated when a tag is created by `pijul tag`, and
+//! whenever a tag patch is applied to a branch of this repository
+//! (by pull, push, apply or bundles). A tag created locally takes
+//! its name from any older tag, whereas tags received from other
+//! repositories never replace existing names.
+
+use error::Error;
+use libpijul::fs_representation::{read_dependencies, read_patch_nochanges, tags_file};
+use libpijul::patch::PatchFlags;
+use libpijul::{Hash, PatchHeader};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{rename, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use toml;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TagRegistry {
+    /// Tag names, and the corresponding hashes in base58.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl TagRegistry {
+    /// Load the registry of `repo_root`, which is empty if the
+    /// registry file doesn't exist.
+    pub fn load(repo_root: &Path) -> Result<Self, Error> {
+        let mut s = String::new();
+        if let Ok(mut f) = File::open(tags_file(repo_root)) {
+            f.read_to_string(&mut s)?;
+            Self::parse(&s)
+        } else {
+            Ok(TagRegistry::default())
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn save(&self, repo_root: &Path) -> Result<(), Error> {
+        let path = tags_file(repo_root);
+        let mut tmp = path.clone();
+        tmp.set_extension("tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(toml::to_string(&self)?.as_bytes())?;
+            f.flush()?;
+        }
+        rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The hash of tag `name`.
+    pub fn get(&self, name: &str) -> Result<Hash, Error> {
+        let hash = self.tags.get(name).ok_or_else(|| Error::NoSuchTag {
+            name: name.to_string(),
+        })?;
+        Hash::from_base58(hash).ok_or(Error::WrongHash)
+    }
+
+    /// Register `hash` as tag `name`, unless that name is already
+    /// taken. Returns `false` if the name was taken.
+    pub fn insert(&mut self, name: &str, hash: &Hash) -> bool {
+        if self.tags.contains_key(name) {
+            return false;
+        }
+        self.tags.insert(name.to_string(), hash.to_base58());
+        true
+    }
+
+    /// Register the tags among `patches`, and save the registry if
+    /// it changed.
+    pub fn register<'a, I: IntoIterator<Item = (&'a Hash, &'a PatchHeader)>>(
+        repo_root: &Path,
+        patches: I,
+    ) -> Result<(), Error> {
+        let mut registry = Self::load(repo_root)?;
+        let mut changed = false;
+        for (hash, header) in patches {
+            if header.flag.contains(PatchFlags::TAG) && registry.insert(&header.name, hash) {
+                changed = true
+            }
+        }
+        if changed {
+            registry.save(repo_root)?
+        }
+        Ok(())
+    }
+
+    /// Same as `register`, for patches already in the patches
+    /// directory of `repo_root`.
+    pub fn register_hashes(repo_root: &Path, hashes: &HashSet<Hash>) -> Result<(), Error> {
+        let mut headers = Vec::new();
+        for hash in hashes.iter() {
+            headers.push((hash, read_patch_nochanges(repo_root, hash.as_ref())?))
+        }
+        Self::register(repo_root, headers.iter().map(|&(h, ref p)| (h, p)))
+    }
+}
+
+/// `hash` and all its dependencies, direct or indirect, found in the
+/// patches directory of `repo_root`. Patches not in that directory
+/// are included, but their dependencies are not explored.
+pub fn dependency_closure(repo_root: &Path, hash: &Hash) -> HashSet<Hash> {
+    let mut closure = HashSet::new();
+    let mut stack = vec![hash.clone()];
+    while let Some(h) = stack.pop() {
+        if !closure.insert(h.clone()) {
+            continue;
+        }
+        if let Ok(deps) = read_dependencies(repo_root, h.as_ref()) {
+            stack.extend(deps.into_iter().filter(|d| !closure.contains(d)))
+        }
+    }
+    closure
+}
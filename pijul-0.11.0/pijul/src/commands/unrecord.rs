@@ -6,7 +6,7 @@ use std::path::Path;
 
 use libpijul::fs_representation::{patch_file_name, patches_dir};
 use libpijul::patch::Patch;
-use libpijul::{unrecord_no_resize, Hash, HashRef, PatchId};
+use libpijul::{unrecord_resize, Hash, HashRef, PatchId};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
@@ -41,8 +41,8 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
     let opts = BasicOptions::from_args(args)?;
     let patches: Option<HashSet<Hash>> = args.values_of("patch")
         .map(|ps| ps.map(|x| Hash::from_base58(x).unwrap()).collect());
-    let mut increase = 409600;
-    let repo = opts.open_and_grow_repo(increase)?;
+    let mut increase = 0;
+    let repo = opts.open_repo()?;
     let branch_name = opts.branch();
 
     let mut patches: HashMap<_, _> = if let Some(ref patches) = patches {
@@ -120,19 +120,15 @@ pub fn run(args: &ArgMatches) -> Result<(), Error> {
     }
     drop(repo);
 
-    let repo_dir = opts.pristine_dir();
-    loop {
-        match unrecord_no_resize(
-            &repo_dir,
-            &opts.repo_root,
-            &branch_name,
-            &mut selected,
-            increase,
-        ) {
-            Err(ref e) if e.lacks_space() => increase *= 2,
-            e => return e.map_err(|x| Error::Repository(x)),
-        }
-    }
+    unrecord_resize(
+        &opts.pristine_dir(),
+        &opts.repo_root,
+        &opts.config,
+        &branch_name,
+        &selected,
+        increase,
+    )?;
+    Ok(())
 }
 
 fn load_patch(repo_root: &Path, ext: HashRef) -> Patch {
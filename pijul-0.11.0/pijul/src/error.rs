@@ -1,6 +1,6 @@
 use std;
 use std::path::PathBuf;
-use {hex, libpijul, regex, reqwest, term, thrussh, thrussh_config, thrussh_keys, toml};
+use {bincode, git2, hex, hyper, libpijul, regex, reqwest, term, thrussh, thrussh_config, thrussh_keys, toml};
 
 #[derive(Debug)]
 pub enum Error {
@@ -17,6 +17,9 @@ pub enum Error {
     StripPrefix(std::path::StripPrefixError),
     Regex(regex::Error),
     ThrusshConfig(thrussh_config::Error),
+    Bincode(bincode::Error),
+    Hyper(hyper::Error),
+    Git(git2::Error),
     HookFailed { cmd: String },
     InARepository { path: std::path::PathBuf },
     NotInARepository,
@@ -42,6 +45,26 @@ pub enum Error {
     PartialPullOverHttp,
     UnknownHost { host: String },
     NoAuthor,
+    HttpStatus { status: u16, message: String },
+    InvalidAddress { address: String },
+    Remote { code: u16, message: String },
+    RemoteProtocol { message: String },
+    UnsupportedProtocolVersion { version: u32 },
+    NothingToResume,
+    InvalidBundle { message: String },
+    InvalidRange { range: String },
+    MissingDependency { hash: libpijul::Hash },
+    PartialPullFromBundle,
+    CannotPushToBundle,
+    GitBranchNotImported { branch: String },
+    NoSuchTag { name: String },
+    NoMergeTool,
+    NoSuchStash { name: String },
+    NothingToAmend,
+    PatchHasDependents { hash: libpijul::Hash, dependent: libpijul::Hash },
+    PatchNotLocal { hash: libpijul::Hash },
+    RepositoryCorrupted { problems: usize },
+    MissingBlobs { count: usize },
 }
 
 impl std::fmt::Display for Error {
@@ -60,6 +83,9 @@ impl std::fmt::Display for Error {
             Error::StripPrefix(ref e) => e.fmt(f),
             Error::Regex(ref e) => e.fmt(f),
             Error::ThrusshConfig(ref e) => e.fmt(f),
+            Error::Bincode(ref e) => e.fmt(f),
+            Error::Hyper(ref e) => e.fmt(f),
+            Error::Git(ref e) => e.fmt(f),
             Error::HookFailed { ref cmd } => write!(f, "Hook failed: {}", cmd),
             Error::InARepository { ref path } => write!(f, "In a repository: {:?}", path),
             Error::NotInARepository => write!(f, "Not in a repository"),
@@ -85,6 +111,42 @@ impl std::fmt::Display for Error {
             Error::PartialPullOverHttp => write!(f, "Partial pull over HTTP is not (yet) supported"),
             Error::UnknownHost { ref host } => write!(f, "Unknown host: {}", host),
             Error::NoAuthor => write!(f, "No authors were given"),
+            Error::HttpStatus { status, ref message } => write!(f, "HTTP error {}: {}", status, message),
+            Error::InvalidAddress { ref address } => write!(f, "Invalid address: {:?}", address),
+            Error::Remote { code, ref message } => write!(f, "Remote error {}: {}", code, message),
+            Error::RemoteProtocol { ref message } => write!(f, "Remote protocol error: {}", message),
+            Error::UnsupportedProtocolVersion { version } => write!(f, "Unsupported protocol version: {}", version),
+            Error::NothingToResume => write!(f, "No interrupted pull to resume"),
+            Error::InvalidBundle { ref message } => write!(f, "Invalid bundle: {}", message),
+            Error::InvalidRange { ref range } => write!(f, "Invalid range: {:?}", range),
+            Error::MissingDependency { ref hash } => write!(f, "Missing dependency: {}", hash.to_base58()),
+            Error::PartialPullFromBundle => write!(f, "Partial pull from a bundle is not supported"),
+            Error::CannotPushToBundle => write!(f, "Cannot push to a bundle, use `pijul bundle create` instead"),
+            Error::GitBranchNotImported { ref branch } => write!(f, "The head of git branch {} was not imported, run `pijul git import` first", branch),
+            Error::NoSuchTag { ref name } => write!(f, "No such tag: {:?}", name),
+            Error::NoMergeTool => write!(f, "No merge tool configured (set merge_tool in .pijul/meta.toml, or use --tool)"),
+            Error::NoSuchStash { ref name } => write!(f, "No such stash: {:?}", name),
+            Error::NothingToAmend => write!(f, "No patch to amend on this branch"),
+            Error::PatchHasDependents {
+                ref hash,
+                ref dependent,
+            } => write!(
+                f,
+                "Patch {} is a dependency of {}, use --force to amend it anyway",
+                hash.to_base58(),
+                dependent.to_base58()
+            ),
+            Error::PatchNotLocal { ref hash } => write!(
+                f,
+                "Patch {} was not recorded here, or was already pushed, and cannot be amended",
+                hash.to_base58()
+            ),
+            Error::RepositoryCorrupted { problems } => {
+                write!(f, "{} problems found in the repository", problems)
+            }
+            Error::MissingBlobs { count } => {
+                write!(f, "{} large files could not be downloaded", count)
+            }
         }
     }
 }
@@ -105,6 +167,9 @@ impl std::error::Error for Error {
             Error::StripPrefix(ref e) => e.description(),
             Error::Regex(ref e) => e.description(),
             Error::ThrusshConfig(ref e) => e.description(),
+            Error::Bincode(ref e) => e.description(),
+            Error::Hyper(ref e) => e.description(),
+            Error::Git(ref e) => e.description(),
             Error::HookFailed { .. } => "Hook failed",
             Error::InARepository { .. } => "In a repository",
             Error::NotInARepository => "Not in a repository",
@@ -130,6 +195,26 @@ impl std::error::Error for Error {
             Error::PartialPullOverHttp => "Partial pull over HTTP is not (yet) supported",
             Error::UnknownHost { .. } => "Unknown host",
             Error::NoAuthor => "No authors were given",
+            Error::HttpStatus { .. } => "HTTP error",
+            Error::InvalidAddress { .. } => "Invalid address",
+            Error::Remote { .. } => "Remote error",
+            Error::RemoteProtocol { .. } => "Remote protocol error",
+            Error::UnsupportedProtocolVersion { .. } => "Unsupported protocol version",
+            Error::NothingToResume => "No interrupted pull to resume",
+            Error::InvalidBundle { .. } => "Invalid bundle",
+            Error::InvalidRange { .. } => "Invalid range",
+            Error::MissingDependency { .. } => "Missing dependency",
+            Error::PartialPullFromBundle => "Partial pull from a bundle is not supported",
+            Error::CannotPushToBundle => "Cannot push to a bundle",
+            Error::GitBranchNotImported { .. } => "The head of the git branch was not imported",
+            Error::NoSuchTag { .. } => "No such tag",
+            Error::NoMergeTool => "No merge tool configured",
+            Error::NoSuchStash { .. } => "No such stash",
+            Error::NothingToAmend => "No patch to amend",
+            Error::PatchHasDependents { .. } => "Patch has dependents",
+            Error::PatchNotLocal { .. } => "Patch not local",
+            Error::RepositoryCorrupted { .. } => "Problems found in the repository",
+            Error::MissingBlobs { .. } => "Large files could not be downloaded",
         }
     }
 
@@ -148,6 +233,9 @@ impl std::error::Error for Error {
             Error::StripPrefix(ref e) => Some(e),
             Error::Regex(ref e) => Some(e),
             Error::ThrusshConfig(ref e) => Some(e),
+            Error::Bincode(ref e) => Some(e),
+            Error::Hyper(ref e) => Some(e),
+            Error::Git(ref e) => Some(e),
             _ => None
         }
     }
@@ -231,6 +319,24 @@ impl From<thrussh_config::Error> for Error {
     }
 }
 
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Error {
+        Error::Bincode(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Error {
+        Error::Hyper(err)
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Error {
+        Error::Git(err)
+    }
+}
+
 impl Error {
     pub fn lacks_space(&self) -> bool {
         match *self {
@@ -240,6 +346,12 @@ impl Error {
     }
 }
 
+impl libpijul::LacksSpace for Error {
+    fn lacks_space(&self) -> bool {
+        Error::lacks_space(self)
+    }
+}
+
 impl From<thrussh::HandlerError<Error>> for Error {
     fn from(err: thrussh::HandlerError<Error>) -> Error {
         match err {
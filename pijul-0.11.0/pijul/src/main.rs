@@ -8,7 +8,9 @@ extern crate cryptovec;
 extern crate dirs;
 extern crate env_logger;
 extern crate futures;
+extern crate hyper;
 extern crate getch;
+extern crate git2;
 extern crate ignore;
 extern crate libpijul;
 extern crate rand;
@@ -42,6 +44,7 @@ extern crate pager;
 extern crate base64;
 extern crate line;
 extern crate rpassword;
+extern crate serde;
 extern crate serde_json;
 
 mod cli;
@@ -80,6 +83,7 @@ fn main() {
                                "init" => init,
                                "add" => add,
                                "record" => record,
+                               "amend" => amend,
                                "pull" => pull,
                                "push" => push,
                                "apply" => apply,
@@ -88,10 +92,15 @@ fn main() {
                                "mv" => mv,
                                "ls" => ls,
                                "revert" => revert,
+                               "stash" => stash,
+                               "resolve" => resolve,
+                               "mergetool" => mergetool,
                                "unrecord" => unrecord,
                                "fork" => fork,
                                "branches" => branches,
                                "delete-branch" => delete_branch,
+                               "check" => check,
+                               "gc" => gc,
                                "checkout" => checkout,
                                "diff" => diff,
                                "credit" => credit,
@@ -102,7 +111,11 @@ fn main() {
                                "show-dependencies" => show_dependencies,
                                "tag" => tag,
                                "sign" => sign,
-                               "challenge" => challenge
+                               "challenge" => challenge,
+                               "serve-http" => serve_http,
+                               "protocol" => protocol,
+                               "bundle" => bundle,
+                               "git" => git
                                );
     let time1 = chrono::Local::now();
     info!("The command took: {:?}", time1.signed_duration_since(time0));
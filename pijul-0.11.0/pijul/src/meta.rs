@@ -1,5 +1,7 @@
 use commands::remote::{parse_remote, Remote};
 use dirs;
+use libpijul::conflict::MarkerStyle;
+use libpijul::{Config, DiffAlgorithm};
 use libpijul::fs_representation::meta_file;
 use std;
 use std::collections::BTreeMap;
@@ -27,6 +29,23 @@ pub struct Meta {
     pub editor: Option<String>,
     pub pull: Option<String>,
     pub push: Option<String>,
+    /// Style of conflict markers in the working copy: "plain"
+    /// (the default), "labeled" or "git".
+    pub conflict_markers: Option<MarkerStyle>,
+    /// External tool used by `pijul mergetool`, either one of
+    /// "meld", "kdiff3" and "vimdiff", or a command line with the
+    /// placeholders `$BASE`, `$LOCAL`, `$REMOTE` and `$MERGED`, where
+    /// arguments containing spaces can be quoted.
+    pub merge_tool: Option<String>,
+    /// Diff algorithm used by record: "optimal" (the default),
+    /// "myers" or "patience". Myers' algorithm is much faster on
+    /// large files.
+    pub diff_algorithm: Option<DiffAlgorithm>,
+    /// Files at least this large (in bytes) are recorded as blobs,
+    /// stored in `.pijul/blobs` instead of in patches.
+    pub large_file_threshold: Option<u64>,
+    /// Patterns of files recorded as blobs, regardless of their size.
+    pub large_file_patterns: Option<Vec<String>>,
     #[serde(default)]
     pub remote: BTreeMap<String, Repository>,
 }
@@ -47,6 +66,11 @@ impl Meta {
             editor: None,
             pull: None,
             push: None,
+            conflict_markers: None,
+            merge_tool: None,
+            diff_algorithm: None,
+            large_file_threshold: None,
+            large_file_patterns: None,
             remote: BTreeMap::new(),
         }
     }
@@ -116,6 +140,17 @@ impl Meta {
         self.get_remote(remote, self.push.as_ref(), port, base_path, local_repo_root)
     }
 
+    /// The settings passed to libpijul when recording and outputting
+    /// the working copy.
+    pub fn config(&self) -> Config {
+        Config {
+            conflict_markers: self.conflict_markers.unwrap_or_default(),
+            diff_algorithm: self.diff_algorithm.unwrap_or_default(),
+            large_file_threshold: self.large_file_threshold,
+            large_file_patterns: self.large_file_patterns.clone().unwrap_or(Vec::new()),
+        }
+    }
+
     pub fn signing_key(&self) -> Result<Option<KeyPair>, Error> {
         if let Some(ref path) = self.signing_key {
             Ok(Some(thrussh_keys::load_secret_key(path, None)?))
@@ -125,11 +160,27 @@ impl Meta {
     }
 }
 
+/// The libpijul settings of the repository at `repo_root`, or the
+/// defaults if it has no meta file. An invalid meta file is an error.
+pub fn load_config(repo_root: &Path) -> Result<Config, Error> {
+    match Meta::load(repo_root) {
+        Ok(meta) => Ok(meta.config()),
+        Err(Error::IO(ref e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Meta::new().config())
+        }
+        Err(e) => Err(e),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Global {
     pub author: String,
     pub editor: Option<String>,
     pub signing_key: Option<String>,
+    /// Maximal number of patches downloaded at the same time.
+    pub download_concurrency: Option<usize>,
+    /// Default external tool for `pijul mergetool`.
+    pub merge_tool: Option<String>,
 }
 
 pub fn global_path() -> Result<PathBuf, Error> {
@@ -224,6 +275,8 @@ impl Global {
             author: String::new(),
             editor: None,
             signing_key: None,
+            download_concurrency: None,
+            merge_tool: None,
         }
     }
 
@@ -0,0 +1,98 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "amend folds changes into the last patch" {
+    make_repo toto
+    cd toto
+    printf "a\nb\n" > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    printf "a\nx\nb\n" > file
+    echo c > other
+    pijul add other
+    pijul amend -a
+    [[ $(pijul log --hash-only | tail -n +2 | wc -l) = 1 ]]
+    pijul log | grep "file"
+    run pijul status -s
+    assert_output ""
+
+    pijul revert -a
+    [[ $(cat file) = $(printf "a\nx\nb") ]]
+    [[ $(cat other) = c ]]
+}
+
+@test "amend changes the name" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "first name" -A "I"
+    pijul amend -a -m "second name"
+    [[ $(pijul log --hash-only | tail -n +2 | wc -l) = 1 ]]
+    pijul log | grep "second name"
+    ! pijul log | grep "first name"
+}
+
+@test "record --amend" {
+    make_repo toto
+    cd toto
+    printf "a\nb\n" > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    printf "a\nb\nc\n" > file
+    pijul record --amend -a
+    [[ $(pijul log --hash-only | tail -n +2 | wc -l) = 1 ]]
+    pijul clone . ../titi
+    [[ $(cat ../titi/file) = $(printf "a\nb\nc") ]]
+}
+
+@test "amend refuses patches with dependents" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    pijul fork other
+    echo b >> a
+    pijul record -a -m "b" -A "I"
+    pijul checkout master
+    echo c > c
+    pijul add c
+    run pijul amend -a
+    assert_failure
+    pijul amend -a --force
+    [[ $(pijul log --hash-only | tail -n +2 | wc -l) = 1 ]]
+}
+
+@test "amend refuses pulled patches" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    cd ..
+    pijul clone toto titi
+    cd titi
+    echo b >> a
+    run pijul amend -a
+    assert_failure
+    [[ $(pijul log --hash-only | tail -n +2 | wc -l) = 1 ]]
+}
+
+@test "amend refuses pushed patches" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    cd ..
+    make_repo titi
+    cd toto
+    pijul push -a ../titi
+    echo b >> a
+    run pijul amend -a
+    assert_failure
+    run pijul status -s
+    assert_output "M a"
+}
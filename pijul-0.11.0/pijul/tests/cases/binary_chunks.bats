@@ -0,0 +1,79 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "small edits to binary files give small patches" {
+    make_repo toto
+    cd toto
+    head -c 1000000 /dev/urandom > file
+    printf "\0" | dd of=file bs=1 seek=10 conv=notrunc
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    printf "edit" | dd of=file bs=1 seek=500000 conv=notrunc
+    pijul record -a -m "edit" -A "I"
+    run pijul status -s
+    assert_output ""
+
+    edit=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+    [[ $(stat -c %s .pijul/patches/$edit.gz) -lt 200000 ]]
+
+    pijul clone . ../titi
+    cmp file ../titi/file
+}
+
+@test "add a binary file" {
+    make_repo toto
+    cd toto
+    head -c 300000 /dev/urandom > file
+    printf "\0" | dd of=file bs=1 seek=10 conv=notrunc
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    run pijul status -s
+    assert_output ""
+    pijul clone . ../titi
+    cmp file ../titi/file
+}
+
+@test "identical binary files share their chunks" {
+    make_repo toto
+    cd toto
+    head -c 300000 /dev/urandom > file
+    printf "\0" | dd of=file bs=1 seek=10 conv=notrunc
+    cp file ../original
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    cp file copy
+    pijul add copy
+    pijul record -a -m "copy" -A "I"
+    copy=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+
+    # The chunks are still there after unrecording the copy.
+    pijul unrecord $copy
+    rm -f copy file
+    pijul revert -a
+    cmp file ../original
+    pijul clone . ../titi
+    cmp ../titi/file ../original
+}
+
+@test "conflicts on binary files are on the whole file" {
+    mkdir a
+    pijul init a
+    head -c 3000 /dev/urandom > a/file
+    printf "\0" | dd of=a/file bs=1 seek=10 conv=notrunc
+    pijul add --repository a file
+    pijul record -a --repository a -m "file" -A Alice
+
+    pijul clone a b
+    printf "a" | dd of=a/file bs=1 seek=100 conv=notrunc
+    pijul record -a --repository a -m "a" -A Alice
+    cp a/file version_a
+    printf "b" | dd of=b/file bs=1 seek=100 conv=notrunc
+    pijul record -a --repository b -m "b" -A Bob
+    cp b/file version_b
+
+    pijul pull -a --repository a b
+    [[ -n $(pijul resolve --repository a --list) ]]
+    (cmp a/file version_a && cmp a/file.1 version_b) ||
+        (cmp a/file version_b && cmp a/file.1 version_a)
+}
@@ -52,3 +52,47 @@ EOF
     head -n 1 log | grep creator
     check_blame $out
 }
+
+@test "blame at a past tag, and deleted lines" {
+    mkdir subdir
+    printf "a\nb\nc\n" > subdir/file.txt
+    pijul init subdir
+    pijul add --repository subdir file.txt
+    pijul record -a --repository subdir -m "add file.txt" -A creator
+    pijul tag --repository subdir -m "v1" -A creator
+    TAG=$(pijul log --repository subdir --hash-only --tags-only | tail -n 1 | cut -d: -f1)
+    printf "a\nc\nd\n" > subdir/file.txt
+    pijul record -a --repository subdir -m "edit file.txt" -A editor
+
+    pijul credit --repository subdir file.txt > log
+    [[ $(grep -c "^editor" log) -eq 1 ]]
+    ! grep " > b$" log
+
+    pijul credit --repository subdir --at $TAG file.txt > log
+    [[ $(grep -c "^creator" log) -eq 3 ]]
+    grep " > b$" log
+    # Lines are credited to the hash of the patch.
+    HASH=$(head -n 1 log | cut -d' ' -f5)
+    pijul log --repository subdir --hash-only | grep "^$HASH"
+
+    pijul credit --repository subdir --show-deleted file.txt > log
+    grep "^editor .* - b$" log
+    [[ $(grep -c " > " log) -eq 3 ]]
+}
+
+@test "blame at a past tag, of a file moved since" {
+    mkdir subdir
+    printf "a\nb\nc\n" > subdir/file.txt
+    pijul init subdir
+    pijul add --repository subdir file.txt
+    pijul record -a --repository subdir -m "add file.txt" -A creator
+    pijul tag --repository subdir -m "v1" -A creator
+    TAG=$(pijul log --repository subdir --hash-only --tags-only | tail -n 1 | cut -d: -f1)
+    pijul mv --repository subdir file.txt other.txt
+    pijul record -a --repository subdir -m "move file.txt" -A editor
+
+    pijul credit --repository subdir --at $TAG file.txt > log
+    [[ $(grep -c "^creator" log) -eq 3 ]]
+    run pijul credit --repository subdir --at $TAG other.txt
+    [ "$status" -ne 0 ]
+}
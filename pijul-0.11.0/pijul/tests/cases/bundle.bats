@@ -0,0 +1,68 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "create and apply a bundle" {
+    make_single_file_repo a toto
+    pijul clone a b
+    FIRST=$(pijul log --repository a --hash-only | tail -n 1 | cut -d: -f1)
+    for i in {1..3}; do
+        make_random_file a/toto
+        pijul record --repository a -a -m $i -A me
+    done
+
+    pijul bundle create --repository a $FIRST.. -o update.bundle
+    pijul bundle apply --repository b update.bundle
+    assert_files_equal a/toto b/toto
+
+    # Applying the same bundle again does nothing.
+    pijul bundle apply --repository b update.bundle
+    assert_files_equal a/toto b/toto
+}
+
+@test "bundle with missing dependencies" {
+    make_single_file_repo a toto
+    FIRST=$(pijul log --repository a --hash-only | tail -n 1 | cut -d: -f1)
+    make_random_file a/toto
+    pijul record --repository a -a -m more -A me
+
+    pijul bundle create --repository a $FIRST.. -o update.bundle
+    pijul init b
+    run pijul bundle apply --repository b update.bundle
+    [ "$status" -ne 0 ]
+    [ ! -e b/toto ]
+}
+
+@test "pull from a bundle" {
+    make_single_file_repo a toto
+    make_random_file a/toto
+    pijul record --repository a -a -m more -A me
+
+    pijul bundle create --repository a -o all.bundle
+    pijul init b
+    pijul pull -a --repository b $PWD/all.bundle
+    assert_files_equal a/toto b/toto
+
+    pijul clone all.bundle c
+    assert_files_equal a/toto c/toto
+}
+
+@test "truncated bundle" {
+    make_single_file_repo a toto
+    make_random_file a/toto
+    pijul record --repository a -a -m more -A me
+
+    pijul bundle create --repository a -o all.bundle
+    head -c -10 all.bundle > truncated.bundle
+    pijul init b
+    run pijul bundle apply --repository b truncated.bundle
+    [ "$status" -ne 0 ]
+    [ ! -e b/toto ]
+}
+
+@test "bundle with a huge header" {
+    printf 'pijul bundle 1\n\xff\xff\xff\xff\xff\xff\xff\x7f' > huge.bundle
+    pijul init b
+    run pijul bundle apply --repository b huge.bundle
+    [ "$status" -ne 0 ]
+}
@@ -0,0 +1,85 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "check a sound repository" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    echo b >> a
+    pijul record -a -m "b" -A "I"
+    run pijul check
+    assert_success "No problems found"
+    run pijul check --repair
+    assert_success "No problems found"
+}
+
+@test "check finds corrupt and missing patches" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    echo b >> a
+    pijul record -a -m "b" -A "I"
+    first=$(pijul log --hash-only | sed -n 3p | cut -d: -f 1)
+    last=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+
+    echo garbage | gzip > .pijul/patches/$last.gz
+    run pijul check
+    [ "$status" -ne 0 ]
+    [[ "$output" == *"Corrupt patch file"* ]]
+
+    rm .pijul/patches/$last.gz .pijul/patches/$first.gz
+    run pijul check --repair
+    [ "$status" -ne 0 ]
+    [[ "$output" == *"Missing file for patch $first"* ]]
+    [[ "$output" == *"Missing file for patch $last"* ]]
+}
+
+@test "check finds and repairs missing dependencies" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    echo b >> a
+    pijul record -a -m "b" -A "I"
+    last=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+
+    pijul info --drop-dependencies $last
+    run pijul check
+    [ "$status" -ne 0 ]
+    [[ "$output" == *"Dependencies or touched files of patch $last are out of date"* ]]
+
+    run pijul check --repair
+    assert_success "No problems found"
+    run pijul check
+    assert_success "No problems found"
+}
+
+@test "check verifies signatures" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    patch=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+
+    echo "{\"hash\":\"$patch\",\"signatures\":{}}" > .pijul/patches/$patch.sig
+    run pijul check
+    assert_success "No problems found"
+
+    echo "{\"hash\":\"${patch}x\",\"signatures\":{}}" > .pijul/patches/$patch.sig
+    run pijul check
+    [ "$status" -ne 0 ]
+    [[ "$output" == *"Invalid signature file"* ]]
+
+    key=$(ssh-keygen -q -t ed25519 -N "" -f key && cut -d' ' -f 2 key.pub)
+    echo "{\"hash\":\"$patch\",\"signatures\":{\"$key\":\"3yZe7d\"}}" > .pijul/patches/$patch.sig
+    run pijul check
+    [ "$status" -ne 0 ]
+    [[ "$output" == *"Invalid signature file"* ]]
+}
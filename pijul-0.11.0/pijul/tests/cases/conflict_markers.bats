@@ -0,0 +1,59 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+make_conflict() {
+    mkdir a
+    pijul init a
+    echo "conflict_markers = \"$1\"" >> a/.pijul/meta.toml
+    printf "x\ny\n" > a/file
+    pijul add --repository a file
+    pijul record -a --repository a -m "file" -A Alice
+
+    pijul clone a b
+    printf "x\nfrom a\ny\n" > a/file
+    pijul record -a --repository a -m "patch a" -A Alice
+
+    printf "x\nfrom b\ny\n" > b/file
+    pijul record -a --repository b -m "patch b" -A Bob
+
+    pijul pull -a --repository a b
+}
+
+@test "plain conflict markers" {
+    make_conflict plain
+    grep "^>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>$" a/file
+    grep "^================================$" a/file
+    grep "^<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<$" a/file
+}
+
+@test "labeled conflict markers" {
+    make_conflict labeled
+    grep -E "^>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>> patch (a|b)$" a/file
+    grep -E "^================================ patch (a|b)$" a/file
+    grep "^<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<$" a/file
+    pijul record -a --repository a -m "nothing" -A Alice | grep "Nothing to record"
+}
+
+@test "git conflict markers" {
+    make_conflict git
+    grep -E "^<<<<<<< patch (a|b)$" a/file
+    grep "^=======$" a/file
+    grep -E "^>>>>>>> patch (a|b)$" a/file
+    pijul record -a --repository a -m "nothing" -A Alice | grep "Nothing to record"
+
+    # Resolve the conflict, as a merge tool would.
+    printf "x\nfrom a\nfrom b\ny\n" > a/file
+    pijul record -a --repository a -m "resolution" -A Alice
+    pijul clone a c
+    assert_files_equal a/file c/file
+}
+
+@test "invalid conflict markers are reported" {
+    mkdir a
+    pijul init a
+    echo 'conflict_markers = "unknown"' >> a/.pijul/meta.toml
+    touch a/file
+    run pijul add --repository a file
+    [ $status -ne 0 ]
+}
@@ -0,0 +1,73 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+record_edit() {
+    make_repo toto
+    cd toto
+    echo "diff_algorithm = \"$1\"" >> .pijul/meta.toml
+    printf "a\nb\nc\nd\ne\nf\n" > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    printf "a\nx\nc\nd\nf\ny\n" > file
+    pijul record -a -m "edit" -A "I"
+    run pijul status -s
+    assert_output ""
+    pijul clone . ../titi
+    [[ $(cat ../titi/file) = $(printf "a\nx\nc\nd\nf\ny") ]]
+}
+
+@test "record with the myers diff" {
+    record_edit myers
+}
+
+@test "record with the patience diff" {
+    record_edit patience
+}
+
+@test "record with the optimal diff" {
+    record_edit optimal
+}
+
+@test "patience diff keeps unique lines" {
+    make_repo toto
+    cd toto
+    echo "diff_algorithm = \"patience\"" >> .pijul/meta.toml
+    printf "fn a() {\n}\nfn b() {\n}\n" > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    printf "fn b() {\n}\nfn a() {\n}\n" > file
+    pijul record -a -m "swap" -A "I"
+    pijul clone . ../titi
+    [[ $(cat ../titi/file) = $(printf "fn b() {\n}\nfn a() {\n}") ]]
+}
+
+@test "record a large file with the myers diff" {
+    make_repo toto
+    cd toto
+    echo "diff_algorithm = \"myers\"" >> .pijul/meta.toml
+    seq 1 100000 > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    seq 1 100000 | sed -e "s/^5000$/x/" -e "/^70000$/d" > file
+    echo y >> file
+    pijul record -a -m "edit" -A "I"
+    run pijul status -s
+    assert_output ""
+    pijul clone . ../titi
+    cmp file ../titi/file
+}
+
+@test "the optimal diff is the default" {
+    make_repo toto
+    cd toto
+    printf "a\nb\nc\n" > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    printf "a\nx\nc\n" > file
+    pijul record -a -m "edit" -A "I"
+    run pijul status -s
+    assert_output ""
+    pijul clone . ../titi
+    [[ $(cat ../titi/file) = $(printf "a\nx\nc") ]]
+}
@@ -0,0 +1,99 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "gc removes the patches of deleted branches" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    pijul fork b
+    echo b >> a
+    pijul record -a -m "b" -A "I"
+    dropped=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+    pijul checkout master
+    pijul delete-branch b
+    [ -f .pijul/patches/$dropped.gz ]
+
+    # The patch is recent, and is kept for the grace period.
+    run pijul gc
+    assert_success "Unregistered 0 patches"
+    [ -f .pijul/patches/$dropped.gz ]
+
+    run pijul gc --grace-period 0
+    assert_success "Unregistered 1 patches"
+    assert_output "Removed 1 patch files"
+    [ ! -f .pijul/patches/$dropped.gz ]
+
+    run pijul status
+    assert_success
+    run pijul check
+    assert_success "No problems found"
+    cd ..
+    pijul clone toto tata
+    cmp toto/a tata/a
+}
+
+@test "gc keeps the patches given with --keep" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    echo b >> a
+    pijul record -a -m "b" -A "I"
+    last=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+    pijul unrecord $last
+    pijul gc --grace-period 0 --keep $last
+    [ -f .pijul/patches/$last.gz ]
+    pijul gc --grace-period 0
+    [ ! -f .pijul/patches/$last.gz ]
+}
+
+@test "gc keeps the dependencies of stashes" {
+    make_repo toto
+    cd toto
+    printf "a\n" > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    printf "a\nb\n" > a
+    pijul record -a -m "b" -A "I"
+    b=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+    printf "a\nb\nc\n" > a
+    pijul stash save
+    pijul unrecord $b
+    pijul revert -a
+
+    run pijul gc --grace-period 0
+    assert_success "Unregistered 0 patches"
+    [ -f .pijul/patches/$b.gz ]
+
+    pijul apply $b
+    pijul stash apply
+    [[ $(cat a) = $(printf "a\nb\nc") ]]
+    run pijul check
+    assert_success "No problems found"
+}
+
+@test "commits during compaction are not lost" {
+    make_repo toto
+    cd toto
+    for i in $(seq 1 10); do
+        echo $i > f$i
+        pijul add f$i
+        pijul record -a -m "$i" -A "I"
+    done
+    pijul gc --grace-period 0 &
+    gc=$!
+    for i in $(seq 11 20); do
+        echo $i > f$i
+        pijul add f$i
+        pijul record -a -m "$i" -A "I"
+    done
+    wait $gc
+    [[ $(pijul log --hash-only | tail -n +2 | wc -l) = 20 ]]
+    [[ -z $(pijul status -s) ]]
+    run pijul check
+    assert_success "No problems found"
+}
@@ -0,0 +1,93 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+make_git_repo() {
+    git init -q $1
+    git -C $1 config user.name "Git User"
+    git -C $1 config user.email "git@example.com"
+}
+
+@test "git import and export" {
+    make_git_repo g
+    echo a > g/file
+    mkdir g/dir
+    echo b > g/dir/other
+    git -C g add file dir/other
+    git -C g commit -q -m "first"
+    echo aa >> g/file
+    git -C g rm -q dir/other
+    git -C g commit -q -m "second"
+
+    pijul init p
+    pijul git import --repository p g
+    assert_files_equal g/file p/file
+    [ ! -e p/dir ]
+    [ "$(pijul log --repository p --hash-only | tail -n +2 | wc -l)" -eq 2 ]
+
+    # Nothing new to import.
+    pijul git import --repository p g
+    [ "$(pijul log --repository p --hash-only | tail -n +2 | wc -l)" -eq 2 ]
+
+    echo aaa >> p/file
+    pijul record --repository p -a -m "from pijul" -A "Me <me@example.com>"
+    pijul git export --repository p g
+    git -C g reset -q --hard
+    assert_files_equal g/file p/file
+    [ "$(git -C g log -1 --format=%s)" = "from pijul" ]
+    [ "$(git -C g log -1 --format=%ae)" = "me@example.com" ]
+
+    # Both sides keep syncing.
+    echo aaaa >> g/file
+    git -C g commit -q -a -m "third"
+    pijul git import --repository p g
+    assert_files_equal g/file p/file
+    [ "$(pijul log --repository p --hash-only | tail -n +2 | wc -l)" -eq 4 ]
+}
+
+@test "git export needs the git branch to be imported" {
+    make_git_repo g
+    echo a > g/file
+    git -C g add file
+    git -C g commit -q -m "first"
+    make_single_file_repo p file
+    run pijul git export --repository p g
+    [ "$status" -ne 0 ]
+}
+
+@test "git import and export symbolic links" {
+    make_git_repo g
+    echo a > g/file
+    ln -s file g/link
+    git -C g add file link
+    git -C g commit -q -m "first"
+
+    pijul init p
+    pijul git import --repository p g
+    [ -L p/link ]
+    [ "$(readlink p/link)" = "file" ]
+
+    ln -s link p/other
+    pijul add --repository p other
+    pijul record --repository p -a -m "other link" -A "Me <me@example.com>"
+    pijul git export --repository p g
+    git -C g reset -q --hard
+    [ -L g/other ]
+    [ "$(readlink g/other)" = "link" ]
+}
+
+@test "git export makes patches non-local" {
+    make_git_repo g
+    echo a > g/file
+    git -C g add file
+    git -C g commit -q -m "first"
+
+    pijul init p
+    pijul git import --repository p g
+    echo b >> p/file
+    pijul record --repository p -a -m "from pijul" -A "Me <me@example.com>"
+    pijul git export --repository p g
+    echo c >> p/file
+    run pijul amend --repository p -a -m "amended"
+    [ $status -ne 0 ]
+}
@@ -0,0 +1,27 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "the working copy is output once the pristine has grown" {
+    make_repo toto
+    cd toto
+    pijul fork other
+    mkdir d
+    for i in $(seq 1 10000); do
+        echo $i > d/$i
+    done
+    pijul add --recursive d
+    pijul record -a -m "many files" -A "I"
+    pijul checkout master
+    [ ! -e d ]
+
+    # Start from a pristine just large enough for its contents, so
+    # that outputting all these files runs out of space.
+    pijul gc
+    RUST_LOG="libpijul::backend=info" pijul checkout other 2> /tmp/log
+    grep "pristine out of space" /tmp/log
+    [[ $(ls d | wc -l) = 10000 ]]
+    [[ $(cat d/1234) = 1234 ]]
+    [[ -z $(pijul status -s) ]]
+    [[ -z $(ls .pijul | grep output) ]]
+}
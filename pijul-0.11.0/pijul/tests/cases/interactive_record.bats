@@ -22,3 +22,67 @@ load ../test_helper
     run pijul status -s
     assert_output "A c"
 }
+
+@test "interactive record, split a change" {
+    make_repo toto
+    cd toto
+    printf "a\nb\nc\n" > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    printf "a\nB\nC\n" > a
+    echo syyyn | pijul record -m "split"
+    pijul revert -a
+    [[ $(cat a) = $(printf "a\nB") ]]
+}
+
+@test "interactive record, split lines depend on the previous ones" {
+    make_repo toto
+    cd toto
+    printf "a\n" > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    printf "a\nb\nc\n" > a
+    echo sn | pijul record -m "split"
+    run pijul status -s
+    assert_output "M a"
+    [[ $(pijul log --hash-only | wc -l) = 1 ]]
+}
+
+@test "interactive record, edit a change" {
+    make_repo toto
+    cd toto
+    printf "a\nb\nc\n" > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    printf "a\nB\nC\n" > a
+    echo e | EDITOR='sed -i s/^+C$/+X/' pijul record -m "edited"
+    pijul revert -a
+    [[ $(cat a) = $(printf "a\nB\nX") ]]
+}
+
+@test "interactive record, edited changes keep the bytes of unedited lines" {
+    make_repo toto
+    cd toto
+    printf "a\r\nb\r\n" > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    printf "a\r\nB\r\nC\r\n" > a
+    echo e | EDITOR='sed -i s/^+C/+X/' pijul record -m "edited"
+    pijul revert -a
+    printf "a\r\nB\r\nX\n" > expected
+    assert_files_equal expected a
+}
+
+@test "interactive record, all changes to a file" {
+    make_repo toto
+    cd toto
+    printf "a\nb\n" > a
+    printf "a\nb\n" > b
+    pijul add a b
+    pijul record -a -m "a" -A "I"
+    printf "x\nb\ny\n" > a
+    printf "x\nb\n" > b
+    echo fny | pijul record -m "b"
+    run pijul status -s
+    assert_output "M a"
+}
@@ -0,0 +1,157 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "large files are stored outside the patches" {
+    make_repo toto
+    cd toto
+    echo "large_file_threshold = 100000" >> .pijul/meta.toml
+    head -c 300000 /dev/urandom > big
+    echo small > small
+    pijul add big small
+    pijul record -a -m "files" -A "I"
+    run pijul status -s
+    assert_output ""
+
+    [[ $(ls .pijul/blobs | wc -l) -eq 1 ]]
+    cmp big .pijul/blobs/*
+    patch=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+    [[ $(stat -c %s .pijul/patches/$patch.gz) -lt 10000 ]]
+
+    pijul clone . ../titi
+    cmp big ../titi/big
+    cmp small ../titi/small
+}
+
+@test "status and diff don't store large files" {
+    make_repo toto
+    cd toto
+    echo "large_file_threshold = 100000" >> .pijul/meta.toml
+    head -c 300000 /dev/urandom > big
+    pijul add big
+    pijul status
+    pijul diff
+    [[ $(ls .pijul/blobs 2> /dev/null | wc -l) -eq 0 ]]
+}
+
+@test "large files keep their permissions" {
+    make_repo toto
+    cd toto
+    echo "large_file_threshold = 100000" >> .pijul/meta.toml
+    head -c 300000 /dev/urandom > big
+    chmod +x big
+    pijul add big
+    pijul record -a -m "big" -A "I"
+    [ ! -x .pijul/blobs/* ]
+
+    pijul clone . ../titi
+    cmp big ../titi/big
+    [ -x ../titi/big ]
+}
+
+@test "missing large files make pulls fail" {
+    make_repo toto
+    cd toto
+    echo "large_file_threshold = 100000" >> .pijul/meta.toml
+    head -c 300000 /dev/urandom > big
+    pijul add big
+    pijul record -a -m "big" -A "I"
+    rm .pijul/blobs/*
+
+    run pijul clone . ../titi
+    [ $status -ne 0 ]
+}
+
+@test "edit and push a large file" {
+    make_repo toto
+    cd toto
+    echo "large_file_patterns = [\"*.bin\"]" >> .pijul/meta.toml
+    head -c 1000 /dev/urandom > file.bin
+    pijul add file.bin
+    pijul record -a -m "file" -A "I"
+    pijul clone . ../titi
+
+    head -c 1000 /dev/urandom > file.bin
+    pijul record -a -m "edit" -A "I"
+    run pijul status -s
+    assert_output ""
+    [[ $(ls .pijul/blobs | wc -l) -eq 2 ]]
+
+    pijul push -a ../titi
+    cmp file.bin ../titi/file.bin
+}
+
+@test "a clone without the configuration keeps large files as blobs" {
+    make_repo toto
+    cd toto
+    echo "large_file_threshold = 100000" >> .pijul/meta.toml
+    head -c 300000 /dev/urandom > big
+    pijul add big
+    pijul record -a -m "big" -A "I"
+
+    pijul clone . ../titi
+    cd ../titi
+    ! grep large_file .pijul/meta.toml
+    run pijul status -s
+    assert_output ""
+
+    head -c 300000 /dev/urandom > big
+    pijul record -a -m "edit" -A "I"
+    patch=$(pijul log --hash-only | sed -n 2p | cut -d: -f 1)
+    [[ $(stat -c %s .pijul/patches/$patch.gz) -lt 10000 ]]
+    [[ $(ls .pijul/blobs | wc -l) -eq 2 ]]
+}
+
+@test "push and pull large files over http, in several parts" {
+    make_repo a
+    echo "large_file_threshold = 100000" >> a/.pijul/meta.toml
+    head -c 3000000 /dev/urandom > a/big
+    pijul add --repository a big
+    pijul record --repository a -a -m "big" -A "I"
+    pijul init b
+
+    pijul serve-http --repository b --address 127.0.0.1:8044 --token secret &
+    SERVER=$!
+    trap "$(trap -p EXIT); kill -9 $SERVER" EXIT
+    sleep 1
+
+    cd a
+    PIJUL_HTTP_TOKEN=secret pijul push -a http://127.0.0.1:8044
+    cd ..
+    cmp a/big b/.pijul/blobs/*
+    pijul clone http://127.0.0.1:8044 c
+    cmp a/big c/big
+}
+
+# For this test to work, `ssh localhost` must connect without asking
+# anything.
+@test "push and pull large files over ssh, in several parts" {
+    make_repo a
+    echo "large_file_threshold = 100000" >> a/.pijul/meta.toml
+    head -c 3000000 /dev/urandom > a/big
+    pijul add --repository a big
+    pijul record --repository a -a -m "big" -A "I"
+    pijul init b
+
+    export HOME=$HOME_BACKUP
+    REMOTE_PIJUL="$PIJUL_EXE" pijul push --repository a -a localhost:$PIJUL_REPO_DIR/b
+    cmp a/big b/.pijul/blobs/*
+    REMOTE_PIJUL="$PIJUL_EXE" pijul clone localhost:$PIJUL_REPO_DIR/b c
+    cmp a/big c/big
+}
+
+@test "stash an edit of a large file" {
+    make_repo toto
+    cd toto
+    echo "large_file_threshold = 100000" >> .pijul/meta.toml
+    head -c 300000 /dev/urandom > big
+    pijul add big
+    pijul record -a -m "big" -A "I"
+    head -c 300000 /dev/urandom > big
+    cp big ../edited
+
+    pijul stash save
+    ! cmp -s big ../edited
+    pijul stash apply
+    cmp big ../edited
+}
@@ -0,0 +1,52 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+count() {
+    pijul log --hash-only "$@" | tail -n +2 | wc -l
+}
+
+hash_of() {
+    pijul log --hash-only --grep "$1" | tail -n 1 | cut -d: -f1
+}
+
+setup_history() {
+    pijul init
+    echo a > a
+    pijul add a
+    pijul record -a -m one -A alice --date 2018-01-01T10:00:00Z
+    echo b > b
+    pijul add b
+    pijul record -a -m two -A bob --date 2018-02-01T10:00:00Z
+    echo aa >> a
+    pijul record -a -m three -A alice --date 2018-03-01T10:00:00Z
+    pijul tag -m release -A carol
+}
+
+@test "log filters on patch headers" {
+    setup_history
+    [[ $(count --author alice) -eq 2 ]]
+    [[ $(count --author 'bob|carol') -eq 2 ]]
+    [[ $(count --since 2018-01-15 --until 2018-02-01) -eq 1 ]]
+    [[ $(count --since 2018-02-01T10:00:00Z --until 2018-03-01) -eq 2 ]]
+    [[ $(count --tags-only) -eq 1 ]]
+    run pijul log --since yesterday
+    [[ $status -ne 0 ]]
+}
+
+@test "log limit and offset" {
+    setup_history
+    [[ $(count --limit 2) -eq 2 ]]
+    [[ $(count --offset 3) -eq 1 ]]
+    [[ $(count --author alice --offset 1 --limit 5) -eq 1 ]]
+    pijul log --hash-only --limit 1 | tail -n 1 | grep ":0$"
+}
+
+@test "log filters on dependencies" {
+    setup_history
+    ONE=$(hash_of one)
+    THREE=$(hash_of three)
+    [[ $(count --dependencies-of $THREE) -eq 2 ]]
+    [[ $(count --depends-on $ONE) -eq 2 ]]
+    [[ $(count --depends-on $ONE --dependencies-of $THREE) -eq 1 ]]
+}
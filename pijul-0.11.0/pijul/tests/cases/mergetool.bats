@@ -0,0 +1,92 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+make_conflict() {
+    mkdir a
+    pijul init a
+    printf "x\ny\n" > a/file
+    pijul add --repository a file
+    pijul record -a --repository a -m "file" -A Alice
+
+    pijul clone a b
+    printf "x\na\ny\n" > a/file
+    pijul record -a --repository a -m "a" -A Alice
+
+    printf "x\nb\ny\n" > b/file
+    pijul record -a --repository b -m "b" -A Bob
+
+    pijul pull -a --repository a b
+}
+
+@test "mergetool records the merged file" {
+    make_conflict
+    pijul mergetool --repository a --tool 'cp $LOCAL $MERGED' -m "resolution" -A resolver
+    ! grep "================================" a/file
+    [[ $(cat a/file) = $(printf "x\na\ny") || $(cat a/file) = $(printf "x\nb\ny") ]]
+    [[ -z $(pijul resolve --repository a --list) ]]
+
+    pijul clone a c
+    assert_files_equal a/file c/file
+}
+
+@test "mergetool gives the base version" {
+    make_conflict
+    pijul mergetool --repository a --tool 'cp $BASE $MERGED' -m "resolution" -A resolver
+    [[ $(cat a/file) = $(printf "x\ny") ]]
+}
+
+@test "mergetool reads the tool from meta.toml" {
+    make_conflict
+    echo 'merge_tool = "cp $REMOTE $MERGED"' >> a/.pijul/meta.toml
+    pijul mergetool --repository a -m "resolution" -A resolver
+    ! grep "================================" a/file
+}
+
+@test "mergetool leaves the conflict if the tool fails" {
+    make_conflict
+    cp a/file before
+    pijul mergetool --repository a --tool 'false $MERGED' -m "resolution" -A resolver
+    assert_files_equal before a/file
+    [[ $(pijul log --repository a --hash-only | wc -l) = 4 ]]
+}
+
+@test "mergetool without a tool fails" {
+    make_conflict
+    run pijul mergetool --repository a
+    [[ $status != 0 ]]
+}
+
+@test "mergetool accepts quoted arguments" {
+    make_conflict
+    mkdir "merge tools"
+    printf '#!/bin/sh\ncp "$1" "$2"\n' > "merge tools/take local"
+    chmod +x "merge tools/take local"
+    pijul mergetool --repository a --tool "\"$PWD/merge tools/take local\" \$LOCAL '\$MERGED'" -m "resolution" -A resolver
+    ! grep "================================" a/file
+}
+
+@test "mergetool skips conflicts with more than two sides" {
+    mkdir a
+    pijul init a
+    printf "x\ny\n" > a/file
+    pijul add --repository a file
+    pijul record -a --repository a -m "file" -A Alice
+    pijul clone a b
+    pijul clone a c
+
+    printf "x\na\ny\n" > a/file
+    pijul record -a --repository a -m "a" -A Alice
+    printf "x\nb\ny\n" > b/file
+    pijul record -a --repository b -m "b" -A Bob
+    printf "x\nc\ny\n" > c/file
+    pijul record -a --repository c -m "c" -A Carol
+    pijul pull -a --repository a b
+    pijul pull -a --repository a c
+
+    cp a/file before
+    run pijul mergetool --repository a --tool 'cp $REMOTE $MERGED' -m "resolution" -A resolver
+    [[ $status = 0 ]]
+    [[ $output = *"more than two sides"* ]]
+    assert_files_equal before a/file
+}
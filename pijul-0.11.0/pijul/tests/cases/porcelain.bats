@@ -0,0 +1,27 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "json output of log, ls and branches" {
+    make_single_file_repo a toto
+    cd a
+    pijul log --format json | grep '"name": "msg"'
+    pijul ls --porcelain | grep '"toto"'
+    pijul branches --format json | grep '"current": true'
+    HASH=$(pijul log --hash-only | tail -n 1 | cut -d: -f1)
+    pijul patch --format json $HASH | grep "\"hash\": \"$HASH\""
+    pijul patch --format json --name $HASH | grep "\"hash\": \"$HASH\""
+    pijul log --format json | grep '"tag": false'
+    pijul show-dependencies --format json | grep "\"hash\": \"$HASH\""
+}
+
+@test "json output of status and diff" {
+    make_single_file_repo a toto
+    cd a
+    echo more >> toto
+    touch newfile
+    pijul status --porcelain | grep '"type": "modified"'
+    pijul status --porcelain | grep '"newfile"'
+    pijul diff --format json | grep '"type": "edit"'
+    pijul diff --format json | grep '"more\\n"'
+}
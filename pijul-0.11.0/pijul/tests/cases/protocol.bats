@@ -0,0 +1,93 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "protocol ends with its input" {
+    make_repo a
+    pijul protocol --repository a < /dev/null
+}
+
+@test "protocol outside a repository" {
+    mkdir a
+    run pijul protocol --repository a < /dev/null
+    [ "$status" -ne 0 ]
+}
+
+@test "protocol agrees on the lowest version" {
+    make_repo a
+    # Hello { version: 1 }, and then Tags, which came in version 2.
+    printf '\x00\x00\x00\x08\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x04\x04\x00\x00\x00' \
+        | pijul protocol --repository a | od -An -tx1 -v | tr -d ' \n' > out
+    # Hello { version: 1 }, and then an error with code 2.
+    [[ $(cut -c 1-24 out) = 000000080000000001000000 ]]
+    [[ $(cut -c 33-44 out) = 050000000200 ]]
+
+    # Hello { version: 100 } gets the version of the server.
+    printf '\x00\x00\x00\x08\x00\x00\x00\x00\x64\x00\x00\x00' \
+        | pijul protocol --repository a | od -An -tx1 -v | tr -d ' \n' > out
+    [[ $(cat out) = 000000080000000004000000 ]]
+}
+
+# For the following tests to work, `ssh localhost` must connect
+# without asking anything.
+
+@test "protocol push and pull over ssh" {
+    make_single_file_repo a toto
+    REMOTE_DIR=$PIJUL_REPO_DIR/remote
+    mkdir $REMOTE_DIR
+
+    export HOME=$HOME_BACKUP
+    REMOTE_PIJUL="$PIJUL_EXE" pijul clone a localhost:$REMOTE_DIR
+    assert_files_equal a/toto $REMOTE_DIR/toto
+
+    append_random a/toto
+    pijul record --repository a -a -m "local" -A me
+    REMOTE_PIJUL="$PIJUL_EXE" pijul push --repository a -a localhost:$REMOTE_DIR
+    assert_files_equal a/toto $REMOTE_DIR/toto
+
+    append_random $REMOTE_DIR/toto
+    pijul record --repository $REMOTE_DIR -a -m "remote" -A me
+    REMOTE_PIJUL="$PIJUL_EXE" pijul pull --repository a -a localhost:$REMOTE_DIR
+    assert_files_equal a/toto $REMOTE_DIR/toto
+}
+
+@test "protocol falls back to remote commands without pijul protocol" {
+    make_single_file_repo a toto
+    make_repo remote
+
+    # A remote Pijul that doesn't know about `pijul protocol`.
+    cat > old_pijul <<END
+#!/bin/sh
+if [ "\$1" = protocol ]; then
+    echo "error: The subcommand 'protocol' wasn't recognized" >&2
+    exit 1
+fi
+exec "$PIJUL_EXE" "\$@"
+END
+    chmod +x old_pijul
+
+    export HOME=$HOME_BACKUP
+    REMOTE_PIJUL="$PIJUL_REPO_DIR/old_pijul" pijul push --repository a -a localhost:$PIJUL_REPO_DIR/remote
+    assert_files_equal a/toto remote/toto
+
+    append_random remote/toto
+    pijul record --repository remote -a -m "remote" -A me
+    REMOTE_PIJUL="$PIJUL_REPO_DIR/old_pijul" pijul pull --repository a -a localhost:$PIJUL_REPO_DIR/remote
+    assert_files_equal a/toto remote/toto
+}
+
+@test "protocol pushes and pulls patches larger than a frame" {
+    make_repo a
+    head -c 20000000 /dev/urandom > a/big
+    pijul add --repository a big
+    pijul record --repository a -a -m "big" -A me
+    REMOTE_DIR=$PIJUL_REPO_DIR/remote
+    mkdir $REMOTE_DIR
+
+    export HOME=$HOME_BACKUP
+    REMOTE_PIJUL="$PIJUL_EXE" pijul clone a localhost:$REMOTE_DIR
+    cmp a/big $REMOTE_DIR/big
+
+    REMOTE_PIJUL="$PIJUL_EXE" pijul clone localhost:$REMOTE_DIR b
+    cmp a/big b/big
+}
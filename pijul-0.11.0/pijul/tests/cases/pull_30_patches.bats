@@ -13,3 +13,43 @@ load ../test_helper
     pijul pull -a --repository b a
     assert_files_equal a/toto b/toto
 }
+
+@test "pull 30 patches over http, with bounded concurrency" {
+    mkdir -p $HOME/.pijulconfig
+    echo 'author = "me"' > $HOME/.pijulconfig/config.toml
+    echo 'download_concurrency = 2' >> $HOME/.pijulconfig/config.toml
+
+    make_single_file_repo a toto
+    for i in {1..30}; do
+        make_random_file a/toto
+        pijul record --repository a -a -m $i -A me
+    done
+
+    pijul serve-http --repository a --address 127.0.0.1:8043 &
+    SERVER=$!
+    trap "$(trap -p EXIT); kill -9 $SERVER" EXIT
+    sleep 1
+
+    pijul clone http://127.0.0.1:8043 b
+    assert_files_equal a/toto b/toto
+}
+
+# For this test to work, `ssh localhost` must connect without asking
+# anything.
+@test "pull 30 patches over ssh, with bounded concurrency" {
+    make_single_file_repo a toto
+    for i in {1..30}; do
+        make_random_file a/toto
+        pijul record --repository a -a -m $i -A me
+    done
+
+    mkdir config
+    echo 'author = "me"' > config/config.toml
+    echo 'download_concurrency = 2' >> config/config.toml
+    export PIJUL_CONFIG_DIR=$PIJUL_REPO_DIR/config
+
+    export HOME=$HOME_BACKUP
+    make_repo b
+    REMOTE_PIJUL="$PIJUL_EXE" pijul pull --repository b -a localhost:$PIJUL_REPO_DIR/a
+    assert_files_equal a/toto b/toto
+}
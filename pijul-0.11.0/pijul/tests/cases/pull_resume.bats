@@ -0,0 +1,33 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "resume a pull" {
+    make_single_file_repo a toto
+    pijul clone a b
+    for i in {1..5}; do
+        make_random_file a/toto
+        pijul record --repository a -a -m $i -A me
+    done
+
+    # Interrupt the pull by hiding the last patch.
+    HASH=$(pijul log --repository a --hash-only | sed -n 2p | cut -d: -f1)
+    mv a/.pijul/patches/$HASH.gz $HASH.gz
+    run pijul pull -a --repository b a
+    [ "$status" -ne 0 ]
+    [ -e b/.pijul/pull_manifest.toml ]
+    mv $HASH.gz a/.pijul/patches/$HASH.gz
+
+    # A truncated patch, left by an interrupted download.
+    head -c 10 a/.pijul/patches/$HASH.gz > b/.pijul/patches/$HASH.gz
+
+    pijul pull --resume --repository b
+    assert_files_equal a/toto b/toto
+    [ ! -e b/.pijul/pull_manifest.toml ]
+}
+
+@test "resume without an interrupted pull" {
+    make_single_file_repo a toto
+    run pijul pull --resume --repository a
+    [ "$status" -ne 0 ]
+}
@@ -0,0 +1,65 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+make_conflict() {
+    mkdir a
+    pijul init a
+    printf "x\ny\n" > a/file
+    pijul add --repository a file
+    pijul record -a --repository a -m "file" -A Alice
+
+    pijul clone a b
+    printf "x\na\ny\n" > a/file
+    pijul record -a --repository a -m "a" -A Alice
+
+    printf "x\nb\ny\n" > b/file
+    pijul record -a --repository b -m "b" -A Bob
+
+    pijul pull -a --repository a b
+}
+
+@test "resolve a conflict by picking a side" {
+    make_conflict
+    pijul resolve --repository a --list | grep "conflicts: file"
+    echo 2 | pijul resolve --repository a -m "resolution" -A resolver
+    [[ $(cat a/file) = $(printf "x\nb\ny") ]]
+    [[ -z $(pijul resolve --repository a --list) ]]
+
+    pijul clone a c
+    assert_files_equal a/file c/file
+}
+
+@test "resolve a conflict by keeping both sides" {
+    make_conflict
+    echo a | pijul resolve --repository a -m "resolution" -A resolver
+    ! grep "================================" a/file
+    grep "^a$" a/file
+    grep "^b$" a/file
+}
+
+@test "skipping a conflict records nothing" {
+    make_conflict
+    echo s | pijul resolve --repository a -m "resolution" -A resolver
+    pijul resolve --repository a --list | grep "conflicts: file"
+    [[ $(pijul log --repository a --hash-only | wc -l) = 4 ]]
+}
+
+@test "resolve a name conflict" {
+    mkdir a b
+    pijul init a
+    echo a > a/file
+    pijul add --repository a file
+    pijul record -a --repository a -m "a" -A Alice
+
+    pijul init b
+    echo b > b/file
+    pijul add --repository b file
+    pijul record -a --repository b -m "b" -A Bob
+
+    pijul pull -a --repository b a
+    pijul resolve --repository b --list | grep "name conflict: file"
+    printf "1\nd\n" | pijul resolve --repository b -m "resolution" -A resolver
+    [[ $(pijul ls --repository b) = "file" ]]
+    [[ -z $(pijul resolve --repository b --list) ]]
+}
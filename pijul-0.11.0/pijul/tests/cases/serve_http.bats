@@ -0,0 +1,49 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+start_server() {
+    pijul serve-http --repository "$1" --address 127.0.0.1:$2 --token secret &
+    SERVER=$!
+    trap "$(trap -p EXIT); kill -9 $SERVER" EXIT
+    sleep 1
+}
+
+@test "partial pull over http" {
+    mkdir a
+    cd a
+    pijul init
+    mkdir x y
+    echo x > x/file
+    echo y > y/file
+    pijul record -a -n -A me -m "x and y"
+    echo yy >> y/file
+    pijul record -a -n -A me -m "y"
+    cd ..
+    start_server a 8041
+
+    pijul init b
+    cd b
+    pijul pull -a http://127.0.0.1:8041 --path x
+    [ "$(ls)" = "x" ]
+    pijul pull -a http://127.0.0.1:8041
+    assert_files_equal y/file ../a/y/file
+}
+
+@test "push over http" {
+    make_single_file_repo a file
+    cd a
+    pijul clone . ../b
+    cd ../b
+    echo more >> file
+    pijul record -a -A me -m "more"
+    cd ..
+    start_server a 8042
+
+    cd b
+    run pijul push -a http://127.0.0.1:8042
+    [ "$status" -ne 0 ]
+
+    PIJUL_HTTP_TOKEN=secret pijul push -a http://127.0.0.1:8042
+    assert_files_equal file ../a/file
+}
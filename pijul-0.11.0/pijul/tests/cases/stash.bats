@@ -0,0 +1,85 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "stash save and apply" {
+    make_repo toto
+    cd toto
+    printf "a\nb\n" > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    printf "a\nx\nb\n" > file
+    pijul stash save -m "work in progress"
+    [[ $(cat file) = $(printf "a\nb") ]]
+    pijul stash list | grep "work in progress"
+
+    pijul stash apply
+    [[ $(cat file) = $(printf "a\nx\nb") ]]
+    run pijul status -s
+    assert_output "M file"
+    [[ $(pijul log --hash-only | tail -n +2 | wc -l) = 1 ]]
+}
+
+@test "stash an added file" {
+    make_repo toto
+    cd toto
+    echo a > a
+    pijul add a
+    pijul record -a -m "a" -A "I"
+    echo b > b
+    pijul add b
+    pijul stash save
+    [[ ! -e b ]]
+    pijul stash apply
+    [[ $(cat b) = b ]]
+    run pijul status -s
+    assert_output "A b"
+}
+
+@test "stash apply refuses to overwrite changes" {
+    make_repo toto
+    cd toto
+    echo a > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    echo b > file
+    pijul stash save
+    echo c > file
+    run pijul stash apply
+    [[ $status != 0 ]]
+    [[ $(cat file) = c ]]
+}
+
+@test "stash apply after the branch changed" {
+    make_repo toto
+    cd toto
+    printf "a\nb\n" > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    printf "a\nx\nb\n" > file
+    pijul stash save
+    printf "a\ny\nb\n" > file
+    pijul record -a -m "y" -A "I"
+    pijul stash apply | grep "Conflict in file"
+    grep "^x$" file
+    grep "^y$" file
+}
+
+@test "stash drop" {
+    make_repo toto
+    cd toto
+    echo a > file
+    pijul add file
+    pijul record -a -m "file" -A "I"
+    echo b > file
+    pijul stash save -m first
+    echo c > file
+    pijul stash save -m second
+    [[ $(pijul stash list | wc -l) = 2 ]]
+    pijul stash list | head -1 | grep second
+    pijul stash drop
+    [[ $(pijul stash list | wc -l) = 1 ]]
+    pijul stash list | grep first
+    run pijul stash drop 3
+    [[ $status != 0 ]]
+}
@@ -28,3 +28,14 @@ load ../test_helper
     echo "$out" > out
     diff -u out $BATS_TEST_DIRNAME/../expected/long_status
 }
+
+@test "status leaves the pristine unchanged" {
+    make_repo toto
+    cd toto
+    echo a > file
+    pijul add file
+    before=$(cat .pijul/pristine/* | md5sum)
+    run pijul status -s
+    assert_output "A file"
+    [ "$(cat .pijul/pristine/* | md5sum)" = "$before" ]
+}
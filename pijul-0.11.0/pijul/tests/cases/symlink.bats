@@ -0,0 +1,50 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "symlinks are recorded and restored" {
+    make_single_file_repo a file.txt
+    ln -s file.txt a/link
+    pijul add --repository a link
+    pijul status --repository a | grep "new symlink:"
+    pijul record -a --repository a -m link -A me
+    pijul clone a b
+    [[ -L b/link ]]
+    [[ $(readlink b/link) = file.txt ]]
+
+    # Changing the target
+    rm a/link
+    ln -s other.txt a/link
+    pijul record -a --repository a -m target -A me
+    pijul pull -a --repository b a
+    [[ -L b/link ]]
+    [[ $(readlink b/link) = other.txt ]]
+}
+
+@test "conflicting symlink targets" {
+    make_single_file_repo a file.txt
+    ln -s file.txt a/link
+    pijul add --repository a link
+    pijul record -a --repository a -m link -A me
+    pijul clone a b
+
+    rm a/link
+    ln -s one a/link
+    pijul record -a --repository a -m one -A me
+    rm b/link
+    ln -s two b/link
+    pijul record -a --repository b -m two -A me
+
+    pijul pull -a --repository b a
+    # A link can't hold conflict markers, it points to one of the
+    # targets.
+    [[ -L b/link ]]
+    TARGET=$(readlink b/link)
+    [[ $TARGET = one || $TARGET = two ]]
+
+    # Recording the link resolves the conflict.
+    pijul record -a --repository b -m resolve -A me
+    pijul pull -a --repository a b
+    [[ -L a/link ]]
+    [[ $(readlink a/link) = $TARGET ]]
+}
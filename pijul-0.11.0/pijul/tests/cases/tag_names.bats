@@ -0,0 +1,53 @@
+#!/usr/bin/env bats
+
+load ../test_helper
+
+@test "list tags and check out a tag" {
+    make_single_file_repo a toto
+    cp a/toto v1_toto
+    pijul tag --repository a -m v1 -A me
+    make_random_file a/toto
+    pijul record --repository a -a -m more -A me
+
+    pijul tag --repository a --list | grep " v1$"
+    cd a
+    pijul checkout --tag v1 release
+    [[ $(pijul branches | grep -c release) -eq 1 ]]
+    cd ..
+    assert_files_equal v1_toto a/toto
+    run pijul checkout --repository a --tag v2
+    [[ $status -ne 0 ]]
+}
+
+@test "pull and push tags" {
+    make_single_file_repo a toto
+    pijul tag --repository a -m v1 -A me
+    make_random_file a/toto
+    pijul record --repository a -a -m more -A me
+    pijul init b
+    pijul init c
+
+    cd b
+    pijul pull -a ../a --tag v1
+    pijul tag --list | grep " v1$"
+    [[ $(pijul log --hash-only | tail -n +2 | wc -l) -eq 2 ]]
+    cd ..
+
+    cd a
+    pijul push -a ../c --tag v1
+    cd ..
+    pijul tag --repository c --list | grep " v1$"
+    [[ $(pijul log --repository c --hash-only | tail -n +2 | wc -l) -eq 2 ]]
+}
+
+@test "apply tags" {
+    make_single_file_repo a toto
+    pijul tag --repository a -m v1 -A me
+    pijul init b
+    pijul log --repository a --hash-only | tail -n +2 | cut -d: -f1 > hashes
+    for h in $(cat hashes); do
+        cp a/.pijul/patches/$h.* b/.pijul/patches/
+    done
+    pijul apply --repository b $(cat hashes)
+    pijul tag --repository b --list | grep " v1$"
+}